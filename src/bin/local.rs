@@ -36,9 +36,10 @@ extern crate log;
 use getopts::{optopt, optflag, getopts, usage};
 
 use std::os;
+use std::sync::Arc;
+use std::io::net::ip::SocketAddr;
 
 use shadowsocks::config::{Config, ServerConfig, ClientConfig, self};
-use shadowsocks::config::DEFAULT_DNS_CACHE_CAPACITY;
 use shadowsocks::relay::{RelayLocal, Relay};
 
 fn main() {
@@ -53,6 +54,11 @@ fn main() {
         optopt("p", "server-port", "server port", ""),
         optopt("l", "local-port", "local socks5 proxy port", ""),
         optopt("m", "encrypt-method", "entryption method", "aes-256-cfb"),
+        optopt("", "acl", "path to access control list", "file.acl"),
+        optopt("", "metrics-address", "address serving metrics for Prometheus", "127.0.0.1:9100"),
+        optopt("", "drain-timeout", "seconds for connections to finish when shutting down", "30"),
+        optopt("", "plugin", "SIP003 plugin of the server", "obfs-local"),
+        optopt("", "plugin-opts", "options of the plugin", ""),
     ];
 
     let matches = getopts(os::args().tail(), &opts).unwrap();
@@ -82,25 +88,29 @@ fn main() {
             Config::new()
         };
 
+    // Servers given on the command line are kept when the configuration file is reloaded
+    let mut cli_servers = Vec::new();
     if matches.opt_present("s") && matches.opt_present("p") && matches.opt_present("k") && matches.opt_present("m") {
         let addr_str = matches.opt_str("s").unwrap();
-        let sc = ServerConfig {
-            addr: addr_str,
-            port: matches.opt_str("p").unwrap().as_slice().parse().expect("`port` should be an integer"),
-            password: matches.opt_str("k").unwrap(),
-            method: match matches.opt_str("m") {
-                Some(method_s) => {
-                    match method_s.parse() {
-                        Some(m) => m,
-                        None => panic!("`{}` is not a supported method", method_s),
-                    }
-                },
-                None => panic!("failed to get method string"),
+        let port = matches.opt_str("p").unwrap().as_slice().parse().expect("`port` should be an integer");
+        let method = match matches.opt_str("m") {
+            Some(method_s) => {
+                match method_s.parse() {
+                    Some(m) => m,
+                    None => panic!("`{}` is not a supported method", method_s),
+                }
             },
-            timeout: None,
-            dns_cache_capacity: DEFAULT_DNS_CACHE_CAPACITY,
+            None => panic!("failed to get method string"),
         };
-        config.server.push(sc);
+        let mut sc = ServerConfig::new(addr_str, port, matches.opt_str("k").unwrap(), method);
+        sc.plugin = matches.opt_str("plugin");
+        sc.plugin_opts = matches.opt_str("plugin-opts");
+        if let Err(err) = sc.validate() {
+            error!("{:?}", err);
+            return;
+        }
+        config.server.push(sc.clone());
+        cli_servers.push(sc);
     } else if !matches.opt_present("s") && !matches.opt_present("b")
             && !matches.opt_present("k") && !matches.opt_present("m") {
         // Do nothing
@@ -118,9 +128,36 @@ fn main() {
 
     config.enable_udp = matches.opt_present("u");
 
+    if let Some(addr) = matches.opt_str("metrics-address") {
+        match addr.parse::<SocketAddr>() {
+            Some(addr) => config.metrics = Some(addr),
+            None => panic!("`{}` is not a valid metrics address", addr),
+        }
+    }
+
+    if let Some(timeout) = matches.opt_str("drain-timeout") {
+        let timeout: u64 = timeout.as_slice().parse().expect("`drain-timeout` should be an integer");
+        config.drain_timeout = timeout * 1000;
+    }
+
+    if let Some(path) = matches.opt_str("acl") {
+        match config::load_acl(path.as_slice()) {
+            Ok(acl) => config.acl = Some(Arc::new(acl)),
+            Err(err) => {
+                error!("{:?}", err);
+                return;
+            }
+        }
+    }
+
     info!("ShadowSocks {:?}", shadowsocks::VERSION);
 
     debug!("Config: {:?}", config);
 
-    RelayLocal::new(config).run();
+    let local = RelayLocal::new(config);
+    if let Some(path) = matches.opt_str("c") {
+        local.reload_on_hangup(path, cli_servers);
+    }
+    local.shutdown_handle().shutdown_on_terminate();
+    local.run();
 }
@@ -37,6 +37,59 @@
 //! }
 //! ```
 //!
+//! Servers remember IVs of recent requests to reject replayed ones, the number of IVs could
+//! be tuned with `"replay_filter_capacity": 1000000`.
+//!
+//! Servers refuse to connect to loopback, private and link-local addresses for clients, which
+//! could be allowed with `"block_private_outbound": false`.
+//!
+//! `sslocal` could also serve as an HTTP proxy, which is enabled by `"local_http_address"` and
+//! `"local_http_port"`.
+//!
+//! The local address serves SOCKS5 by default, set `"local_mode": "redir"` to serve as a
+//! transparent proxy, which is what `ssredir` does.
+//!
+//! Tunnels forward everything received on a local address to a fixed address through the
+//! servers, for both TCP and UDP (if UDP relay is enabled):
+//!
+//! ```ignore
+//! {
+//!     "tunnels": [
+//!         {
+//!             "local_address": "127.0.0.1",
+//!             "local_port": 5353,
+//!             "forward_address": "8.8.8.8",
+//!             "forward_port": 53
+//!         }
+//!     ]
+//! }
+//! ```
+//!
+//! The DNS forwarder resolves names through the servers, except names in the direct domains,
+//! which are sent to a resolver directly (the first `nameserver` of `/etc/resolv.conf` by default):
+//!
+//! ```ignore
+//! {
+//!     "local_dns_address": "127.0.0.1",
+//!     "local_dns_port": 53,
+//!     "local_dns_upstream_address": "8.8.8.8",
+//!     "local_dns_upstream_port": 53,
+//!     "local_dns_direct_domains": ["lan", "example.com"],
+//!     "local_dns_direct_resolver": "192.168.1.1"
+//! }
+//! ```
+//!
+//! SOCKS5 clients of `sslocal` have to authenticate with username and password if any user is
+//! defined, either by `"local_users": [{"username": "alice", "password": "secret"}]` or by
+//! `"local_users_file"`, a file with a `username:password` on every line. Clients of the HTTP
+//! proxy send the same credentials in `Proxy-Authorization`.
+//!
+//! `"acl"` is the path of an access control list in the format of shadowsocks-libev, which
+//! decides what `sslocal` connects directly, and what `ssserver` refuses to connect.
+//!
+//! Connections are relayed by a pool of event loops, which has one thread for every CPU by
+//! default, it could be changed with `"workers": 4`.
+//!
 //! But this configuration is not for using multiple shadowsocks server, so we
 //! introduce an extended configuration file format:
 //!
@@ -62,25 +115,153 @@
 //! }
 //! ```
 //!
-//! These defined server will be used with a load balancing algorithm.
+//! These defined server will be used with a load balancing algorithm, which is round robin by
+//! default. With `"load_balancing": "weighted_round_robin"`, servers are picked in proportion to
+//! their `"weight"` (1 to 100, 1 by default). With `"load_balancing": "consistent_hash"`, connections
+//! to the same host are relayed by the same server, or connections of the same client with
+//! `"consistent_hash_key": "client"`; servers also have shares of hosts in proportion to their
+//! weights.
+//!
+//! With `"health_check": true`, `sslocal` probes every server in the background by requesting
+//! an HTTP server through it, servers failing to respond are not used until they recover, and
+//! the healthy server with the lowest latency is preferred instead of `"load_balancing"`:
+//!
+//! ```ignore
+//! {
+//!     "health_check": true,
+//!     "health_check_address": "www.google.com",
+//!     "health_check_port": 80,
+//!     "health_check_interval": 10,
+//!     "health_check_timeout": 5,
+//!     "health_check_fall": 3,
+//!     "health_check_rise": 2
+//! }
+//! ```
+//!
+//! If connecting through a server fails, or the server closes the connection before responding,
+//! `sslocal` sends the request again through another server, until `"retry_attempts": 3` servers
+//! have been tried or `"retry_timeout": 10` seconds have passed since the client connected.
+//!
+//! `ssserver` could also be managed by the protocol of `ss-manager` of shadowsocks-libev, which adds
+//! and removes servers while running. Servers added without `method` use the `"method"` here:
+//!
+//! ```ignore
+//! {
+//!     "manager_address": "127.0.0.1:6001",
+//!     "manager_server_address": "0.0.0.0",
+//!     "manager_report_interval": 10,
+//!     "method": "aes-256-gcm"
+//! }
+//! ```
+//!
+//! A server in `"servers"` could be shared by multiple users with their own passwords, then
+//! `"password"` is only needed by ciphers of the 2022 edition as the identity key (iPSK):
+//!
+//! ```ignore
+//! {
+//!     "address": "0.0.0.0",
+//!     "port": 8388,
+//!     "method": "aes-256-gcm",
+//!     "users": [
+//!         {"name": "alice", "password": "alice-password"},
+//!         {"name": "bob", "password": "bob-password"}
+//!     ]
+//! }
+//! ```
+//!
+//! Clients of a multi-user server of the 2022 edition set `"password"` to `"iPSK:uPSK"`, users of
+//! other AEAD ciphers are identified by trying their keys, so they only need their own passwords.
+//!
+//! Relaying could be limited in bytes per second, uploading and downloading separately. Limits of
+//! the whole process are `"rate_limit"`, limits of every connection are `"connection_rate_limit"`,
+//! and servers in `"servers"` could have their own `"rate_limit"` of all of their connections:
+//!
+//! ```ignore
+//! {
+//!     "rate_limit": {"upload": 10485760, "download": 10485760},
+//!     "connection_rate_limit": {"download": 1048576}
+//! }
+//! ```
+//!
+//! Servers and users could have a `"quota"` of bytes transferred in both directions, connections
+//! are closed and refused once it is exceeded. Traffic is saved to `"traffic_state_file"` every
+//! minute, and loaded from it on start, so quotas are kept across restarts.
+//!
+//! Both `sslocal` and `ssserver` could serve metrics in the Prometheus text format over HTTP at
+//! `"metrics_address": "127.0.0.1:9100"`.
+//!
+//! When they are shut down by SIGTERM or SIGINT, established TCP connections are given
+//! `"drain_timeout": 30` seconds to finish before they are closed.
+//!
+//! Servers could be wrapped by a SIP003 plugin such as `obfs-local` on `sslocal` and
+//! `obfs-server` on `ssserver`, which is a path or a name in `PATH`, with its options:
+//!
+//! ```ignore
+//! {
+//!     "plugin": "obfs-local",
+//!     "plugin_opts": "obfs=http;obfs-host=www.example.com"
+//! }
+//! ```
 //!
 
 use serialize::json;
 
 use std::io::{File, Read, Open};
-use std::io::net::ip::{Port, SocketAddr};
+use std::io::net::ip::{IpAddr, Port, SocketAddr};
+use std::collections::BTreeMap;
+use std::sync::Arc;
 use std::string::ToString;
 use std::option::Option;
 use std::default::Default;
-use std::fmt::{Debug, Formatter, self};
+use std::fmt::{Debug, Display, Formatter, self};
+use std::str::FromStr;
+use std::os;
+use std::ascii::AsciiExt;
 
-use crypto::cipher::CipherType;
+use crypto::cipher::{CipherType, CipherCategory};
+use crypto::aead2022;
+use relay::replay_filter::DEFAULT_REPLAY_FILTER_CAPACITY;
+use relay::socks5::Address;
+use relay::acl::AccessControl;
 
 /// Default DNS cache capacity
 pub const DEFAULT_DNS_CACHE_CAPACITY: usize = 65536;
 
+/// Default resolver queried by the DNS forwarder through the servers
+pub const DEFAULT_DNS_UPSTREAM_ADDRESS: &'static str = "8.8.8.8";
+
+const DNS_PORT: Port = 53;
+
+/// Default target requested through servers by health checks
+pub const DEFAULT_HEALTH_CHECK_ADDRESS: &'static str = "www.google.com";
+pub const DEFAULT_HEALTH_CHECK_PORT: Port = 80;
+
+/// Default seconds between two rounds of health checks
+pub const DEFAULT_HEALTH_CHECK_INTERVAL: u64 = 10;
+
+/// Default seconds before a health check fails
+pub const DEFAULT_HEALTH_CHECK_TIMEOUT: u64 = 5;
+
+/// Default number of servers tried by a connection of `sslocal`
+pub const DEFAULT_RETRY_ATTEMPTS: usize = 3;
+
+/// Default seconds for retrying a connection through other servers
+pub const DEFAULT_RETRY_TIMEOUT: u64 = 10;
+
+/// Default seconds for established connections to finish after shutting down
+pub const DEFAULT_DRAIN_TIMEOUT: u64 = 30;
+
+/// Maximum weight of a server
+pub const MAX_SERVER_WEIGHT: u64 = 100;
+
+/// Default listening address of servers added by the manager
+pub const DEFAULT_MANAGER_SERVER_ADDRESS: &'static str = "0.0.0.0";
+
+/// Default seconds between two reports of traffic to the manager
+pub const DEFAULT_MANAGER_REPORT_INTERVAL: u64 = 10;
+
 /// Configuration for a server
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct ServerConfig {
     pub addr: String,
     pub port: Port,
@@ -88,11 +269,276 @@ pub struct ServerConfig {
     pub method: CipherType,
     pub timeout: Option<u64>,
     pub dns_cache_capacity: usize,
+    /// Share of connections relative to other servers, for weighted load balancing
+    pub weight: u32,
+    /// Users sharing the port of a multi-user server, each of them has its own password
+    pub users: Vec<UserConfig>,
+    /// Bytes the port could transfer in both directions, unlimited if it is `None`
+    pub quota: Option<usize>,
+    /// Rates of all connections of the port
+    pub rate_limit: RateLimitConfig,
+    /// SIP003 plugin which TCP connections go through, a path or a name in `PATH`
+    pub plugin: Option<String>,
+    /// Options of the plugin, in `SS_PLUGIN_OPTIONS`
+    pub plugin_opts: Option<String>,
+}
+
+/// Rates of relaying in bytes per second, which are unlimited if they are `None`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    /// From clients to targets
+    pub upload: Option<usize>,
+    /// From targets to clients
+    pub download: Option<usize>,
+}
+
+/// A user of a multi-user server
+#[derive(Clone, Debug, PartialEq)]
+pub struct UserConfig {
+    pub name: String,
+    /// The uPSK for ciphers of the 2022 edition
+    pub password: String,
+    /// Bytes the user could transfer in both directions, unlimited if it is `None`
+    pub quota: Option<usize>,
+}
+
+impl UserConfig {
+    /// Key of this user for the method of the server
+    pub fn key(&self, method: CipherType) -> Vec<u8> {
+        match method.category() {
+            CipherCategory::Aead2022 =>
+                aead2022::decode_psk(method, self.password.as_slice()).expect("invalid pre-shared key"),
+            _ => method.bytes_to_key(self.password.as_bytes()),
+        }
+    }
+
+    /// Checks the user for a multi-user server with `method`
+    pub fn validate(&self, method: CipherType) -> Result<(), Error> {
+        if self.name.is_empty() {
+            return Err(Error::new(ErrorKind::Invalid, "users should have names", None));
+        }
+        if method.category() == CipherCategory::Aead2022
+                && aead2022::decode_psk(method, self.password.as_slice()).is_none() {
+            return Err(Error::new(ErrorKind::Invalid,
+                                  "invalid pre-shared key",
+                                  Some(format!("`password` of user `{}` should be a base64 encoded key of {} bytes",
+                                               self.name, method.key_size()))));
+        }
+        Ok(())
+    }
+}
+
+impl ServerConfig {
+    /// A server with one password and the default settings, other settings could be changed on
+    /// the returned value
+    pub fn new(addr: String, port: Port, password: String, method: CipherType) -> ServerConfig {
+        ServerConfig {
+            addr: addr,
+            port: port,
+            password: password,
+            method: method,
+            timeout: None,
+            dns_cache_capacity: DEFAULT_DNS_CACHE_CAPACITY,
+            weight: 1,
+            users: Vec::new(),
+            quota: None,
+            rate_limit: Default::default(),
+            plugin: None,
+            plugin_opts: None,
+        }
+    }
+
+    /// Checks the configuration, ciphers of the 2022 edition require `password` to be
+    /// a base64 encoded pre-shared key with exactly the key length of the method.
+    ///
+    /// Clients of a multi-user server of the 2022 edition set `password` to `iPSK:uPSK`.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.method.category() == CipherCategory::Aead2022 {
+            let psks = self.password.as_slice().split(':').collect::<Vec<&str>>();
+            if psks.len() > 2 || psks.iter().any(|psk| aead2022::decode_psk(self.method, *psk).is_none()) {
+                return Err(Error::new(ErrorKind::Invalid,
+                                      "invalid pre-shared key",
+                                      Some(format!("`password` of server {}:{} should be a base64 encoded key of {} \
+                                                    bytes, or `iPSK:uPSK` of two keys",
+                                                   self.addr, self.port, self.method.key_size()))));
+            }
+            if psks.len() == 2 && !aead2022::supports_identity(self.method) {
+                return Err(Error::new(ErrorKind::Invalid,
+                                      "identity headers are not supported by the method",
+                                      Some(format!("server {}:{} has `iPSK:uPSK` as `password`",
+                                                   self.addr, self.port))));
+            }
+        }
+
+        if !self.users.is_empty() {
+            // Users are identified by authenticating their requests
+            let supported = match self.method.category() {
+                CipherCategory::Stream => false,
+                CipherCategory::Aead => true,
+                CipherCategory::Aead2022 => aead2022::supports_identity(self.method),
+            };
+            if !supported {
+                return Err(Error::new(ErrorKind::Invalid,
+                                      "multiple users are not supported by the method",
+                                      Some(format!("server {}:{} with {:?}", self.addr, self.port, self.method))));
+            }
+
+            for (i, user) in self.users.iter().enumerate() {
+                try!(user.validate(self.method));
+                if self.users[..i].iter().any(|u| u.name == user.name) {
+                    return Err(Error::new(ErrorKind::Invalid, "duplicated user", Some(user.name.clone())));
+                }
+            }
+        }
+
+        match self.plugin {
+            Some(ref plugin) if plugin.is_empty() => {
+                return Err(Error::new(ErrorKind::Invalid,
+                                      "`plugin` should not be empty",
+                                      Some(format!("server {}:{}", self.addr, self.port))));
+            },
+            None if self.plugin_opts.is_some() => {
+                return Err(Error::new(ErrorKind::Invalid,
+                                      "`plugin_opts` is given without `plugin`",
+                                      Some(format!("server {}:{}", self.addr, self.port))));
+            },
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Master key of this server, or the pre-shared key for ciphers of the 2022 edition,
+    /// which is the uPSK if `password` is `iPSK:uPSK`
+    pub fn key(&self) -> Vec<u8> {
+        match self.method.category() {
+            CipherCategory::Aead2022 => {
+                let psk = self.password.as_slice().split(':').last().unwrap();
+                aead2022::decode_psk(self.method, psk).expect("invalid pre-shared key")
+            },
+            _ => self.method.bytes_to_key(self.password.as_bytes()),
+        }
+    }
+
+    /// The iPSK of a multi-user server of the 2022 edition, if `password` is `iPSK:uPSK`
+    pub fn identity_key(&self) -> Option<Vec<u8>> {
+        if self.method.category() != CipherCategory::Aead2022 {
+            return None;
+        }
+        self.password.as_slice().find(':').map(|pos| {
+            aead2022::decode_psk(self.method, &self.password[..pos]).expect("invalid pre-shared key")
+        })
+    }
 }
 
 /// Listening address
 pub type ClientConfig = SocketAddr;
 
+/// Protocol served on the local listening address
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LocalMode {
+    /// SOCKS5 proxy, which also accepts SOCKS4 and SOCKS4a
+    Socks5,
+    /// Transparent proxy for TCP connections redirected by `REDIRECT` and UDP datagrams
+    /// redirected by `TPROXY` of iptables or nftables (Linux only)
+    Redir,
+}
+
+/// A local address which forwards all connections and datagrams to a fixed address
+#[derive(Clone, Debug)]
+pub struct TunnelConfig {
+    pub local: ClientConfig,
+    pub forward: Address,
+}
+
+/// The DNS forwarder
+#[derive(Clone, Debug)]
+pub struct DnsConfig {
+    /// Listening address of both UDP and TCP
+    pub local: ClientConfig,
+    /// Resolver which is queried through the servers
+    pub upstream: Address,
+    /// Names in these domains are resolved by `direct_resolver` without the servers
+    pub direct_domains: Vec<String>,
+    pub direct_resolver: Option<SocketAddr>,
+}
+
+/// Health checks of servers by `sslocal`
+#[derive(Clone, Debug)]
+pub struct HealthCheckConfig {
+    /// An HTTP server which is requested through the servers
+    pub target: Address,
+    /// Milliseconds between two rounds of checks
+    pub interval: u64,
+    /// Milliseconds before a check fails
+    pub timeout: u64,
+    /// A server is not used after failing this many checks in a row
+    pub fall: usize,
+    /// A failed server is used again after passing this many checks in a row
+    pub rise: usize,
+}
+
+/// How `sslocal` picks a server for a connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadBalancing {
+    RoundRobin,
+    /// Servers are picked in proportion to their weights
+    WeightedRoundRobin,
+    /// The same key is relayed by the same server, as long as it works
+    ConsistentHash(HashKey),
+}
+
+/// What consistent hashing is keyed by
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashKey {
+    /// Host of the target, which is the domain name or the IP address
+    Target,
+    /// IP address of the client
+    Client,
+}
+
+/// Where the manager of `ssserver` receives commands
+#[derive(Clone, Debug, PartialEq)]
+pub enum ManagerAddress {
+    SocketAddress(SocketAddr),
+    /// Path of a Unix datagram socket
+    UnixSocket(Path),
+}
+
+impl FromStr for ManagerAddress {
+    /// `host:port` for UDP, or a path with `/` for Unix sockets
+    fn from_str(s: &str) -> Option<ManagerAddress> {
+        if s.contains("/") {
+            Some(ManagerAddress::UnixSocket(Path::new(s)))
+        } else {
+            s.parse().map(ManagerAddress::SocketAddress)
+        }
+    }
+}
+
+impl Display for ManagerAddress {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            ManagerAddress::SocketAddress(ref addr) => write!(f, "{}", addr),
+            ManagerAddress::UnixSocket(ref path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// The manager of `ssserver`, which adds and removes servers while running
+#[derive(Clone, Debug)]
+pub struct ManagerConfig {
+    pub addr: ManagerAddress,
+    /// Listening address of servers added by the manager
+    pub server_addr: String,
+    /// Method of servers added without `method`
+    pub method: Option<CipherType>,
+    /// Milliseconds between two reports of traffic
+    pub report_interval: u64,
+    /// Timeout of servers added by the manager, milliseconds
+    pub timeout: Option<u64>,
+}
+
 #[derive(Clone, Copy)]
 pub enum ConfigType {
     Local,
@@ -104,8 +550,39 @@ pub enum ConfigType {
 pub struct Config {
     pub server: Vec<ServerConfig>,
     pub local: Option<ClientConfig>,
+    pub local_mode: LocalMode,
+    /// Listening address of the HTTP proxy
+    pub local_http: Option<ClientConfig>,
+    /// Usernames and passwords of SOCKS5 clients, authentication is disabled if it is empty
+    pub local_users: BTreeMap<String, String>,
+    pub tunnels: Vec<TunnelConfig>,
+    pub local_dns: Option<DnsConfig>,
+    pub load_balancing: LoadBalancing,
+    pub health_check: Option<HealthCheckConfig>,
+    /// Number of servers tried by a connection, including the first one
+    pub retry_attempts: usize,
+    /// Milliseconds since a client connected, after which its connection is not retried
+    pub retry_timeout: u64,
+    /// Access control list, which is shared by all relays
+    pub acl: Option<Arc<AccessControl>>,
     pub enable_udp: bool,
     pub timeout: Option<u64>,
+    pub replay_filter_capacity: usize,
+    /// Whether servers refuse to connect to private addresses
+    pub block_private_outbound: bool,
+    /// Number of event loop threads for relaying TCP connections
+    pub workers: usize,
+    pub manager: Option<ManagerConfig>,
+    /// Rates of the whole process
+    pub rate_limit: RateLimitConfig,
+    /// Rates of every connection
+    pub connection_rate_limit: RateLimitConfig,
+    /// File where traffic of servers and users is saved, so that it survives restarts
+    pub traffic_state_file: Option<Path>,
+    /// Listening address of the HTTP server of metrics
+    pub metrics: Option<SocketAddr>,
+    /// Milliseconds for established connections to finish after shutting down
+    pub drain_timeout: u64,
 }
 
 impl Default for Config {
@@ -164,13 +641,373 @@ macro_rules! try_config(
     );
 );
 
+fn add_user(users: &mut BTreeMap<String, String>, name: &str, passwd: &str) -> Result<(), Error> {
+    // Both of them are sent with a single byte of length
+    if name.is_empty() || name.len() > 255 || passwd.is_empty() || passwd.len() > 255 {
+        return Err(Error::new(ErrorKind::Invalid,
+                              "username and password should have 1 to 255 bytes",
+                              Some(format!("user `{}`", name))));
+    }
+
+    users.insert(name.to_string(), passwd.to_string());
+    Ok(())
+}
+
+/// Reads SOCKS5 users from a file, which has one `username:password` on every line.
+/// Empty lines and lines starting with `#` are ignored.
+fn load_users_file(path: &str, users: &mut BTreeMap<String, String>) -> Result<(), Error> {
+    let content = match File::open(&Path::new(path)).and_then(|mut f| f.read_to_string()) {
+        Ok(content) => content,
+        Err(err) => return Err(Error::new(ErrorKind::IoError,
+                                          "error while reading `local_users_file`",
+                                          Some(err.to_string()))),
+    };
+
+    for line in content.as_slice().lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("#") {
+            continue;
+        }
+
+        match line.find(':') {
+            Some(pos) => try!(add_user(users, &line[..pos], &line[pos + 1..])),
+            None => return Err(Error::new(ErrorKind::Malformed,
+                                          "lines of `local_users_file` should be `username:password`",
+                                          None)),
+        }
+    }
+    Ok(())
+}
+
+fn parse_users(o: &json::Json) -> Result<Vec<UserConfig>, Error> {
+    let users = match o.find("users") {
+        Some(users) => try_config!(users.as_array(), ErrorKind::Malformed, "`users` should be an array"),
+        None => return Ok(Vec::new()),
+    };
+
+    let mut result = Vec::with_capacity(users.len());
+    for user in users.iter() {
+        let name = try_config!(user.find("name").and_then(|n| n.as_string()),
+                               ErrorKind::Malformed,
+                               "`name` of `users` should be a string");
+        let password = try_config!(user.find("password").and_then(|p| p.as_string()),
+                                   ErrorKind::Malformed,
+                                   "`password` of `users` should be a string");
+        result.push(UserConfig {
+            name: name.to_string(),
+            password: password.to_string(),
+            quota: try!(parse_quota(user.find("quota"))),
+        });
+    }
+    Ok(result)
+}
+
+fn parse_rate(o: &json::Object, key: &str) -> Result<Option<usize>, Error> {
+    match o.get(&key.to_string()) {
+        Some(r) => match r.as_u64() {
+            Some(r) if r > 0 => Ok(Some(r as usize)),
+            _ => Err(Error::new(ErrorKind::Malformed,
+                                "rates should be positive integers of bytes per second",
+                                Some(format!("`{}`", key)))),
+        },
+        None => Ok(None),
+    }
+}
+
+fn parse_rate_limit(o: Option<&json::Json>) -> Result<RateLimitConfig, Error> {
+    match o {
+        Some(o) => {
+            let o = try_config!(o.as_object(), ErrorKind::Malformed, "`rate_limit` should be an object");
+            Ok(RateLimitConfig {
+                upload: try!(parse_rate(o, "upload")),
+                download: try!(parse_rate(o, "download")),
+            })
+        },
+        None => Ok(Default::default()),
+    }
+}
+
+fn parse_plugin_field(v: Option<&json::Json>, key: &str) -> Result<Option<String>, Error> {
+    match v {
+        Some(v) => match v.as_string() {
+            Some(v) => Ok(Some(v.to_string())),
+            None => Err(Error::new(ErrorKind::Malformed, "plugins should be strings", Some(format!("`{}`", key)))),
+        },
+        None => Ok(None),
+    }
+}
+
+fn parse_quota(quota: Option<&json::Json>) -> Result<Option<usize>, Error> {
+    match quota {
+        Some(quota) => match quota.as_u64() {
+            Some(quota) if quota > 0 => Ok(Some(quota as usize)),
+            _ => Err(Error::new(ErrorKind::Malformed, "`quota` should be a positive integer of bytes", None)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Loads the access control list at `path`
+pub fn load_acl(path: &str) -> Result<AccessControl, Error> {
+    AccessControl::load_from_file(path).map_err(|err| {
+        Error::new(ErrorKind::Invalid, "error while loading `acl`", Some(err.to_string()))
+    })
+}
+
+fn parse_tunnel(o: &json::Json) -> Result<TunnelConfig, Error> {
+    let o = try_config!(o.as_object(), ErrorKind::Malformed, "`tunnels` should be an array of objects");
+
+    let addr_str = try_config!(try_config!(o.get(&"local_address".to_string()),
+                                           ErrorKind::MissingField,
+                                           "need to specify `local_address` of a tunnel").as_string(),
+                               ErrorKind::Malformed,
+                               "`local_address` should be a string");
+    let ip = try_config!(addr_str.parse(),
+                         ErrorKind::Malformed,
+                         "`local_address` is not a valid IP address");
+    let port = try_config!(try_config!(o.get(&"local_port".to_string()),
+                                       ErrorKind::MissingField,
+                                       "need to specify `local_port` of a tunnel").as_u64(),
+                           ErrorKind::Malformed,
+                           "`local_port` should be an integer") as Port;
+
+    let forward_str = try_config!(try_config!(o.get(&"forward_address".to_string()),
+                                              ErrorKind::MissingField,
+                                              "need to specify `forward_address` of a tunnel").as_string(),
+                                  ErrorKind::Malformed,
+                                  "`forward_address` should be a string");
+    let forward_port = try_config!(try_config!(o.get(&"forward_port".to_string()),
+                                               ErrorKind::MissingField,
+                                               "need to specify `forward_port` of a tunnel").as_u64(),
+                                   ErrorKind::Malformed,
+                                   "`forward_port` should be an integer") as Port;
+
+    Ok(TunnelConfig {
+        local: SocketAddr {
+            ip: ip,
+            port: port,
+        },
+        forward: try!(make_address(forward_str, forward_port)),
+    })
+}
+
+fn make_address(addr: &str, port: Port) -> Result<Address, Error> {
+    match addr.parse::<IpAddr>() {
+        Some(ip) => Ok(Address::SocketAddress(ip, port)),
+        None => {
+            // Domain names are sent with a single byte of length
+            if addr.is_empty() || addr.len() > 255 {
+                return Err(Error::new(ErrorKind::Invalid,
+                                      "address should be an IP address or a domain name",
+                                      Some(addr.to_string())));
+            }
+            Ok(Address::DomainNameAddress(addr.to_string(), port))
+        }
+    }
+}
+
+fn parse_dns(o: &json::Object) -> Result<Option<DnsConfig>, Error> {
+    let has_address = o.contains_key(&"local_dns_address".to_string());
+    let has_port = o.contains_key(&"local_dns_port".to_string());
+    if !has_address && !has_port {
+        return Ok(None);
+    } else if !has_address || !has_port {
+        panic!("You have to provide `local_dns_address` and `local_dns_port` together");
+    }
+
+    let addr_str = try_config!(o.get(&"local_dns_address".to_string()).unwrap().as_string(),
+                               ErrorKind::Malformed,
+                               "`local_dns_address` should be a string");
+    let ip = try_config!(addr_str.parse(),
+                         ErrorKind::Malformed,
+                         "`local_dns_address` is not a valid IP address");
+    let port = try_config!(o.get(&"local_dns_port".to_string()).unwrap().as_u64(),
+                           ErrorKind::Malformed,
+                           "`local_dns_port` should be an integer") as Port;
+
+    let upstream_str = match o.get(&"local_dns_upstream_address".to_string()) {
+        Some(addr) => try_config!(addr.as_string(),
+                                  ErrorKind::Malformed,
+                                  "`local_dns_upstream_address` should be a string"),
+        None => DEFAULT_DNS_UPSTREAM_ADDRESS,
+    };
+    let upstream_port = match o.get(&"local_dns_upstream_port".to_string()) {
+        Some(port) => try_config!(port.as_u64(),
+                                  ErrorKind::Malformed,
+                                  "`local_dns_upstream_port` should be an integer") as Port,
+        None => DNS_PORT,
+    };
+
+    let mut direct_domains = Vec::new();
+    if let Some(domains) = o.get(&"local_dns_direct_domains".to_string()) {
+        let domains = try_config!(domains.as_array(),
+                                  ErrorKind::Malformed,
+                                  "`local_dns_direct_domains` should be an array");
+        for domain in domains.iter() {
+            let domain = try_config!(domain.as_string(),
+                                     ErrorKind::Malformed,
+                                     "`local_dns_direct_domains` should be an array of strings");
+            direct_domains.push(domain.trim_matches('.').to_ascii_lowercase());
+        }
+    }
+
+    let direct_resolver = match o.get(&"local_dns_direct_resolver".to_string()) {
+        Some(addr) => {
+            let addr_str = try_config!(addr.as_string(),
+                                       ErrorKind::Malformed,
+                                       "`local_dns_direct_resolver` should be a string");
+            let ip = try_config!(addr_str.parse(),
+                                 ErrorKind::Malformed,
+                                 "`local_dns_direct_resolver` is not a valid IP address");
+            Some(SocketAddr {
+                ip: ip,
+                port: DNS_PORT,
+            })
+        },
+        None => None,
+    };
+
+    Ok(Some(DnsConfig {
+        local: SocketAddr {
+            ip: ip,
+            port: port,
+        },
+        upstream: try!(make_address(upstream_str, upstream_port)),
+        direct_domains: direct_domains,
+        direct_resolver: direct_resolver,
+    }))
+}
+
+fn parse_manager(o: &json::Object, timeout: Option<u64>) -> Result<Option<ManagerConfig>, Error> {
+    let addr = match o.get(&"manager_address".to_string()) {
+        Some(addr) => try_config!(addr.as_string(), ErrorKind::Malformed, "`manager_address` should be a string"),
+        None => return Ok(None),
+    };
+    let addr = try_config!(addr.parse::<ManagerAddress>(),
+                           ErrorKind::Malformed,
+                           "`manager_address` should be `host:port` or a path of Unix socket",
+                           addr.to_string());
+
+    let server_addr = match o.get(&"manager_server_address".to_string()) {
+        Some(addr) => try_config!(addr.as_string(),
+                                  ErrorKind::Malformed,
+                                  "`manager_server_address` should be a string"),
+        None => DEFAULT_MANAGER_SERVER_ADDRESS,
+    };
+
+    let method = match o.get(&"method".to_string()) {
+        Some(m) => {
+            let m = try_config!(m.as_string(), ErrorKind::Malformed, "`method` should be a string");
+            Some(try_config!(m.parse::<CipherType>(),
+                             ErrorKind::Invalid,
+                             "not supported method",
+                             format!("`{}` is not a supported method", m)))
+        },
+        None => None,
+    };
+
+    Ok(Some(ManagerConfig {
+        addr: addr,
+        server_addr: server_addr.to_string(),
+        method: method,
+        report_interval: try!(get_positive(o, "manager_report_interval", DEFAULT_MANAGER_REPORT_INTERVAL)) * 1000,
+        timeout: timeout,
+    }))
+}
+
+fn get_positive(o: &json::Object, key: &str, default: u64) -> Result<u64, Error> {
+    match o.get(&key.to_string()) {
+        Some(v) => match v.as_u64() {
+            Some(v) if v > 0 => Ok(v),
+            _ => Err(Error::new(ErrorKind::Malformed, "should be a positive integer", Some(format!("`{}`", key)))),
+        },
+        None => Ok(default),
+    }
+}
+
+fn parse_health_check(o: &json::Object) -> Result<Option<HealthCheckConfig>, Error> {
+    match o.get(&"health_check".to_string()) {
+        Some(enabled) => {
+            if !try_config!(enabled.as_boolean(), ErrorKind::Malformed, "`health_check` should be a boolean") {
+                return Ok(None);
+            }
+        },
+        None => return Ok(None),
+    }
+
+    let addr = match o.get(&"health_check_address".to_string()) {
+        Some(addr) => try_config!(addr.as_string(),
+                                  ErrorKind::Malformed,
+                                  "`health_check_address` should be a string"),
+        None => DEFAULT_HEALTH_CHECK_ADDRESS,
+    };
+    let port = try!(get_positive(o, "health_check_port", DEFAULT_HEALTH_CHECK_PORT as u64)) as Port;
+
+    Ok(Some(HealthCheckConfig {
+        target: try!(make_address(addr, port)),
+        interval: try!(get_positive(o, "health_check_interval", DEFAULT_HEALTH_CHECK_INTERVAL)) * 1000,
+        timeout: try!(get_positive(o, "health_check_timeout", DEFAULT_HEALTH_CHECK_TIMEOUT)) * 1000,
+        fall: try!(get_positive(o, "health_check_fall", 3)) as usize,
+        rise: try!(get_positive(o, "health_check_rise", 2)) as usize,
+    }))
+}
+
+fn parse_load_balancing(o: &json::Object) -> Result<LoadBalancing, Error> {
+    let strategy = match o.get(&"load_balancing".to_string()) {
+        Some(s) => try_config!(s.as_string(), ErrorKind::Malformed, "`load_balancing` should be a string"),
+        None => return Ok(LoadBalancing::RoundRobin),
+    };
+
+    match strategy {
+        "round_robin" => Ok(LoadBalancing::RoundRobin),
+        "weighted_round_robin" => Ok(LoadBalancing::WeightedRoundRobin),
+        "consistent_hash" => {
+            let key = match o.get(&"consistent_hash_key".to_string()) {
+                Some(k) => try_config!(k.as_string(),
+                                       ErrorKind::Malformed,
+                                       "`consistent_hash_key` should be a string"),
+                None => "target",
+            };
+            match key {
+                "target" => Ok(LoadBalancing::ConsistentHash(HashKey::Target)),
+                "client" => Ok(LoadBalancing::ConsistentHash(HashKey::Client)),
+                _ => Err(Error::new(ErrorKind::Invalid,
+                                    "`consistent_hash_key` should be `target` or `client`",
+                                    Some(key.to_string()))),
+            }
+        },
+        _ => Err(Error::new(ErrorKind::Invalid,
+                            "`load_balancing` should be `round_robin`, `weighted_round_robin` or `consistent_hash`",
+                            Some(strategy.to_string()))),
+    }
+}
+
 impl Config {
     pub fn new() -> Config {
         Config {
             server: Vec::new(),
             local: None,
+            local_mode: LocalMode::Socks5,
+            local_http: None,
+            local_users: BTreeMap::new(),
+            tunnels: Vec::new(),
+            local_dns: None,
+            load_balancing: LoadBalancing::RoundRobin,
+            health_check: None,
+            retry_attempts: DEFAULT_RETRY_ATTEMPTS,
+            retry_timeout: DEFAULT_RETRY_TIMEOUT * 1000,
+            acl: None,
             enable_udp: false,
             timeout: None,
+            replay_filter_capacity: DEFAULT_REPLAY_FILTER_CAPACITY,
+            block_private_outbound: true,
+            workers: os::num_cpus(),
+            manager: None,
+            rate_limit: Default::default(),
+            connection_rate_limit: Default::default(),
+            traffic_state_file: None,
+            metrics: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT * 1000,
         }
     }
 
@@ -183,6 +1020,48 @@ impl Config {
             None => None,
         };
 
+        if let Some(c) = o.get(&"replay_filter_capacity".to_string()) {
+            config.replay_filter_capacity = try_config!(c.as_u64(),
+                                                        ErrorKind::Malformed,
+                                                        "`replay_filter_capacity` should be an integer") as usize;
+            if config.replay_filter_capacity == 0 {
+                return Err(Error::new(ErrorKind::Invalid, "`replay_filter_capacity` should be positive", None));
+            }
+        }
+
+        if let Some(b) = o.get(&"block_private_outbound".to_string()) {
+            config.block_private_outbound = try_config!(b.as_boolean(), ErrorKind::Malformed,
+                                                        "`block_private_outbound` should be a boolean");
+        }
+
+        if let Some(w) = o.get(&"workers".to_string()) {
+            config.workers = try_config!(w.as_u64(), ErrorKind::Malformed, "`workers` should be an integer") as usize;
+            if config.workers == 0 {
+                return Err(Error::new(ErrorKind::Invalid, "`workers` should be positive", None));
+            }
+        }
+
+        config.rate_limit = try!(parse_rate_limit(o.get(&"rate_limit".to_string())));
+        config.connection_rate_limit = try!(parse_rate_limit(o.get(&"connection_rate_limit".to_string())));
+
+        if let Some(addr) = o.get(&"metrics_address".to_string()) {
+            let addr = try_config!(addr.as_string(), ErrorKind::Malformed, "`metrics_address` should be a string");
+            config.metrics = Some(try_config!(addr.parse::<SocketAddr>(),
+                                              ErrorKind::Malformed,
+                                              "`metrics_address` should be `ip:port`",
+                                              addr.to_string()));
+        }
+
+        if let Some(t) = o.get(&"drain_timeout".to_string()) {
+            config.drain_timeout = try_config!(t.as_u64(), ErrorKind::Malformed,
+                                               "`drain_timeout` should be an integer") * 1000;
+        }
+
+        if let Some(path) = o.get(&"acl".to_string()) {
+            let path = try_config!(path.as_string(), ErrorKind::Malformed, "`acl` should be a string");
+            config.acl = Some(Arc::new(try!(load_acl(path))));
+        }
+
         if o.contains_key(&"servers".to_string()) {
             let server_list =
                 try_config!(o.get(&"servers".to_string()).unwrap().as_array(),
@@ -204,6 +1083,14 @@ impl Config {
                 let addr_str = try_config!(addr_o.as_string(),
                                            ErrorKind::Malformed, "`address` should be a string");
 
+                // Multi-user servers only need `password` as the iPSK of the 2022 edition
+                let users = try!(parse_users(server));
+                let password = match server.find("password") {
+                    Some(p) => try_config!(p.as_string(), ErrorKind::Malformed, "`password` should be a string"),
+                    None if !users.is_empty() => "",
+                    None => return Err(Error::new(ErrorKind::MissingField, "need to specify a password", None)),
+                };
+
                 let cfg = ServerConfig {
                     addr: addr_str.to_string(),
                     port: try_config!(
@@ -212,12 +1099,7 @@ impl Config {
                                             "need to specify a server port").as_u64(),
                                 ErrorKind::Malformed,
                                 "`port` should be an integer") as Port,
-                    password: try_config!(
-                                    try_config!(server.find("password"),
-                                                ErrorKind::MissingField,
-                                                "need to specify a password").as_string(),
-                                    ErrorKind::Malformed,
-                                    "`password` should be a string").to_string(),
+                    password: password.to_string(),
                     method: method,
                     timeout: match server.find("timeout") {
                         Some(t) => Some(try_config!(t.as_u64(),
@@ -230,9 +1112,24 @@ impl Config {
                                                ErrorKind::Malformed,
                                                "`dns_cache_capacity` should be an integer") as usize,
                         None => DEFAULT_DNS_CACHE_CAPACITY,
-                    }
+                    },
+                    weight: match server.find("weight") {
+                        Some(w) => match w.as_u64() {
+                            Some(w) if w > 0 && w <= MAX_SERVER_WEIGHT => w as u32,
+                            _ => return Err(Error::new(ErrorKind::Malformed,
+                                                       "`weight` should be an integer between 1 and 100",
+                                                       None)),
+                        },
+                        None => 1,
+                    },
+                    users: users,
+                    quota: try!(parse_quota(server.find("quota"))),
+                    rate_limit: try!(parse_rate_limit(server.find("rate_limit"))),
+                    plugin: try!(parse_plugin_field(server.find("plugin"), "plugin")),
+                    plugin_opts: try!(parse_plugin_field(server.find("plugin_opts"), "plugin_opts")),
                 };
 
+                try!(cfg.validate());
                 config.server.push(cfg);
             }
 
@@ -285,11 +1182,28 @@ impl Config {
                                            "`dns_cache_capacity` should be an integer") as usize,
                     None => DEFAULT_DNS_CACHE_CAPACITY,
                 },
+                weight: 1,
+                users: Vec::new(),
+                quota: try!(parse_quota(o.get("quota"))),
+                rate_limit: Default::default(),
+                plugin: try!(parse_plugin_field(o.get("plugin"), "plugin")),
+                plugin_opts: try!(parse_plugin_field(o.get("plugin_opts"), "plugin_opts")),
             };
 
+            try!(single_server.validate());
             config.server = vec![single_server];
         }
 
+        if !require_local_info {
+            config.manager = try!(parse_manager(o, config.timeout));
+
+            if let Some(path) = o.get(&"traffic_state_file".to_string()) {
+                let path = try_config!(path.as_string(), ErrorKind::Malformed,
+                                       "`traffic_state_file` should be a string");
+                config.traffic_state_file = Some(Path::new(path));
+            }
+        }
+
         if require_local_info {
             let has_local_address = o.contains_key(&"local_address".to_string());
             let has_local_port = o.contains_key(&"local_port".to_string());
@@ -318,6 +1232,84 @@ impl Config {
             } else if has_local_address ^ has_local_port {
                 panic!("You have to provide `local_address` and `local_port` together");
             }
+
+            if let Some(mode) = o.get(&"local_mode".to_string()) {
+                let mode = try_config!(mode.as_string(), ErrorKind::Malformed, "`local_mode` should be a string");
+                config.local_mode = match mode {
+                    "socks5" => LocalMode::Socks5,
+                    "redir" => LocalMode::Redir,
+                    _ => return Err(Error::new(ErrorKind::Invalid,
+                                               "`local_mode` should be `socks5` or `redir`",
+                                               Some(mode.to_string()))),
+                };
+            }
+
+            let has_http_address = o.contains_key(&"local_http_address".to_string());
+            let has_http_port = o.contains_key(&"local_http_port".to_string());
+
+            if has_http_address && has_http_port {
+                let addr_str = try_config!(o.get(&"local_http_address".to_string()).unwrap().as_string(),
+                                           ErrorKind::Malformed,
+                                           "`local_http_address` should be a string");
+                let ip = try_config!(addr_str.parse(),
+                                     ErrorKind::Malformed,
+                                     "`local_http_address` is not a valid IP address");
+                let port = try_config!(o.get(&"local_http_port".to_string()).unwrap().as_u64(),
+                                       ErrorKind::Malformed,
+                                       "`local_http_port` should be an integer") as Port;
+
+                config.local_http = Some(SocketAddr {
+                    ip: ip,
+                    port: port,
+                });
+            } else if has_http_address ^ has_http_port {
+                panic!("You have to provide `local_http_address` and `local_http_port` together");
+            }
+
+            if let Some(users) = o.get(&"local_users".to_string()) {
+                let users = try_config!(users.as_array(),
+                                        ErrorKind::Malformed,
+                                        "`local_users` should be an array");
+                for user in users.iter() {
+                    let user = try_config!(user.as_object(),
+                                           ErrorKind::Malformed,
+                                           "`local_users` should be an array of objects");
+                    let name = try_config!(user.get(&"username".to_string()).and_then(|n| n.as_string()),
+                                           ErrorKind::MissingField,
+                                           "`username` of `local_users` should be a string");
+                    let passwd = try_config!(user.get(&"password".to_string()).and_then(|p| p.as_string()),
+                                             ErrorKind::MissingField,
+                                             "`password` of `local_users` should be a string");
+                    try!(add_user(&mut config.local_users, name, passwd));
+                }
+            }
+
+            if let Some(path) = o.get(&"local_users_file".to_string()) {
+                let path = try_config!(path.as_string(),
+                                       ErrorKind::Malformed,
+                                       "`local_users_file` should be a string");
+                try!(load_users_file(path, &mut config.local_users));
+            }
+
+            if let Some(tunnels) = o.get(&"tunnels".to_string()) {
+                let tunnels = try_config!(tunnels.as_array(),
+                                          ErrorKind::Malformed,
+                                          "`tunnels` should be an array");
+                for tunnel in tunnels.iter() {
+                    config.tunnels.push(try!(parse_tunnel(tunnel)));
+                }
+            }
+
+            config.local_dns = try!(parse_dns(o));
+            config.load_balancing = try!(parse_load_balancing(o));
+            config.health_check = try!(parse_health_check(o));
+            config.retry_attempts = try!(get_positive(o, "retry_attempts", DEFAULT_RETRY_ATTEMPTS as u64)) as usize;
+            config.retry_timeout = try!(get_positive(o, "retry_timeout", DEFAULT_RETRY_TIMEOUT)) * 1000;
+
+            if config.local_users.is_empty()
+                    && (o.contains_key(&"local_users".to_string()) || o.contains_key(&"local_users_file".to_string())) {
+                return Err(Error::new(ErrorKind::Invalid, "no user is defined for SOCKS5 authentication", None));
+            }
         }
 
         Ok(config)
@@ -374,3 +1366,131 @@ impl Config {
         })
     }
 }
+
+#[cfg(test)]
+mod test_config {
+    use serialize::json;
+    use std::io::{File, TempDir};
+    use std::io::net::ip::{Ipv4Addr, SocketAddr};
+    use std::iter::repeat;
+
+    use relay::socks5::Address;
+    use super::{parse_tunnel, TunnelConfig, Config, ConfigType, Error, ErrorKind};
+
+    const LOCAL_CONFIG: &'static str = r#""server": "127.0.0.1", "server_port": 8388, "password": "server-password",
+                                          "method": "aes-256-cfb""#;
+
+    fn local_config(users: &str) -> Result<Config, Error> {
+        Config::load_from_str(format!("{{{}, {}}}", LOCAL_CONFIG, users).as_slice(), ConfigType::Local)
+    }
+
+    fn tunnel(s: &str) -> Result<TunnelConfig, Error> {
+        parse_tunnel(&json::Json::from_str(s).unwrap())
+    }
+
+    fn tunnel_error(s: &str) -> ErrorKind {
+        match tunnel(s) {
+            Ok(..) => panic!("{} should be refused", s),
+            Err(err) => err.kind,
+        }
+    }
+
+    #[test]
+    fn test_parse_tunnel() {
+        let config = tunnel(r#"{"local_address": "127.0.0.1", "local_port": 5353,
+                                "forward_address": "8.8.8.8", "forward_port": 53}"#).unwrap();
+        assert_eq!(config.local, SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 5353 });
+        assert_eq!(config.forward, Address::SocketAddress(Ipv4Addr(8, 8, 8, 8), 53));
+
+        let config = tunnel(r#"{"local_address": "::1", "local_port": 8080,
+                                "forward_address": "example.com", "forward_port": 80}"#).unwrap();
+        assert_eq!(config.local.port, 8080);
+        assert_eq!(config.forward, Address::DomainNameAddress("example.com".to_string(), 80));
+    }
+
+    #[test]
+    fn test_parse_invalid_tunnel() {
+        match tunnel_error(r#"["127.0.0.1", 5353]"#) {
+            ErrorKind::Malformed => {},
+            _ => panic!("a tunnel which is not an object should be malformed"),
+        }
+        match tunnel_error(r#"{"local_address": "127.0.0.1", "forward_address": "8.8.8.8", "forward_port": 53}"#) {
+            ErrorKind::MissingField => {},
+            _ => panic!("`local_port` should be missing"),
+        }
+        match tunnel_error(r#"{"local_address": "127.0.0.1", "local_port": 5353, "forward_address": "8.8.8.8"}"#) {
+            ErrorKind::MissingField => {},
+            _ => panic!("`forward_port` should be missing"),
+        }
+        match tunnel_error(r#"{"local_address": "localhost", "local_port": 5353,
+                               "forward_address": "8.8.8.8", "forward_port": 53}"#) {
+            ErrorKind::Malformed => {},
+            _ => panic!("`local_address` should be an IP address"),
+        }
+        match tunnel_error(r#"{"local_address": "127.0.0.1", "local_port": 5353,
+                               "forward_address": "8.8.8.8", "forward_port": "53"}"#) {
+            ErrorKind::Malformed => {},
+            _ => panic!("`forward_port` should be an integer"),
+        }
+
+        let long_name = repeat("a").take(256).collect::<String>();
+        for forward in ["", long_name.as_slice()].iter() {
+            let s = format!(r#"{{"local_address": "127.0.0.1", "local_port": 5353,
+                                 "forward_address": "{}", "forward_port": 53}}"#, forward);
+            match tunnel_error(s.as_slice()) {
+                ErrorKind::Invalid => {},
+                _ => panic!("`forward_address` {:?} should be invalid", forward),
+            }
+        }
+    }
+
+    #[test]
+    fn test_local_users() {
+        let dir = TempDir::new("test_local_users").unwrap();
+        let path = dir.path().join("users");
+        File::create(&path).write_str("# username:password\n\nbob:bob:password\n  carol:carol-password  \n").unwrap();
+
+        let config = local_config(format!(r#""local_users": [{{"username": "alice", "password": "alice-password"}}],
+                                             "local_users_file": "{}""#, path.display()).as_slice()).unwrap();
+        let passwd = |name: &str| config.local_users.get(&name.to_string()).map(|p| p.clone());
+        assert_eq!(config.local_users.len(), 3);
+        assert_eq!(passwd("alice"), Some("alice-password".to_string()));
+        assert_eq!(passwd("bob"), Some("bob:password".to_string()));
+        assert_eq!(passwd("carol"), Some("carol-password".to_string()));
+
+        assert!(local_config(r#""timeout": 300"#).unwrap().local_users.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_local_users() {
+        let long_name = repeat("a").take(256).collect::<String>();
+        let invalid = [
+            r#""local_users": []"#.to_string(),
+            r#""local_users": [{"username": "alice", "password": ""}]"#.to_string(),
+            format!(r#""local_users": [{{"username": "{}", "password": "secret"}}]"#, long_name),
+        ];
+        for users in invalid.iter() {
+            match local_config(users.as_slice()) {
+                Err(Error { kind: ErrorKind::Invalid, .. }) => {},
+                _ => panic!("{} should be invalid", users),
+            }
+        }
+
+        match local_config(r#""local_users": [{"username": "alice"}]"#) {
+            Err(Error { kind: ErrorKind::MissingField, .. }) => {},
+            _ => panic!("`password` of `local_users` should be missing"),
+        }
+        match local_config(r#""local_users_file": "/nonexistent/users""#) {
+            Err(Error { kind: ErrorKind::IoError, .. }) => {},
+            _ => panic!("`local_users_file` should not be readable"),
+        }
+
+        let dir = TempDir::new("test_invalid_local_users").unwrap();
+        let path = dir.path().join("users");
+        File::create(&path).write_str("alice\n").unwrap();
+        match local_config(format!(r#""local_users_file": "{}""#, path.display()).as_slice()) {
+            Err(Error { kind: ErrorKind::Malformed, .. }) => {},
+            _ => panic!("lines without a colon should be malformed"),
+        }
+    }
+}
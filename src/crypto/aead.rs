@@ -0,0 +1,210 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! AEAD ciphers defined in the shadowsocks AEAD specification
+//!
+//! Every session starts with a random salt, which has the same length as the key.
+//! A subkey is derived from the master key and the salt with HKDF-SHA1 (info = "ss-subkey"),
+//! and all the following chunks are sealed with that subkey and a little endian
+//! nonce counter, which starts from zero and is increased after each operation.
+
+use crypto::cipher::{CipherType, CipherResult};
+use crypto::digest::DigestType;
+use crypto::hkdf;
+#[cfg(all(feature = "cipher-aes-gcm", feature = "openssl", not(feature = "crypto-rust")))]
+use crypto::openssl;
+#[cfg(all(feature = "cipher-chacha20-ietf-poly1305", feature = "enable-sodium", not(feature = "crypto-rust")))]
+use crypto::sodium;
+#[cfg(feature = "crypto-rust")]
+use crypto::rust;
+use crypto::aead2022;
+use crypto::cipher::CipherCategory;
+
+/// Info string used by HKDF for deriving session subkeys
+pub const SUBKEY_INFO: &'static [u8] = b"ss-subkey";
+
+/// Nonce length for all supported AEAD ciphers
+pub const NONCE_SIZE: usize = 12;
+
+/// Length of the authentication tag for all supported AEAD ciphers
+pub const TAG_SIZE: usize = 16;
+
+/// Maximum payload length in one TCP chunk
+pub const MAX_PAYLOAD_SIZE: usize = 0x3FFF;
+
+pub trait AeadCipher {
+    /// Seals `data` and appends the authentication tag, then increases the nonce
+    fn encrypt(&mut self, data: &[u8]) -> CipherResult<Vec<u8>>;
+    /// Opens `data` (ciphertext with the tag appended), then increases the nonce
+    fn decrypt(&mut self, data: &[u8]) -> CipherResult<Vec<u8>>;
+    fn tag_size(&self) -> usize;
+}
+
+/// Increases a little endian nonce by one
+pub fn increase_nonce(nonce: &mut [u8]) {
+    for i in nonce.iter_mut() {
+        *i += 1;
+        if *i != 0 {
+            break;
+        }
+    }
+}
+
+/// Derives the session subkey from master key and salt
+pub fn derive_subkey(t: CipherType, key: &[u8], salt: &[u8]) -> Vec<u8> {
+    hkdf::hkdf(DigestType::Sha1, salt, key, SUBKEY_INFO, t.key_size())
+}
+
+/// Creates an AEAD cipher for a session.
+///
+/// `key` is the master key generated by `CipherType::bytes_to_key` (or the pre-shared key
+/// for the 2022 edition), and `salt` is the salt sent at the beginning of the session (or UDP packet).
+pub fn with_type(t: CipherType, key: &[u8], salt: &[u8]) -> Box<AeadCipher + Send> {
+    let subkey = match t.category() {
+        CipherCategory::Aead2022 => aead2022::derive_subkey(t, key, salt),
+        _ => derive_subkey(t, key, salt),
+    };
+
+    new_cipher(t, subkey.as_slice())
+}
+
+/// Creates an AEAD cipher with the session subkey directly
+pub fn new_cipher(t: CipherType, subkey: &[u8]) -> Box<AeadCipher + Send> {
+    new_cipher_with_nonce(t, subkey, &[0u8; NONCE_SIZE])
+}
+
+/// Creates an AEAD cipher with the session subkey, and the nonce starts from `nonce` instead of zero
+pub fn new_cipher_with_nonce(t: CipherType, subkey: &[u8], nonce: &[u8]) -> Box<AeadCipher + Send> {
+    if t.category() == CipherCategory::Stream {
+        panic!("{:?} is not an AEAD cipher", t);
+    }
+
+    new_backend_cipher(t, subkey, nonce)
+}
+
+#[cfg(feature = "crypto-rust")]
+fn new_backend_cipher(t: CipherType, subkey: &[u8], nonce: &[u8]) -> Box<AeadCipher + Send> {
+    box rust::RustAeadCipher::with_nonce(t, subkey, nonce) as Box<AeadCipher + Send>
+}
+
+#[cfg(not(feature = "crypto-rust"))]
+fn new_backend_cipher(t: CipherType, subkey: &[u8], nonce: &[u8]) -> Box<AeadCipher + Send> {
+    match t {
+        #[cfg(all(feature = "cipher-aes-gcm", feature = "openssl"))]
+        CipherType::Aes128Gcm | CipherType::Aes256Gcm =>
+            box openssl::OpenSSLAeadCipher::with_nonce(t, subkey, nonce) as Box<AeadCipher + Send>,
+        #[cfg(all(feature = "cipher-2022-blake3-aes-256-gcm", feature = "openssl"))]
+        CipherType::Blake3Aes256Gcm =>
+            box openssl::OpenSSLAeadCipher::with_nonce(t, subkey, nonce) as Box<AeadCipher + Send>,
+
+        #[cfg(all(feature = "cipher-chacha20-ietf-poly1305", feature = "enable-sodium"))]
+        CipherType::ChaCha20IetfPoly1305 =>
+            box sodium::SodiumAeadCipher::with_nonce(t, subkey, nonce) as Box<AeadCipher + Send>,
+        #[cfg(all(feature = "cipher-2022-blake3-chacha20-poly1305", feature = "enable-sodium"))]
+        CipherType::Blake3ChaCha20Poly1305 =>
+            box sodium::SodiumAeadCipher::with_nonce(t, subkey, nonce) as Box<AeadCipher + Send>,
+
+        _ => panic!("{:?} is not supported by the enabled crypto backends", t),
+    }
+}
+
+#[cfg(test)]
+mod test_aead {
+    use serialize::hex::FromHex;
+
+    use crypto::aead::{self, AeadCipher};
+    use crypto::cipher::CipherType;
+
+    // Vectors below are generated with an independent implementation of the
+    // shadowsocks AEAD construction, password is "password" and salt is 00 01 02 ...
+
+    fn check_vectors(t: CipherType, subkey: &str, tcp_frame: &str, udp_packet: &str) {
+        let key = t.bytes_to_key(b"password");
+        let salt = range(0, t.salt_size()).map(|x| x as u8).collect::<Vec<u8>>();
+
+        assert_eq!(aead::derive_subkey(t, key.as_slice(), salt.as_slice()), subkey.from_hex().unwrap());
+
+        let message = b"hello world";
+
+        let mut enc = aead::with_type(t, key.as_slice(), salt.as_slice());
+        let mut frame = enc.encrypt(&[0x00, message.len() as u8]).unwrap();
+        frame.push_all(enc.encrypt(message).unwrap().as_slice());
+        assert_eq!(frame, tcp_frame.from_hex().unwrap());
+
+        let mut dec = aead::with_type(t, key.as_slice(), salt.as_slice());
+        let len_tag = 2 + dec.tag_size();
+        assert_eq!(dec.decrypt(&frame[..len_tag]).unwrap(), vec![0x00, message.len() as u8]);
+        assert_eq!(dec.decrypt(&frame[len_tag..]).unwrap().as_slice(), message);
+
+        let mut udp_enc = aead::with_type(t, key.as_slice(), salt.as_slice());
+        assert_eq!(udp_enc.encrypt(message).unwrap(), udp_packet.from_hex().unwrap());
+    }
+
+    #[test]
+    fn test_increase_nonce() {
+        let mut nonce = [0xffu8, 0xff, 0x00, 0x00];
+        aead::increase_nonce(&mut nonce);
+        assert_eq!(nonce, [0x00u8, 0x00, 0x01, 0x00]);
+    }
+
+    #[cfg(feature = "cipher-aes-gcm")]
+    #[test]
+    fn test_aes_128_gcm_vectors() {
+        check_vectors(CipherType::Aes128Gcm,
+                      "ed2a618d9490d1701de885d82aa80616",
+                      "5c25f1fce5ac2f6e707995b23c88ed00e9b2947477b58a1be9421248952d7353c54275fb917b18af18e3e4b347",
+                      "344b87186f592929c850b01c5e79208828f60c20e406118c70fb4d");
+    }
+
+    #[cfg(feature = "cipher-aes-gcm")]
+    #[test]
+    fn test_aes_256_gcm_vectors() {
+        check_vectors(CipherType::Aes256Gcm,
+                      "ee187aed3f87574907a39db98606f60a526114831288097cac66054b33a9464f",
+                      "7eae910102c3179441a1d58c462a5851ea739d45b319456821185ea63589696ef650b9e721e4eadc528b16e819",
+                      "16c0ffcf8afa4536221fe6523328f1626b1c39787af5f2fdd7a9e7");
+    }
+
+    #[cfg(feature = "cipher-chacha20-ietf-poly1305")]
+    #[test]
+    fn test_chacha20_ietf_poly1305_vectors() {
+        check_vectors(CipherType::ChaCha20IetfPoly1305,
+                      "ee187aed3f87574907a39db98606f60a526114831288097cac66054b33a9464f",
+                      "ad43de965423c912352d6260ef7feb0d4ad8e8adc7498c105cea55c92c07dcc4ea85eb0781717a15498fb3aff8",
+                      "c52de29d919a0537e48a0739f997292fa0db047ff1d01b7f269c61");
+    }
+
+    #[cfg(feature = "cipher-aes-gcm")]
+    #[test]
+    fn test_aead_tampered() {
+        let t = CipherType::Aes256Gcm;
+        let key = t.bytes_to_key(b"password");
+        let salt = t.gen_init_vec();
+
+        let mut enc = aead::with_type(t, key.as_slice(), salt.as_slice());
+        let mut encrypted = enc.encrypt(b"hello world").unwrap();
+        encrypted[0] ^= 0x01;
+
+        let mut dec = aead::with_type(t, key.as_slice(), salt.as_slice());
+        assert!(dec.decrypt(encrypted.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,410 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! Cryptographic parts of the Shadowsocks 2022 edition (SIP022)
+//!
+//! Keys of the 2022 edition are base64 encoded pre-shared keys (PSK), whose length must be
+//! exactly the key size of the method. Session subkeys are derived with BLAKE3:
+//!
+//! ```plain
+//! subkey = blake3::derive_key("shadowsocks 2022 session subkey", psk || salt)
+//! ```
+//!
+//! TCP streams use the same chunk construction as the AEAD ciphers, the UDP packets carry a
+//! separate header with session ID and packet ID:
+//!
+//! ```plain
+//! 2022-blake3-aes-256-gcm:
+//!     AES-ECB(psk, session_id || packet_id) || AEAD(subkey(session_id), packet_id[4..], body)
+//! 2022-blake3-chacha20-poly1305:
+//!     nonce(24) || XChaCha20-Poly1305(psk, nonce, session_id || packet_id || body)
+//! ```
+//!
+//! A multi-user server has an identity PSK (iPSK), and every user has its own PSK (uPSK). Requests
+//! to it carry an identity header after the salt (TCP) or the separate header (UDP), which is only
+//! defined for the AES ciphers. Sessions are then encrypted with the uPSK:
+//!
+//! ```plain
+//! TCP: AES-ECB(blake3::derive_key("shadowsocks 2022 identity subkey", ipsk || salt), blake3(upsk)[..16])
+//! UDP: AES-ECB(ipsk, blake3(upsk)[..16] ^ (session_id || packet_id))
+//! ```
+
+use std::io::{BufReader, Writer};
+use std::iter::repeat;
+use std::rand::{self, Rng};
+
+use serialize::base64::FromBase64;
+use time;
+
+use crypto::blake3;
+use crypto::cipher::{self, CipherType, CipherResult};
+use crypto::aead;
+use crypto::CryptoMode;
+#[cfg(all(feature = "cipher-2022-blake3-aes-256-gcm", not(feature = "crypto-rust")))]
+use crypto::openssl::aes_ecb_crypt_block;
+#[cfg(all(feature = "cipher-2022-blake3-chacha20-poly1305", not(feature = "crypto-rust")))]
+use crypto::sodium::{xchacha20_poly1305_seal, xchacha20_poly1305_open, XCHACHA20_POLY1305_NONCE_SIZE};
+#[cfg(feature = "crypto-rust")]
+use crypto::rust::{aes_ecb_crypt_block, xchacha20_poly1305_seal, xchacha20_poly1305_open,
+                   XCHACHA20_POLY1305_NONCE_SIZE};
+
+/// Context string of BLAKE3 for deriving session subkeys
+pub const SESSION_SUBKEY_CONTEXT: &'static str = "shadowsocks 2022 session subkey";
+
+/// Context string of BLAKE3 for deriving identity subkeys of TCP streams
+pub const IDENTITY_SUBKEY_CONTEXT: &'static str = "shadowsocks 2022 identity subkey";
+
+/// Length of an identity header
+pub const IDENTITY_HEADER_SIZE: usize = 16;
+
+/// Maximum payload length in one TCP chunk
+pub const MAX_PAYLOAD_SIZE: usize = 0xFFFF;
+
+/// Length of the separate header (session ID and packet ID) of UDP packets
+pub const SEPARATE_HEADER_SIZE: usize = 16;
+
+/// Maximum allowed difference (in seconds) between timestamp in headers and local time
+pub const MAX_TIME_DIFF: i64 = 30;
+
+/// Checks whether the timestamp in a header is within `MAX_TIME_DIFF` of local time
+pub fn is_timestamp_valid(timestamp: u64) -> bool {
+    let diff = time::get_time().sec - timestamp as i64;
+    diff <= MAX_TIME_DIFF && diff >= -MAX_TIME_DIFF
+}
+
+/// Derives the session subkey from pre-shared key and salt (or UDP session ID)
+pub fn derive_subkey(t: CipherType, key: &[u8], salt: &[u8]) -> Vec<u8> {
+    let mut key_material = key.to_vec();
+    key_material.push_all(salt);
+    blake3::derive_key(SESSION_SUBKEY_CONTEXT, key_material.as_slice(), t.key_size())
+}
+
+/// Decodes a base64 encoded pre-shared key, returns `None` if it is not valid base64
+/// or its length is not exactly the key size of `t`
+pub fn decode_psk(t: CipherType, psk: &str) -> Option<Vec<u8>> {
+    match psk.from_base64() {
+        Ok(key) => {
+            if key.len() == t.key_size() {
+                Some(key)
+            } else {
+                None
+            }
+        },
+        Err(..) => None,
+    }
+}
+
+/// Whether requests with `t` could carry identity headers for multi-user servers
+pub fn supports_identity(t: CipherType) -> bool {
+    match t {
+        #[cfg(feature = "cipher-2022-blake3-aes-256-gcm")]
+        CipherType::Blake3Aes256Gcm => true,
+        _ => false,
+    }
+}
+
+/// Hash of a uPSK, which identifies the user in identity headers
+pub fn identity_hash(user_key: &[u8]) -> Vec<u8> {
+    blake3::hash(user_key)[..IDENTITY_HEADER_SIZE].to_vec()
+}
+
+/// Identity header of a TCP stream with `salt` to a multi-user server, `key` is the iPSK
+pub fn make_stream_identity(t: CipherType, key: &[u8], salt: &[u8], user_key: &[u8]) -> CipherResult<Vec<u8>> {
+    stream_identity_crypt(t, key, salt, identity_hash(user_key).as_slice(), CryptoMode::Encrypt)
+}
+
+/// Decrypts the identity header of a TCP stream with `salt`, returns the hash of the uPSK
+pub fn read_stream_identity(t: CipherType, key: &[u8], salt: &[u8], header: &[u8]) -> CipherResult<Vec<u8>> {
+    stream_identity_crypt(t, key, salt, header, CryptoMode::Decrypt)
+}
+
+fn stream_identity_crypt(t: CipherType, key: &[u8], salt: &[u8], block: &[u8], mode: CryptoMode)
+        -> CipherResult<Vec<u8>> {
+    match t {
+        #[cfg(feature = "cipher-2022-blake3-aes-256-gcm")]
+        CipherType::Blake3Aes256Gcm => {
+            let mut key_material = key.to_vec();
+            key_material.push_all(salt);
+            let subkey = blake3::derive_key(IDENTITY_SUBKEY_CONTEXT, key_material.as_slice(), t.key_size());
+            aes_ecb_crypt_block(subkey.as_slice(), block, mode)
+        },
+
+        _ => panic!("{:?} does not support identity headers", t),
+    }
+}
+
+fn make_separate_header(session_id: u64, packet_id: u64) -> Vec<u8> {
+    let mut header = Vec::with_capacity(SEPARATE_HEADER_SIZE);
+    header.write_be_u64(session_id).unwrap();
+    header.write_be_u64(packet_id).unwrap();
+    header
+}
+
+fn parse_separate_header(header: &[u8]) -> (u64, u64) {
+    let mut reader = BufReader::new(header);
+    let session_id = reader.read_be_u64().unwrap();
+    let packet_id = reader.read_be_u64().unwrap();
+    (session_id, packet_id)
+}
+
+fn packet_too_short() -> cipher::Error {
+    cipher::Error {
+        kind: cipher::ErrorKind::InvalidData,
+        desc: "UDP packet is too short",
+        detail: None,
+    }
+}
+
+/// Encrypts a UDP packet, `body` is the main header with payload
+pub fn encrypt_udp_packet(t: CipherType, key: &[u8], session_id: u64, packet_id: u64, body: &[u8])
+        -> CipherResult<Vec<u8>> {
+    let separate_header = make_separate_header(session_id, packet_id);
+
+    match t {
+        #[cfg(feature = "cipher-2022-blake3-aes-256-gcm")]
+        CipherType::Blake3Aes256Gcm => {
+            let subkey = derive_subkey(t, key, &separate_header[..8]);
+            let mut packet = try!(aes_ecb_crypt_block(key, separate_header.as_slice(), CryptoMode::Encrypt));
+            let mut cipher = aead::new_cipher_with_nonce(t, subkey.as_slice(), &separate_header[4..]);
+            packet.push_all(try!(cipher.encrypt(body)).as_slice());
+            Ok(packet)
+        },
+
+        #[cfg(feature = "cipher-2022-blake3-chacha20-poly1305")]
+        CipherType::Blake3ChaCha20Poly1305 => {
+            let mut packet: Vec<u8> = repeat(0u8).take(XCHACHA20_POLY1305_NONCE_SIZE).collect();
+            rand::thread_rng().fill_bytes(packet.as_mut_slice());
+            let mut plain = separate_header;
+            plain.push_all(body);
+            let sealed = try!(xchacha20_poly1305_seal(key, packet.as_slice(), plain.as_slice()));
+            packet.push_all(sealed.as_slice());
+            Ok(packet)
+        },
+
+        _ => panic!("{:?} is not a cipher of the 2022 edition", t),
+    }
+}
+
+/// Encrypts a UDP packet to a multi-user server, the separate header and the identity header are
+/// encrypted with the iPSK `key`, and the body with the uPSK `user_key`
+pub fn encrypt_udp_packet_with_identity(t: CipherType, key: &[u8], user_key: &[u8], session_id: u64,
+                                        packet_id: u64, body: &[u8]) -> CipherResult<Vec<u8>> {
+    match t {
+        #[cfg(feature = "cipher-2022-blake3-aes-256-gcm")]
+        CipherType::Blake3Aes256Gcm => {
+            let separate_header = make_separate_header(session_id, packet_id);
+            let mut packet = try!(aes_ecb_crypt_block(key, separate_header.as_slice(), CryptoMode::Encrypt));
+
+            let mut identity = identity_hash(user_key);
+            for (b, h) in identity.iter_mut().zip(separate_header.iter()) {
+                *b ^= *h;
+            }
+            packet.push_all(try!(aes_ecb_crypt_block(key, identity.as_slice(), CryptoMode::Encrypt)).as_slice());
+
+            let subkey = derive_subkey(t, user_key, &separate_header[..8]);
+            let mut cipher = aead::new_cipher_with_nonce(t, subkey.as_slice(), &separate_header[4..]);
+            packet.push_all(try!(cipher.encrypt(body)).as_slice());
+            Ok(packet)
+        },
+
+        _ => panic!("{:?} does not support identity headers", t),
+    }
+}
+
+/// Decrypts the identity header of a UDP packet to a multi-user server, returns the hash of the uPSK
+pub fn read_udp_identity(t: CipherType, key: &[u8], packet: &[u8]) -> CipherResult<Vec<u8>> {
+    match t {
+        #[cfg(feature = "cipher-2022-blake3-aes-256-gcm")]
+        CipherType::Blake3Aes256Gcm => {
+            if packet.len() < SEPARATE_HEADER_SIZE + IDENTITY_HEADER_SIZE + aead::TAG_SIZE {
+                return Err(packet_too_short());
+            }
+
+            let separate_header = try!(aes_ecb_crypt_block(key, &packet[..SEPARATE_HEADER_SIZE],
+                                                           CryptoMode::Decrypt));
+            let identity_header = &packet[SEPARATE_HEADER_SIZE..SEPARATE_HEADER_SIZE + IDENTITY_HEADER_SIZE];
+            let mut identity = try!(aes_ecb_crypt_block(key, identity_header, CryptoMode::Decrypt));
+            for (b, h) in identity.iter_mut().zip(separate_header.iter()) {
+                *b ^= *h;
+            }
+            Ok(identity)
+        },
+
+        _ => panic!("{:?} does not support identity headers", t),
+    }
+}
+
+/// Decrypts a UDP packet to a multi-user server, whose user has been identified by `read_udp_identity`.
+/// Returns session ID, packet ID and the body.
+pub fn decrypt_udp_packet_with_identity(t: CipherType, key: &[u8], user_key: &[u8], packet: &[u8])
+        -> CipherResult<(u64, u64, Vec<u8>)> {
+    match t {
+        #[cfg(feature = "cipher-2022-blake3-aes-256-gcm")]
+        CipherType::Blake3Aes256Gcm => {
+            if packet.len() < SEPARATE_HEADER_SIZE + IDENTITY_HEADER_SIZE + aead::TAG_SIZE {
+                return Err(packet_too_short());
+            }
+
+            let separate_header = try!(aes_ecb_crypt_block(key, &packet[..SEPARATE_HEADER_SIZE],
+                                                           CryptoMode::Decrypt));
+            let (session_id, packet_id) = parse_separate_header(separate_header.as_slice());
+
+            let subkey = derive_subkey(t, user_key, &separate_header[..8]);
+            let mut cipher = aead::new_cipher_with_nonce(t, subkey.as_slice(), &separate_header[4..]);
+            let body = try!(cipher.decrypt(&packet[SEPARATE_HEADER_SIZE + IDENTITY_HEADER_SIZE..]));
+            Ok((session_id, packet_id, body))
+        },
+
+        _ => panic!("{:?} does not support identity headers", t),
+    }
+}
+
+/// Decrypts a UDP packet, returns session ID, packet ID and the body
+pub fn decrypt_udp_packet(t: CipherType, key: &[u8], packet: &[u8]) -> CipherResult<(u64, u64, Vec<u8>)> {
+    match t {
+        #[cfg(feature = "cipher-2022-blake3-aes-256-gcm")]
+        CipherType::Blake3Aes256Gcm => {
+            if packet.len() < SEPARATE_HEADER_SIZE + aead::TAG_SIZE {
+                return Err(packet_too_short());
+            }
+
+            let separate_header = try!(aes_ecb_crypt_block(key, &packet[..SEPARATE_HEADER_SIZE],
+                                                           CryptoMode::Decrypt));
+            let (session_id, packet_id) = parse_separate_header(separate_header.as_slice());
+
+            let subkey = derive_subkey(t, key, &separate_header[..8]);
+            let mut cipher = aead::new_cipher_with_nonce(t, subkey.as_slice(), &separate_header[4..]);
+            let body = try!(cipher.decrypt(&packet[SEPARATE_HEADER_SIZE..]));
+            Ok((session_id, packet_id, body))
+        },
+
+        #[cfg(feature = "cipher-2022-blake3-chacha20-poly1305")]
+        CipherType::Blake3ChaCha20Poly1305 => {
+            let nonce_size = XCHACHA20_POLY1305_NONCE_SIZE;
+            if packet.len() < nonce_size + SEPARATE_HEADER_SIZE + aead::TAG_SIZE {
+                return Err(packet_too_short());
+            }
+
+            let plain = try!(xchacha20_poly1305_open(key, &packet[..nonce_size], &packet[nonce_size..]));
+            let (session_id, packet_id) = parse_separate_header(&plain[..SEPARATE_HEADER_SIZE]);
+            Ok((session_id, packet_id, plain[SEPARATE_HEADER_SIZE..].to_vec()))
+        },
+
+        _ => panic!("{:?} is not a cipher of the 2022 edition", t),
+    }
+}
+
+#[cfg(test)]
+mod test_aead2022 {
+    use serialize::hex::FromHex;
+
+    use crypto::aead2022;
+    use crypto::aead;
+    use crypto::cipher::CipherType;
+
+    const PSK: &'static str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    // Fixed request header: type 0, timestamp 1700000000, variable header length 20
+    const FIXED_HEADER: [u8; 11] = [0x00, 0x00, 0x00, 0x00, 0x00, 0x65, 0x53, 0xf1, 0x00, 0x00, 0x14];
+
+    fn check_vectors(t: CipherType, sealed_header: &str) {
+        let key = aead2022::decode_psk(t, PSK).unwrap();
+        let salt = range(32, 64).map(|x| x as u8).collect::<Vec<u8>>();
+
+        assert_eq!(aead2022::derive_subkey(t, key.as_slice(), salt.as_slice()),
+                   "374fca03e4dae7f998fd7e59c1edfcc8e3197f4db1c19ca1671be3b66a92ddda".from_hex().unwrap());
+
+        let mut enc = aead::with_type(t, key.as_slice(), salt.as_slice());
+        assert_eq!(enc.encrypt(&FIXED_HEADER).unwrap(), sealed_header.from_hex().unwrap());
+    }
+
+    #[cfg(feature = "cipher-2022-blake3-aes-256-gcm")]
+    #[test]
+    fn test_decode_psk() {
+        let t = CipherType::Blake3Aes256Gcm;
+        assert!(aead2022::decode_psk(t, PSK).is_some());
+        // 16 bytes key is too short for a 256-bit cipher
+        assert!(aead2022::decode_psk(t, "AAECAwQFBgcICQoLDA0ODw==").is_none());
+        assert!(aead2022::decode_psk(t, "not base64!").is_none());
+    }
+
+    #[cfg(feature = "cipher-2022-blake3-aes-256-gcm")]
+    #[test]
+    fn test_2022_blake3_aes_256_gcm() {
+        check_vectors(CipherType::Blake3Aes256Gcm, "aa4efbd2198e0f750c826e0fdb3ffec1f316f5c024abe4b429c3c0");
+
+        let t = CipherType::Blake3Aes256Gcm;
+        let key = aead2022::decode_psk(t, PSK).unwrap();
+        let packet = aead2022::encrypt_udp_packet(t, key.as_slice(), 0x0102030405060708, 1, b"body").unwrap();
+        assert_eq!(&packet[..16], "e4a143bc51277a003d85d6825ad10fb2".from_hex().unwrap().as_slice());
+
+        let (session_id, packet_id, body) = aead2022::decrypt_udp_packet(t, key.as_slice(), packet.as_slice())
+                                                .unwrap();
+        assert_eq!(session_id, 0x0102030405060708);
+        assert_eq!(packet_id, 1);
+        assert_eq!(body.as_slice(), b"body");
+    }
+
+    #[cfg(feature = "cipher-2022-blake3-aes-256-gcm")]
+    #[test]
+    fn test_identity_headers() {
+        let t = CipherType::Blake3Aes256Gcm;
+        let key = aead2022::decode_psk(t, PSK).unwrap();
+        let user_key = range(64, 96).map(|x| x as u8).collect::<Vec<u8>>();
+        let salt = range(32, 64).map(|x| x as u8).collect::<Vec<u8>>();
+        let hash = aead2022::identity_hash(user_key.as_slice());
+
+        let header = aead2022::make_stream_identity(t, key.as_slice(), salt.as_slice(), user_key.as_slice()).unwrap();
+        assert_eq!(header.len(), aead2022::IDENTITY_HEADER_SIZE);
+        assert!(header != hash);
+        assert_eq!(aead2022::read_stream_identity(t, key.as_slice(), salt.as_slice(), header.as_slice()).unwrap(),
+                   hash);
+
+        let packet = aead2022::encrypt_udp_packet_with_identity(t, key.as_slice(), user_key.as_slice(), 42, 7,
+                                                                b"body").unwrap();
+        assert_eq!(aead2022::read_udp_identity(t, key.as_slice(), packet.as_slice()).unwrap(), hash);
+        let (session_id, packet_id, body) =
+            aead2022::decrypt_udp_packet_with_identity(t, key.as_slice(), user_key.as_slice(), packet.as_slice())
+                     .unwrap();
+        assert_eq!(session_id, 42);
+        assert_eq!(packet_id, 7);
+        assert_eq!(body.as_slice(), b"body");
+
+        // The body is not sealed with the iPSK
+        assert!(aead2022::decrypt_udp_packet(t, key.as_slice(), packet.as_slice()).is_err());
+    }
+
+    #[cfg(feature = "cipher-2022-blake3-chacha20-poly1305")]
+    #[test]
+    fn test_2022_blake3_chacha20_poly1305() {
+        check_vectors(CipherType::Blake3ChaCha20Poly1305, "aaf3381628f811755309fbfc5fe7d0d41a273cf148352a0bdcfec3");
+
+        let t = CipherType::Blake3ChaCha20Poly1305;
+        let key = aead2022::decode_psk(t, PSK).unwrap();
+        let packet = aead2022::encrypt_udp_packet(t, key.as_slice(), 42, 7, b"body").unwrap();
+        let (session_id, packet_id, body) = aead2022::decrypt_udp_packet(t, key.as_slice(), packet.as_slice())
+                                                .unwrap();
+        assert_eq!(session_id, 42);
+        assert_eq!(packet_id, 7);
+        assert_eq!(body.as_slice(), b"body");
+    }
+}
@@ -0,0 +1,354 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! BLAKE3 hash function
+//!
+//! A portable implementation of BLAKE3, which is used by the Shadowsocks 2022 edition
+//! for deriving session subkeys. Only the plain hash and `derive_key` modes are provided.
+
+use std::cmp;
+
+const OUT_LEN: usize = 32;
+const BLOCK_LEN: usize = 64;
+const CHUNK_LEN: usize = 1024;
+
+const CHUNK_START: u32 = 1 << 0;
+const CHUNK_END: u32 = 1 << 1;
+const PARENT: u32 = 1 << 2;
+const ROOT: u32 = 1 << 3;
+const DERIVE_KEY_CONTEXT: u32 = 1 << 5;
+const DERIVE_KEY_MATERIAL: u32 = 1 << 6;
+
+const IV: [u32; 8] = [
+    0x6A09E667, 0xBB67AE85, 0x3C6EF372, 0xA54FF53A, 0x510E527F, 0x9B05688C, 0x1F83D9AB, 0x5BE0CD19,
+];
+
+const MSG_PERMUTATION: [usize; 16] = [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8];
+
+#[inline]
+fn g(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize, mx: u32, my: u32) {
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(mx);
+    state[d] = (state[d] ^ state[a]).rotate_right(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(12);
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(my);
+    state[d] = (state[d] ^ state[a]).rotate_right(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(7);
+}
+
+fn round(state: &mut [u32; 16], m: &[u32; 16]) {
+    // Mix the columns
+    g(state, 0, 4, 8, 12, m[0], m[1]);
+    g(state, 1, 5, 9, 13, m[2], m[3]);
+    g(state, 2, 6, 10, 14, m[4], m[5]);
+    g(state, 3, 7, 11, 15, m[6], m[7]);
+    // Mix the diagonals
+    g(state, 0, 5, 10, 15, m[8], m[9]);
+    g(state, 1, 6, 11, 12, m[10], m[11]);
+    g(state, 2, 7, 8, 13, m[12], m[13]);
+    g(state, 3, 4, 9, 14, m[14], m[15]);
+}
+
+fn permute(m: &mut [u32; 16]) {
+    let mut permuted = [0u32; 16];
+    for i in range(0us, 16) {
+        permuted[i] = m[MSG_PERMUTATION[i]];
+    }
+    *m = permuted;
+}
+
+fn compress(chaining_value: &[u32; 8], block_words: &[u32; 16], counter: u64, block_len: u32, flags: u32)
+        -> [u32; 16] {
+    let mut state = [
+        chaining_value[0], chaining_value[1], chaining_value[2], chaining_value[3],
+        chaining_value[4], chaining_value[5], chaining_value[6], chaining_value[7],
+        IV[0], IV[1], IV[2], IV[3],
+        counter as u32, (counter >> 32) as u32, block_len, flags,
+    ];
+    let mut block = *block_words;
+
+    for r in range(0, 7) {
+        round(&mut state, &block);
+        if r < 6 {
+            permute(&mut block);
+        }
+    }
+
+    for i in range(0us, 8) {
+        state[i] ^= state[i + 8];
+        state[i + 8] ^= chaining_value[i];
+    }
+    state
+}
+
+fn first_8_words(compression_output: [u32; 16]) -> [u32; 8] {
+    let mut out = [0u32; 8];
+    for i in range(0us, 8) {
+        out[i] = compression_output[i];
+    }
+    out
+}
+
+fn words_from_le_bytes(bytes: &[u8], words: &mut [u32]) {
+    for (i, word) in words.iter_mut().enumerate() {
+        let b = &bytes[i * 4..i * 4 + 4];
+        *word = (b[0] as u32) | ((b[1] as u32) << 8) | ((b[2] as u32) << 16) | ((b[3] as u32) << 24);
+    }
+}
+
+struct Output {
+    input_chaining_value: [u32; 8],
+    block_words: [u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+}
+
+impl Output {
+    fn chaining_value(&self) -> [u32; 8] {
+        first_8_words(compress(&self.input_chaining_value, &self.block_words,
+                               self.counter, self.block_len, self.flags))
+    }
+
+    fn root_output_bytes(&self, out: &mut [u8]) {
+        let mut output_block_counter = 0u64;
+        for out_block in out.chunks_mut(2 * OUT_LEN) {
+            let words = compress(&self.input_chaining_value, &self.block_words,
+                                 output_block_counter, self.block_len, self.flags | ROOT);
+            for (word, out_word) in words.iter().zip(out_block.chunks_mut(4)) {
+                for (i, b) in out_word.iter_mut().enumerate() {
+                    *b = (*word >> (8 * i)) as u8;
+                }
+            }
+            output_block_counter += 1;
+        }
+    }
+}
+
+struct ChunkState {
+    chaining_value: [u32; 8],
+    chunk_counter: u64,
+    block: [u8; BLOCK_LEN],
+    block_len: usize,
+    blocks_compressed: usize,
+    flags: u32,
+}
+
+impl ChunkState {
+    fn new(key_words: [u32; 8], chunk_counter: u64, flags: u32) -> ChunkState {
+        ChunkState {
+            chaining_value: key_words,
+            chunk_counter: chunk_counter,
+            block: [0u8; BLOCK_LEN],
+            block_len: 0,
+            blocks_compressed: 0,
+            flags: flags,
+        }
+    }
+
+    fn len(&self) -> usize {
+        BLOCK_LEN * self.blocks_compressed + self.block_len
+    }
+
+    fn start_flag(&self) -> u32 {
+        if self.blocks_compressed == 0 { CHUNK_START } else { 0 }
+    }
+
+    fn update(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            // If the block buffer is full, compress it and clear it. More
+            // input is coming, so this compression is not CHUNK_END.
+            if self.block_len == BLOCK_LEN {
+                let mut block_words = [0u32; 16];
+                words_from_le_bytes(&self.block, &mut block_words);
+                self.chaining_value = first_8_words(compress(&self.chaining_value, &block_words,
+                                                             self.chunk_counter, BLOCK_LEN as u32,
+                                                             self.flags | self.start_flag()));
+                self.blocks_compressed += 1;
+                self.block = [0u8; BLOCK_LEN];
+                self.block_len = 0;
+            }
+
+            let want = BLOCK_LEN - self.block_len;
+            let take = cmp::min(want, input.len());
+            for i in range(0us, take) {
+                self.block[self.block_len + i] = input[i];
+            }
+            self.block_len += take;
+            input = &input[take..];
+        }
+    }
+
+    fn output(&self) -> Output {
+        let mut block_words = [0u32; 16];
+        words_from_le_bytes(&self.block, &mut block_words);
+        Output {
+            input_chaining_value: self.chaining_value,
+            block_words: block_words,
+            counter: self.chunk_counter,
+            block_len: self.block_len as u32,
+            flags: self.flags | self.start_flag() | CHUNK_END,
+        }
+    }
+}
+
+fn parent_output(left_child_cv: [u32; 8], right_child_cv: [u32; 8], key_words: [u32; 8], flags: u32) -> Output {
+    let mut block_words = [0u32; 16];
+    for i in range(0us, 8) {
+        block_words[i] = left_child_cv[i];
+        block_words[i + 8] = right_child_cv[i];
+    }
+    Output {
+        input_chaining_value: key_words,
+        block_words: block_words,
+        counter: 0,
+        block_len: BLOCK_LEN as u32,
+        flags: PARENT | flags,
+    }
+}
+
+/// An incremental BLAKE3 hasher
+pub struct Hasher {
+    chunk_state: ChunkState,
+    key_words: [u32; 8],
+    cv_stack: Vec<[u32; 8]>,
+    flags: u32,
+}
+
+impl Hasher {
+    fn new_internal(key_words: [u32; 8], flags: u32) -> Hasher {
+        Hasher {
+            chunk_state: ChunkState::new(key_words, 0, flags),
+            key_words: key_words,
+            cv_stack: Vec::new(),
+            flags: flags,
+        }
+    }
+
+    /// Constructs a new `Hasher` for the regular hash function
+    pub fn new() -> Hasher {
+        Hasher::new_internal(IV, 0)
+    }
+
+    /// Constructs a new `Hasher` for the key derivation function with a hardcoded,
+    /// globally unique and application-specific context string
+    pub fn new_derive_key(context: &str) -> Hasher {
+        let mut context_hasher = Hasher::new_internal(IV, DERIVE_KEY_CONTEXT);
+        context_hasher.update(context.as_bytes());
+        let mut context_key = [0u8; OUT_LEN];
+        context_hasher.finalize(&mut context_key);
+
+        let mut context_key_words = [0u32; 8];
+        words_from_le_bytes(&context_key, &mut context_key_words);
+        Hasher::new_internal(context_key_words, DERIVE_KEY_MATERIAL)
+    }
+
+    fn add_chunk_chaining_value(&mut self, mut new_cv: [u32; 8], mut total_chunks: u64) {
+        // Merge completed subtrees, the number of trailing zero bits of `total_chunks`
+        // is the number of subtrees that are finished.
+        while total_chunks & 1 == 0 {
+            let left = self.cv_stack.pop().expect("BLAKE3 chaining value stack underflow");
+            new_cv = parent_output(left, new_cv, self.key_words, self.flags).chaining_value();
+            total_chunks >>= 1;
+        }
+        self.cv_stack.push(new_cv);
+    }
+
+    /// Adds input to the hash state
+    pub fn update(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            // If the current chunk is complete, finalize it and reset the
+            // chunk state. More input is coming, so this chunk is not ROOT.
+            if self.chunk_state.len() == CHUNK_LEN {
+                let chunk_cv = self.chunk_state.output().chaining_value();
+                let total_chunks = self.chunk_state.chunk_counter + 1;
+                self.add_chunk_chaining_value(chunk_cv, total_chunks);
+                self.chunk_state = ChunkState::new(self.key_words, total_chunks, self.flags);
+            }
+
+            let want = CHUNK_LEN - self.chunk_state.len();
+            let take = cmp::min(want, input.len());
+            self.chunk_state.update(&input[..take]);
+            input = &input[take..];
+        }
+    }
+
+    /// Finalizes the hash and writes `out.len()` bytes of output
+    pub fn finalize(&self, out: &mut [u8]) {
+        let mut output = self.chunk_state.output();
+        for cv in self.cv_stack.iter().rev() {
+            output = parent_output(*cv, output.chaining_value(), self.key_words, self.flags);
+        }
+        output.root_output_bytes(out);
+    }
+}
+
+/// Computes the 32 bytes BLAKE3 hash of `input`
+pub fn hash(input: &[u8]) -> Vec<u8> {
+    let mut hasher = Hasher::new();
+    hasher.update(input);
+
+    let mut out = [0u8; OUT_LEN];
+    hasher.finalize(&mut out);
+    out.to_vec()
+}
+
+/// Derives a key of `len` bytes from `key_material` in the given `context`
+pub fn derive_key(context: &str, key_material: &[u8], len: usize) -> Vec<u8> {
+    let mut hasher = Hasher::new_derive_key(context);
+    hasher.update(key_material);
+
+    let mut out: Vec<u8> = range(0, len).map(|_| 0u8).collect();
+    hasher.finalize(out.as_mut_slice());
+    out
+}
+
+#[cfg(test)]
+mod test_blake3 {
+    use serialize::hex::ToHex;
+
+    use crypto::blake3;
+
+    #[test]
+    fn test_hash() {
+        assert_eq!(blake3::hash(b"").to_hex(),
+                   "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262".to_string());
+        assert_eq!(blake3::hash(b"abc").to_hex(),
+                   "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85".to_string());
+    }
+
+    #[test]
+    fn test_hash_multiple_chunks() {
+        // Covers chunk chaining and parent nodes (3 chunks)
+        let input = range(0us, 2049).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        assert_eq!(blake3::hash(input.as_slice()).to_hex(),
+                   "5f4d72f40d7a5f82b15ca2b2e44b1de3c2ef86c426c95c1af0b6879522563030".to_string());
+    }
+
+    #[test]
+    fn test_derive_key() {
+        // Official test vector with empty input
+        assert_eq!(blake3::derive_key("BLAKE3 2019-12-27 16:29:52 test vectors context", b"", 32).to_hex(),
+                   "2cc39783c223154fea8dfb7c1b1660f2ac2dcbd1c1de8277b0b0dd39b7e50d7d".to_string());
+    }
+}
@@ -24,13 +24,18 @@
 use std::str::FromStr;
 use std::fmt::{Debug, Display, self};
 use std::rand::{self, Rng};
+use std::iter::repeat;
 
-use crypto::openssl;
 use crypto::table;
-#[cfg(feature = "enable-sodium")]
+#[cfg(all(feature = "openssl", not(feature = "crypto-rust")))]
+use crypto::openssl;
+#[cfg(all(feature = "enable-sodium", not(feature = "crypto-rust")))]
 use crypto::sodium;
+#[cfg(feature = "crypto-rust")]
+use crypto::rust;
 use crypto::CryptoMode;
 use crypto::rc4_md5;
+use crypto::aead;
 
 use crypto::digest::{self, DigestType};
 
@@ -39,7 +44,19 @@ use crypto::digest::{self, DigestType};
 /// The `update` method could be called multiple times, and the `finalize` method will
 /// encrypt the last block
 pub trait Cipher {
-    fn update(&mut self, data: &[u8]) -> CipherResult<Vec<u8>>;
+    fn update(&mut self, data: &[u8]) -> CipherResult<Vec<u8>> {
+        let mut out: Vec<u8> = repeat(0u8).take(data.len()).collect();
+        let len = try!(self.update_into(data, out.as_mut_slice()));
+        out.truncate(len);
+        Ok(out)
+    }
+
+    /// Processes `data` into `out` without allocating, returns the number of bytes written.
+    ///
+    /// `out` must be at least as long as `data`. All supported ciphers work in stream modes,
+    /// so exactly `data.len()` bytes will be written.
+    fn update_into(&mut self, data: &[u8], out: &mut [u8]) -> CipherResult<usize>;
+
     fn finalize(&mut self) -> CipherResult<Vec<u8>>;
 }
 
@@ -48,6 +65,9 @@ pub type CipherResult<T> = Result<T, Error>;
 #[derive(Copy)]
 pub enum ErrorKind {
     OpenSSLError,
+    SodiumError,
+    RustCryptoError,
+    InvalidData,
 }
 
 pub struct Error {
@@ -149,7 +169,30 @@ const CIPHER_CHACHA20: &'static str = "chacha20";
 #[cfg(feature = "cipher-salsa20")]
 const CIPHER_SALSA20: &'static str = "salsa20";
 
-#[derive(Clone, Debug, Copy)]
+#[cfg(feature = "cipher-aes-gcm")]
+const CIPHER_AES_128_GCM: &'static str = "aes-128-gcm";
+#[cfg(feature = "cipher-aes-gcm")]
+const CIPHER_AES_256_GCM: &'static str = "aes-256-gcm";
+#[cfg(feature = "cipher-chacha20-ietf-poly1305")]
+const CIPHER_CHACHA20_IETF_POLY1305: &'static str = "chacha20-ietf-poly1305";
+
+#[cfg(feature = "cipher-2022-blake3-aes-256-gcm")]
+const CIPHER_2022_BLAKE3_AES_256_GCM: &'static str = "2022-blake3-aes-256-gcm";
+#[cfg(feature = "cipher-2022-blake3-chacha20-poly1305")]
+const CIPHER_2022_BLAKE3_CHACHA20_POLY1305: &'static str = "2022-blake3-chacha20-poly1305";
+
+/// Category of ciphers
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+pub enum CipherCategory {
+    /// Stream ciphers, IV is sent at the beginning of a stream
+    Stream,
+    /// AEAD ciphers, salt is sent at the beginning of a stream, data is sent in authenticated chunks
+    Aead,
+    /// AEAD ciphers of the Shadowsocks 2022 edition (SIP022), key is a base64 encoded pre-shared key
+    Aead2022,
+}
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
 pub enum CipherType {
     Table,
 
@@ -192,12 +235,17 @@ pub enum CipherType {
 
     #[cfg(feature = "cipher-chacha20")] ChaCha20,
     #[cfg(feature = "cipher-salsa20")] Salsa20,
+
+    #[cfg(feature = "cipher-aes-gcm")] Aes128Gcm,
+    #[cfg(feature = "cipher-aes-gcm")] Aes256Gcm,
+    #[cfg(feature = "cipher-chacha20-ietf-poly1305")] ChaCha20IetfPoly1305,
+
+    #[cfg(feature = "cipher-2022-blake3-aes-256-gcm")] Blake3Aes256Gcm,
+    #[cfg(feature = "cipher-2022-blake3-chacha20-poly1305")] Blake3ChaCha20Poly1305,
 }
 
 impl CipherType {
     pub fn block_size(&self) -> usize {
-        use libsodium_ffi::{crypto_stream_chacha20_NONCEBYTES, crypto_stream_salsa20_NONCEBYTES};
-
         match *self {
             CipherType::Table => 0,
 
@@ -238,14 +286,19 @@ impl CipherType {
             #[cfg(feature = "cipher-rc4")] CipherType::Rc4Md5 => 16,
             #[cfg(feature = "cipher-seed-cfb")] CipherType::SeedCfb => 16,
 
-            #[cfg(feature = "cipher-chacha20")] CipherType::ChaCha20 => crypto_stream_chacha20_NONCEBYTES as usize,
-            #[cfg(feature = "cipher-salsa20")] CipherType::Salsa20 => crypto_stream_salsa20_NONCEBYTES as usize,
+            #[cfg(feature = "cipher-chacha20")] CipherType::ChaCha20 => 8,
+            #[cfg(feature = "cipher-salsa20")] CipherType::Salsa20 => 8,
+
+            #[cfg(feature = "cipher-aes-gcm")] CipherType::Aes128Gcm => 16,
+            #[cfg(feature = "cipher-aes-gcm")] CipherType::Aes256Gcm => 16,
+            #[cfg(feature = "cipher-chacha20-ietf-poly1305")] CipherType::ChaCha20IetfPoly1305 => 0,
+
+            #[cfg(feature = "cipher-2022-blake3-aes-256-gcm")] CipherType::Blake3Aes256Gcm => 16,
+            #[cfg(feature = "cipher-2022-blake3-chacha20-poly1305")] CipherType::Blake3ChaCha20Poly1305 => 0,
         }
     }
 
     pub fn key_size(&self) -> usize {
-        use libsodium_ffi::{crypto_stream_chacha20_KEYBYTES, crypto_stream_salsa20_KEYBYTES};
-
         match *self {
             CipherType::Table => 0,
 
@@ -286,11 +339,61 @@ impl CipherType {
             #[cfg(feature = "cipher-rc4")] CipherType::Rc4Md5 => 16,
             #[cfg(feature = "cipher-seed-cfb")] CipherType::SeedCfb => 16,
 
-            #[cfg(feature = "cipher-chacha20")] CipherType::ChaCha20 => crypto_stream_chacha20_KEYBYTES as usize,
-            #[cfg(feature = "cipher-salsa20")] CipherType::Salsa20 => crypto_stream_salsa20_KEYBYTES as usize,
+            #[cfg(feature = "cipher-chacha20")] CipherType::ChaCha20 => 32,
+            #[cfg(feature = "cipher-salsa20")] CipherType::Salsa20 => 32,
+
+            #[cfg(feature = "cipher-aes-gcm")] CipherType::Aes128Gcm => 16,
+            #[cfg(feature = "cipher-aes-gcm")] CipherType::Aes256Gcm => 32,
+            #[cfg(feature = "cipher-chacha20-ietf-poly1305")] CipherType::ChaCha20IetfPoly1305 => 32,
+
+            #[cfg(feature = "cipher-2022-blake3-aes-256-gcm")] CipherType::Blake3Aes256Gcm => 32,
+            #[cfg(feature = "cipher-2022-blake3-chacha20-poly1305")] CipherType::Blake3ChaCha20Poly1305 => 32,
+        }
+    }
+
+    pub fn category(&self) -> CipherCategory {
+        match *self {
+            #[cfg(feature = "cipher-aes-gcm")] CipherType::Aes128Gcm => CipherCategory::Aead,
+            #[cfg(feature = "cipher-aes-gcm")] CipherType::Aes256Gcm => CipherCategory::Aead,
+            #[cfg(feature = "cipher-chacha20-ietf-poly1305")] CipherType::ChaCha20IetfPoly1305 => CipherCategory::Aead,
+
+            #[cfg(feature = "cipher-2022-blake3-aes-256-gcm")]
+            CipherType::Blake3Aes256Gcm => CipherCategory::Aead2022,
+            #[cfg(feature = "cipher-2022-blake3-chacha20-poly1305")]
+            CipherType::Blake3ChaCha20Poly1305 => CipherCategory::Aead2022,
+
+            _ => CipherCategory::Stream,
+        }
+    }
+
+    /// Length of salt for AEAD ciphers, which is the same as the key length
+    pub fn salt_size(&self) -> usize {
+        match self.category() {
+            CipherCategory::Aead | CipherCategory::Aead2022 => self.key_size(),
+            CipherCategory::Stream => 0,
+        }
+    }
+
+    /// Length of authentication tag, 0 for stream ciphers
+    pub fn tag_size(&self) -> usize {
+        match self.category() {
+            CipherCategory::Aead | CipherCategory::Aead2022 => aead::TAG_SIZE,
+            CipherCategory::Stream => 0,
+        }
+    }
+
+    /// Length of the IV (stream ciphers) or salt (AEAD ciphers) at the beginning of a stream
+    pub fn iv_size(&self) -> usize {
+        match self.category() {
+            CipherCategory::Aead | CipherCategory::Aead2022 => self.salt_size(),
+            CipherCategory::Stream => self.block_size(),
         }
     }
 
+    /// Generates the master key from password with `EVP_BytesToKey` (MD5).
+    ///
+    /// Ciphers of the 2022 edition do not use this, their keys are decoded from base64
+    /// by `crypto::aead2022::decode_psk`.
     pub fn bytes_to_key(&self, key: &[u8]) -> Vec<u8> {
         let iv_len = self.block_size();
         let key_len = self.key_size();
@@ -316,8 +419,9 @@ impl CipherType {
         key
     }
 
+    /// Generates a random IV for stream ciphers, or a random salt for AEAD ciphers
     pub fn gen_init_vec(&self) -> Vec<u8> {
-        let iv_len = self.block_size();
+        let iv_len = self.iv_size();
         let mut iv = Vec::with_capacity(iv_len);
         unsafe { iv.set_len(iv_len); }
         rand::thread_rng().fill_bytes(iv.as_mut_slice());
@@ -432,28 +536,67 @@ impl FromStr for CipherType {
             CIPHER_SALSA20 =>
                 Some(CipherType::Salsa20),
 
+            #[cfg(feature = "cipher-aes-gcm")]
+            CIPHER_AES_128_GCM =>
+                Some(CipherType::Aes128Gcm),
+            #[cfg(feature = "cipher-aes-gcm")]
+            CIPHER_AES_256_GCM =>
+                Some(CipherType::Aes256Gcm),
+            #[cfg(feature = "cipher-chacha20-ietf-poly1305")]
+            CIPHER_CHACHA20_IETF_POLY1305 =>
+                Some(CipherType::ChaCha20IetfPoly1305),
+
+            #[cfg(feature = "cipher-2022-blake3-aes-256-gcm")]
+            CIPHER_2022_BLAKE3_AES_256_GCM =>
+                Some(CipherType::Blake3Aes256Gcm),
+            #[cfg(feature = "cipher-2022-blake3-chacha20-poly1305")]
+            CIPHER_2022_BLAKE3_CHACHA20_POLY1305 =>
+                Some(CipherType::Blake3ChaCha20Poly1305),
+
             _ => None
         }
     }
 }
 
 /// Generate a specific Cipher with key and initialize vector
+/// Creates a stream cipher, AEAD ciphers should be created by `crypto::aead::with_type`
 pub fn with_type(t: CipherType, key: &[u8], iv: &[u8], mode: CryptoMode) -> Box<Cipher + Send> {
+    if t.category() != CipherCategory::Stream {
+        panic!("{:?} is an AEAD cipher, use crypto::aead::with_type instead", t);
+    }
+
     match t {
         CipherType::Table => box table::TableCipher::new(key, mode) as Box<Cipher + Send>,
 
-        #[cfg(feature = "cipher-chacha20")]
+        #[cfg(feature = "cipher-rc4")]
+        CipherType::Rc4Md5 =>
+            box rc4_md5::Rc4Md5Cipher::new(key, iv, mode) as Box<Cipher + Send>,
+
+        _ => new_backend_cipher(t, key, iv, mode),
+    }
+}
+
+/// All ciphers are provided by the pure Rust backend if the `crypto-rust` feature is enabled
+#[cfg(feature = "crypto-rust")]
+fn new_backend_cipher(t: CipherType, key: &[u8], iv: &[u8], mode: CryptoMode) -> Box<Cipher + Send> {
+    box rust::RustCipher::new(t, key, iv, mode) as Box<Cipher + Send>
+}
+
+/// ChaCha20 and Salsa20 are provided by libsodium, the others by OpenSSL
+#[cfg(not(feature = "crypto-rust"))]
+fn new_backend_cipher(t: CipherType, key: &[u8], iv: &[u8], mode: CryptoMode) -> Box<Cipher + Send> {
+    match t {
+        #[cfg(all(feature = "cipher-chacha20", feature = "enable-sodium"))]
         CipherType::ChaCha20 =>
             box sodium::SodiumCipher::new(t, key, iv) as Box<Cipher + Send>,
-        #[cfg(feature = "cipher-salsa20")]
+        #[cfg(all(feature = "cipher-salsa20", feature = "enable-sodium"))]
         CipherType::Salsa20 =>
             box sodium::SodiumCipher::new(t, key, iv) as Box<Cipher + Send>,
 
-        #[cfg(feature = "cipher-rc4")]
-        CipherType::Rc4Md5 =>
-            box rc4_md5::Rc4Md5Cipher::new(key, iv, mode) as Box<Cipher + Send>,
-
+        #[cfg(feature = "openssl")]
         _ => box openssl::OpenSSLCipher::new(t, key, iv, mode) as Box<Cipher + Send>,
+        #[cfg(not(feature = "openssl"))]
+        _ => panic!("{:?} is not supported by the enabled crypto backends", t),
     }
 }
 
@@ -475,4 +618,122 @@ mod test_cipher {
 
         assert!(message.as_bytes() == decrypted_msg.as_slice());
     }
+
+    #[test]
+    fn test_update_into() {
+        let types = [
+            CipherType::Table,
+            CipherType::Aes256Cfb,
+            CipherType::Aes256Ofb,
+            CipherType::BfCfb,
+            CipherType::Rc4Md5,
+            CipherType::ChaCha20,
+            CipherType::Salsa20,
+        ];
+        let message = range(0, 1000).map(|x| x as u8).collect::<Vec<u8>>();
+
+        for t in types.iter() {
+            let key = t.bytes_to_key(b"PassWORD");
+            let iv = t.gen_init_vec();
+            let mut encryptor = with_type(*t, key.as_slice(), iv.as_slice(), CryptoMode::Encrypt);
+            let mut encryptor_into = with_type(*t, key.as_slice(), iv.as_slice(), CryptoMode::Encrypt);
+
+            // Chunks which are not aligned to the block size of any ciphers
+            let mut out = [0u8; 100];
+            for chunk in message.as_slice().chunks(99) {
+                let encrypted_msg = encryptor.update(chunk).unwrap();
+                let len = encryptor_into.update_into(chunk, &mut out).unwrap();
+                assert!(encrypted_msg.as_slice() == &out[..len], "{:?} differs in update_into", t);
+            }
+        }
+    }
+}
+
+/// Throughput of `update` against `update_into` for each stream cipher, measured by `cargo bench`.
+/// These are libtest benchmarks, criterion doesn't support the toolchain this crate is built with.
+#[cfg(test)]
+mod bench_cipher {
+    extern crate test;
+
+    use std::iter::repeat;
+
+    use crypto::cipher::{Cipher, CipherType, with_type};
+    use crypto::CryptoMode;
+
+    // Same as the size of blocks read by the relay
+    const CHUNK_SIZE: usize = 2048;
+    const CHUNK_COUNT: usize = 64;
+
+    fn bench_update(b: &mut test::Bencher, t: CipherType) {
+        let key = t.bytes_to_key(b"PassWORD");
+        let iv = t.gen_init_vec();
+        let mut cipher = with_type(t, key.as_slice(), iv.as_slice(), CryptoMode::Encrypt);
+        let data: Vec<u8> = repeat(0u8).take(CHUNK_SIZE).collect();
+
+        b.iter(|| {
+            for _ in range(0, CHUNK_COUNT) {
+                test::black_box(cipher.update(data.as_slice()).unwrap());
+            }
+        });
+        b.bytes = (CHUNK_SIZE * CHUNK_COUNT) as u64;
+    }
+
+    fn bench_update_into(b: &mut test::Bencher, t: CipherType) {
+        let key = t.bytes_to_key(b"PassWORD");
+        let iv = t.gen_init_vec();
+        let mut cipher = with_type(t, key.as_slice(), iv.as_slice(), CryptoMode::Encrypt);
+        let data: Vec<u8> = repeat(0u8).take(CHUNK_SIZE).collect();
+        let mut out: Vec<u8> = repeat(0u8).take(CHUNK_SIZE).collect();
+
+        b.iter(|| {
+            for _ in range(0, CHUNK_COUNT) {
+                test::black_box(cipher.update_into(data.as_slice(), out.as_mut_slice()).unwrap());
+            }
+        });
+        b.bytes = (CHUNK_SIZE * CHUNK_COUNT) as u64;
+    }
+
+    macro_rules! cipher_benches {
+        ($($(#[$cfg:meta])* $update:ident, $update_into:ident => $t:expr;)*) => {
+            $(
+                $(#[$cfg])*
+                #[bench]
+                fn $update(b: &mut test::Bencher) {
+                    bench_update(b, $t)
+                }
+
+                $(#[$cfg])*
+                #[bench]
+                fn $update_into(b: &mut test::Bencher) {
+                    bench_update_into(b, $t)
+                }
+            )*
+        }
+    }
+
+    cipher_benches! {
+        bench_table_update, bench_table_update_into => CipherType::Table;
+        #[cfg(feature = "cipher-aes-cfb")]
+        bench_aes_128_cfb_update, bench_aes_128_cfb_update_into => CipherType::Aes128Cfb;
+        #[cfg(feature = "cipher-aes-cfb")]
+        bench_aes_256_cfb_update, bench_aes_256_cfb_update_into => CipherType::Aes256Cfb;
+        #[cfg(feature = "cipher-aes-cfb")]
+        bench_aes_256_cfb8_update, bench_aes_256_cfb8_update_into => CipherType::Aes256Cfb8;
+        #[cfg(feature = "cipher-aes-ofb")]
+        bench_aes_256_ofb_update, bench_aes_256_ofb_update_into => CipherType::Aes256Ofb;
+        #[cfg(feature = "cipher-bf-cfb")]
+        bench_bf_cfb_update, bench_bf_cfb_update_into => CipherType::BfCfb;
+        #[cfg(feature = "cipher-cast5-cfb")]
+        bench_cast5_cfb_update, bench_cast5_cfb_update_into => CipherType::Cast5Cfb;
+        #[cfg(feature = "cipher-des-cfb")]
+        bench_des_cfb_update, bench_des_cfb_update_into => CipherType::DesCfb;
+        #[cfg(feature = "cipher-rc2-cfb")]
+        bench_rc2_cfb_update, bench_rc2_cfb_update_into => CipherType::Rc2Cfb;
+        #[cfg(feature = "cipher-rc4")]
+        bench_rc4_md5_update, bench_rc4_md5_update_into => CipherType::Rc4Md5;
+        #[cfg(feature = "cipher-chacha20")]
+        bench_chacha20_update, bench_chacha20_update_into => CipherType::ChaCha20;
+        #[cfg(feature = "cipher-salsa20")]
+        bench_salsa20_update, bench_salsa20_update_into => CipherType::Salsa20;
+    }
 }
@@ -21,17 +21,22 @@
 
 //! Message digest algorithm
 
+#[cfg(all(feature = "openssl", not(feature = "crypto-rust")))]
 use crypto::openssl;
+#[cfg(feature = "crypto-rust")]
+use crypto::rust;
 
 pub trait Digest: Send {
     fn update(&mut self, data: &[u8]);
     fn digest(&mut self) -> Vec<u8>;
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub enum DigestType {
     Md5,
     Sha1,
+    /// SHA-0, which is only provided by OpenSSL
+    #[cfg(not(feature = "crypto-rust"))]
     Sha,
 }
 
@@ -40,14 +45,32 @@ impl DigestType {
         match *self {
             DigestType::Md5 => 16,
             DigestType::Sha1 => 20,
+            #[cfg(not(feature = "crypto-rust"))]
             DigestType::Sha => 20,
         }
     }
+
+    /// Internal block size of the hash function, used by HMAC
+    pub fn block_size(&self) -> usize {
+        match *self {
+            DigestType::Md5 | DigestType::Sha1 => 64,
+            #[cfg(not(feature = "crypto-rust"))]
+            DigestType::Sha => 64,
+        }
+    }
 }
 
+#[cfg(feature = "crypto-rust")]
 pub fn with_type(t: DigestType) -> Box<Digest + Send> {
-    match t {
-        DigestType::Md5 | DigestType::Sha1 | DigestType::Sha =>
-            box openssl::OpenSSLDigest::new(t) as Box<Digest + Send>,
-    }
+    box rust::RustDigest::new(t) as Box<Digest + Send>
+}
+
+#[cfg(all(feature = "openssl", not(feature = "crypto-rust")))]
+pub fn with_type(t: DigestType) -> Box<Digest + Send> {
+    box openssl::OpenSSLDigest::new(t) as Box<Digest + Send>
+}
+
+#[cfg(not(any(feature = "openssl", feature = "crypto-rust")))]
+pub fn with_type(t: DigestType) -> Box<Digest + Send> {
+    panic!("{:?} is not supported by the enabled crypto backends", t)
 }
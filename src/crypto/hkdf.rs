@@ -0,0 +1,105 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! HMAC and HKDF (RFC 5869) built on top of `Digest`
+//!
+//! AEAD ciphers derive a per-session subkey from the master key and the salt with
+//! HKDF-SHA1, exactly like the other shadowsocks implementations do.
+
+use std::iter::repeat;
+
+use crypto::digest::{self, DigestType};
+
+/// Computes HMAC of `data` with `key`
+pub fn hmac(t: DigestType, key: &[u8], data: &[u8]) -> Vec<u8> {
+    let block_size = t.block_size();
+
+    let mut padded_key = if key.len() > block_size {
+        let mut d = digest::with_type(t);
+        d.update(key);
+        d.digest()
+    } else {
+        key.to_vec()
+    };
+    padded_key.resize(block_size, 0u8);
+
+    let ipad = padded_key.iter().map(|&x| x ^ 0x36).collect::<Vec<u8>>();
+    let opad = padded_key.iter().map(|&x| x ^ 0x5c).collect::<Vec<u8>>();
+
+    let mut inner = digest::with_type(t);
+    inner.update(ipad.as_slice());
+    inner.update(data);
+    let inner_digest = inner.digest();
+
+    let mut outer = digest::with_type(t);
+    outer.update(opad.as_slice());
+    outer.update(inner_digest.as_slice());
+    outer.digest()
+}
+
+/// HKDF-Extract followed by HKDF-Expand, returns `len` bytes of output keying material
+pub fn hkdf(t: DigestType, salt: &[u8], ikm: &[u8], info: &[u8], len: usize) -> Vec<u8> {
+    let salt = if salt.is_empty() {
+        repeat(0u8).take(t.digest_len()).collect::<Vec<u8>>()
+    } else {
+        salt.to_vec()
+    };
+
+    let prk = hmac(t, salt.as_slice(), ikm);
+
+    let mut okm = Vec::with_capacity(len + t.digest_len());
+    let mut prev: Vec<u8> = Vec::new();
+    let mut counter = 1u8;
+    while okm.len() < len {
+        let mut input = prev.clone();
+        input.push_all(info);
+        input.push(counter);
+
+        prev = hmac(t, prk.as_slice(), input.as_slice());
+        okm.push_all(prev.as_slice());
+        counter += 1;
+    }
+
+    okm.truncate(len);
+    okm
+}
+
+#[cfg(test)]
+mod test_hkdf {
+    use serialize::hex::FromHex;
+
+    use crypto::digest::DigestType;
+    use crypto::hkdf::hkdf;
+
+    #[test]
+    fn test_hkdf_sha1_rfc5869() {
+        // RFC 5869, Test Case 4
+        let ikm = "0b0b0b0b0b0b0b0b0b0b0b".from_hex().unwrap();
+        let salt = "000102030405060708090a0b0c".from_hex().unwrap();
+        let info = "f0f1f2f3f4f5f6f7f8f9".from_hex().unwrap();
+        let expected = "085a01ea1b10f36933068b56efa5ad81a4f14b822f5b091568a9cdd4f155fda2c22e422478d305f3f896"
+                            .from_hex().unwrap();
+
+        let okm = hkdf(DigestType::Sha1, salt.as_slice(), ikm.as_slice(), info.as_slice(), 42);
+        assert_eq!(okm, expected);
+    }
+}
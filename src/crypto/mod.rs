@@ -25,12 +25,19 @@ extern crate libc;
 extern crate test;
 
 pub mod cipher;
+#[cfg(feature = "openssl")]
 pub mod openssl;
 pub mod digest;
 pub mod table;
 pub mod rc4_md5;
+pub mod aead;
+pub mod aead2022;
+pub mod hkdf;
+pub mod blake3;
 #[cfg(feature = "enable-sodium")]
 pub mod sodium;
+#[cfg(feature = "crypto-rust")]
+pub mod rust;
 
 #[derive(Clone, Copy)]
 pub enum CryptoMode {
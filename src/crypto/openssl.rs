@@ -27,6 +27,7 @@ extern crate libc;
 
 use crypto::cipher::{Cipher, CipherType, CipherResult};
 use crypto::cipher;
+use crypto::aead::{self, AeadCipher};
 
 use crypto::digest::Digest;
 use crypto::digest;
@@ -34,6 +35,7 @@ use crypto::CryptoMode;
 
 use std::ptr;
 use std::clone::Clone;
+use std::iter::repeat;
 
 mod ffi {
     extern crate libc;
@@ -52,6 +54,10 @@ mod ffi {
     pub const CRYPTO_MODE_ENCRYPT: libc::c_int = 1;
     pub const CRYPTO_MODE_DECRYPT: libc::c_int = 0;
 
+    pub const EVP_CTRL_GCM_SET_IVLEN: libc::c_int = 0x9;
+    pub const EVP_CTRL_GCM_GET_TAG: libc::c_int = 0x10;
+    pub const EVP_CTRL_GCM_SET_TAG: libc::c_int = 0x11;
+
     #[allow(dead_code)]
     #[link(name = "crypto")]
     extern {
@@ -68,6 +74,8 @@ mod ffi {
                                 inbuf: *const libc::c_uchar, inlen: libc::c_int) -> libc::c_int;
         pub fn EVP_CipherFinal(ctx: *mut EVP_CIPHER_CTX, res: *mut libc::c_uchar, len: *mut libc::c_int)
             -> libc::c_int;
+        pub fn EVP_CIPHER_CTX_ctrl(ctx: *mut EVP_CIPHER_CTX, ctrl_type: libc::c_int, arg: libc::c_int,
+                                   ptr: *mut libc::c_void) -> libc::c_int;
 
         // Ciphers
         #[cfg(feature = "cipher-aes-cfb")]
@@ -128,6 +136,15 @@ mod ffi {
         #[cfg(feature = "cipher-rc4")]
         pub fn EVP_rc4() -> *const EVP_CIPHER;
 
+        #[cfg(feature = "cipher-aes-gcm")]
+        pub fn EVP_aes_128_gcm() -> *const EVP_CIPHER;
+        #[cfg(feature = "cipher-aes-gcm")]
+        pub fn EVP_aes_256_gcm() -> *const EVP_CIPHER;
+
+        pub fn EVP_aes_128_ecb() -> *const EVP_CIPHER;
+        pub fn EVP_aes_256_ecb() -> *const EVP_CIPHER;
+        pub fn EVP_CIPHER_CTX_set_padding(ctx: *mut EVP_CIPHER_CTX, padding: libc::c_int) -> libc::c_int;
+
         // MD
         pub fn EVP_MD_CTX_create() -> *mut EVP_MD_CTX;
         pub fn EVP_MD_CTX_init(ctx: *mut EVP_MD_CTX);
@@ -173,6 +190,7 @@ impl OpenSSLDigest {
         unsafe {
             match t {
                 digest::DigestType::Md5 => ffi::EVP_md5(),
+                #[cfg(not(feature = "crypto-rust"))]
                 digest::DigestType::Sha => ffi::EVP_sha(),
                 digest::DigestType::Sha1 => ffi::EVP_sha1(),
             }
@@ -338,12 +356,21 @@ impl OpenSSLCrypto {
     }
 
     pub fn update(&self, data: &[u8]) -> CipherResult<Vec<u8>> {
-        let pdata: *const u8 = data.as_ptr();
-        let datalen: libc::c_int = data.len() as libc::c_int;
-
         let mut out = Vec::with_capacity(data.len() + self.cipher_type.block_size());
         unsafe { out.set_len(data.len() + self.cipher_type.block_size()); }
 
+        let len = try!(self.update_into(data, out.as_mut_slice()));
+        out.truncate(len);
+        Ok(out)
+    }
+
+    /// Ciphers are used in stream modes, so `out` with the same length as `data` is enough
+    pub fn update_into(&self, data: &[u8], out: &mut [u8]) -> CipherResult<usize> {
+        debug_assert!(out.len() >= data.len());
+
+        let pdata: *const u8 = data.as_ptr();
+        let datalen: libc::c_int = data.len() as libc::c_int;
+
         let mut len: libc::c_int = 0;
         let pres: *mut u8 = out.as_mut_ptr();
 
@@ -360,8 +387,7 @@ impl OpenSSLCrypto {
             }
         }
 
-        out.truncate(len as usize);
-        Ok(out)
+        Ok(len as usize)
     }
 
     pub fn finalize(&self) -> CipherResult<Vec<u8>> {
@@ -456,6 +482,10 @@ impl Cipher for OpenSSLCipher {
         self.worker.update(data)
     }
 
+    fn update_into(&mut self, data: &[u8], out: &mut [u8]) -> CipherResult<usize> {
+        self.worker.update_into(data, out)
+    }
+
     fn finalize(&mut self) -> CipherResult<Vec<u8>> {
         self.worker.finalize()
     }
@@ -463,6 +493,206 @@ impl Cipher for OpenSSLCipher {
 
 unsafe impl Send for OpenSSLCipher {}
 
+/// AEAD cipher binding for OpenSSL's `libcrypto`, supports AES-GCM.
+///
+/// The nonce is managed internally and increased after each `encrypt` or `decrypt`.
+#[cfg(feature = "cipher-aes-gcm")]
+pub struct OpenSSLAeadCipher {
+    evp_ctx: *mut ffi::EVP_CIPHER_CTX,
+    cipher: *const ffi::EVP_CIPHER,
+    key: Vec<u8>,
+    nonce: Vec<u8>,
+}
+
+#[cfg(feature = "cipher-aes-gcm")]
+impl OpenSSLAeadCipher {
+    pub fn new(cipher_type: cipher::CipherType, key: &[u8]) -> OpenSSLAeadCipher {
+        let nonce = repeat(0u8).take(aead::NONCE_SIZE).collect::<Vec<u8>>();
+        OpenSSLAeadCipher::with_nonce(cipher_type, key, nonce.as_slice())
+    }
+
+    /// Creates a cipher starting from `nonce` instead of zero
+    pub fn with_nonce(cipher_type: cipher::CipherType, key: &[u8], nonce: &[u8]) -> OpenSSLAeadCipher {
+        debug_assert!(key.len() == cipher_type.key_size());
+        debug_assert!(nonce.len() == aead::NONCE_SIZE);
+
+        let cipher = unsafe {
+            match cipher_type {
+                cipher::CipherType::Aes128Gcm => ffi::EVP_aes_128_gcm(),
+                cipher::CipherType::Aes256Gcm => ffi::EVP_aes_256_gcm(),
+                #[cfg(feature = "cipher-2022-blake3-aes-256-gcm")]
+                cipher::CipherType::Blake3Aes256Gcm => ffi::EVP_aes_256_gcm(),
+                _ => panic!("Unsupported AEAD cipher type of OpenSSL"),
+            }
+        };
+
+        let evp_ctx = unsafe { ffi::EVP_CIPHER_CTX_new() };
+        assert!(!evp_ctx.is_null());
+
+        OpenSSLAeadCipher {
+            evp_ctx: evp_ctx,
+            cipher: cipher,
+            key: key.to_vec(),
+            nonce: nonce.to_vec(),
+        }
+    }
+
+    fn init(&mut self, mode: libc::c_int) -> CipherResult<()> {
+        unsafe {
+            if ffi::EVP_CipherInit_ex(self.evp_ctx, self.cipher, ptr::null_mut(),
+                                      ptr::null(), ptr::null(), mode) != 1
+                || ffi::EVP_CIPHER_CTX_ctrl(self.evp_ctx, ffi::EVP_CTRL_GCM_SET_IVLEN,
+                                            self.nonce.len() as libc::c_int, ptr::null_mut()) != 1
+                || ffi::EVP_CipherInit_ex(self.evp_ctx, ptr::null(), ptr::null_mut(),
+                                          self.key.as_ptr(), self.nonce.as_ptr(), mode) != 1 {
+                return Err(cipher::Error {
+                    kind: cipher::ErrorKind::OpenSSLError,
+                    desc: "Failed on EVP_CipherInit_ex",
+                    detail: None,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, data: &[u8], out: &mut [u8]) -> CipherResult<usize> {
+        let mut len: libc::c_int = 0;
+        unsafe {
+            if ffi::EVP_CipherUpdate(self.evp_ctx, out.as_mut_ptr(), &mut len,
+                                     data.as_ptr(), data.len() as libc::c_int) != 1 {
+                return Err(cipher::Error {
+                    kind: cipher::ErrorKind::OpenSSLError,
+                    desc: "Failed on EVP_CipherUpdate",
+                    detail: None,
+                });
+            }
+        }
+        Ok(len as usize)
+    }
+}
+
+#[cfg(feature = "cipher-aes-gcm")]
+impl AeadCipher for OpenSSLAeadCipher {
+    fn encrypt(&mut self, data: &[u8]) -> CipherResult<Vec<u8>> {
+        try!(self.init(ffi::CRYPTO_MODE_ENCRYPT));
+
+        let mut out: Vec<u8> = repeat(0u8).take(data.len() + aead::TAG_SIZE).collect();
+        let len = try!(self.update(data, out.as_mut_slice()));
+
+        let mut final_len: libc::c_int = 0;
+        unsafe {
+            if ffi::EVP_CipherFinal(self.evp_ctx, out[len..].as_mut_ptr(), &mut final_len) != 1
+                || ffi::EVP_CIPHER_CTX_ctrl(self.evp_ctx, ffi::EVP_CTRL_GCM_GET_TAG, aead::TAG_SIZE as libc::c_int,
+                                            out[data.len()..].as_mut_ptr() as *mut libc::c_void) != 1 {
+                return Err(cipher::Error {
+                    kind: cipher::ErrorKind::OpenSSLError,
+                    desc: "Failed on EVP_CipherFinal",
+                    detail: None,
+                });
+            }
+        }
+
+        aead::increase_nonce(self.nonce.as_mut_slice());
+        Ok(out)
+    }
+
+    fn decrypt(&mut self, data: &[u8]) -> CipherResult<Vec<u8>> {
+        if data.len() < aead::TAG_SIZE {
+            return Err(cipher::Error {
+                kind: cipher::ErrorKind::OpenSSLError,
+                desc: "AEAD data is too short",
+                detail: None,
+            });
+        }
+
+        try!(self.init(ffi::CRYPTO_MODE_DECRYPT));
+
+        let (ciphertext, tag) = data.split_at(data.len() - aead::TAG_SIZE);
+        let mut out: Vec<u8> = repeat(0u8).take(ciphertext.len() + aead::TAG_SIZE).collect();
+        let len = try!(self.update(ciphertext, out.as_mut_slice()));
+
+        let mut final_len: libc::c_int = 0;
+        unsafe {
+            if ffi::EVP_CIPHER_CTX_ctrl(self.evp_ctx, ffi::EVP_CTRL_GCM_SET_TAG, aead::TAG_SIZE as libc::c_int,
+                                        tag.as_ptr() as *mut libc::c_void) != 1
+                || ffi::EVP_CipherFinal(self.evp_ctx, out[len..].as_mut_ptr(), &mut final_len) != 1 {
+                return Err(cipher::Error {
+                    kind: cipher::ErrorKind::OpenSSLError,
+                    desc: "AEAD authentication failed",
+                    detail: None,
+                });
+            }
+        }
+
+        out.truncate(len + final_len as usize);
+        aead::increase_nonce(self.nonce.as_mut_slice());
+        Ok(out)
+    }
+
+    fn tag_size(&self) -> usize {
+        aead::TAG_SIZE
+    }
+}
+
+#[cfg(feature = "cipher-aes-gcm")]
+#[unsafe_destructor]
+impl Drop for OpenSSLAeadCipher {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::EVP_CIPHER_CTX_cleanup(self.evp_ctx);
+            ffi::EVP_CIPHER_CTX_free(self.evp_ctx);
+        }
+    }
+}
+
+#[cfg(feature = "cipher-aes-gcm")]
+unsafe impl Send for OpenSSLAeadCipher {}
+
+/// Encrypts or decrypts exactly one block with AES-ECB, key length decides AES-128 or AES-256
+pub fn aes_ecb_crypt_block(key: &[u8], block: &[u8], mode: CryptoMode) -> CipherResult<Vec<u8>> {
+    debug_assert!(block.len() == 16);
+
+    let cipher = unsafe {
+        match key.len() {
+            16 => ffi::EVP_aes_128_ecb(),
+            32 => ffi::EVP_aes_256_ecb(),
+            _ => panic!("Invalid AES key length {}", key.len()),
+        }
+    };
+
+    let op = match mode {
+        CryptoMode::Encrypt => ffi::CRYPTO_MODE_ENCRYPT,
+        CryptoMode::Decrypt => ffi::CRYPTO_MODE_DECRYPT,
+    };
+
+    let mut out: Vec<u8> = repeat(0u8).take(block.len() * 2).collect();
+    let mut len: libc::c_int = 0;
+    let succeed = unsafe {
+        let ctx = ffi::EVP_CIPHER_CTX_new();
+        assert!(!ctx.is_null());
+
+        let succeed = ffi::EVP_CipherInit_ex(ctx, cipher, ptr::null_mut(), key.as_ptr(), ptr::null(), op) == 1
+            && ffi::EVP_CIPHER_CTX_set_padding(ctx, 0) == 1
+            && ffi::EVP_CipherUpdate(ctx, out.as_mut_ptr(), &mut len,
+                                     block.as_ptr(), block.len() as libc::c_int) == 1;
+
+        ffi::EVP_CIPHER_CTX_cleanup(ctx);
+        ffi::EVP_CIPHER_CTX_free(ctx);
+        succeed
+    };
+
+    if !succeed {
+        return Err(cipher::Error {
+            kind: cipher::ErrorKind::OpenSSLError,
+            desc: "Failed on AES-ECB",
+            detail: None,
+        });
+    }
+
+    out.truncate(len as usize);
+    Ok(out)
+}
+
 #[cfg(test)]
 mod test_openssl {
     extern crate test;
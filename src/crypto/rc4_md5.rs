@@ -21,37 +21,34 @@
 
 //! Rc4Md5 cipher definition
 
-use crypto::openssl::{OpenSSLCrypto, OpenSSLDigest};
-use crypto::cipher::{Cipher, CipherType, CipherResult};
-use crypto::digest::{Digest, DigestType};
+use crypto::cipher::{self, Cipher, CipherType, CipherResult};
+use crypto::digest::{self, DigestType};
 use crypto::CryptoMode;
 
 /// Rc4Md5 Cipher
 pub struct Rc4Md5Cipher {
-    crypto: OpenSSLCrypto,
+    crypto: Box<Cipher + Send>,
 }
 
 impl Rc4Md5Cipher {
     pub fn new(key: &[u8], iv: &[u8], mode: CryptoMode) -> Rc4Md5Cipher {
-        let mut md5_digest = OpenSSLDigest::new(DigestType::Md5);
+        let mut md5_digest = digest::with_type(DigestType::Md5);
         md5_digest.update(key);
         md5_digest.update(iv);
         let key = md5_digest.digest();
 
         Rc4Md5Cipher {
-            crypto: OpenSSLCrypto::new(CipherType::Rc4, key.as_slice(), b"", mode)
+            crypto: cipher::with_type(CipherType::Rc4, key.as_slice(), b"", mode)
         }
     }
 }
 
 impl Cipher for Rc4Md5Cipher {
-    fn update(&mut self, data: &[u8]) -> CipherResult<Vec<u8>> {
-        self.crypto.update(data)
+    fn update_into(&mut self, data: &[u8], out: &mut [u8]) -> CipherResult<usize> {
+        self.crypto.update_into(data, out)
     }
 
     fn finalize(&mut self) -> CipherResult<Vec<u8>> {
         self.crypto.finalize()
     }
 }
-
-unsafe impl Send for Rc4Md5Cipher {}
@@ -0,0 +1,238 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Camellia block cipher defined in RFC 3713
+
+use rust_crypto::symmetriccipher::BlockEncryptor;
+
+use super::{read_be_u64, write_be_u64};
+
+const SIGMAS: [u64; 6] = [
+    0xa09e667f3bcc908b,
+    0xb67ae8584caa73b2,
+    0xc6ef372fe94f82be,
+    0x54ff53a5f1d36f1c,
+    0x10e527fade682d1d,
+    0xb05688c2b3e6c1fd,
+];
+
+pub struct Camellia {
+    // 26 subkeys for 128 bits keys, 34 for 192 and 256 bits keys
+    k: Vec<u64>,
+}
+
+#[inline]
+fn sbox1(x: u8) -> u64 {
+    SBOX1[x as usize] as u64
+}
+
+#[inline]
+fn sbox2(x: u8) -> u64 {
+    SBOX1[x as usize].rotate_left(1) as u64
+}
+
+#[inline]
+fn sbox3(x: u8) -> u64 {
+    SBOX1[x as usize].rotate_left(7) as u64
+}
+
+#[inline]
+fn sbox4(x: u8) -> u64 {
+    SBOX1[x.rotate_left(1) as usize] as u64
+}
+
+fn f(input: u64, key: u64) -> u64 {
+    let x = input ^ key;
+
+    // S-function and P-function at once, the multipliers are the columns of P
+    0x0101010001000001 * sbox1((x >> 56) as u8)
+        ^ 0x0001010101010000 * sbox2((x >> 48) as u8)
+        ^ 0x0100010100010100 * sbox3((x >> 40) as u8)
+        ^ 0x0101000100000101 * sbox4((x >> 32) as u8)
+        ^ 0x0001010100010101 * sbox2((x >> 24) as u8)
+        ^ 0x0100010101000101 * sbox3((x >> 16) as u8)
+        ^ 0x0101000101010001 * sbox4((x >> 8) as u8)
+        ^ 0x0101010001010100 * sbox1(x as u8)
+}
+
+fn fl(input: u64, key: u64) -> u64 {
+    let (mut x1, mut x2) = ((input >> 32) as u32, input as u32);
+    let (k1, k2) = ((key >> 32) as u32, key as u32);
+
+    x2 ^= (x1 & k1).rotate_left(1);
+    x1 ^= x2 | k2;
+
+    ((x1 as u64) << 32) | x2 as u64
+}
+
+fn flinv(input: u64, key: u64) -> u64 {
+    let (mut y1, mut y2) = ((input >> 32) as u32, input as u32);
+    let (k1, k2) = ((key >> 32) as u32, key as u32);
+
+    y1 ^= y2 | k2;
+    y2 ^= (y1 & k1).rotate_left(1);
+
+    ((y1 as u64) << 32) | y2 as u64
+}
+
+// High and low 64 bits of a 128 bits value rotated left by `shift` bits
+fn rotl128(val: (u64, u64), shift: usize) -> (u64, u64) {
+    let (hi, lo) = if shift >= 64 { (val.1, val.0) } else { val };
+    let shift = shift % 64;
+    if shift == 0 {
+        (hi, lo)
+    } else {
+        ((hi << shift) | (lo >> (64 - shift)), (lo << shift) | (hi >> (64 - shift)))
+    }
+}
+
+impl Camellia {
+    pub fn new(key: &[u8]) -> Camellia {
+        let kl = (read_be_u64(&key[0..8]), read_be_u64(&key[8..16]));
+        let kr = match key.len() {
+            16 => (0, 0),
+            24 => {
+                let k = read_be_u64(&key[16..24]);
+                (k, !k)
+            },
+            32 => (read_be_u64(&key[16..24]), read_be_u64(&key[24..32])),
+            _ => panic!("Camellia key must be 16, 24 or 32 bytes"),
+        };
+
+        let mut d1 = kl.0 ^ kr.0;
+        let mut d2 = kl.1 ^ kr.1;
+        d2 ^= f(d1, SIGMAS[0]);
+        d1 ^= f(d2, SIGMAS[1]);
+        d1 ^= kl.0;
+        d2 ^= kl.1;
+        d2 ^= f(d1, SIGMAS[2]);
+        d1 ^= f(d2, SIGMAS[3]);
+        let ka = (d1, d2);
+
+        // (key, rotation) of each pair of subkeys in the order they are used, RFC 3713 Section 2.2
+        let schedule: Vec<((u64, u64), usize)> = if key.len() == 16 {
+            vec![(kl, 0), (ka, 0), (kl, 15), (ka, 15), (ka, 30), (kl, 45), (ka, 45),
+                 (ka, 60), (kl, 77), (kl, 94), (ka, 94), (kl, 111), (ka, 111)]
+        } else {
+            let mut d1 = ka.0 ^ kr.0;
+            let mut d2 = ka.1 ^ kr.1;
+            d2 ^= f(d1, SIGMAS[4]);
+            d1 ^= f(d2, SIGMAS[5]);
+            let kb = (d1, d2);
+
+            vec![(kl, 0), (kb, 0), (kr, 15), (ka, 15), (kr, 30), (kb, 30), (kl, 45), (ka, 45),
+                 (kl, 60), (kr, 60), (kb, 60), (kl, 77), (ka, 77), (kr, 94), (ka, 94),
+                 (kl, 111), (kb, 111)]
+        };
+
+        let mut k = Vec::with_capacity(schedule.len() * 2);
+        for &(v, shift) in schedule.iter() {
+            let (hi, lo) = rotl128(v, shift);
+            k.push(hi);
+            k.push(lo);
+        }
+
+        if key.len() == 16 {
+            // k10 is the only subkey that does not come in a pair, it is the low half of KL <<< 60
+            k[13] = rotl128(kl, 60).1;
+        }
+
+        Camellia {
+            k: k,
+        }
+    }
+}
+
+impl BlockEncryptor for Camellia {
+    fn block_size(&self) -> usize {
+        16
+    }
+
+    fn encrypt_block(&self, input: &[u8], output: &mut [u8]) {
+        let k = &self.k;
+        let n = k.len();
+
+        let mut d1 = read_be_u64(&input[0..8]) ^ k[0];
+        let mut d2 = read_be_u64(&input[8..16]) ^ k[1];
+
+        // 6 rounds between each FL/FLINV layer
+        let mut i = 2;
+        while i < n - 2 {
+            if i % 8 == 0 {
+                d1 = fl(d1, k[i]);
+                d2 = flinv(d2, k[i + 1]);
+            } else {
+                d2 ^= f(d1, k[i]);
+                d1 ^= f(d2, k[i + 1]);
+            }
+            i += 2;
+        }
+
+        write_be_u64(d2 ^ k[n - 2], &mut output[0..8]);
+        write_be_u64(d1 ^ k[n - 1], &mut output[8..16]);
+    }
+}
+
+const SBOX1: [u8; 256] = [
+    0x70, 0x82, 0x2c, 0xec, 0xb3, 0x27, 0xc0, 0xe5, 0xe4, 0x85, 0x57, 0x35, 0xea, 0x0c, 0xae, 0x41,
+    0x23, 0xef, 0x6b, 0x93, 0x45, 0x19, 0xa5, 0x21, 0xed, 0x0e, 0x4f, 0x4e, 0x1d, 0x65, 0x92, 0xbd,
+    0x86, 0xb8, 0xaf, 0x8f, 0x7c, 0xeb, 0x1f, 0xce, 0x3e, 0x30, 0xdc, 0x5f, 0x5e, 0xc5, 0x0b, 0x1a,
+    0xa6, 0xe1, 0x39, 0xca, 0xd5, 0x47, 0x5d, 0x3d, 0xd9, 0x01, 0x5a, 0xd6, 0x51, 0x56, 0x6c, 0x4d,
+    0x8b, 0x0d, 0x9a, 0x66, 0xfb, 0xcc, 0xb0, 0x2d, 0x74, 0x12, 0x2b, 0x20, 0xf0, 0xb1, 0x84, 0x99,
+    0xdf, 0x4c, 0xcb, 0xc2, 0x34, 0x7e, 0x76, 0x05, 0x6d, 0xb7, 0xa9, 0x31, 0xd1, 0x17, 0x04, 0xd7,
+    0x14, 0x58, 0x3a, 0x61, 0xde, 0x1b, 0x11, 0x1c, 0x32, 0x0f, 0x9c, 0x16, 0x53, 0x18, 0xf2, 0x22,
+    0xfe, 0x44, 0xcf, 0xb2, 0xc3, 0xb5, 0x7a, 0x91, 0x24, 0x08, 0xe8, 0xa8, 0x60, 0xfc, 0x69, 0x50,
+    0xaa, 0xd0, 0xa0, 0x7d, 0xa1, 0x89, 0x62, 0x97, 0x54, 0x5b, 0x1e, 0x95, 0xe0, 0xff, 0x64, 0xd2,
+    0x10, 0xc4, 0x00, 0x48, 0xa3, 0xf7, 0x75, 0xdb, 0x8a, 0x03, 0xe6, 0xda, 0x09, 0x3f, 0xdd, 0x94,
+    0x87, 0x5c, 0x83, 0x02, 0xcd, 0x4a, 0x90, 0x33, 0x73, 0x67, 0xf6, 0xf3, 0x9d, 0x7f, 0xbf, 0xe2,
+    0x52, 0x9b, 0xd8, 0x26, 0xc8, 0x37, 0xc6, 0x3b, 0x81, 0x96, 0x6f, 0x4b, 0x13, 0xbe, 0x63, 0x2e,
+    0xe9, 0x79, 0xa7, 0x8c, 0x9f, 0x6e, 0xbc, 0x8e, 0x29, 0xf5, 0xf9, 0xb6, 0x2f, 0xfd, 0xb4, 0x59,
+    0x78, 0x98, 0x06, 0x6a, 0xe7, 0x46, 0x71, 0xba, 0xd4, 0x25, 0xab, 0x42, 0x88, 0xa2, 0x8d, 0xfa,
+    0x72, 0x07, 0xb9, 0x55, 0xf8, 0xee, 0xac, 0x0a, 0x36, 0x49, 0x2a, 0x68, 0x3c, 0x38, 0xf1, 0xa4,
+    0x40, 0x28, 0xd3, 0x7b, 0xbb, 0xc9, 0x43, 0xc1, 0x15, 0xe3, 0xad, 0xf4, 0x77, 0xc7, 0x80, 0x9e,
+];
+
+#[cfg(test)]
+mod test_camellia {
+    use rust_crypto::symmetriccipher::BlockEncryptor;
+    use serialize::hex::FromHex;
+
+    use super::Camellia;
+
+    // Test vectors from RFC 3713 Appendix A
+    const PLAINTEXT: &'static str = "0123456789abcdeffedcba9876543210";
+
+    fn check(key: &str, expected: &str) {
+        let cipher = Camellia::new(key.from_hex().unwrap().as_slice());
+
+        let mut out = [0u8; 16];
+        cipher.encrypt_block(PLAINTEXT.from_hex().unwrap().as_slice(), &mut out);
+        assert_eq!(out.to_vec(), expected.from_hex().unwrap());
+    }
+
+    #[test]
+    fn test_camellia_rfc3713() {
+        check("0123456789abcdeffedcba9876543210", "67673138549669730857065648eabe43");
+        check("0123456789abcdeffedcba98765432100011223344556677", "b4993401b3e996f84ee5cee7d79b09b9");
+        check("0123456789abcdeffedcba987654321000112233445566778899aabbccddeeff",
+              "9acc237dff16d76c20ef7c919e3a7509");
+    }
+}
@@ -0,0 +1,135 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! IDEA block cipher
+//!
+//! Multiplication is done modulo 2^16 + 1, where the zero word stands for 2^16.
+
+use rust_crypto::symmetriccipher::BlockEncryptor;
+
+const ROUNDS: usize = 8;
+const SUBKEYS: usize = ROUNDS * 6 + 4;
+
+pub struct Idea {
+    keys: [u16; SUBKEYS],
+}
+
+#[inline]
+fn mul(a: u16, b: u16) -> u16 {
+    let x = a as u32;
+    let y = b as u32;
+    let r = if x == 0 {
+        0x10001 - y
+    } else if y == 0 {
+        0x10001 - x
+    } else {
+        let c = x * y;
+        let (lo, hi) = (c & 0xffff, c >> 16);
+        if lo >= hi { lo - hi } else { lo + 0x10001 - hi }
+    };
+    (r & 0xffff) as u16
+}
+
+#[inline]
+fn read_be_u16(b: &[u8]) -> u16 {
+    ((b[0] as u16) << 8) | b[1] as u16
+}
+
+#[inline]
+fn write_be_u16(v: u16, b: &mut [u8]) {
+    b[0] = (v >> 8) as u8;
+    b[1] = v as u8;
+}
+
+impl Idea {
+    pub fn new(key: &[u8]) -> Idea {
+        assert!(key.len() == 16, "IDEA key must be 16 bytes");
+
+        let mut keys = [0u16; SUBKEYS];
+        for i in range(0us, 8) {
+            keys[i] = read_be_u16(&key[i * 2..]);
+        }
+
+        // Each group of 8 subkeys is the 128 bits key rotated left by 25 bits
+        for i in range(8us, SUBKEYS) {
+            let a = if (i + 1) % 8 == 0 { keys[i - 15] } else { keys[i - 7] };
+            let b = if (i + 2) % 8 < 2 { keys[i - 14] } else { keys[i - 6] };
+            keys[i] = (a << 9) | (b >> 7);
+        }
+
+        Idea {
+            keys: keys,
+        }
+    }
+}
+
+impl BlockEncryptor for Idea {
+    fn block_size(&self) -> usize {
+        8
+    }
+
+    fn encrypt_block(&self, input: &[u8], output: &mut [u8]) {
+        let k = &self.keys;
+        let mut x1 = read_be_u16(&input[0..2]);
+        let mut x2 = read_be_u16(&input[2..4]);
+        let mut x3 = read_be_u16(&input[4..6]);
+        let mut x4 = read_be_u16(&input[6..8]);
+
+        for i in range(0us, ROUNDS) {
+            let j = i * 6;
+            let y1 = mul(x1, k[j]);
+            let y2 = x2.wrapping_add(k[j + 1]);
+            let y3 = x3.wrapping_add(k[j + 2]);
+            let y4 = mul(x4, k[j + 3]);
+
+            let t0 = mul(y1 ^ y3, k[j + 4]);
+            let t1 = mul((y2 ^ y4).wrapping_add(t0), k[j + 5]);
+            let t2 = t0.wrapping_add(t1);
+
+            x1 = y1 ^ t1;
+            x2 = y3 ^ t1;
+            x3 = y2 ^ t2;
+            x4 = y4 ^ t2;
+        }
+
+        write_be_u16(mul(x1, k[48]), &mut output[0..2]);
+        write_be_u16(x3.wrapping_add(k[49]), &mut output[2..4]);
+        write_be_u16(x2.wrapping_add(k[50]), &mut output[4..6]);
+        write_be_u16(mul(x4, k[51]), &mut output[6..8]);
+    }
+}
+
+#[cfg(test)]
+mod test_idea {
+    use rust_crypto::symmetriccipher::BlockEncryptor;
+    use serialize::hex::FromHex;
+
+    use super::Idea;
+
+    #[test]
+    fn test_idea_known_answer() {
+        let cipher = Idea::new("00010002000300040005000600070008".from_hex().unwrap().as_slice());
+
+        let mut out = [0u8; 8];
+        cipher.encrypt_block("0000000100020003".from_hex().unwrap().as_slice(), &mut out);
+        assert_eq!(out.to_vec(), "11fbed2b01986de5".from_hex().unwrap());
+    }
+}
@@ -0,0 +1,598 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Ciphers and digests implemented in pure Rust
+//!
+//! This backend does not link to any C library, so it could be used where OpenSSL or
+//! libsodium is not available (for example, statically linked musl builds). Primitives
+//! come from the `rust-crypto` crate, and block ciphers which are not provided by it
+//! (CAST5, DES, IDEA, RC2, SEED and Camellia) are implemented in the submodules.
+//!
+//! Every cipher here produces byte-identical output to the OpenSSL and libsodium backends.
+
+use std::iter::repeat;
+
+use rust_crypto::aead::{AeadEncryptor, AeadDecryptor};
+use rust_crypto::aes::KeySize;
+use rust_crypto::aes_gcm::AesGcm;
+use rust_crypto::aessafe;
+use rust_crypto::blowfish::Blowfish;
+use rust_crypto::chacha20::ChaCha20;
+use rust_crypto::digest::Digest as RustCryptoDigest;
+use rust_crypto::mac::Mac;
+use rust_crypto::md5::Md5;
+use rust_crypto::poly1305::Poly1305;
+use rust_crypto::rc4::Rc4;
+use rust_crypto::salsa20::Salsa20;
+use rust_crypto::sha1::Sha1;
+use rust_crypto::symmetriccipher::{BlockEncryptor, BlockDecryptor, SynchronousStreamCipher};
+use rust_crypto::util::fixed_time_eq;
+
+use crypto::cipher::{self, Cipher, CipherType, CipherResult};
+use crypto::aead::{self, AeadCipher};
+use crypto::digest::{Digest, DigestType};
+use crypto::CryptoMode;
+
+use self::mode::{BlockModeCipher, Mode};
+
+pub mod mode;
+pub mod cast5;
+pub mod des;
+pub mod idea;
+pub mod rc2;
+pub mod seed;
+pub mod camellia;
+
+/// Nonce length of XChaCha20-Poly1305
+pub const XCHACHA20_POLY1305_NONCE_SIZE: usize = 24;
+
+#[inline]
+fn read_be_u32(b: &[u8]) -> u32 {
+    ((b[0] as u32) << 24) | ((b[1] as u32) << 16) | ((b[2] as u32) << 8) | b[3] as u32
+}
+
+#[inline]
+fn write_be_u32(v: u32, b: &mut [u8]) {
+    b[0] = (v >> 24) as u8;
+    b[1] = (v >> 16) as u8;
+    b[2] = (v >> 8) as u8;
+    b[3] = v as u8;
+}
+
+#[inline]
+fn read_be_u64(b: &[u8]) -> u64 {
+    ((read_be_u32(&b[0..4]) as u64) << 32) | read_be_u32(&b[4..8]) as u64
+}
+
+#[inline]
+fn write_be_u64(v: u64, b: &mut [u8]) {
+    write_be_u32((v >> 32) as u32, &mut b[0..4]);
+    write_be_u32(v as u32, &mut b[4..8]);
+}
+
+fn aes_encryptor(key: &[u8]) -> Box<BlockEncryptor + Send> {
+    match key.len() {
+        16 => box aessafe::AesSafe128Encryptor::new(key) as Box<BlockEncryptor + Send>,
+        24 => box aessafe::AesSafe192Encryptor::new(key) as Box<BlockEncryptor + Send>,
+        32 => box aessafe::AesSafe256Encryptor::new(key) as Box<BlockEncryptor + Send>,
+        _ => panic!("Invalid AES key length {}", key.len()),
+    }
+}
+
+/// Stream cipher implemented in pure Rust, supports all stream ciphers in `CipherType`
+/// except `Table` and `Rc4Md5`, which are built on top of the other ciphers.
+pub struct RustCipher {
+    cipher: Box<SynchronousStreamCipher + Send>,
+}
+
+impl RustCipher {
+    pub fn new(t: CipherType, key: &[u8], iv: &[u8], mode: CryptoMode) -> RustCipher {
+        let block = |cipher: Box<BlockEncryptor + Send>, m: Mode| {
+            box BlockModeCipher::new(cipher, m, iv, mode) as Box<SynchronousStreamCipher + Send>
+        };
+
+        let cipher = match t {
+            #[cfg(feature = "cipher-aes-cfb")]
+            CipherType::Aes128Cfb | CipherType::Aes128Cfb128
+                | CipherType::Aes192Cfb | CipherType::Aes192Cfb128
+                | CipherType::Aes256Cfb | CipherType::Aes256Cfb128 => block(aes_encryptor(key), Mode::Cfb),
+            #[cfg(feature = "cipher-aes-cfb")]
+            CipherType::Aes128Cfb8 | CipherType::Aes192Cfb8 | CipherType::Aes256Cfb8 =>
+                block(aes_encryptor(key), Mode::Cfb8),
+            #[cfg(feature = "cipher-aes-cfb")]
+            CipherType::Aes128Cfb1 | CipherType::Aes192Cfb1 | CipherType::Aes256Cfb1 =>
+                block(aes_encryptor(key), Mode::Cfb1),
+
+            #[cfg(feature = "cipher-aes-ofb")]
+            CipherType::Aes128Ofb | CipherType::Aes192Ofb | CipherType::Aes256Ofb =>
+                block(aes_encryptor(key), Mode::Ofb),
+
+            #[cfg(feature = "cipher-aes-ctr")]
+            CipherType::Aes128Ctr | CipherType::Aes192Ctr | CipherType::Aes256Ctr =>
+                block(aes_encryptor(key), Mode::Ctr),
+
+            #[cfg(feature = "cipher-bf-cfb")]
+            CipherType::BfCfb => block(box Blowfish::new(key) as Box<BlockEncryptor + Send>, Mode::Cfb),
+
+            #[cfg(feature = "cipher-camellia-cfb")]
+            CipherType::Camellia128Cfb | CipherType::Camellia192Cfb | CipherType::Camellia256Cfb =>
+                block(box camellia::Camellia::new(key) as Box<BlockEncryptor + Send>, Mode::Cfb),
+
+            #[cfg(feature = "cipher-cast5-cfb")]
+            CipherType::Cast5Cfb => block(box cast5::Cast5::new(key) as Box<BlockEncryptor + Send>, Mode::Cfb),
+            #[cfg(feature = "cipher-des-cfb")]
+            CipherType::DesCfb => block(box des::Des::new(key) as Box<BlockEncryptor + Send>, Mode::Cfb),
+            #[cfg(feature = "cipher-idea-cfb")]
+            CipherType::IdeaCfb => block(box idea::Idea::new(key) as Box<BlockEncryptor + Send>, Mode::Cfb),
+            #[cfg(feature = "cipher-rc2-cfb")]
+            CipherType::Rc2Cfb => block(box rc2::Rc2::new(key) as Box<BlockEncryptor + Send>, Mode::Cfb),
+            #[cfg(feature = "cipher-seed-cfb")]
+            CipherType::SeedCfb => block(box seed::Seed::new(key) as Box<BlockEncryptor + Send>, Mode::Cfb),
+
+            #[cfg(feature = "cipher-rc4")]
+            CipherType::Rc4 => box Rc4::new(key) as Box<SynchronousStreamCipher + Send>,
+
+            #[cfg(feature = "cipher-chacha20")]
+            CipherType::ChaCha20 => box ChaCha20::new(key, iv) as Box<SynchronousStreamCipher + Send>,
+            #[cfg(feature = "cipher-salsa20")]
+            CipherType::Salsa20 => box Salsa20::new(key, iv) as Box<SynchronousStreamCipher + Send>,
+
+            _ => panic!("Unsupported cipher type {:?} of the Rust backend", t),
+        };
+
+        RustCipher {
+            cipher: cipher,
+        }
+    }
+}
+
+impl Cipher for RustCipher {
+    fn update_into(&mut self, data: &[u8], out: &mut [u8]) -> CipherResult<usize> {
+        self.cipher.process(data, &mut out[..data.len()]);
+        Ok(data.len())
+    }
+
+    fn finalize(&mut self) -> CipherResult<Vec<u8>> {
+        Ok(Vec::new())
+    }
+}
+
+fn authentication_failed() -> cipher::Error {
+    cipher::Error {
+        kind: cipher::ErrorKind::RustCryptoError,
+        desc: "AEAD authentication failed",
+        detail: None,
+    }
+}
+
+fn data_too_short() -> cipher::Error {
+    cipher::Error {
+        kind: cipher::ErrorKind::RustCryptoError,
+        desc: "AEAD data is too short",
+        detail: None,
+    }
+}
+
+// Poly1305 tag of ChaCha20-Poly1305 without additional data, RFC 8439 Section 2.8
+fn chacha20_poly1305_tag(poly_key: &[u8], ciphertext: &[u8]) -> [u8; 16] {
+    let mut mac = Poly1305::new(poly_key);
+    mac.input(ciphertext);
+
+    let padding = (16 - ciphertext.len() % 16) % 16;
+    mac.input(&[0u8; 16][..padding]);
+
+    let mut lengths = [0u8; 16];
+    let len = ciphertext.len() as u64;
+    for i in range(0us, 8) {
+        lengths[8 + i] = (len >> (8 * i)) as u8;
+    }
+    mac.input(&lengths);
+
+    let mut tag = [0u8; 16];
+    mac.raw_result(&mut tag);
+    tag
+}
+
+// The first block of the key stream is for the Poly1305 key, the payload starts from the second block
+fn chacha20_poly1305_init(mut chacha: ChaCha20) -> (ChaCha20, [u8; 32]) {
+    let mut block = [0u8; 64];
+    chacha.process(&[0u8; 64], &mut block);
+
+    let mut poly_key = [0u8; 32];
+    for (k, b) in poly_key.iter_mut().zip(block.iter()) {
+        *k = *b;
+    }
+    (chacha, poly_key)
+}
+
+fn chacha20_poly1305_seal(chacha: ChaCha20, data: &[u8]) -> Vec<u8> {
+    let (mut chacha, poly_key) = chacha20_poly1305_init(chacha);
+
+    let mut out: Vec<u8> = repeat(0u8).take(data.len()).collect();
+    chacha.process(data, out.as_mut_slice());
+    let tag = chacha20_poly1305_tag(&poly_key, out.as_slice());
+    out.push_all(&tag);
+    out
+}
+
+fn chacha20_poly1305_open(chacha: ChaCha20, data: &[u8]) -> CipherResult<Vec<u8>> {
+    if data.len() < aead::TAG_SIZE {
+        return Err(data_too_short());
+    }
+
+    let (mut chacha, poly_key) = chacha20_poly1305_init(chacha);
+
+    let (ciphertext, tag) = data.split_at(data.len() - aead::TAG_SIZE);
+    if !fixed_time_eq(&chacha20_poly1305_tag(&poly_key, ciphertext), tag) {
+        return Err(authentication_failed());
+    }
+
+    let mut out: Vec<u8> = repeat(0u8).take(ciphertext.len()).collect();
+    chacha.process(ciphertext, out.as_mut_slice());
+    Ok(out)
+}
+
+/// AEAD cipher implemented in pure Rust, supports AES-GCM and ChaCha20-IETF-Poly1305.
+///
+/// The nonce is managed internally and increased after each `encrypt` or `decrypt`.
+pub struct RustAeadCipher {
+    cipher_type: CipherType,
+    key: Vec<u8>,
+    nonce: Vec<u8>,
+}
+
+impl RustAeadCipher {
+    pub fn new(t: CipherType, key: &[u8]) -> RustAeadCipher {
+        let nonce = repeat(0u8).take(aead::NONCE_SIZE).collect::<Vec<u8>>();
+        RustAeadCipher::with_nonce(t, key, nonce.as_slice())
+    }
+
+    /// Creates a cipher starting from `nonce` instead of zero
+    pub fn with_nonce(t: CipherType, key: &[u8], nonce: &[u8]) -> RustAeadCipher {
+        debug_assert!(key.len() == t.key_size());
+        debug_assert!(nonce.len() == aead::NONCE_SIZE);
+
+        RustAeadCipher {
+            cipher_type: t,
+            key: key.to_vec(),
+            nonce: nonce.to_vec(),
+        }
+    }
+
+    fn aes_gcm(&self) -> Option<AesGcm<'static>> {
+        let key_size = match self.cipher_type {
+            #[cfg(feature = "cipher-aes-gcm")]
+            CipherType::Aes128Gcm => KeySize::KeySize128,
+            #[cfg(feature = "cipher-aes-gcm")]
+            CipherType::Aes256Gcm => KeySize::KeySize256,
+            #[cfg(feature = "cipher-2022-blake3-aes-256-gcm")]
+            CipherType::Blake3Aes256Gcm => KeySize::KeySize256,
+            _ => return None,
+        };
+
+        Some(AesGcm::new(key_size, self.key.as_slice(), self.nonce.as_slice(), &[]))
+    }
+}
+
+impl AeadCipher for RustAeadCipher {
+    fn encrypt(&mut self, data: &[u8]) -> CipherResult<Vec<u8>> {
+        let out = match self.aes_gcm() {
+            Some(mut gcm) => {
+                let mut out: Vec<u8> = repeat(0u8).take(data.len() + aead::TAG_SIZE).collect();
+                {
+                    let (ciphertext, tag) = out.split_at_mut(data.len());
+                    gcm.encrypt(data, ciphertext, tag);
+                }
+                out
+            },
+            None => {
+                let chacha = ChaCha20::new(self.key.as_slice(), self.nonce.as_slice());
+                chacha20_poly1305_seal(chacha, data)
+            }
+        };
+
+        aead::increase_nonce(self.nonce.as_mut_slice());
+        Ok(out)
+    }
+
+    fn decrypt(&mut self, data: &[u8]) -> CipherResult<Vec<u8>> {
+        if data.len() < aead::TAG_SIZE {
+            return Err(data_too_short());
+        }
+
+        let out = match self.aes_gcm() {
+            Some(mut gcm) => {
+                let (ciphertext, tag) = data.split_at(data.len() - aead::TAG_SIZE);
+                let mut out: Vec<u8> = repeat(0u8).take(ciphertext.len()).collect();
+                if !gcm.decrypt(ciphertext, out.as_mut_slice(), tag) {
+                    return Err(authentication_failed());
+                }
+                out
+            },
+            None => {
+                let chacha = ChaCha20::new(self.key.as_slice(), self.nonce.as_slice());
+                try!(chacha20_poly1305_open(chacha, data))
+            }
+        };
+
+        aead::increase_nonce(self.nonce.as_mut_slice());
+        Ok(out)
+    }
+
+    fn tag_size(&self) -> usize {
+        aead::TAG_SIZE
+    }
+}
+
+/// Seals `data` with XChaCha20-Poly1305, `nonce` is 24 bytes long
+pub fn xchacha20_poly1305_seal(key: &[u8], nonce: &[u8], data: &[u8]) -> CipherResult<Vec<u8>> {
+    debug_assert!(nonce.len() == XCHACHA20_POLY1305_NONCE_SIZE);
+    Ok(chacha20_poly1305_seal(ChaCha20::new_xchacha20(key, nonce), data))
+}
+
+/// Opens `data` (ciphertext with the tag appended) with XChaCha20-Poly1305
+pub fn xchacha20_poly1305_open(key: &[u8], nonce: &[u8], data: &[u8]) -> CipherResult<Vec<u8>> {
+    debug_assert!(nonce.len() == XCHACHA20_POLY1305_NONCE_SIZE);
+    chacha20_poly1305_open(ChaCha20::new_xchacha20(key, nonce), data)
+}
+
+/// Encrypts or decrypts exactly one block with AES-ECB, key length decides AES-128 or AES-256
+pub fn aes_ecb_crypt_block(key: &[u8], block: &[u8], mode: CryptoMode) -> CipherResult<Vec<u8>> {
+    debug_assert!(block.len() == 16);
+
+    let mut out = [0u8; 16];
+    match (mode, key.len()) {
+        (CryptoMode::Encrypt, 16) => aessafe::AesSafe128Encryptor::new(key).encrypt_block(block, &mut out),
+        (CryptoMode::Encrypt, 32) => aessafe::AesSafe256Encryptor::new(key).encrypt_block(block, &mut out),
+        (CryptoMode::Decrypt, 16) => aessafe::AesSafe128Decryptor::new(key).decrypt_block(block, &mut out),
+        (CryptoMode::Decrypt, 32) => aessafe::AesSafe256Decryptor::new(key).decrypt_block(block, &mut out),
+        _ => panic!("Invalid AES key length {}", key.len()),
+    }
+    Ok(out.to_vec())
+}
+
+/// Message digest implemented in pure Rust, supports MD5 and SHA-1
+pub struct RustDigest {
+    digest: Box<RustCryptoDigest + Send>,
+}
+
+impl RustDigest {
+    pub fn new(t: DigestType) -> RustDigest {
+        let digest = match t {
+            DigestType::Md5 => box Md5::new() as Box<RustCryptoDigest + Send>,
+            DigestType::Sha1 => box Sha1::new() as Box<RustCryptoDigest + Send>,
+        };
+
+        RustDigest {
+            digest: digest,
+        }
+    }
+}
+
+impl Digest for RustDigest {
+    fn update(&mut self, data: &[u8]) {
+        self.digest.input(data);
+    }
+
+    fn digest(&mut self) -> Vec<u8> {
+        let mut out: Vec<u8> = repeat(0u8).take(self.digest.output_bytes()).collect();
+        self.digest.result(out.as_mut_slice());
+        out
+    }
+}
+
+#[cfg(all(test, feature = "openssl"))]
+mod test_openssl_equivalence {
+    use std::rand::{self, Rng};
+
+    use crypto::cipher::{Cipher, CipherType};
+    use crypto::digest::{Digest, DigestType};
+    use crypto::openssl;
+    use crypto::rust::{RustCipher, RustDigest};
+    use crypto::CryptoMode;
+
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut buf: Vec<u8> = range(0, len).map(|_| 0u8).collect();
+        rand::thread_rng().fill_bytes(buf.as_mut_slice());
+        buf
+    }
+
+    // Feeds both backends with the same chunks, which are not aligned to the block size
+    fn assert_equivalent(types: &[CipherType]) {
+        let message = random_bytes(1000);
+        let chunks = [1us, 7, 16, 33, 64, 100, 779];
+
+        for t in types.iter() {
+            let key = t.bytes_to_key(b"PassWORD");
+            let iv = t.gen_init_vec();
+
+            let mut rust_enc = RustCipher::new(*t, key.as_slice(), iv.as_slice(), CryptoMode::Encrypt);
+            let mut ossl_enc = openssl::OpenSSLCipher::new(*t, key.as_slice(), iv.as_slice(), CryptoMode::Encrypt);
+
+            let mut rust_encrypted = Vec::new();
+            let mut ossl_encrypted = Vec::new();
+            let mut pos = 0;
+            for len in chunks.iter() {
+                let chunk = &message[pos..pos + *len];
+                rust_encrypted.push_all(rust_enc.update(chunk).unwrap().as_slice());
+                ossl_encrypted.push_all(ossl_enc.update(chunk).unwrap().as_slice());
+                pos += *len;
+            }
+            rust_encrypted.push_all(rust_enc.finalize().unwrap().as_slice());
+            ossl_encrypted.push_all(ossl_enc.finalize().unwrap().as_slice());
+
+            assert!(rust_encrypted == ossl_encrypted, "{:?} encrypts differently", t);
+
+            let mut rust_dec = RustCipher::new(*t, key.as_slice(), iv.as_slice(), CryptoMode::Decrypt);
+            let decrypted = rust_dec.update(ossl_encrypted.as_slice()).unwrap();
+            assert!(decrypted == message, "{:?} decrypts differently", t);
+        }
+    }
+
+    #[test]
+    fn test_default_ciphers() {
+        assert_equivalent(&[
+            CipherType::Aes128Cfb, CipherType::Aes128Cfb1, CipherType::Aes128Cfb8, CipherType::Aes128Cfb128,
+            CipherType::Aes192Cfb, CipherType::Aes192Cfb1, CipherType::Aes192Cfb8, CipherType::Aes192Cfb128,
+            CipherType::Aes256Cfb, CipherType::Aes256Cfb1, CipherType::Aes256Cfb8, CipherType::Aes256Cfb128,
+
+            CipherType::Aes128Ofb, CipherType::Aes192Ofb, CipherType::Aes256Ofb,
+
+            CipherType::BfCfb,
+            CipherType::Cast5Cfb,
+            CipherType::DesCfb,
+            CipherType::Rc2Cfb,
+            CipherType::Rc4,
+        ]);
+    }
+
+    #[cfg(feature = "cipher-aes-ctr")]
+    #[test]
+    fn test_aes_ctr() {
+        assert_equivalent(&[CipherType::Aes128Ctr, CipherType::Aes192Ctr, CipherType::Aes256Ctr]);
+    }
+
+    #[cfg(feature = "cipher-camellia-cfb")]
+    #[test]
+    fn test_camellia_cfb() {
+        assert_equivalent(&[CipherType::Camellia128Cfb, CipherType::Camellia192Cfb, CipherType::Camellia256Cfb]);
+    }
+
+    #[cfg(feature = "cipher-idea-cfb")]
+    #[test]
+    fn test_idea_cfb() {
+        assert_equivalent(&[CipherType::IdeaCfb]);
+    }
+
+    #[cfg(feature = "cipher-seed-cfb")]
+    #[test]
+    fn test_seed_cfb() {
+        assert_equivalent(&[CipherType::SeedCfb]);
+    }
+
+    #[cfg(feature = "cipher-aes-gcm")]
+    #[test]
+    fn test_aes_gcm() {
+        use crypto::aead::AeadCipher;
+        use crypto::rust::RustAeadCipher;
+
+        for t in [CipherType::Aes128Gcm, CipherType::Aes256Gcm].iter() {
+            let key = random_bytes(t.key_size());
+            let mut rust_cipher = RustAeadCipher::new(*t, key.as_slice());
+            let mut ossl_cipher = openssl::OpenSSLAeadCipher::new(*t, key.as_slice());
+
+            // Several chunks to make sure that the nonces are increased in the same way
+            for len in [0us, 1, 15, 16, 17, 1000].iter() {
+                let chunk = random_bytes(*len);
+                let sealed = rust_cipher.encrypt(chunk.as_slice()).unwrap();
+                assert!(sealed == ossl_cipher.encrypt(chunk.as_slice()).unwrap(), "{:?} seals differently", t);
+            }
+        }
+
+        for key_size in [16us, 32].iter() {
+            let key = random_bytes(*key_size);
+            let block = random_bytes(16);
+            let encrypted = super::aes_ecb_crypt_block(key.as_slice(), block.as_slice(), CryptoMode::Encrypt);
+            assert_eq!(encrypted.unwrap(),
+                       openssl::aes_ecb_crypt_block(key.as_slice(), block.as_slice(), CryptoMode::Encrypt).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_digests() {
+        let message = random_bytes(1000);
+
+        for t in [DigestType::Md5, DigestType::Sha1].iter() {
+            let mut rust_digest = RustDigest::new(*t);
+            let mut ossl_digest = openssl::OpenSSLDigest::new(*t);
+            rust_digest.update(message.as_slice());
+            ossl_digest.update(message.as_slice());
+            assert_eq!(rust_digest.digest(), ossl_digest.digest());
+        }
+    }
+}
+
+#[cfg(all(test, feature = "enable-sodium"))]
+mod test_sodium_equivalence {
+    use std::rand::{self, Rng};
+
+    use crypto::cipher::{Cipher, CipherType};
+    use crypto::sodium;
+    use crypto::rust::RustCipher;
+    use crypto::CryptoMode;
+
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut buf: Vec<u8> = range(0, len).map(|_| 0u8).collect();
+        rand::thread_rng().fill_bytes(buf.as_mut_slice());
+        buf
+    }
+
+    #[test]
+    fn test_stream_ciphers() {
+        let message = random_bytes(1000);
+
+        for t in [CipherType::ChaCha20, CipherType::Salsa20].iter() {
+            let key = t.bytes_to_key(b"PassWORD");
+            let iv = t.gen_init_vec();
+
+            let mut rust_enc = RustCipher::new(*t, key.as_slice(), iv.as_slice(), CryptoMode::Encrypt);
+            let mut sodium_enc = sodium::SodiumCipher::new(*t, key.as_slice(), iv.as_slice());
+
+            // libsodium keeps the position of the key stream across updates, so do we
+            for chunk in message.as_slice().chunks(100) {
+                assert!(rust_enc.update(chunk).unwrap() == sodium_enc.update(chunk).unwrap(),
+                        "{:?} encrypts differently", t);
+            }
+        }
+    }
+
+    #[cfg(feature = "cipher-chacha20-ietf-poly1305")]
+    #[test]
+    fn test_chacha20_ietf_poly1305() {
+        use crypto::aead::AeadCipher;
+        use crypto::rust::RustAeadCipher;
+
+        let t = CipherType::ChaCha20IetfPoly1305;
+        let key = random_bytes(t.key_size());
+        let mut rust_cipher = RustAeadCipher::new(t, key.as_slice());
+        let mut sodium_cipher = sodium::SodiumAeadCipher::new(t, key.as_slice());
+
+        for len in [0us, 1, 15, 16, 17, 1000].iter() {
+            let chunk = random_bytes(*len);
+            assert!(rust_cipher.encrypt(chunk.as_slice()).unwrap() == sodium_cipher.encrypt(chunk.as_slice()).unwrap());
+        }
+    }
+
+    #[cfg(feature = "cipher-2022-blake3-chacha20-poly1305")]
+    #[test]
+    fn test_xchacha20_poly1305() {
+        let key = random_bytes(32);
+        let nonce = random_bytes(super::XCHACHA20_POLY1305_NONCE_SIZE);
+        let message = random_bytes(1000);
+
+        let sealed = super::xchacha20_poly1305_seal(key.as_slice(), nonce.as_slice(), message.as_slice()).unwrap();
+        assert_eq!(sealed,
+                   sodium::xchacha20_poly1305_seal(key.as_slice(), nonce.as_slice(), message.as_slice()).unwrap());
+
+        let opened = sodium::xchacha20_poly1305_open(key.as_slice(), nonce.as_slice(), sealed.as_slice()).unwrap();
+        assert_eq!(opened, message);
+    }
+}
@@ -0,0 +1,235 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Modes of operation for block ciphers
+//!
+//! All modes only use the encryption direction of the block cipher, and the output always
+//! has the same length as the input, just like what OpenSSL does with these modes.
+
+use std::iter::repeat;
+
+use rust_crypto::symmetriccipher::{BlockEncryptor, SynchronousStreamCipher};
+
+use crypto::CryptoMode;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// CFB with full block feedback (CFB64 or CFB128)
+    Cfb,
+    /// CFB with 8 bits feedback
+    Cfb8,
+    /// CFB with 1 bit feedback
+    Cfb1,
+    Ofb,
+    /// CTR with a 128 bits big endian counter
+    Ctr,
+}
+
+pub struct BlockModeCipher {
+    cipher: Box<BlockEncryptor + Send>,
+    mode: Mode,
+    crypto_mode: CryptoMode,
+    register: Vec<u8>,
+    keystream: Vec<u8>,
+    pos: usize,
+}
+
+impl BlockModeCipher {
+    pub fn new(cipher: Box<BlockEncryptor + Send>, mode: Mode, iv: &[u8], crypto_mode: CryptoMode)
+            -> BlockModeCipher {
+        let block_size = cipher.block_size();
+        assert!(iv.len() >= block_size);
+
+        BlockModeCipher {
+            cipher: cipher,
+            mode: mode,
+            crypto_mode: crypto_mode,
+            register: iv[..block_size].to_vec(),
+            keystream: repeat(0u8).take(block_size).collect(),
+            pos: 0,
+        }
+    }
+
+    fn encrypting(&self) -> bool {
+        match self.crypto_mode {
+            CryptoMode::Encrypt => true,
+            CryptoMode::Decrypt => false,
+        }
+    }
+
+    fn refresh_keystream(&mut self) {
+        self.cipher.encrypt_block(self.register.as_slice(), self.keystream.as_mut_slice());
+    }
+
+    fn cfb(&mut self, input: u8) -> u8 {
+        if self.pos == 0 {
+            self.refresh_keystream();
+        }
+
+        let output = input ^ self.keystream[self.pos];
+        self.register[self.pos] = if self.encrypting() { output } else { input };
+        self.pos = (self.pos + 1) % self.register.len();
+        output
+    }
+
+    fn cfb8(&mut self, input: u8) -> u8 {
+        self.refresh_keystream();
+
+        let output = input ^ self.keystream[0];
+        let feedback = if self.encrypting() { output } else { input };
+
+        let len = self.register.len();
+        for i in range(1, len) {
+            self.register[i - 1] = self.register[i];
+        }
+        self.register[len - 1] = feedback;
+        output
+    }
+
+    fn cfb1(&mut self, input: u8) -> u8 {
+        let mut output = 0u8;
+
+        // Bits are processed from the most significant one
+        for bit in range(0us, 8).rev() {
+            self.refresh_keystream();
+
+            let in_bit = (input >> bit) & 1;
+            let out_bit = in_bit ^ (self.keystream[0] >> 7);
+            output |= out_bit << bit;
+
+            let feedback = if self.encrypting() { out_bit } else { in_bit };
+            let len = self.register.len();
+            for i in range(0, len - 1) {
+                self.register[i] = (self.register[i] << 1) | (self.register[i + 1] >> 7);
+            }
+            self.register[len - 1] = (self.register[len - 1] << 1) | feedback;
+        }
+
+        output
+    }
+
+    fn ofb(&mut self, input: u8) -> u8 {
+        if self.pos == 0 {
+            self.refresh_keystream();
+            self.register.clone_from_slice(self.keystream.as_slice());
+        }
+
+        let output = input ^ self.keystream[self.pos];
+        self.pos = (self.pos + 1) % self.register.len();
+        output
+    }
+
+    fn ctr(&mut self, input: u8) -> u8 {
+        if self.pos == 0 {
+            self.refresh_keystream();
+            for b in self.register.iter_mut().rev() {
+                *b = b.wrapping_add(1);
+                if *b != 0 {
+                    break;
+                }
+            }
+        }
+
+        let output = input ^ self.keystream[self.pos];
+        self.pos = (self.pos + 1) % self.register.len();
+        output
+    }
+}
+
+impl SynchronousStreamCipher for BlockModeCipher {
+    fn process(&mut self, input: &[u8], output: &mut [u8]) {
+        assert!(input.len() == output.len());
+
+        for (i, o) in input.iter().zip(output.iter_mut()) {
+            *o = match self.mode {
+                Mode::Cfb => self.cfb(*i),
+                Mode::Cfb8 => self.cfb8(*i),
+                Mode::Cfb1 => self.cfb1(*i),
+                Mode::Ofb => self.ofb(*i),
+                Mode::Ctr => self.ctr(*i),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_mode {
+    use rust_crypto::aessafe::AesSafe128Encryptor;
+    use rust_crypto::symmetriccipher::{BlockEncryptor, SynchronousStreamCipher};
+    use serialize::hex::FromHex;
+
+    use crypto::CryptoMode;
+    use super::{BlockModeCipher, Mode};
+
+    // Test vectors from NIST SP 800-38A, AES-128, the first two blocks
+    const KEY: &'static str = "2b7e151628aed2a6abf7158809cf4f3c";
+    const PLAINTEXT: &'static str = "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51";
+
+    fn check(mode: Mode, iv: &str, expected: &str) {
+        let key = KEY.from_hex().unwrap();
+        let iv = iv.from_hex().unwrap();
+        let plaintext = PLAINTEXT.from_hex().unwrap();
+        let expected = expected.from_hex().unwrap();
+
+        let encryptor = box AesSafe128Encryptor::new(key.as_slice()) as Box<BlockEncryptor + Send>;
+        let mut enc = BlockModeCipher::new(encryptor, mode, iv.as_slice(), CryptoMode::Encrypt);
+
+        // Feeds in uneven pieces to cover the position tracking
+        let mut encrypted = plaintext.clone();
+        enc.process(&plaintext[..5], &mut encrypted[..5]);
+        enc.process(&plaintext[5..], &mut encrypted[5..]);
+        assert_eq!(&encrypted[..expected.len()], expected.as_slice());
+
+        let decryptor = box AesSafe128Encryptor::new(key.as_slice()) as Box<BlockEncryptor + Send>;
+        let mut dec = BlockModeCipher::new(decryptor, mode, iv.as_slice(), CryptoMode::Decrypt);
+        let mut decrypted = encrypted.clone();
+        dec.process(encrypted.as_slice(), decrypted.as_mut_slice());
+        assert_eq!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn test_cfb128() {
+        check(Mode::Cfb, "000102030405060708090a0b0c0d0e0f",
+              "3b3fd92eb72dad20333449f8e83cfb4ac8a64537a0b3a93fcde3cdad9f1ce58b");
+    }
+
+    #[test]
+    fn test_cfb8() {
+        check(Mode::Cfb8, "000102030405060708090a0b0c0d0e0f", "3b79424c9c0dd436bace9e0ed4586a4f");
+    }
+
+    #[test]
+    fn test_cfb1() {
+        check(Mode::Cfb1, "000102030405060708090a0b0c0d0e0f", "68b3");
+    }
+
+    #[test]
+    fn test_ofb() {
+        check(Mode::Ofb, "000102030405060708090a0b0c0d0e0f",
+              "3b3fd92eb72dad20333449f8e83cfb4a7789508d16918f03f53c52dac54ed825");
+    }
+
+    #[test]
+    fn test_ctr() {
+        check(Mode::Ctr, "f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff",
+              "874d6191b620e3261bef6864990db6ce9806f66b7970fdff8617187bb9fffdff");
+    }
+}
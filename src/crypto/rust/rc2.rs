@@ -0,0 +1,145 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! RC2 block cipher defined in RFC 2268
+//!
+//! The effective key length is the same as the key length, which is what OpenSSL's
+//! `EVP_rc2_cfb64` uses for a 16 bytes key.
+
+use rust_crypto::symmetriccipher::BlockEncryptor;
+
+pub struct Rc2 {
+    keys: [u16; 64],
+}
+
+impl Rc2 {
+    pub fn new(key: &[u8]) -> Rc2 {
+        assert!(key.len() > 0 && key.len() <= 128, "RC2 key must be 1 to 128 bytes");
+
+        let key_len = key.len();
+
+        let mut buf = [0u8; 128];
+        for (b, k) in buf.iter_mut().zip(key.iter()) {
+            *b = *k;
+        }
+        for i in range(key_len, 128) {
+            buf[i] = PI_TABLE[(buf[i - 1].wrapping_add(buf[i - key_len])) as usize];
+        }
+        // Effective key length in bytes equals to the key length, so no bits are masked out
+        buf[128 - key_len] = PI_TABLE[buf[128 - key_len] as usize];
+        for i in range(0us, 128 - key_len).rev() {
+            buf[i] = PI_TABLE[(buf[i + 1] ^ buf[i + key_len]) as usize];
+        }
+
+        let mut keys = [0u16; 64];
+        for i in range(0us, 64) {
+            keys[i] = ((buf[2 * i + 1] as u16) << 8) | buf[2 * i] as u16;
+        }
+
+        Rc2 {
+            keys: keys,
+        }
+    }
+
+    fn mix(&self, r: &mut [u16; 4], j: &mut usize) {
+        const SHIFTS: [usize; 4] = [1, 2, 3, 5];
+
+        for i in range(0us, 4) {
+            let (a, b, c) = (r[(i + 3) % 4], r[(i + 2) % 4], r[(i + 1) % 4]);
+            r[i] = r[i].wrapping_add(self.keys[*j])
+                       .wrapping_add(a & b)
+                       .wrapping_add(!a & c)
+                       .rotate_left(SHIFTS[i]);
+            *j += 1;
+        }
+    }
+
+    fn mash(&self, r: &mut [u16; 4]) {
+        for i in range(0us, 4) {
+            let idx = (r[(i + 3) % 4] & 63) as usize;
+            r[i] = r[i].wrapping_add(self.keys[idx]);
+        }
+    }
+}
+
+impl BlockEncryptor for Rc2 {
+    fn block_size(&self) -> usize {
+        8
+    }
+
+    fn encrypt_block(&self, input: &[u8], output: &mut [u8]) {
+        let mut r = [0u16; 4];
+        for i in range(0us, 4) {
+            r[i] = ((input[2 * i + 1] as u16) << 8) | input[2 * i] as u16;
+        }
+
+        // 5 mixing rounds, 1 mashing round, 6 mixing rounds, 1 mashing round, 5 mixing rounds
+        let mut j = 0;
+        for i in range(0us, 16) {
+            self.mix(&mut r, &mut j);
+            if i == 4 || i == 10 {
+                self.mash(&mut r);
+            }
+        }
+
+        for i in range(0us, 4) {
+            output[2 * i] = r[i] as u8;
+            output[2 * i + 1] = (r[i] >> 8) as u8;
+        }
+    }
+}
+
+const PI_TABLE: [u8; 256] = [
+    0xd9, 0x78, 0xf9, 0xc4, 0x19, 0xdd, 0xb5, 0xed, 0x28, 0xe9, 0xfd, 0x79, 0x4a, 0xa0, 0xd8, 0x9d,
+    0xc6, 0x7e, 0x37, 0x83, 0x2b, 0x76, 0x53, 0x8e, 0x62, 0x4c, 0x64, 0x88, 0x44, 0x8b, 0xfb, 0xa2,
+    0x17, 0x9a, 0x59, 0xf5, 0x87, 0xb3, 0x4f, 0x13, 0x61, 0x45, 0x6d, 0x8d, 0x09, 0x81, 0x7d, 0x32,
+    0xbd, 0x8f, 0x40, 0xeb, 0x86, 0xb7, 0x7b, 0x0b, 0xf0, 0x95, 0x21, 0x22, 0x5c, 0x6b, 0x4e, 0x82,
+    0x54, 0xd6, 0x65, 0x93, 0xce, 0x60, 0xb2, 0x1c, 0x73, 0x56, 0xc0, 0x14, 0xa7, 0x8c, 0xf1, 0xdc,
+    0x12, 0x75, 0xca, 0x1f, 0x3b, 0xbe, 0xe4, 0xd1, 0x42, 0x3d, 0xd4, 0x30, 0xa3, 0x3c, 0xb6, 0x26,
+    0x6f, 0xbf, 0x0e, 0xda, 0x46, 0x69, 0x07, 0x57, 0x27, 0xf2, 0x1d, 0x9b, 0xbc, 0x94, 0x43, 0x03,
+    0xf8, 0x11, 0xc7, 0xf6, 0x90, 0xef, 0x3e, 0xe7, 0x06, 0xc3, 0xd5, 0x2f, 0xc8, 0x66, 0x1e, 0xd7,
+    0x08, 0xe8, 0xea, 0xde, 0x80, 0x52, 0xee, 0xf7, 0x84, 0xaa, 0x72, 0xac, 0x35, 0x4d, 0x6a, 0x2a,
+    0x96, 0x1a, 0xd2, 0x71, 0x5a, 0x15, 0x49, 0x74, 0x4b, 0x9f, 0xd0, 0x5e, 0x04, 0x18, 0xa4, 0xec,
+    0xc2, 0xe0, 0x41, 0x6e, 0x0f, 0x51, 0xcb, 0xcc, 0x24, 0x91, 0xaf, 0x50, 0xa1, 0xf4, 0x70, 0x39,
+    0x99, 0x7c, 0x3a, 0x85, 0x23, 0xb8, 0xb4, 0x7a, 0xfc, 0x02, 0x36, 0x5b, 0x25, 0x55, 0x97, 0x31,
+    0x2d, 0x5d, 0xfa, 0x98, 0xe3, 0x8a, 0x92, 0xae, 0x05, 0xdf, 0x29, 0x10, 0x67, 0x6c, 0xba, 0xc9,
+    0xd3, 0x00, 0xe6, 0xcf, 0xe1, 0x9e, 0xa8, 0x2c, 0x63, 0x16, 0x01, 0x3f, 0x58, 0xe2, 0x89, 0xa9,
+    0x0d, 0x38, 0x34, 0x1b, 0xab, 0x33, 0xff, 0xb0, 0xbb, 0x48, 0x0c, 0x5f, 0xb9, 0xb1, 0xcd, 0x2e,
+    0xc5, 0xf3, 0xdb, 0x47, 0xe5, 0xa5, 0x9c, 0x77, 0x0a, 0xa6, 0x20, 0x68, 0xfe, 0x7f, 0xc1, 0xad,
+];
+
+#[cfg(test)]
+mod test_rc2 {
+    use rust_crypto::symmetriccipher::BlockEncryptor;
+    use serialize::hex::FromHex;
+
+    use super::Rc2;
+
+    #[test]
+    fn test_rc2_rfc2268() {
+        // Effective key length 128 bits, RFC 2268 Section 5
+        let cipher = Rc2::new("88bca90e90875a7f0f79c384627bafb2".from_hex().unwrap().as_slice());
+
+        let mut out = [0u8; 8];
+        cipher.encrypt_block("0000000000000000".from_hex().unwrap().as_slice(), &mut out);
+        assert_eq!(out.to_vec(), "2269552ab0f85ca6".from_hex().unwrap());
+    }
+}
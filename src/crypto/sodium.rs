@@ -24,16 +24,52 @@
 extern crate libc;
 
 use std::iter::repeat;
+use std::ptr;
+use std::slice;
 
-use crypto::cipher::{Cipher, CipherType, CipherResult};
+use crypto::cipher::{self, Cipher, CipherType, CipherResult};
+use crypto::aead::{self, AeadCipher};
 
 const BLOCK_SIZE: usize = 64; // Just for Salsa20 and Chacha20
 
 mod ffi {
+    extern crate libc;
+
     pub use libsodium_ffi::crypto_stream_chacha20_xor_ic;
     pub use libsodium_ffi::crypto_stream_salsa20_xor_ic;
+
+    // AEAD functions are not exported by libsodium-sys yet
+    #[link(name = "sodium")]
+    extern {
+        pub fn crypto_aead_chacha20poly1305_ietf_encrypt(c: *mut libc::c_uchar, clen_p: *mut libc::c_ulonglong,
+                                                         m: *const libc::c_uchar, mlen: libc::c_ulonglong,
+                                                         ad: *const libc::c_uchar, adlen: libc::c_ulonglong,
+                                                         nsec: *const libc::c_uchar, npub: *const libc::c_uchar,
+                                                         k: *const libc::c_uchar) -> libc::c_int;
+        pub fn crypto_aead_chacha20poly1305_ietf_decrypt(m: *mut libc::c_uchar, mlen_p: *mut libc::c_ulonglong,
+                                                         nsec: *mut libc::c_uchar,
+                                                         c: *const libc::c_uchar, clen: libc::c_ulonglong,
+                                                         ad: *const libc::c_uchar, adlen: libc::c_ulonglong,
+                                                         npub: *const libc::c_uchar,
+                                                         k: *const libc::c_uchar) -> libc::c_int;
+
+        pub fn crypto_aead_xchacha20poly1305_ietf_encrypt(c: *mut libc::c_uchar, clen_p: *mut libc::c_ulonglong,
+                                                          m: *const libc::c_uchar, mlen: libc::c_ulonglong,
+                                                          ad: *const libc::c_uchar, adlen: libc::c_ulonglong,
+                                                          nsec: *const libc::c_uchar, npub: *const libc::c_uchar,
+                                                          k: *const libc::c_uchar) -> libc::c_int;
+        pub fn crypto_aead_xchacha20poly1305_ietf_decrypt(m: *mut libc::c_uchar, mlen_p: *mut libc::c_ulonglong,
+                                                          nsec: *mut libc::c_uchar,
+                                                          c: *const libc::c_uchar, clen: libc::c_ulonglong,
+                                                          ad: *const libc::c_uchar, adlen: libc::c_ulonglong,
+                                                          npub: *const libc::c_uchar,
+                                                          k: *const libc::c_uchar) -> libc::c_int;
+    }
 }
 
+/// Nonce length of XChaCha20-Poly1305
+pub const XCHACHA20_POLY1305_NONCE_SIZE: usize = 24;
+
 pub struct SodiumCipher {
     cipher_type: CipherType,
     key: Vec<u8>,
@@ -57,45 +93,52 @@ impl SodiumCipher {
             buf: Vec::new(),
         }
     }
-}
-
-impl Cipher for SodiumCipher {
-    fn update(&mut self, data: &[u8]) -> CipherResult<Vec<u8>> {
-        let padding_len = self.counter % BLOCK_SIZE;
-        let mut pad;
-        let padded_data =
-            if padding_len == 0 {
-                data
-            } else {
-                pad = Some(repeat(0u8).take(padding_len).chain(data.iter().map(|&x| x)).collect::<Vec<u8>>());
-                pad.as_ref().unwrap().as_slice()
-            };
-
-        if self.buf.len() < padding_len + data.len() {
-            self.buf.resize(padding_len + data.len(), 0u8);
-        }
 
+    // XORs `len` bytes of the key stream starting from the beginning of the current block,
+    // `input` and `output` are allowed to be the same buffer
+    unsafe fn xor_key_stream(&self, input: *const u8, output: *mut u8, len: usize) {
         match self.cipher_type {
-            CipherType::ChaCha20 => unsafe {
-                ffi::crypto_stream_chacha20_xor_ic(self.buf.as_mut_ptr() as *mut libc::c_char, padded_data.as_ptr(),
-                                                  (padding_len + data.len()) as libc::c_ulonglong,
+            CipherType::ChaCha20 => {
+                ffi::crypto_stream_chacha20_xor_ic(output as *mut libc::c_char, input,
+                                                  len as libc::c_ulonglong,
                                                   self.iv.as_ptr(),
                                                   (self.counter / BLOCK_SIZE) as libc::uint64_t,
                                                   self.key.as_ptr());
             },
-            CipherType::Salsa20 => unsafe {
-                ffi::crypto_stream_salsa20_xor_ic(self.buf.as_mut_ptr() as *mut libc::c_char, padded_data.as_ptr(),
-                                                (padding_len + data.len()) as libc::c_ulonglong,
+            CipherType::Salsa20 => {
+                ffi::crypto_stream_salsa20_xor_ic(output as *mut libc::c_char, input,
+                                                len as libc::c_ulonglong,
                                                 self.iv.as_ptr(),
                                                 (self.counter / BLOCK_SIZE) as libc::uint64_t,
                                                 self.key.as_ptr());
             },
             _ => unreachable!(),
         }
+    }
+}
+
+impl Cipher for SodiumCipher {
+    fn update_into(&mut self, data: &[u8], out: &mut [u8]) -> CipherResult<usize> {
+        debug_assert!(out.len() >= data.len());
+
+        let padding_len = self.counter % BLOCK_SIZE;
+        if padding_len == 0 {
+            unsafe { self.xor_key_stream(data.as_ptr(), out.as_mut_ptr(), data.len()); }
+        } else {
+            // The position is in the middle of a block, pads the data to the beginning of the block
+            // in the internal buffer, which will be reused by the later calls
+            self.buf.clear();
+            self.buf.resize(padding_len, 0u8);
+            self.buf.push_all(data);
+
+            let pbuf = self.buf.as_mut_ptr();
+            unsafe { self.xor_key_stream(pbuf as *const u8, pbuf, padding_len + data.len()); }
+            slice::bytes::copy_memory(out, &self.buf[padding_len..]);
+        }
 
         self.counter += data.len();
 
-        Ok(self.buf[padding_len..padding_len + data.len()].to_vec())
+        Ok(data.len())
     }
 
     fn finalize(&mut self) -> CipherResult<Vec<u8>> {
@@ -103,6 +146,164 @@ impl Cipher for SodiumCipher {
     }
 }
 
+/// AEAD cipher binding for libsodium, supports ChaCha20-IETF-Poly1305
+pub struct SodiumAeadCipher {
+    key: Vec<u8>,
+    nonce: Vec<u8>,
+}
+
+impl SodiumAeadCipher {
+    pub fn new(t: CipherType, key: &[u8]) -> SodiumAeadCipher {
+        let nonce = repeat(0u8).take(aead::NONCE_SIZE).collect::<Vec<u8>>();
+        SodiumAeadCipher::with_nonce(t, key, nonce.as_slice())
+    }
+
+    /// Creates a cipher starting from `nonce` instead of zero
+    pub fn with_nonce(t: CipherType, key: &[u8], nonce: &[u8]) -> SodiumAeadCipher {
+        match t {
+            CipherType::ChaCha20IetfPoly1305 => (),
+            #[cfg(feature = "cipher-2022-blake3-chacha20-poly1305")]
+            CipherType::Blake3ChaCha20Poly1305 => (),
+            _ => panic!("Sodium does not support {:?} AEAD cipher", t),
+        }
+
+        debug_assert!(nonce.len() == aead::NONCE_SIZE);
+
+        SodiumAeadCipher {
+            key: key.to_vec(),
+            nonce: nonce.to_vec(),
+        }
+    }
+}
+
+impl AeadCipher for SodiumAeadCipher {
+    fn encrypt(&mut self, data: &[u8]) -> CipherResult<Vec<u8>> {
+        let mut out: Vec<u8> = repeat(0u8).take(data.len() + aead::TAG_SIZE).collect();
+        let mut out_len: libc::c_ulonglong = 0;
+
+        let ret = unsafe {
+            ffi::crypto_aead_chacha20poly1305_ietf_encrypt(out.as_mut_ptr(), &mut out_len,
+                                                           data.as_ptr(), data.len() as libc::c_ulonglong,
+                                                           ptr::null(), 0,
+                                                           ptr::null(), self.nonce.as_ptr(),
+                                                           self.key.as_ptr())
+        };
+
+        if ret != 0 {
+            return Err(cipher::Error {
+                kind: cipher::ErrorKind::SodiumError,
+                desc: "Failed on crypto_aead_chacha20poly1305_ietf_encrypt",
+                detail: None,
+            });
+        }
+
+        out.truncate(out_len as usize);
+        aead::increase_nonce(self.nonce.as_mut_slice());
+        Ok(out)
+    }
+
+    fn decrypt(&mut self, data: &[u8]) -> CipherResult<Vec<u8>> {
+        if data.len() < aead::TAG_SIZE {
+            return Err(cipher::Error {
+                kind: cipher::ErrorKind::SodiumError,
+                desc: "AEAD data is too short",
+                detail: None,
+            });
+        }
+
+        let mut out: Vec<u8> = repeat(0u8).take(data.len() - aead::TAG_SIZE).collect();
+        let mut out_len: libc::c_ulonglong = 0;
+
+        let ret = unsafe {
+            ffi::crypto_aead_chacha20poly1305_ietf_decrypt(out.as_mut_ptr(), &mut out_len,
+                                                           ptr::null_mut(),
+                                                           data.as_ptr(), data.len() as libc::c_ulonglong,
+                                                           ptr::null(), 0,
+                                                           self.nonce.as_ptr(),
+                                                           self.key.as_ptr())
+        };
+
+        if ret != 0 {
+            return Err(cipher::Error {
+                kind: cipher::ErrorKind::SodiumError,
+                desc: "AEAD authentication failed",
+                detail: None,
+            });
+        }
+
+        out.truncate(out_len as usize);
+        aead::increase_nonce(self.nonce.as_mut_slice());
+        Ok(out)
+    }
+
+    fn tag_size(&self) -> usize {
+        aead::TAG_SIZE
+    }
+}
+
+/// Seals `data` with XChaCha20-Poly1305, `nonce` is 24 bytes long
+pub fn xchacha20_poly1305_seal(key: &[u8], nonce: &[u8], data: &[u8]) -> CipherResult<Vec<u8>> {
+    debug_assert!(nonce.len() == XCHACHA20_POLY1305_NONCE_SIZE);
+
+    let mut out: Vec<u8> = repeat(0u8).take(data.len() + aead::TAG_SIZE).collect();
+    let mut out_len: libc::c_ulonglong = 0;
+
+    let ret = unsafe {
+        ffi::crypto_aead_xchacha20poly1305_ietf_encrypt(out.as_mut_ptr(), &mut out_len,
+                                                        data.as_ptr(), data.len() as libc::c_ulonglong,
+                                                        ptr::null(), 0,
+                                                        ptr::null(), nonce.as_ptr(),
+                                                        key.as_ptr())
+    };
+
+    if ret != 0 {
+        return Err(cipher::Error {
+            kind: cipher::ErrorKind::SodiumError,
+            desc: "Failed on crypto_aead_xchacha20poly1305_ietf_encrypt",
+            detail: None,
+        });
+    }
+
+    out.truncate(out_len as usize);
+    Ok(out)
+}
+
+/// Opens `data` (ciphertext with the tag appended) with XChaCha20-Poly1305
+pub fn xchacha20_poly1305_open(key: &[u8], nonce: &[u8], data: &[u8]) -> CipherResult<Vec<u8>> {
+    debug_assert!(nonce.len() == XCHACHA20_POLY1305_NONCE_SIZE);
+
+    if data.len() < aead::TAG_SIZE {
+        return Err(cipher::Error {
+            kind: cipher::ErrorKind::SodiumError,
+            desc: "AEAD data is too short",
+            detail: None,
+        });
+    }
+
+    let mut out: Vec<u8> = repeat(0u8).take(data.len() - aead::TAG_SIZE).collect();
+    let mut out_len: libc::c_ulonglong = 0;
+
+    let ret = unsafe {
+        ffi::crypto_aead_xchacha20poly1305_ietf_decrypt(out.as_mut_ptr(), &mut out_len,
+                                                        ptr::null_mut(),
+                                                        data.as_ptr(), data.len() as libc::c_ulonglong,
+                                                        ptr::null(), 0,
+                                                        nonce.as_ptr(),
+                                                        key.as_ptr())
+    };
+
+    if ret != 0 {
+        return Err(cipher::Error {
+            kind: cipher::ErrorKind::SodiumError,
+            desc: "AEAD authentication failed",
+            detail: None,
+        });
+    }
+
+    out.truncate(out_len as usize);
+    Ok(out)
+}
+
 #[cfg(test)]
 mod test_sodium {
     use crypto::cipher::{Cipher, CipherType};
@@ -67,15 +67,18 @@ impl TableCipher {
         }
     }
 
-    fn process(&mut self, data: &[u8]) -> CipherResult<Vec<u8>> {
-        let r = data.iter().map(|d| self.table[*d as usize]).collect();
-        Ok(r)
+    fn process(&mut self, data: &[u8], out: &mut [u8]) -> CipherResult<usize> {
+        for (o, d) in out.iter_mut().zip(data.iter()) {
+            *o = self.table[*d as usize];
+        }
+        Ok(data.len())
     }
 }
 
 impl Cipher for TableCipher {
-    fn update(&mut self, data: &[u8]) -> CipherResult<Vec<u8>> {
-        self.process(data)
+    fn update_into(&mut self, data: &[u8], out: &mut [u8]) -> CipherResult<usize> {
+        debug_assert!(out.len() >= data.len());
+        self.process(data, out)
     }
 
     fn finalize(&mut self) -> CipherResult<Vec<u8>> {
@@ -32,8 +32,13 @@ extern crate "rustc-serialize" as serialize;
 #[macro_use]
 extern crate log;
 extern crate collect;
+extern crate time;
+extern crate regex;
 
+#[cfg(feature = "enable-sodium")]
 extern crate "libsodium-sys" as libsodium_ffi;
+#[cfg(feature = "crypto-rust")]
+extern crate "crypto" as rust_crypto;
 
 use std::fmt::{Debug, Formatter, self};
 
@@ -0,0 +1,501 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! Access control lists in the `.acl` format of shadowsocks-libev
+//!
+//! ```ignore
+//! [proxy_all]
+//!
+//! [bypass_list]
+//! 10.0.0.0/8
+//! fd00::/8
+//! |localhost
+//! ||lan
+//! (^|\.)example\.com$
+//!
+//! [outbound_block_list]
+//! 127.0.0.0/8
+//! ```
+//!
+//! `sslocal` connects to addresses in `[bypass_list]` directly, and to addresses in `[proxy_list]`
+//! through the servers. Other addresses go through the servers in `[proxy_all]` mode (the default),
+//! or are connected directly in `[bypass_all]` mode. `ssserver` refuses to connect to addresses in
+//! `[outbound_block_list]`. `[white_list]`, `[black_list]`, `[accept_all]` and `[reject_all]` are
+//! accepted as aliases of `[bypass_list]`, `[proxy_list]`, `[proxy_all]` and `[bypass_all]`.
+//!
+//! Every rule is an IPv4 or IPv6 address with an optional prefix length, a domain name prefixed
+//! by `|` which matches exactly, a domain prefixed by `||` which matches itself and all its
+//! subdomains, or a regular expression of domain names. Regular expressions like
+//! `(^|\.)example\.com$` are common in lists of shadowsocks-libev, they are matched as domains
+//! with subdomains, so that lists with hundreds of thousands of rules are still fast.
+
+use std::ascii::AsciiExt;
+use std::collections::HashSet;
+use std::fmt::{self, Debug, Formatter};
+use std::io::{File, IoError, IoResult, InvalidInput};
+use std::io::net::ip::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use regex::Regex;
+
+use relay::socks5::Address;
+
+/// What to do with addresses which are not in any list
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    ProxyAll,
+    BypassAll,
+}
+
+/// Sorted ranges without overlapping
+struct Ranges<T> {
+    ranges: Vec<(T, T)>,
+}
+
+impl<T: Ord + Copy> Ranges<T> {
+    fn new() -> Ranges<T> {
+        Ranges {
+            ranges: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, start: T, end: T) {
+        self.ranges.push((start, end));
+    }
+
+    // Sorts and merges all ranges, should be called after all of them have been pushed
+    fn build(&mut self) {
+        self.ranges.sort();
+
+        let mut merged: Vec<(T, T)> = Vec::with_capacity(self.ranges.len());
+        for &(start, end) in self.ranges.iter() {
+            if let Some(last) = merged.last_mut() {
+                if start <= last.1 {
+                    if end > last.1 {
+                        last.1 = end;
+                    }
+                    continue;
+                }
+            }
+            merged.push((start, end));
+        }
+        self.ranges = merged;
+    }
+
+    fn contains(&self, val: T) -> bool {
+        // Finds the last range starting before or at `val`
+        let (mut low, mut high) = (0, self.ranges.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.ranges[mid].0 <= val {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low > 0 && val <= self.ranges[low - 1].1
+    }
+}
+
+fn ipv4_to_u32(a: u8, b: u8, c: u8, d: u8) -> u32 {
+    ((a as u32) << 24) | ((b as u32) << 16) | ((c as u32) << 8) | d as u32
+}
+
+fn ipv6_to_u64s(s: [u16; 8]) -> (u64, u64) {
+    let mut high = 0u64;
+    let mut low = 0u64;
+    for i in range(0, 4) {
+        high = (high << 16) | s[i] as u64;
+        low = (low << 16) | s[i + 4] as u64;
+    }
+    (high, low)
+}
+
+// Mask of the highest `prefix` bits of a 64 bits integer
+fn mask64(prefix: usize) -> u64 {
+    if prefix == 0 { 0 } else if prefix >= 64 { !0 } else { !0u64 << (64 - prefix) }
+}
+
+/// Rules of one list
+struct Rules {
+    ipv4: Ranges<u32>,
+    ipv6: Ranges<(u64, u64)>,
+    domains: HashSet<String>,
+    suffixes: HashSet<String>,
+    regexes: Vec<Regex>,
+}
+
+impl Rules {
+    fn new() -> Rules {
+        Rules {
+            ipv4: Ranges::new(),
+            ipv6: Ranges::new(),
+            domains: HashSet::new(),
+            suffixes: HashSet::new(),
+            regexes: Vec::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ipv4.ranges.is_empty() && self.ipv6.ranges.is_empty() && self.domains.is_empty()
+            && self.suffixes.is_empty() && self.regexes.is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.ipv4.ranges.len() + self.ipv6.ranges.len() + self.domains.len() + self.suffixes.len()
+            + self.regexes.len()
+    }
+
+    fn add(&mut self, rule: &str) -> Result<(), String> {
+        if rule.starts_with("||") {
+            self.suffixes.insert(normalize_domain(&rule[2..]));
+        } else if rule.starts_with("|") {
+            self.domains.insert(normalize_domain(&rule[1..]));
+        } else if self.add_cidr(rule) {
+            // Added as IP ranges
+        } else if let Some(suffix) = regex_to_suffix(rule) {
+            self.suffixes.insert(suffix);
+        } else {
+            match Regex::new(rule) {
+                Ok(re) => self.regexes.push(re),
+                Err(err) => return Err(format!("invalid rule `{}`: {}", rule, err)),
+            }
+        }
+        Ok(())
+    }
+
+    // Returns false if the rule is not an IP address with an optional prefix length
+    fn add_cidr(&mut self, rule: &str) -> bool {
+        let (ip, prefix) = match rule.find('/') {
+            Some(pos) => (&rule[..pos], Some(&rule[pos + 1..])),
+            None => (rule, None),
+        };
+        let ip = match ip.parse::<IpAddr>() {
+            Some(ip) => ip,
+            None => return false,
+        };
+
+        match ip {
+            Ipv4Addr(a, b, c, d) => {
+                let prefix = match prefix {
+                    Some(p) => match p.parse::<usize>() {
+                        Some(p) if p <= 32 => p,
+                        _ => return false,
+                    },
+                    None => 32,
+                };
+                let mask = (mask64(prefix) >> 32) as u32;
+                let start = ipv4_to_u32(a, b, c, d) & mask;
+                self.ipv4.push(start, start | !mask);
+            },
+            Ipv6Addr(a, b, c, d, e, f, g, h) => {
+                let prefix = match prefix {
+                    Some(p) => match p.parse::<usize>() {
+                        Some(p) if p <= 128 => p,
+                        _ => return false,
+                    },
+                    None => 128,
+                };
+                let (high, low) = ipv6_to_u64s([a, b, c, d, e, f, g, h]);
+                let high_mask = mask64(prefix);
+                let low_mask = if prefix > 64 { mask64(prefix - 64) } else { 0 };
+                let start = (high & high_mask, low & low_mask);
+                self.ipv6.push(start, (start.0 | !high_mask, start.1 | !low_mask));
+            },
+        }
+        true
+    }
+
+    fn build(&mut self) {
+        self.ipv4.build();
+        self.ipv6.build();
+    }
+
+    fn check_ip(&self, ip: &IpAddr) -> bool {
+        match *ip {
+            Ipv4Addr(a, b, c, d) => self.ipv4.contains(ipv4_to_u32(a, b, c, d)),
+            Ipv6Addr(a, b, c, d, e, f, g, h) => self.ipv6.contains(ipv6_to_u64s([a, b, c, d, e, f, g, h])),
+        }
+    }
+
+    fn check_domain(&self, name: &str) -> bool {
+        let name = normalize_domain(name);
+        if self.domains.contains(&name) {
+            return true;
+        }
+
+        // Tries the name and all its parent domains
+        let mut domain = name.as_slice();
+        loop {
+            if self.suffixes.contains(domain) {
+                return true;
+            }
+            match domain.find('.') {
+                Some(pos) => domain = &domain[pos + 1..],
+                None => break,
+            }
+        }
+
+        self.regexes.iter().any(|re| re.is_match(name.as_slice()))
+    }
+
+    fn check(&self, addr: &Address) -> bool {
+        match *addr {
+            Address::SocketAddress(ref ip, _) => self.check_ip(ip),
+            Address::DomainNameAddress(ref name, _) => match name.parse::<IpAddr>() {
+                Some(ip) => self.check_ip(&ip),
+                None => self.check_domain(name.as_slice()),
+            },
+        }
+    }
+}
+
+fn normalize_domain(name: &str) -> String {
+    name.trim_matches('.').to_ascii_lowercase()
+}
+
+// Converts `(^|\.)example\.com$` into `example.com`, `None` if it is another regular expression
+fn regex_to_suffix(rule: &str) -> Option<String> {
+    let prefix = "(^|\\.)";
+    if !rule.starts_with(prefix) || !rule.ends_with("$") {
+        return None;
+    }
+
+    let escaped = &rule[prefix.len()..rule.len() - 1];
+    let mut domain = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('.') => domain.push('.'),
+                _ => return None,
+            },
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | '_' => domain.push(c),
+            _ => return None,
+        }
+    }
+
+    if domain.is_empty() {
+        None
+    } else {
+        Some(normalize_domain(domain.as_slice()))
+    }
+}
+
+#[derive(Copy)]
+enum List {
+    Bypass,
+    Proxy,
+    OutboundBlock,
+}
+
+/// Rules loaded from an ACL file
+pub struct AccessControl {
+    mode: Mode,
+    bypass: Rules,
+    proxy: Rules,
+    outbound_block: Rules,
+}
+
+impl AccessControl {
+    /// Parses the content of an ACL file
+    pub fn parse(content: &str) -> Result<AccessControl, String> {
+        let mut acl = AccessControl {
+            mode: Mode::ProxyAll,
+            bypass: Rules::new(),
+            proxy: Rules::new(),
+            outbound_block: Rules::new(),
+        };
+
+        let mut list = None;
+        for (num, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("#") {
+                continue;
+            }
+
+            match line {
+                "[proxy_all]" | "[accept_all]" => acl.mode = Mode::ProxyAll,
+                "[bypass_all]" | "[reject_all]" => acl.mode = Mode::BypassAll,
+                "[bypass_list]" | "[white_list]" => list = Some(List::Bypass),
+                "[proxy_list]" | "[black_list]" => list = Some(List::Proxy),
+                "[outbound_block_list]" => list = Some(List::OutboundBlock),
+                _ if line.starts_with("[") && line.ends_with("]") => {
+                    return Err(format!("line {}: unknown section `{}`", num + 1, line));
+                },
+                _ => {
+                    let rules = match list {
+                        Some(List::Bypass) => &mut acl.bypass,
+                        Some(List::Proxy) => &mut acl.proxy,
+                        Some(List::OutboundBlock) => &mut acl.outbound_block,
+                        None => return Err(format!("line {}: rule `{}` is not in any list", num + 1, line)),
+                    };
+                    if let Err(err) = rules.add(line) {
+                        return Err(format!("line {}: {}", num + 1, err));
+                    }
+                }
+            }
+        }
+
+        acl.bypass.build();
+        acl.proxy.build();
+        acl.outbound_block.build();
+        Ok(acl)
+    }
+
+    pub fn load_from_file(path: &str) -> IoResult<AccessControl> {
+        let content = try!(File::open(&Path::new(path)).and_then(|mut f| f.read_to_string()));
+        AccessControl::parse(content.as_slice()).map_err(|err| {
+            IoError {
+                kind: InvalidInput,
+                desc: "invalid ACL file",
+                detail: Some(err),
+            }
+        })
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Whether `sslocal` should connect to `addr` directly instead of through the servers
+    pub fn check_bypass(&self, addr: &Address) -> bool {
+        if self.proxy.check(addr) {
+            return false;
+        }
+
+        match self.mode {
+            Mode::ProxyAll => self.bypass.check(addr),
+            Mode::BypassAll => true,
+        }
+    }
+
+    /// Whether `ssserver` must not connect to `addr`
+    pub fn check_outbound_blocked(&self, addr: &Address) -> bool {
+        !self.outbound_block.is_empty() && self.outbound_block.check(addr)
+    }
+}
+
+impl Debug for AccessControl {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "AccessControl {{ mode: {:?}, bypass: {} rules, proxy: {} rules, outbound_block: {} rules }}",
+               self.mode, self.bypass.len(), self.proxy.len(), self.outbound_block.len())
+    }
+}
+
+#[cfg(test)]
+mod test_acl {
+    use std::io::net::ip::{Ipv4Addr, Ipv6Addr};
+
+    use relay::socks5::Address;
+    use super::{AccessControl, Mode};
+
+    fn ip4(a: u8, b: u8, c: u8, d: u8) -> Address {
+        Address::SocketAddress(Ipv4Addr(a, b, c, d), 80)
+    }
+
+    fn domain(name: &str) -> Address {
+        Address::DomainNameAddress(name.to_string(), 443)
+    }
+
+    #[test]
+    fn test_proxy_all() {
+        let acl = AccessControl::parse("
+            [proxy_all]
+
+            [bypass_list]
+            # Private networks
+            10.0.0.0/8
+            192.168.1.1
+            fd00::/8
+            |localhost
+            ||lan
+            (^|\\.)example\\.com$
+            ^cdn[0-9]+\\.net$
+
+            [proxy_list]
+            10.1.0.0/16
+            ||proxied.example.com
+        ").unwrap();
+        assert_eq!(acl.mode(), Mode::ProxyAll);
+
+        assert!(acl.check_bypass(&ip4(10, 2, 3, 4)));
+        assert!(!acl.check_bypass(&ip4(10, 1, 3, 4)));
+        assert!(acl.check_bypass(&ip4(192, 168, 1, 1)));
+        assert!(!acl.check_bypass(&ip4(192, 168, 1, 2)));
+        assert!(acl.check_bypass(&Address::SocketAddress(Ipv6Addr(0xfd12, 0, 0, 0, 0, 0, 0, 1), 80)));
+        assert!(!acl.check_bypass(&Address::SocketAddress(Ipv6Addr(0xfe80, 0, 0, 0, 0, 0, 0, 1), 80)));
+
+        assert!(acl.check_bypass(&domain("localhost")));
+        assert!(!acl.check_bypass(&domain("www.localhost")));
+        assert!(acl.check_bypass(&domain("nas.LAN")));
+        assert!(acl.check_bypass(&domain("example.com")));
+        assert!(acl.check_bypass(&domain("www.example.com")));
+        assert!(!acl.check_bypass(&domain("proxied.example.com")));
+        assert!(!acl.check_bypass(&domain("badexample.com")));
+        assert!(acl.check_bypass(&domain("cdn12.net")));
+        assert!(!acl.check_bypass(&domain("www.cdn12.net")));
+        assert!(acl.check_bypass(&domain("10.0.0.1")));
+    }
+
+    #[test]
+    fn test_bypass_all() {
+        let acl = AccessControl::parse("[bypass_all]\n[proxy_list]\n||google.com\n").unwrap();
+        assert!(!acl.check_bypass(&domain("www.google.com")));
+        assert!(acl.check_bypass(&domain("www.example.com")));
+        assert!(acl.check_bypass(&ip4(8, 8, 8, 8)));
+    }
+
+    #[test]
+    fn test_outbound_block() {
+        let acl = AccessControl::parse("[outbound_block_list]\n127.0.0.0/8\n0.0.0.0/0\n::1\n").unwrap();
+        assert!(acl.check_outbound_blocked(&ip4(127, 0, 0, 1)));
+        assert!(acl.check_outbound_blocked(&ip4(255, 255, 255, 255)));
+        assert!(acl.check_outbound_blocked(&Address::SocketAddress(Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 1), 80)));
+        assert!(!acl.check_outbound_blocked(&Address::SocketAddress(Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 2), 80)));
+        assert!(!acl.check_outbound_blocked(&domain("example.com")));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(AccessControl::parse("10.0.0.0/8\n").is_err());
+        assert!(AccessControl::parse("[unknown]\n").is_err());
+        assert!(AccessControl::parse("[bypass_list]\n(unclosed\n").is_err());
+    }
+
+    #[test]
+    fn test_large_list() {
+        // As large as lists of China routes and domains used with shadowsocks-libev
+        let mut content = "[proxy_all]\n[bypass_list]\n".to_string();
+        for i in range(0, 50000us) {
+            content.push_str(format!("{}.{}.{}.0/24\n", 1 + i / 65536, i / 256 % 256, i % 256).as_slice());
+            content.push_str(format!("||site{}.example\n", i).as_slice());
+        }
+        let acl = AccessControl::parse(content.as_slice()).unwrap();
+
+        assert!(acl.check_bypass(&ip4(1, 0, 0, 1)));
+        assert!(acl.check_bypass(&ip4(1, 195, 79, 255)));
+        assert!(!acl.check_bypass(&ip4(1, 195, 80, 0)));
+        assert!(acl.check_bypass(&domain("site0.example")));
+        assert!(acl.check_bypass(&domain("www.site49999.example")));
+        assert!(!acl.check_bypass(&domain("site50000.example")));
+    }
+}
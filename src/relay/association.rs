@@ -0,0 +1,111 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! UDP associations of SOCKS5 clients
+//!
+//! A client sends UDP ASSOCIATE through a TCP control connection before using the UDP relay of
+//! sslocal, and the association lasts until the control connection is closed. The TCP relay
+//! records associations here, so the UDP relay could tell whether datagrams come from a client
+//! which has been through the handshake (and the authentication, if it is required).
+
+use std::io::net::ip::IpAddr;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Clients holding UDP associations, shared by the TCP and UDP relays
+#[derive(Clone)]
+pub struct Associations {
+    // Number of control connections of every client
+    clients: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Associations {
+    pub fn new() -> Associations {
+        Associations {
+            clients: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Records an association of the client at `ip`, which is removed after the returned
+    /// guard has been dropped
+    pub fn associate(&self, ip: IpAddr) -> Association {
+        let mut clients = self.clients.lock().unwrap();
+        let count = match clients.get(&ip) {
+            Some(count) => *count,
+            None => 0,
+        };
+        clients.insert(ip, count + 1);
+
+        Association {
+            clients: self.clients.clone(),
+            ip: ip,
+        }
+    }
+
+    /// Whether the client at `ip` holds any association
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.clients.lock().unwrap().contains_key(ip)
+    }
+}
+
+/// An association held by a control connection
+pub struct Association {
+    clients: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    ip: IpAddr,
+}
+
+impl Drop for Association {
+    fn drop(&mut self) {
+        let mut clients = self.clients.lock().unwrap();
+        let count = match clients.get(&self.ip) {
+            Some(count) => *count,
+            None => return,
+        };
+
+        if count > 1 {
+            clients.insert(self.ip, count - 1);
+        } else {
+            clients.remove(&self.ip);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_association {
+    use std::io::net::ip::Ipv4Addr;
+
+    use relay::association::Associations;
+
+    #[test]
+    fn test_associate() {
+        let associations = Associations::new();
+        let ip = Ipv4Addr(192, 168, 1, 2);
+        assert!(!associations.contains(&ip));
+
+        let first = associations.associate(ip);
+        let second = associations.associate(ip);
+        drop(first);
+        assert!(associations.contains(&ip));
+        drop(second);
+        assert!(!associations.contains(&ip));
+    }
+}
@@ -19,14 +19,22 @@
 // IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
 // CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+//! DNS resolver with a LRU cache
+//!
+//! Lookups are blocking calls of the system resolver, so they are run in a task pool with
+//! `resolve_async` to keep them out of event loops.
+//!
+//! Hits, misses and evictions of the cache are counted in `Metrics`.
+
 use std::sync::{Arc, Mutex, TaskPool};
-// use std::sync::atomic::{AtomicOption, SeqCst};
 use std::io::net::addrinfo::get_host_addresses;
 use std::io::net::ip::IpAddr;
 
 use collect::LruCache;
 
-const TASK_POOL_SIZE: usize = 4;
+use relay::metrics::{Metrics, Cache};
+
+const TASK_POOL_SIZE: usize = 16;
 
 struct DnsLruCache {
     cache: LruCache<String, Vec<IpAddr>>,
@@ -37,10 +45,11 @@ struct DnsLruCache {
 pub struct CachedDns {
     lru_cache: Arc<Mutex<DnsLruCache>>,
     pool: TaskPool,
+    metrics: Arc<Metrics>,
 }
 
 impl CachedDns {
-    pub fn with_capacity(cache_capacity: usize) -> CachedDns {
+    pub fn with_capacity(cache_capacity: usize, metrics: Arc<Metrics>) -> CachedDns {
         CachedDns {
             lru_cache: Arc::new(Mutex::new(DnsLruCache {
                 cache: LruCache::new(cache_capacity),
@@ -48,30 +57,34 @@ impl CachedDns {
                 totally_matched: 0,
             })),
             pool: TaskPool::new(TASK_POOL_SIZE),
+            metrics: metrics,
         }
     }
 
-    pub fn resolve(&self, addr: &str) -> Option<Vec<IpAddr>> {
+    fn lookup_cache(&self, addr: &str) -> Option<Vec<IpAddr>> {
         let addr_string = addr.to_string();
 
-        {
-            let mut cache = self.lru_cache.lock().unwrap();
-            match cache.cache.get(&addr_string).map(|x| x.clone()) {
-                Some(addrs) => {
-                    cache.totally_matched += 1;
-                    debug!("DNS cache matched!: {}", addr_string);
-                    debug!("DNS cache matched: {}, missed: {}", cache.totally_matched, cache.totally_missed);
-                    return Some(addrs)
-                },
-                None => {
-                    cache.totally_missed += 1;
-                    debug!("DNS cache missed!: {}", addr_string);
-                    debug!("DNS cache matched: {}, missed: {}", cache.totally_matched, cache.totally_missed);
-                }
+        let mut cache = self.lru_cache.lock().unwrap();
+        let addrs = cache.cache.get(&addr_string).map(|x| x.clone());
+        self.metrics.add_dns_lookup(addrs.is_some());
+        match addrs {
+            Some(addrs) => {
+                cache.totally_matched += 1;
+                debug!("DNS cache matched!: {}", addr_string);
+                debug!("DNS cache matched: {}, missed: {}", cache.totally_matched, cache.totally_missed);
+                Some(addrs)
+            },
+            None => {
+                cache.totally_missed += 1;
+                debug!("DNS cache missed!: {}", addr_string);
+                debug!("DNS cache matched: {}, missed: {}", cache.totally_matched, cache.totally_missed);
+                None
             }
         }
+    }
 
-        let addrs = match get_host_addresses(addr) {
+    fn resolve_and_cache(lru_cache: &Mutex<DnsLruCache>, metrics: &Metrics, addr: String) -> Option<Vec<IpAddr>> {
+        let addrs = match get_host_addresses(addr.as_slice()) {
             Ok(addrs) => addrs,
             Err(err) => {
                 error!("Failed to resolve {}: {}", addr, err);
@@ -79,13 +92,27 @@ impl CachedDns {
             }
         };
 
+        metrics.insert_lru(Cache::Dns, &mut lru_cache.lock().unwrap().cache, addr, addrs.clone());
+        Some(addrs)
+    }
+
+    /// Resolves `addr` without blocking the current thread.
+    ///
+    /// Cached addresses are passed to `callback` immediately, otherwise `callback` will be
+    /// called in a thread of the task pool after the lookup.
+    pub fn resolve_async<F>(&self, addr: &str, callback: F)
+            where F: FnOnce(Option<Vec<IpAddr>>) + Send {
+        if let Some(addrs) = self.lookup_cache(addr) {
+            callback(Some(addrs));
+            return;
+        }
+
         let cloned_mutex = self.lru_cache.clone();
-        let cloned_addr = addrs.clone();
+        let metrics = self.metrics.clone();
+        let addr_string = addr.to_string();
         self.pool.execute(move || {
-            let mut cache = cloned_mutex.lock().unwrap();
-            cache.cache.insert(addr_string, cloned_addr);
+            callback(CachedDns::resolve_and_cache(&*cloned_mutex, &*metrics, addr_string));
         });
-        Some(addrs)
     }
 }
 
@@ -0,0 +1,757 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! DNS forwarder running along with the local relays
+//!
+//! Queries are received over both UDP and TCP, and sent to the upstream resolver through the
+//! servers by the UDP relay. If UDP relay is disabled, every query is sent by a TCP connection
+//! through the servers instead, as DNS over TCP.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::io::{File, IoError, IoResult, OtherIoError};
+use std::io::net::ip::{SocketAddr, IpAddr, Ipv4Addr};
+use std::iter::repeat;
+use std::rand;
+
+use config::{Config, ServerConfig, LoadBalancing};
+use relay::Relay;
+use relay::socks5;
+use relay::loadbalancing::server::{LoadBalancer, HealthChecker, Failures, new_load_balancer, resolve_servers};
+use relay::eventloop::{EventLoop, Handler, Token, Interest, Ready, TcpListener, TcpStream, UdpSocket, Notifier, now_ms};
+use relay::tcprelay::tunnel::{Endpoint, Codec};
+use relay::plugin::Plugins;
+#[cfg(feature = "enable-udp")]
+use relay::udprelay::local::UdpRelayClient;
+use relay::udprelay::unspecified_addr;
+use relay::dnsrelay::{DnsCache, Question, parse_question, message_id, set_message_id, in_domains};
+
+const SOCKET_TOKEN: Token = Token(0);
+const LISTENER_TOKEN: Token = Token(1);
+const RELAY_SOCKET_TOKEN: Token = Token(2);
+const DIRECT_SOCKET_TOKEN: Token = Token(3);
+const SWEEP_TOKEN: Token = Token(4);
+// TCP connections have tokens of their IDs, which start from here
+const FIRST_CONN_ID: usize = 5;
+
+const DNS_CACHE_CAPACITY: usize = 4096;
+const MAX_PENDING_QUERIES: usize = 4096;
+const MAX_MESSAGE_SIZE: usize = 65535;
+
+// Milliseconds
+const QUERY_TIMEOUT: u64 = 5000;
+const CLIENT_TIMEOUT: u64 = 30000;
+const SWEEP_INTERVAL: u64 = 1000;
+
+const RESOLV_CONF: &'static str = "/etc/resolv.conf";
+
+#[cfg(not(feature = "enable-udp"))]
+enum UdpRelayClient {}
+
+#[cfg(not(feature = "enable-udp"))]
+impl UdpRelayClient {
+    fn send_to(&mut self, _: &UdpSocket, _: socks5::Address, _: &[u8], _: SocketAddr, _: &ServerConfig)
+            -> IoResult<()> {
+        match *self {}
+    }
+
+    fn decrypt_response(&mut self, _: &[u8], _: SocketAddr, _: &ServerConfig) -> IoResult<Option<Vec<u8>>> {
+        match *self {}
+    }
+}
+
+#[cfg(feature = "enable-udp")]
+fn udp_relay_client(addr: SocketAddr) -> Option<UdpRelayClient> {
+    Some(UdpRelayClient::new(addr))
+}
+
+#[cfg(not(feature = "enable-udp"))]
+fn udp_relay_client(_: SocketAddr) -> Option<UdpRelayClient> {
+    None
+}
+
+#[inline]
+fn make_io_error(desc: &'static str, detail: Option<String>) -> IoError {
+    IoError {
+        kind: OtherIoError,
+        desc: desc,
+        detail: detail,
+    }
+}
+
+/// The first `nameserver` of the system which is not the forwarder itself
+fn system_resolver(local: &SocketAddr) -> Option<SocketAddr> {
+    let content = match File::open(&Path::new(RESOLV_CONF)).and_then(|mut f| f.read_to_string()) {
+        Ok(content) => content,
+        Err(err) => {
+            error!("Failed to read {}: {}", RESOLV_CONF, err);
+            return None;
+        }
+    };
+
+    for line in content.as_slice().lines() {
+        let line = line.trim();
+        if !line.starts_with("nameserver") {
+            continue;
+        }
+
+        match line["nameserver".len()..].trim().parse::<IpAddr>() {
+            Some(ip) if ip != local.ip => return Some(SocketAddr { ip: ip, port: 53 }),
+            _ => {},
+        }
+    }
+    None
+}
+
+/// DNS forwarder, which is enabled by `local_dns` of `Config`
+#[derive(Clone)]
+pub struct DnsRelayLocal {
+    config: Config,
+    health_checker: Option<Arc<HealthChecker>>,
+    plugins: Arc<Plugins>,
+    servers: Arc<Mutex<Servers>>,
+}
+
+/// Servers and the event loop using them, shared by clones of a `DnsRelayLocal`
+struct Servers {
+    list: Vec<ServerConfig>,
+    notifier: Option<Notifier<Message>>,
+    shut_down: bool,
+}
+
+impl DnsRelayLocal {
+    pub fn new(config: Config, health_checker: Option<Arc<HealthChecker>>, plugins: Arc<Plugins>) -> DnsRelayLocal {
+        if config.local_dns.is_none() {
+            panic!("You have to provide configuration for the DNS forwarder");
+        }
+
+        let servers = Servers {
+            list: config.server.clone(),
+            notifier: None,
+            shut_down: false,
+        };
+        DnsRelayLocal {
+            config: config,
+            health_checker: health_checker,
+            plugins: plugins,
+            servers: Arc::new(Mutex::new(servers)),
+        }
+    }
+
+    /// Replaces the servers which queries are forwarded through, they are resolved in the calling
+    /// thread.
+    ///
+    /// It could be called before or after `run`.
+    pub fn set_servers(&self, servers: Vec<ServerConfig>) {
+        let (server_set, server_addr) = resolve_servers(servers.as_slice());
+        let mut guard = self.servers.lock().unwrap();
+        if let Some(ref notifier) = guard.notifier {
+            // The event loop has exited if it fails
+            let _ = notifier.notify(Message::Servers(servers.clone(), server_set, server_addr));
+        }
+        guard.list = servers;
+    }
+}
+
+/// Messages to the event loop
+enum Message {
+    /// Replaces the servers of the load balancer, with their resolved addresses
+    Servers(Vec<ServerConfig>, HashMap<SocketAddr, ServerConfig>, HashMap<String, SocketAddr>),
+    /// Drops pending queries and exits
+    Shutdown,
+}
+
+#[derive(Copy)]
+enum Client {
+    Udp(SocketAddr),
+    /// ID of the TCP connection
+    Tcp(usize),
+}
+
+/// A forwarded query waiting for its response
+struct Pending {
+    question: Question,
+    client: Client,
+    // ID of the query from the client
+    id: u16,
+    expires_at: u64,
+}
+
+/// A client sending queries over TCP, every message has a length prefix of 2 bytes
+struct ClientConn {
+    client: Endpoint,
+    received: Vec<u8>,
+    last_active: u64,
+}
+
+impl ClientConn {
+    // Returns queries which have been received completely
+    fn ready(&mut self, ready: Ready, buf: &mut [u8]) -> IoResult<Vec<Vec<u8>>> {
+        if ready.error {
+            try!(self.client.stream.take_socket_error());
+        }
+
+        let mut queries = Vec::new();
+        if ready.readable || ready.hangup {
+            while !self.client.read_closed {
+                match try!(self.client.read(buf)) {
+                    None => break,
+                    Some(0) => {},
+                    Some(n) => self.received.push_all(&buf[..n]),
+                }
+            }
+
+            while let Some(len) = framed_length(self.received.as_slice()) {
+                queries.push(self.received[2..2 + len].to_vec());
+                self.received = self.received[2 + len..].to_vec();
+            }
+        }
+
+        if ready.hangup {
+            self.client.hang_up();
+        }
+        try!(self.client.flush());
+        Ok(queries)
+    }
+}
+
+/// A query sent by DNS over TCP through the server
+struct UpstreamConn {
+    remote: Endpoint,
+    codec: Codec,
+    // Decrypted response
+    received: Vec<u8>,
+    query_id: u16,
+    expires_at: u64,
+}
+
+impl UpstreamConn {
+    // Returns the response once it has been received completely
+    fn ready(&mut self, ready: Ready, buf: &mut [u8]) -> IoResult<Option<Vec<u8>>> {
+        if ready.error {
+            try!(self.remote.stream.take_socket_error());
+        }
+        if self.remote.connecting && (ready.writable || ready.hangup) {
+            try!(self.remote.finish_connect());
+        }
+        try!(self.remote.flush());
+
+        if ready.readable || ready.hangup {
+            while !self.remote.read_closed {
+                match try!(self.remote.read(buf)) {
+                    None => break,
+                    Some(0) => {},
+                    Some(n) => try!(self.codec.transform(false, &buf[..n], &mut self.received)),
+                }
+            }
+        }
+
+        match framed_length(self.received.as_slice()) {
+            Some(len) => Ok(Some(self.received[2..2 + len].to_vec())),
+            None if self.remote.read_closed => {
+                Err(make_io_error("connection closed before the DNS response", None))
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+// Length of the first message of DNS over TCP, if it has been received completely
+fn framed_length(data: &[u8]) -> Option<usize> {
+    if data.len() < 2 {
+        return None;
+    }
+    let len = ((data[0] as usize) << 8) | data[1] as usize;
+    if data.len() < 2 + len { None } else { Some(len) }
+}
+
+fn frame(msg: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(2 + msg.len());
+    framed.push((msg.len() >> 8) as u8);
+    framed.push(msg.len() as u8);
+    framed.push_all(msg);
+    framed
+}
+
+enum Conn {
+    Client(ClientConn),
+    Upstream(UpstreamConn),
+}
+
+struct DnsLocalHandler {
+    socket: UdpSocket,
+    listener: TcpListener,
+    upstream: socks5::Address,
+    // Socket to the servers, if queries are sent by the UDP relay
+    relay_socket: Option<(UdpSocket, UdpRelayClient)>,
+    direct_domains: Vec<String>,
+    direct: Option<(UdpSocket, SocketAddr)>,
+    server_load_balancer: Box<LoadBalancer + Send>,
+    load_balancing: LoadBalancing,
+    health_checker: Option<Arc<HealthChecker>>,
+    failures: Arc<Failures>,
+    plugins: Arc<Plugins>,
+    server_set: HashMap<SocketAddr, ServerConfig>,
+    server_addr: HashMap<String, SocketAddr>,
+    cache: DnsCache,
+    // Forwarded queries by their new IDs
+    pending: HashMap<u16, Pending>,
+    next_query_id: u16,
+    conns: HashMap<usize, Conn>,
+    next_conn_id: usize,
+    buf: Vec<u8>,
+}
+
+impl DnsLocalHandler {
+    // Queries are forwarded with new IDs, which are unique among pending queries
+    fn alloc_query_id(&mut self) -> u16 {
+        loop {
+            let id = self.next_query_id;
+            self.next_query_id = if id == ::std::u16::MAX { 0 } else { id + 1 };
+            if !self.pending.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    fn handle_query(&mut self, event_loop: &mut EventLoop<DnsLocalHandler>, client: Client, query: &[u8]) {
+        let question = match parse_question(query) {
+            Some(q) => q,
+            None => {
+                debug!("Dropped malformed DNS query");
+                return;
+            }
+        };
+        let id = message_id(query).unwrap();
+
+        if let Some(response) = self.cache.get(&question, id, now_ms()) {
+            debug!("DNS cache hit {}", question.name);
+            self.respond(event_loop, client, response);
+            return;
+        }
+
+        if self.pending.len() >= MAX_PENDING_QUERIES {
+            warn!("Too many pending DNS queries, dropped query of {}", question.name);
+            return;
+        }
+
+        let query_id = self.alloc_query_id();
+        let mut msg = query.to_vec();
+        set_message_id(msg.as_mut_slice(), query_id);
+
+        let result = if in_domains(question.name.as_slice(), self.direct_domains.as_slice()) {
+            debug!("DNS query {} directly", question.name);
+            let &(ref socket, addr) = self.direct.as_ref().unwrap();
+            socket.send_to(msg.as_slice(), &addr).map(|_| ())
+        } else {
+            debug!("DNS query {} through {}", question.name, self.upstream);
+            self.query_upstream(event_loop, query_id, msg)
+        };
+
+        match result {
+            Ok(..) => {
+                self.pending.insert(query_id, Pending {
+                    question: question,
+                    client: client,
+                    id: id,
+                    expires_at: now_ms() + QUERY_TIMEOUT,
+                });
+            },
+            Err(err) => error!("Failed to forward DNS query of {}: {}", question.name, err),
+        }
+    }
+
+    fn query_upstream(&mut self, event_loop: &mut EventLoop<DnsLocalHandler>, query_id: u16, msg: Vec<u8>)
+            -> IoResult<()> {
+        let server = self.server_load_balancer.pick_server().clone();
+        let server_addr = match self.server_addr.get(&server.addr) {
+            Some(addr) => *addr,
+            None => return Err(make_io_error("server could not be resolved", Some(server.addr.clone()))),
+        };
+
+        if self.relay_socket.is_none() {
+            return self.query_tcp(event_loop, server, server_addr, query_id, msg);
+        }
+
+        let &mut (ref socket, ref mut relay) = self.relay_socket.as_mut().unwrap();
+        relay.send_to(socket, self.upstream.clone(), msg.as_slice(), server_addr, &server)
+    }
+
+    fn query_tcp(&mut self, event_loop: &mut EventLoop<DnsLocalHandler>, server: ServerConfig,
+                 server_addr: SocketAddr, query_id: u16, msg: Vec<u8>) -> IoResult<()> {
+        // Only TCP goes through the plugin of the server
+        let server_addr = try!(self.plugins.tcp_addr(&server)).unwrap_or(server_addr);
+        let id = self.next_conn_id;
+        self.next_conn_id += 1;
+
+        let mut remote = Endpoint::connecting(try!(TcpStream::connect(&server_addr)), Token(id));
+        let mut codec = try!(Codec::for_request(&server, &self.upstream, &mut remote.out));
+        try!(codec.transform(true, frame(msg.as_slice()).as_slice(), &mut remote.out));
+        try!(remote.update_interest(event_loop, true));
+
+        self.conns.insert(id, Conn::Upstream(UpstreamConn {
+            remote: remote,
+            codec: codec,
+            received: Vec::new(),
+            query_id: query_id,
+            expires_at: now_ms() + QUERY_TIMEOUT,
+        }));
+        Ok(())
+    }
+
+    fn handle_response(&mut self, event_loop: &mut EventLoop<DnsLocalHandler>, mut msg: Vec<u8>) {
+        let query_id = match message_id(msg.as_slice()) {
+            Some(id) => id,
+            None => return,
+        };
+
+        // Responses must answer the questions, or they may be forged
+        let matched = match self.pending.get(&query_id) {
+            Some(pending) => parse_question(msg.as_slice()).as_ref() == Some(&pending.question),
+            None => false,
+        };
+        if !matched {
+            debug!("Dropped unexpected DNS response");
+            return;
+        }
+
+        let pending = self.pending.remove(&query_id).unwrap();
+        self.cache.insert(pending.question, msg.as_slice(), now_ms());
+        set_message_id(msg.as_mut_slice(), pending.id);
+        self.respond(event_loop, pending.client, msg);
+    }
+
+    fn respond(&mut self, event_loop: &mut EventLoop<DnsLocalHandler>, client: Client, msg: Vec<u8>) {
+        match client {
+            Client::Udp(addr) => {
+                if let Err(err) = self.socket.send_to(msg.as_slice(), &addr) {
+                    error!("Failed to send DNS response to {}: {}", addr, err);
+                }
+            },
+            Client::Tcp(id) => {
+                let result = match self.conns.get_mut(&id) {
+                    Some(&mut Conn::Client(ref mut conn)) => {
+                        conn.client.out.push_all(frame(msg.as_slice()).as_slice());
+                        conn.client.flush().and_then(|_| conn.client.update_interest(event_loop, true))
+                    },
+                    // The client has gone
+                    _ => return,
+                };
+
+                if let Err(err) = result {
+                    debug!("Failed to send DNS response: {}", err);
+                    self.conns.remove(&id);
+                }
+            }
+        }
+    }
+
+    fn accept(&mut self, event_loop: &mut EventLoop<DnsLocalHandler>) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok(Some((stream, _))) => stream,
+                Ok(None) => break,
+                Err(err) => {
+                    error!("Failed to accept: {}", err);
+                    break;
+                }
+            };
+
+            let id = self.next_conn_id;
+            self.next_conn_id += 1;
+
+            let mut client = Endpoint::new(stream, Token(id));
+            if let Err(err) = client.update_interest(event_loop, true) {
+                error!("Failed to register client: {}", err);
+                continue;
+            }
+            self.conns.insert(id, Conn::Client(ClientConn {
+                client: client,
+                received: Vec::new(),
+                last_active: now_ms(),
+            }));
+        }
+    }
+
+    fn conn_ready(&mut self, event_loop: &mut EventLoop<DnsLocalHandler>, id: usize, ready: Ready) {
+        let conn = match self.conns.remove(&id) {
+            Some(conn) => conn,
+            None => return,
+        };
+
+        match conn {
+            Conn::Client(mut conn) => {
+                let result = conn.ready(ready, self.buf.as_mut_slice()).and_then(|queries| {
+                    conn.client.update_interest(event_loop, true).map(|_| queries)
+                });
+                match result {
+                    Ok(queries) => {
+                        if !conn.client.write_closed {
+                            conn.last_active = now_ms();
+                            self.conns.insert(id, Conn::Client(conn));
+                        }
+                        for query in queries.iter() {
+                            self.handle_query(event_loop, Client::Tcp(id), query.as_slice());
+                        }
+                    },
+                    Err(err) => debug!("DNS client closed: {}", err),
+                }
+            },
+            Conn::Upstream(mut conn) => {
+                match conn.ready(ready, self.buf.as_mut_slice()) {
+                    Ok(Some(response)) => self.handle_response(event_loop, response),
+                    Ok(None) => match conn.remote.update_interest(event_loop, true) {
+                        Ok(..) => {
+                            self.conns.insert(id, Conn::Upstream(conn));
+                        },
+                        Err(err) => {
+                            error!("Failed to register DNS query: {}", err);
+                            self.pending.remove(&conn.query_id);
+                        }
+                    },
+                    Err(err) => {
+                        error!("Failed to query DNS through the server: {}", err);
+                        self.pending.remove(&conn.query_id);
+                    }
+                }
+            }
+        }
+    }
+
+    fn recv_responses(&mut self, event_loop: &mut EventLoop<DnsLocalHandler>, token: Token) {
+        loop {
+            let received = if token == RELAY_SOCKET_TOKEN {
+                self.relay_socket.as_ref().unwrap().0.recv_from(self.buf.as_mut_slice())
+            } else {
+                self.direct.as_ref().unwrap().0.recv_from(self.buf.as_mut_slice())
+            };
+            let (len, from) = match received {
+                Ok(Some(r)) => r,
+                Ok(None) => break,
+                Err(err) => {
+                    error!("Failed in UDP recv_from: {}", err);
+                    break;
+                }
+            };
+            let packet = self.buf[..len].to_vec();
+
+            let msg = if token == RELAY_SOCKET_TOKEN {
+                let server = match self.server_set.get(&from) {
+                    Some(s) => s.clone(),
+                    None => continue,
+                };
+                match self.relay_socket.as_mut().unwrap().1.decrypt_response(packet.as_slice(), from, &server) {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) => continue,
+                    Err(err) => {
+                        error!("Invalid UDP response from {}: {}", from, err);
+                        continue;
+                    }
+                }
+            } else if from == self.direct.as_ref().unwrap().1 {
+                packet
+            } else {
+                continue;
+            };
+
+            self.handle_response(event_loop, msg);
+        }
+    }
+
+    // Drops expired queries and idle clients
+    fn sweep(&mut self) {
+        let now = now_ms();
+
+        let expired: Vec<u16> = self.pending.iter()
+            .filter(|&(_, pending)| pending.expires_at <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired.iter() {
+            if let Some(pending) = self.pending.remove(id) {
+                debug!("DNS query of {} timed out", pending.question.name);
+            }
+        }
+
+        let idle: Vec<usize> = self.conns.iter()
+            .filter(|&(_, conn)| match *conn {
+                Conn::Client(ref c) => c.last_active + CLIENT_TIMEOUT <= now,
+                Conn::Upstream(ref u) => u.expires_at <= now,
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in idle.iter() {
+            self.conns.remove(id);
+        }
+    }
+}
+
+impl Handler for DnsLocalHandler {
+    type Message = Message;
+
+    // Replaced servers are still known, so that responses to pending queries are received
+    fn notify(&mut self, event_loop: &mut EventLoop<DnsLocalHandler>, msg: Message) {
+        match msg {
+            Message::Servers(servers, server_set, server_addr) => {
+                self.server_load_balancer = new_load_balancer(servers, self.load_balancing,
+                                                              self.health_checker.clone(), self.failures.clone());
+                self.server_set.extend(server_set.into_iter());
+                self.server_addr.extend(server_addr.into_iter());
+            },
+            Message::Shutdown => {
+                debug!("Dropped {} pending DNS queries", self.pending.len());
+                event_loop.shutdown();
+            },
+        }
+    }
+
+    fn ready(&mut self, event_loop: &mut EventLoop<DnsLocalHandler>, token: Token, ready: Ready) {
+        if token == SOCKET_TOKEN {
+            loop {
+                let (len, src) = match self.socket.recv_from(self.buf.as_mut_slice()) {
+                    Ok(Some(r)) => r,
+                    Ok(None) => break,
+                    Err(err) => {
+                        error!("Failed in UDP recv_from: {}", err);
+                        break;
+                    }
+                };
+                let query = self.buf[..len].to_vec();
+                self.handle_query(event_loop, Client::Udp(src), query.as_slice());
+            }
+        } else if token == LISTENER_TOKEN {
+            self.accept(event_loop);
+        } else if token == RELAY_SOCKET_TOKEN || token == DIRECT_SOCKET_TOKEN {
+            self.recv_responses(event_loop, token);
+        } else {
+            self.conn_ready(event_loop, token.0, ready);
+        }
+    }
+
+    fn timeout(&mut self, event_loop: &mut EventLoop<DnsLocalHandler>, _: Token) {
+        self.sweep();
+        event_loop.timeout_ms(SWEEP_TOKEN, SWEEP_INTERVAL);
+    }
+}
+
+impl Relay for DnsRelayLocal {
+    fn run(&self) {
+        let dns = self.config.local_dns.clone().unwrap();
+
+        let mut event_loop = EventLoop::new().ok().expect("Failed to create event loop");
+
+        // Servers replaced from now on are sent to the event loop
+        let servers = {
+            let mut servers = self.servers.lock().unwrap();
+            if servers.shut_down {
+                return;
+            }
+            servers.notifier = Some(event_loop.notifier());
+            servers.list.clone()
+        };
+        let (server_set, server_addr) = resolve_servers(servers.as_slice());
+        // Kept when the load balancer is replaced
+        let failures = Arc::new(Failures::new());
+        let server_load_balancer = new_load_balancer(servers, self.config.load_balancing, self.health_checker.clone(),
+                                                     failures.clone());
+
+        let socket = UdpSocket::bind(&dns.local).ok().expect("Failed to bind DNS socket");
+        let listener = TcpListener::bind(&dns.local).ok().expect("Failed to bind DNS listener");
+        info!("Shadowsocks DNS forwarder listening on {}", dns.local);
+
+        let relay_socket = if self.config.enable_udp {
+            let addr = match server_set.keys().next() {
+                Some(addr) => unspecified_addr(&addr.ip),
+                None => unspecified_addr(&Ipv4Addr(0, 0, 0, 0)),
+            };
+            udp_relay_client(addr).map(|relay| {
+                (UdpSocket::bind(&addr).ok().expect("Failed to bind udp socket"), relay)
+            })
+        } else {
+            None
+        };
+        if relay_socket.is_none() {
+            info!("UDP relay is disabled, DNS queries are sent over TCP");
+        }
+
+        let direct = if dns.direct_domains.is_empty() {
+            None
+        } else {
+            match dns.direct_resolver.or_else(|| system_resolver(&dns.local)) {
+                Some(addr) => {
+                    let socket = UdpSocket::bind(&unspecified_addr(&addr.ip)).ok().expect("Failed to bind udp socket");
+                    Some((socket, addr))
+                },
+                None => {
+                    warn!("No resolver for direct domains, they are resolved through the servers");
+                    None
+                }
+            }
+        };
+
+        event_loop.register(&socket, SOCKET_TOKEN, Interest::readable()).ok().expect("Failed to register udp socket");
+        event_loop.register(&listener, LISTENER_TOKEN, Interest::readable()).ok().expect("Failed to register listener");
+        if let Some((ref socket, _)) = relay_socket {
+            event_loop.register(socket, RELAY_SOCKET_TOKEN, Interest::readable())
+                      .ok().expect("Failed to register udp socket");
+        }
+        if let Some((ref socket, _)) = direct {
+            event_loop.register(socket, DIRECT_SOCKET_TOKEN, Interest::readable())
+                      .ok().expect("Failed to register udp socket");
+        }
+        event_loop.timeout_ms(SWEEP_TOKEN, SWEEP_INTERVAL);
+
+        let mut handler = DnsLocalHandler {
+            socket: socket,
+            listener: listener,
+            upstream: dns.upstream,
+            relay_socket: relay_socket,
+            direct_domains: if direct.is_some() { dns.direct_domains } else { Vec::new() },
+            direct: direct,
+            server_load_balancer: server_load_balancer,
+            load_balancing: self.config.load_balancing,
+            health_checker: self.health_checker.clone(),
+            failures: failures,
+            plugins: self.plugins.clone(),
+            server_set: server_set,
+            server_addr: server_addr,
+            cache: DnsCache::new(DNS_CACHE_CAPACITY),
+            pending: HashMap::new(),
+            next_query_id: rand::random(),
+            conns: HashMap::new(),
+            next_conn_id: FIRST_CONN_ID,
+            buf: repeat(0u8).take(MAX_MESSAGE_SIZE).collect(),
+        };
+
+        if let Err(err) = event_loop.run(&mut handler) {
+            error!("DNS event loop exited: {}", err);
+        }
+    }
+
+    // Clients retry queries which are not answered, so they are not waited for
+    fn shutdown(&self, _: u64) {
+        let mut servers = self.servers.lock().unwrap();
+        if let Some(ref notifier) = servers.notifier {
+            let _ = notifier.notify(Message::Shutdown);
+        }
+        servers.shut_down = true;
+    }
+}
@@ -0,0 +1,342 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! DNS forwarder of sslocal
+//!
+//! Only parts of DNS messages which are needed by forwarding and caching are parsed,
+//! as described in RFC 1035.
+
+use collect::LruCache;
+
+pub mod local;
+
+/// Size of the fixed header
+const HEADER_SIZE: usize = 12;
+
+/// Type of the EDNS pseudo record (RFC 6891), whose TTL field has other meanings
+const TYPE_OPT: u16 = 41;
+
+/// Truncated flag, in the third byte of the header
+const FLAG_TC: u8 = 0x02;
+
+const RCODE_NOERROR: u8 = 0;
+const RCODE_NXDOMAIN: u8 = 3;
+
+// Compression pointers in a name are followed at most this many times
+const MAX_POINTERS: usize = 16;
+
+macro_rules! try_opt(
+    ($e:expr) => (
+        match $e {
+            Some(v) => v,
+            None => return None,
+        }
+    );
+);
+
+/// The only question of a query, which is the key of cached responses
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Question {
+    /// Lowercased name without the trailing dot
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> Option<u16> {
+    if pos + 2 > msg.len() {
+        return None;
+    }
+    Some(((msg[pos] as u16) << 8) | msg[pos + 1] as u16)
+}
+
+fn read_u32(msg: &[u8], pos: usize) -> Option<u32> {
+    let high = try_opt!(read_u16(msg, pos)) as u32;
+    let low = try_opt!(read_u16(msg, pos + 2)) as u32;
+    Some((high << 16) | low)
+}
+
+fn write_u32(msg: &mut [u8], pos: usize, val: u32) {
+    msg[pos] = (val >> 24) as u8;
+    msg[pos + 1] = (val >> 16) as u8;
+    msg[pos + 2] = (val >> 8) as u8;
+    msg[pos + 3] = val as u8;
+}
+
+/// Reads the name at `pos`, returns the lowercased name and the position right after it
+fn read_name(msg: &[u8], pos: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut pos = pos;
+    // Position after the name, which is known once a pointer is met
+    let mut end = None;
+    let mut pointers = 0;
+
+    loop {
+        let len = *try_opt!(msg.get(pos)) as usize;
+        if len & 0xc0 == 0xc0 {
+            let offset = try_opt!(read_u16(msg, pos)) as usize & 0x3fff;
+            if end.is_none() {
+                end = Some(pos + 2);
+            }
+            pointers += 1;
+            if pointers > MAX_POINTERS {
+                return None;
+            }
+            pos = offset;
+            continue;
+        } else if len & 0xc0 != 0 {
+            return None;
+        }
+
+        if len == 0 {
+            return Some((name, end.unwrap_or(pos + 1)));
+        }
+
+        if pos + 1 + len > msg.len() {
+            return None;
+        }
+        if !name.is_empty() {
+            name.push('.');
+        }
+        for &b in msg[pos + 1..pos + 1 + len].iter() {
+            let b = if b >= b'A' && b <= b'Z' { b + (b'a' - b'A') } else { b };
+            name.push(b as char);
+        }
+        pos += 1 + len;
+    }
+}
+
+/// ID of the message
+pub fn message_id(msg: &[u8]) -> Option<u16> {
+    read_u16(msg, 0)
+}
+
+pub fn set_message_id(msg: &mut [u8], id: u16) {
+    msg[0] = (id >> 8) as u8;
+    msg[1] = id as u8;
+}
+
+/// Parses the question of a message, which must have exactly one question
+pub fn parse_question(msg: &[u8]) -> Option<Question> {
+    if msg.len() < HEADER_SIZE || try_opt!(read_u16(msg, 4)) != 1 {
+        return None;
+    }
+
+    let (name, pos) = try_opt!(read_name(msg, HEADER_SIZE));
+    Some(Question {
+        name: name,
+        qtype: try_opt!(read_u16(msg, pos)),
+        qclass: try_opt!(read_u16(msg, pos + 2)),
+    })
+}
+
+/// Positions of TTL fields of all resource records, except the EDNS pseudo record
+fn ttl_offsets(msg: &[u8]) -> Option<Vec<usize>> {
+    if msg.len() < HEADER_SIZE {
+        return None;
+    }
+
+    let questions = try_opt!(read_u16(msg, 4));
+    let records = try_opt!(read_u16(msg, 6)) as usize
+        + try_opt!(read_u16(msg, 8)) as usize
+        + try_opt!(read_u16(msg, 10)) as usize;
+
+    let mut pos = HEADER_SIZE;
+    for _ in range(0, questions) {
+        let (_, next) = try_opt!(read_name(msg, pos));
+        pos = next + 4;
+    }
+
+    let mut offsets = Vec::new();
+    for _ in range(0, records) {
+        let (_, next) = try_opt!(read_name(msg, pos));
+        let rtype = try_opt!(read_u16(msg, next));
+        let rdlength = try_opt!(read_u16(msg, next + 8)) as usize;
+        if rtype != TYPE_OPT {
+            offsets.push(next + 4);
+        }
+        pos = next + 10 + rdlength;
+        if pos > msg.len() {
+            return None;
+        }
+    }
+    Some(offsets)
+}
+
+/// Seconds which the response could be cached for, `None` if it shouldn't be cached.
+///
+/// Only complete responses with answers, or negative responses with an SOA record are cached.
+pub fn cache_ttl(msg: &[u8]) -> Option<u32> {
+    if msg.len() < HEADER_SIZE || msg[2] & FLAG_TC != 0 {
+        return None;
+    }
+
+    let rcode = msg[3] & 0x0f;
+    if rcode != RCODE_NOERROR && rcode != RCODE_NXDOMAIN {
+        return None;
+    }
+
+    let offsets = try_opt!(ttl_offsets(msg));
+    offsets.iter().filter_map(|&pos| read_u32(msg, pos)).min()
+}
+
+/// Whether `name` is one of `domains`, or a subdomain of them
+pub fn in_domains(name: &str, domains: &[String]) -> bool {
+    domains.iter().any(|domain| {
+        let domain = domain.as_slice();
+        name == domain
+            || (name.len() > domain.len() && name.ends_with(domain)
+                && name.as_bytes()[name.len() - domain.len() - 1] == b'.')
+    })
+}
+
+struct CachedResponse {
+    message: Vec<u8>,
+    ttl_offsets: Vec<usize>,
+    // Milliseconds
+    cached_at: u64,
+    expires_at: u64,
+}
+
+/// Responses cached by their questions, until their TTLs expire
+pub struct DnsCache {
+    responses: LruCache<Question, CachedResponse>,
+}
+
+impl DnsCache {
+    pub fn new(capacity: usize) -> DnsCache {
+        DnsCache {
+            responses: LruCache::new(capacity),
+        }
+    }
+
+    /// Caches a response received at `now`, if it is cacheable
+    pub fn insert(&mut self, question: Question, msg: &[u8], now: u64) {
+        let ttl = match cache_ttl(msg) {
+            Some(ttl) if ttl > 0 => ttl,
+            _ => return,
+        };
+
+        self.responses.insert(question, CachedResponse {
+            message: msg.to_vec(),
+            ttl_offsets: ttl_offsets(msg).unwrap(),
+            cached_at: now,
+            expires_at: now + ttl as u64 * 1000,
+        });
+    }
+
+    /// Returns the cached response as the answer of query `id`, TTLs are reduced by the time
+    /// it has been cached for
+    pub fn get(&mut self, question: &Question, id: u16, now: u64) -> Option<Vec<u8>> {
+        let expired = match self.responses.get(question) {
+            Some(cached) => cached.expires_at <= now,
+            None => return None,
+        };
+        if expired {
+            self.responses.remove(question);
+            return None;
+        }
+
+        let cached = self.responses.get(question).unwrap();
+        let elapsed = ((now - cached.cached_at) / 1000) as u32;
+        let mut msg = cached.message.clone();
+        set_message_id(msg.as_mut_slice(), id);
+        for &pos in cached.ttl_offsets.iter() {
+            let ttl = read_u32(msg.as_slice(), pos).unwrap();
+            write_u32(msg.as_mut_slice(), pos, if ttl > elapsed { ttl - elapsed } else { 0 });
+        }
+        Some(msg)
+    }
+}
+
+#[cfg(test)]
+mod test_dns {
+    use super::{Question, DnsCache, parse_question, message_id, cache_ttl, in_domains};
+
+    // Response of `Example.COM A` with an answer of TTL 300 and an EDNS record
+    fn response() -> Vec<u8> {
+        let mut msg = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 1];
+        msg.push_all(b"\x07Example\x03COM\x00");
+        msg.push_all(&[0, 1, 0, 1]);
+        // The name is a pointer to the question
+        msg.push_all(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0x01, 0x2c, 0, 4, 93, 184, 216, 34]);
+        msg.push_all(&[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 0]);
+        msg
+    }
+
+    fn question() -> Question {
+        Question {
+            name: "example.com".to_string(),
+            qtype: 1,
+            qclass: 1,
+        }
+    }
+
+    #[test]
+    fn test_parse_question() {
+        assert_eq!(parse_question(response().as_slice()), Some(question()));
+        assert_eq!(parse_question(&response()[..20]), None);
+
+        // Pointers to themselves
+        let mut msg = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        msg.push_all(&[0xc0, 12, 0, 1, 0, 1]);
+        assert_eq!(parse_question(msg.as_slice()), None);
+    }
+
+    #[test]
+    fn test_cache_ttl() {
+        let mut msg = response();
+        assert_eq!(cache_ttl(msg.as_slice()), Some(300));
+
+        // SERVFAIL
+        msg[3] = 0x82;
+        assert_eq!(cache_ttl(msg.as_slice()), None);
+
+        // Truncated
+        let mut msg = response();
+        msg[2] |= 0x02;
+        assert_eq!(cache_ttl(msg.as_slice()), None);
+    }
+
+    #[test]
+    fn test_cache() {
+        let mut cache = DnsCache::new(16);
+        cache.insert(question(), response().as_slice(), 1000);
+
+        let msg = cache.get(&question(), 0xabcd, 11500).unwrap();
+        assert_eq!(message_id(msg.as_slice()), Some(0xabcd));
+        assert_eq!(cache_ttl(msg.as_slice()), Some(290));
+
+        assert!(cache.get(&question(), 0, 301000).is_none());
+        assert!(cache.get(&question(), 0, 1000).is_none());
+    }
+
+    #[test]
+    fn test_in_domains() {
+        let domains = vec!["lan".to_string(), "example.com".to_string()];
+        assert!(in_domains("lan", domains.as_slice()));
+        assert!(in_domains("nas.lan", domains.as_slice()));
+        assert!(in_domains("www.example.com", domains.as_slice()));
+        assert!(!in_domains("badexample.com", domains.as_slice()));
+        assert!(!in_domains("example.org", domains.as_slice()));
+    }
+}
@@ -0,0 +1,489 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! Readiness-based event loop on top of Linux `epoll`
+//!
+//! There is no implementation for other systems, building relays for them fails.
+//!
+//! Sockets in `net` are non-blocking, they are registered into an `EventLoop` with a `Token`,
+//! and the `Handler` of the loop is called whenever any of them becomes readable or writable.
+//! One loop runs in one thread and handles any number of connections, relays run a small pool
+//! of loops instead of a thread for every connection.
+//!
+//! Other threads talk to a loop with `Notifier`, which wakes the loop up and delivers a message
+//! to `Handler::notify`. This is how results of blocking jobs (such as DNS lookups) get back.
+
+extern crate libc;
+
+use std::io::{IoResult, IoError};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Sender, Receiver};
+use std::collections::BTreeMap;
+use std::usize;
+
+use time;
+
+pub use self::net::{TcpListener, TcpStream, UdpSocket, UnixDatagram};
+
+pub mod net;
+
+// epoll is not in libc, its values are the same on all architectures
+mod ffi {
+    extern crate libc;
+
+    use std::os;
+
+    pub use self::libc::{EINTR, EAGAIN, O_NONBLOCK};
+
+    pub const EPOLLIN: u32 = 0x001;
+    pub const EPOLLOUT: u32 = 0x004;
+    pub const EPOLLERR: u32 = 0x008;
+    pub const EPOLLHUP: u32 = 0x010;
+    pub const EPOLLRDHUP: u32 = 0x2000;
+
+    pub const EPOLL_CTL_ADD: libc::c_int = 1;
+    pub const EPOLL_CTL_DEL: libc::c_int = 2;
+    pub const EPOLL_CTL_MOD: libc::c_int = 3;
+
+    // `O_CLOEXEC`, which is not defined by libc either. It only differs on Alpha, PA-RISC and SPARC,
+    // which Rust doesn't support.
+    pub const O_CLOEXEC: libc::c_int = 0o2000000;
+    pub const EPOLL_CLOEXEC: libc::c_int = O_CLOEXEC;
+
+    // The kernel packs the struct on x86_64 only, so do glibc and musl
+    #[repr(C)]
+    #[cfg_attr(target_arch = "x86_64", repr(packed))]
+    #[derive(Copy)]
+    pub struct epoll_event {
+        pub events: u32,
+        pub data: u64,
+    }
+
+    extern {
+        pub fn epoll_create1(flags: libc::c_int) -> libc::c_int;
+        pub fn epoll_ctl(epfd: libc::c_int, op: libc::c_int, fd: libc::c_int, event: *mut epoll_event)
+            -> libc::c_int;
+        pub fn epoll_wait(epfd: libc::c_int, events: *mut epoll_event, maxevents: libc::c_int,
+                          timeout: libc::c_int) -> libc::c_int;
+
+        pub fn pipe2(fds: *mut libc::c_int, flags: libc::c_int) -> libc::c_int;
+        pub fn read(fd: libc::c_int, buf: *mut libc::c_void, count: libc::size_t) -> libc::ssize_t;
+        pub fn write(fd: libc::c_int, buf: *const libc::c_void, count: libc::size_t) -> libc::ssize_t;
+        pub fn close(fd: libc::c_int) -> libc::c_int;
+    }
+
+    pub fn errno() -> libc::c_int {
+        os::errno() as libc::c_int
+    }
+}
+
+/// Maximum number of events handled in one round
+const MAX_EVENTS: usize = 1024;
+
+/// Token of the wake up pipe, handlers should not use it
+pub const NOTIFY_TOKEN: Token = Token(usize::MAX);
+
+/// Identifies a registered socket in callbacks of `Handler`
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, PartialOrd, Ord)]
+pub struct Token(pub usize);
+
+/// What a registered socket is waiting for
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Interest {
+    pub readable: bool,
+    pub writable: bool,
+}
+
+impl Interest {
+    pub fn none() -> Interest {
+        Interest { readable: false, writable: false }
+    }
+
+    pub fn readable() -> Interest {
+        Interest { readable: true, writable: false }
+    }
+
+    fn bits(&self) -> u32 {
+        let mut bits = 0;
+        if self.readable {
+            bits |= ffi::EPOLLIN | ffi::EPOLLRDHUP;
+        }
+        if self.writable {
+            bits |= ffi::EPOLLOUT;
+        }
+        bits
+    }
+}
+
+/// Readiness of a registered socket
+///
+/// `hangup` and `error` are always reported regardless of the interest, until the socket
+/// is closed or deregistered.
+#[derive(Copy, Clone, Debug)]
+pub struct Ready {
+    /// There is data to read, or the peer has sent EOF
+    pub readable: bool,
+    pub writable: bool,
+    /// Both directions of the connection have been shut down, nothing could be written anymore
+    pub hangup: bool,
+    /// An error is pending on the socket, it will be returned by the next read or write
+    pub error: bool,
+}
+
+impl Ready {
+    fn from_bits(bits: u32) -> Ready {
+        Ready {
+            readable: bits & (ffi::EPOLLIN | ffi::EPOLLRDHUP) != 0,
+            writable: bits & ffi::EPOLLOUT != 0,
+            hangup: bits & ffi::EPOLLHUP != 0,
+            error: bits & ffi::EPOLLERR != 0,
+        }
+    }
+}
+
+/// Anything that could be registered into an `EventLoop`
+pub trait Evented {
+    fn as_raw_fd(&self) -> libc::c_int;
+}
+
+/// Callbacks of an `EventLoop`, all of them are called in the thread running the loop
+pub trait Handler: Sized {
+    /// Messages sent with `Notifier`
+    type Message: Send;
+
+    /// A registered socket becomes ready
+    fn ready(&mut self, event_loop: &mut EventLoop<Self>, token: Token, ready: Ready);
+
+    /// A message sent by another thread
+    #[allow(unused_variables)]
+    fn notify(&mut self, event_loop: &mut EventLoop<Self>, msg: Self::Message) {}
+
+    /// A timeout set by `EventLoop::timeout_ms` has expired
+    #[allow(unused_variables)]
+    fn timeout(&mut self, event_loop: &mut EventLoop<Self>, token: Token) {}
+}
+
+/// Identifies a pending timeout, for cancelling it
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Timeout {
+    deadline: u64,
+    seq: u64,
+}
+
+// Write end of the wake up pipe, closed when the last `Notifier` is dropped
+struct Waker {
+    fd: libc::c_int,
+}
+
+impl Waker {
+    fn wakeup(&self) {
+        let b = 1u8;
+        // The pipe is non-blocking, if it is full the loop is going to wake up anyway
+        unsafe {
+            ffi::write(self.fd, &b as *const u8 as *const libc::c_void, 1);
+        }
+    }
+}
+
+impl Drop for Waker {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::close(self.fd);
+        }
+    }
+}
+
+/// Sends messages to an `EventLoop` from any thread
+pub struct Notifier<M: Send> {
+    sender: Sender<M>,
+    waker: Arc<Waker>,
+}
+
+impl<M: Send> Notifier<M> {
+    /// Sends `msg` to the loop, returns it back if the loop has gone
+    pub fn notify(&self, msg: M) -> Result<(), M> {
+        match self.sender.send(msg) {
+            Ok(..) => {
+                self.waker.wakeup();
+                Ok(())
+            },
+            Err(err) => Err(err.0),
+        }
+    }
+}
+
+impl<M: Send> Clone for Notifier<M> {
+    fn clone(&self) -> Notifier<M> {
+        Notifier {
+            sender: self.sender.clone(),
+            waker: self.waker.clone(),
+        }
+    }
+}
+
+unsafe impl<M: Send> Send for Notifier<M> {}
+unsafe impl Send for Waker {}
+unsafe impl Sync for Waker {}
+
+/// Milliseconds from an arbitrary point, only for measuring intervals
+pub fn now_ms() -> u64 {
+    time::precise_time_ns() / 1000000
+}
+
+pub struct EventLoop<H: Handler> {
+    epfd: libc::c_int,
+    running: bool,
+    timers: BTreeMap<Timeout, Token>,
+    timer_seq: u64,
+    wakeup_fd: libc::c_int,
+    notifier: Notifier<H::Message>,
+    receiver: Receiver<H::Message>,
+}
+
+impl<H: Handler> EventLoop<H> {
+    pub fn new() -> IoResult<EventLoop<H>> {
+        let epfd = unsafe { ffi::epoll_create1(ffi::EPOLL_CLOEXEC) };
+        if epfd < 0 {
+            return Err(IoError::last_error());
+        }
+
+        let mut fds = [0 as libc::c_int; 2];
+        if unsafe { ffi::pipe2(fds.as_mut_ptr(), ffi::O_NONBLOCK | ffi::O_CLOEXEC) } < 0 {
+            let err = IoError::last_error();
+            unsafe { ffi::close(epfd); }
+            return Err(err);
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let event_loop = EventLoop {
+            epfd: epfd,
+            running: false,
+            timers: BTreeMap::new(),
+            timer_seq: 0,
+            wakeup_fd: fds[0],
+            notifier: Notifier {
+                sender: tx,
+                waker: Arc::new(Waker { fd: fds[1] }),
+            },
+            receiver: rx,
+        };
+
+        try!(event_loop.ctl(ffi::EPOLL_CTL_ADD, fds[0], NOTIFY_TOKEN, Interest::readable()));
+        Ok(event_loop)
+    }
+
+    fn ctl(&self, op: libc::c_int, fd: libc::c_int, token: Token, interest: Interest) -> IoResult<()> {
+        let mut event = ffi::epoll_event {
+            events: interest.bits(),
+            data: token.0 as u64,
+        };
+
+        if unsafe { ffi::epoll_ctl(self.epfd, op, fd, &mut event) } < 0 {
+            return Err(IoError::last_error());
+        }
+        Ok(())
+    }
+
+    /// Starts watching `io` for `interest`, level triggered
+    pub fn register<E: Evented>(&mut self, io: &E, token: Token, interest: Interest) -> IoResult<()> {
+        self.ctl(ffi::EPOLL_CTL_ADD, io.as_raw_fd(), token, interest)
+    }
+
+    pub fn reregister<E: Evented>(&mut self, io: &E, token: Token, interest: Interest) -> IoResult<()> {
+        self.ctl(ffi::EPOLL_CTL_MOD, io.as_raw_fd(), token, interest)
+    }
+
+    /// Stops watching `io`, closing a socket deregisters it automatically
+    pub fn deregister<E: Evented>(&mut self, io: &E) -> IoResult<()> {
+        self.ctl(ffi::EPOLL_CTL_DEL, io.as_raw_fd(), Token(0), Interest::none())
+    }
+
+    /// Calls `Handler::timeout` with `token` after `delay` milliseconds
+    pub fn timeout_ms(&mut self, token: Token, delay: u64) -> Timeout {
+        self.timer_seq += 1;
+        let timeout = Timeout {
+            deadline: now_ms() + delay,
+            seq: self.timer_seq,
+        };
+        self.timers.insert(timeout, token);
+        timeout
+    }
+
+    /// Cancels a pending timeout, returns `false` if it has already expired
+    pub fn clear_timeout(&mut self, timeout: Timeout) -> bool {
+        self.timers.remove(&timeout).is_some()
+    }
+
+    /// A sender for delivering messages to this loop from other threads
+    pub fn notifier(&self) -> Notifier<H::Message> {
+        self.notifier.clone()
+    }
+
+    /// Stops the loop after the current round
+    pub fn shutdown(&mut self) {
+        self.running = false;
+    }
+
+    // Milliseconds to wait for, -1 for infinity
+    fn poll_timeout(&self) -> libc::c_int {
+        match self.timers.keys().next() {
+            Some(t) => {
+                let now = now_ms();
+                if t.deadline <= now { 0 } else { (t.deadline - now) as libc::c_int }
+            },
+            None => -1,
+        }
+    }
+
+    fn drain_wakeup(&mut self) {
+        let mut buf = [0u8; 128];
+        loop {
+            let n = unsafe {
+                ffi::read(self.wakeup_fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len() as libc::size_t)
+            };
+            if n < buf.len() as libc::ssize_t {
+                break;
+            }
+        }
+    }
+
+    fn fire_timers(&mut self, handler: &mut H) {
+        let now = now_ms();
+        loop {
+            let expired = match self.timers.keys().next() {
+                Some(t) if t.deadline <= now => *t,
+                _ => break,
+            };
+
+            let token = self.timers.remove(&expired).unwrap();
+            handler.timeout(self, token);
+        }
+    }
+
+    /// Runs the loop until `shutdown` is called
+    pub fn run(&mut self, handler: &mut H) -> IoResult<()> {
+        let mut events: Vec<ffi::epoll_event> = range(0, MAX_EVENTS).map(|_| ffi::epoll_event {
+            events: 0,
+            data: 0,
+        }).collect();
+
+        self.running = true;
+        while self.running {
+            let n = unsafe {
+                ffi::epoll_wait(self.epfd, events.as_mut_ptr(), MAX_EVENTS as libc::c_int, self.poll_timeout())
+            };
+
+            if n < 0 {
+                if ffi::errno() == ffi::EINTR {
+                    continue;
+                }
+                return Err(IoError::last_error());
+            }
+
+            for ev in events[..n as usize].iter() {
+                let token = Token(ev.data as usize);
+                let bits = ev.events;
+
+                if token == NOTIFY_TOKEN {
+                    self.drain_wakeup();
+                    while let Ok(msg) = self.receiver.try_recv() {
+                        handler.notify(self, msg);
+                    }
+                } else {
+                    handler.ready(self, token, Ready::from_bits(bits));
+                }
+            }
+
+            self.fire_timers(handler);
+        }
+
+        Ok(())
+    }
+}
+
+#[unsafe_destructor]
+impl<H: Handler> Drop for EventLoop<H> {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::close(self.wakeup_fd);
+            ffi::close(self.epfd);
+        }
+    }
+}
+
+/// Returns `true` if the last error of a non-blocking operation means "try again later"
+pub fn would_block() -> bool {
+    let errno = ffi::errno();
+    errno == ffi::EAGAIN || errno == ffi::EINTR
+}
+
+#[cfg(test)]
+mod test_eventloop {
+    use std::thread::Thread;
+
+    use relay::eventloop::{EventLoop, Handler, Token, Ready};
+
+    struct Counter {
+        messages: usize,
+        timeouts: Vec<Token>,
+    }
+
+    impl Handler for Counter {
+        type Message = usize;
+
+        fn ready(&mut self, _: &mut EventLoop<Counter>, _: Token, _: Ready) {}
+
+        fn notify(&mut self, event_loop: &mut EventLoop<Counter>, msg: usize) {
+            self.messages += msg;
+            if self.messages == 10 {
+                event_loop.timeout_ms(Token(2), 20);
+                let cancelled = event_loop.timeout_ms(Token(3), 10);
+                event_loop.clear_timeout(cancelled);
+                event_loop.timeout_ms(Token(1), 10);
+            }
+        }
+
+        fn timeout(&mut self, event_loop: &mut EventLoop<Counter>, token: Token) {
+            self.timeouts.push(token);
+            if self.timeouts.len() == 2 {
+                event_loop.shutdown();
+            }
+        }
+    }
+
+    #[test]
+    fn test_notify_and_timeout() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let notifier = event_loop.notifier();
+        Thread::spawn(move || {
+            for i in range(0us, 5) {
+                notifier.notify(i).ok().unwrap();
+            }
+        });
+
+        let mut counter = Counter { messages: 0, timeouts: Vec::new() };
+        event_loop.run(&mut counter).unwrap();
+
+        assert_eq!(counter.messages, 10);
+        assert_eq!(counter.timeouts, vec![Token(1), Token(2)]);
+    }
+}
@@ -0,0 +1,707 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! Non-blocking sockets for `EventLoop`
+//!
+//! Reads and writes return `Ok(None)` if the operation would block, the caller should
+//! wait for the next readiness event of the socket and try again.
+
+extern crate libc;
+
+use std::io::{IoResult, IoError};
+use std::io::net::ip::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::mem;
+use std::num::Int;
+
+use relay::eventloop::{Evented, would_block};
+
+mod ffi {
+    extern crate libc;
+
+    pub use self::libc::{AF_UNIX, AF_INET, AF_INET6, SOCK_STREAM, SOCK_DGRAM};
+    pub use self::libc::{SOL_SOCKET, SO_REUSEADDR, SO_ERROR, IPPROTO_TCP, TCP_NODELAY, SHUT_RD, SHUT_WR};
+    pub use self::libc::EINPROGRESS;
+    pub use relay::eventloop::ffi::O_CLOEXEC;
+
+    // Flags of the type of sockets, which have the values of the flags of files on Linux
+    pub const SOCK_NONBLOCK: libc::c_int = self::libc::O_NONBLOCK;
+    pub const SOCK_CLOEXEC: libc::c_int = O_CLOEXEC;
+
+    // Values which libc doesn't define
+    pub const SOL_IP: libc::c_int = 0;
+    pub const SOL_IPV6: libc::c_int = 41;
+    // Original destination of connections redirected by netfilter
+    pub const SO_ORIGINAL_DST: libc::c_int = 80;
+    pub const IP6T_SO_ORIGINAL_DST: libc::c_int = 80;
+    // TPROXY, the control message of the original destination has the same type as the option
+    pub const IP_TRANSPARENT: libc::c_int = 19;
+    pub const IP_RECVORIGDSTADDR: libc::c_int = 20;
+    pub const IPV6_RECVORIGDSTADDR: libc::c_int = 74;
+    pub const IPV6_TRANSPARENT: libc::c_int = 75;
+
+    pub const MSG_NOSIGNAL: libc::c_int = 0x4000;
+
+    pub type socklen_t = u32;
+
+    #[repr(C)]
+    #[derive(Copy)]
+    pub struct sockaddr_in {
+        pub sin_family: u16,
+        pub sin_port: u16,
+        pub sin_addr: [u8; 4],
+        pub sin_zero: [u8; 8],
+    }
+
+    #[repr(C)]
+    #[derive(Copy)]
+    pub struct sockaddr_in6 {
+        pub sin6_family: u16,
+        pub sin6_port: u16,
+        pub sin6_flowinfo: u32,
+        pub sin6_addr: [u8; 16],
+        pub sin6_scope_id: u32,
+    }
+
+    #[repr(C)]
+    pub struct sockaddr_un {
+        pub sun_family: u16,
+        pub sun_path: [u8; 108],
+    }
+
+    // Large enough for both of the above
+    #[repr(C)]
+    #[derive(Copy)]
+    pub struct sockaddr_storage {
+        pub ss_family: u16,
+        pub __ss_pad: [u64; 15],
+    }
+
+    #[repr(C)]
+    pub struct iovec {
+        pub iov_base: *mut libc::c_void,
+        pub iov_len: libc::size_t,
+    }
+
+    #[repr(C)]
+    pub struct msghdr {
+        pub msg_name: *mut libc::c_void,
+        pub msg_namelen: socklen_t,
+        pub msg_iov: *mut iovec,
+        pub msg_iovlen: libc::size_t,
+        pub msg_control: *mut libc::c_void,
+        pub msg_controllen: libc::size_t,
+        pub msg_flags: libc::c_int,
+    }
+
+    #[repr(C)]
+    pub struct cmsghdr {
+        pub cmsg_len: libc::size_t,
+        pub cmsg_level: libc::c_int,
+        pub cmsg_type: libc::c_int,
+    }
+
+    extern {
+        pub fn socket(domain: libc::c_int, ty: libc::c_int, protocol: libc::c_int) -> libc::c_int;
+        pub fn bind(fd: libc::c_int, addr: *const libc::c_void, len: socklen_t) -> libc::c_int;
+        pub fn listen(fd: libc::c_int, backlog: libc::c_int) -> libc::c_int;
+        pub fn accept4(fd: libc::c_int, addr: *mut libc::c_void, len: *mut socklen_t, flags: libc::c_int)
+            -> libc::c_int;
+        pub fn connect(fd: libc::c_int, addr: *const libc::c_void, len: socklen_t) -> libc::c_int;
+        pub fn getsockopt(fd: libc::c_int, level: libc::c_int, name: libc::c_int,
+                          val: *mut libc::c_void, len: *mut socklen_t) -> libc::c_int;
+        pub fn setsockopt(fd: libc::c_int, level: libc::c_int, name: libc::c_int,
+                          val: *const libc::c_void, len: socklen_t) -> libc::c_int;
+        pub fn getsockname(fd: libc::c_int, addr: *mut libc::c_void, len: *mut socklen_t) -> libc::c_int;
+        pub fn getpeername(fd: libc::c_int, addr: *mut libc::c_void, len: *mut socklen_t) -> libc::c_int;
+        pub fn recv(fd: libc::c_int, buf: *mut libc::c_void, len: libc::size_t, flags: libc::c_int)
+            -> libc::ssize_t;
+        pub fn send(fd: libc::c_int, buf: *const libc::c_void, len: libc::size_t, flags: libc::c_int)
+            -> libc::ssize_t;
+        pub fn recvfrom(fd: libc::c_int, buf: *mut libc::c_void, len: libc::size_t, flags: libc::c_int,
+                        addr: *mut libc::c_void, addrlen: *mut socklen_t) -> libc::ssize_t;
+        pub fn sendto(fd: libc::c_int, buf: *const libc::c_void, len: libc::size_t, flags: libc::c_int,
+                      addr: *const libc::c_void, addrlen: socklen_t) -> libc::ssize_t;
+        pub fn recvmsg(fd: libc::c_int, msg: *mut msghdr, flags: libc::c_int) -> libc::ssize_t;
+        pub fn shutdown(fd: libc::c_int, how: libc::c_int) -> libc::c_int;
+        pub fn close(fd: libc::c_int) -> libc::c_int;
+    }
+}
+
+fn to_sockaddr(addr: &SocketAddr) -> (ffi::sockaddr_storage, ffi::socklen_t) {
+    let mut storage: ffi::sockaddr_storage = unsafe { mem::zeroed() };
+    let port = addr.port.to_be();
+    let len = match addr.ip {
+        Ipv4Addr(a, b, c, d) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut ffi::sockaddr_in) };
+            sin.sin_family = ffi::AF_INET as u16;
+            sin.sin_port = port;
+            sin.sin_addr = [a, b, c, d];
+            mem::size_of::<ffi::sockaddr_in>()
+        },
+        Ipv6Addr(a, b, c, d, e, f, g, h) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut ffi::sockaddr_in6) };
+            sin6.sin6_family = ffi::AF_INET6 as u16;
+            sin6.sin6_port = port;
+            for (i, seg) in [a, b, c, d, e, f, g, h].iter().enumerate() {
+                sin6.sin6_addr[i * 2] = (*seg >> 8) as u8;
+                sin6.sin6_addr[i * 2 + 1] = *seg as u8;
+            }
+            mem::size_of::<ffi::sockaddr_in6>()
+        }
+    };
+    (storage, len as ffi::socklen_t)
+}
+
+fn from_sockaddr(storage: &ffi::sockaddr_storage) -> IoResult<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        ffi::AF_INET => {
+            let sin = unsafe { &*(storage as *const _ as *const ffi::sockaddr_in) };
+            let a = sin.sin_addr;
+            Ok(SocketAddr {
+                ip: Ipv4Addr(a[0], a[1], a[2], a[3]),
+                port: Int::from_be(sin.sin_port),
+            })
+        },
+        ffi::AF_INET6 => {
+            let sin6 = unsafe { &*(storage as *const _ as *const ffi::sockaddr_in6) };
+            let a = sin6.sin6_addr;
+            let seg = |&: i: usize| ((a[i * 2] as u16) << 8) | a[i * 2 + 1] as u16;
+            Ok(SocketAddr {
+                ip: Ipv6Addr(seg(0), seg(1), seg(2), seg(3), seg(4), seg(5), seg(6), seg(7)),
+                port: Int::from_be(sin6.sin6_port),
+            })
+        },
+        _ => Err(IoError {
+            kind: ::std::io::OtherIoError,
+            desc: "Unsupported address family",
+            detail: None,
+        })
+    }
+}
+
+fn to_sockaddr_un(path: &Path) -> IoResult<(ffi::sockaddr_un, ffi::socklen_t)> {
+    let bytes = path.as_vec();
+    let mut addr: ffi::sockaddr_un = unsafe { mem::zeroed() };
+    // Leaves room for the terminating NUL
+    if bytes.len() >= addr.sun_path.len() {
+        return Err(IoError {
+            kind: ::std::io::InvalidInput,
+            desc: "Path of Unix socket is too long",
+            detail: Some(path.display().to_string()),
+        });
+    }
+
+    addr.sun_family = ffi::AF_UNIX as u16;
+    for (dst, src) in addr.sun_path.iter_mut().zip(bytes.iter()) {
+        *dst = *src;
+    }
+    Ok((addr, (mem::size_of::<u16>() + bytes.len() + 1) as ffi::socklen_t))
+}
+
+// Path of a Unix socket, `None` for unnamed and abstract sockets
+fn from_sockaddr_un(addr: &ffi::sockaddr_un, len: ffi::socklen_t) -> Option<Path> {
+    let len = len as usize;
+    if len <= mem::size_of::<u16>() {
+        return None;
+    }
+
+    let path = &addr.sun_path[..::std::cmp::min(len - mem::size_of::<u16>(), addr.sun_path.len())];
+    let path = match path.iter().position(|b| *b == 0) {
+        Some(pos) => &path[..pos],
+        None => path,
+    };
+    if path.is_empty() { None } else { Some(Path::new(path)) }
+}
+
+fn family_of(ip: &IpAddr) -> libc::c_int {
+    match *ip {
+        Ipv4Addr(..) => ffi::AF_INET,
+        Ipv6Addr(..) => ffi::AF_INET6,
+    }
+}
+
+// Owns a socket descriptor and closes it on drop
+struct Socket {
+    fd: libc::c_int,
+}
+
+impl Socket {
+    fn new(family: libc::c_int, ty: libc::c_int) -> IoResult<Socket> {
+        let fd = unsafe { ffi::socket(family, ty | ffi::SOCK_NONBLOCK | ffi::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(IoError::last_error());
+        }
+        Ok(Socket { fd: fd })
+    }
+
+    fn set_int_opt(&self, level: libc::c_int, name: libc::c_int, val: libc::c_int) -> IoResult<()> {
+        let ret = unsafe {
+            ffi::setsockopt(self.fd, level, name,
+                            &val as *const _ as *const libc::c_void,
+                            mem::size_of::<libc::c_int>() as ffi::socklen_t)
+        };
+        if ret < 0 {
+            return Err(IoError::last_error());
+        }
+        Ok(())
+    }
+
+    fn bind(&self, addr: &SocketAddr) -> IoResult<()> {
+        let (storage, len) = to_sockaddr(addr);
+        if unsafe { ffi::bind(self.fd, &storage as *const _ as *const libc::c_void, len) } < 0 {
+            return Err(IoError::last_error());
+        }
+        Ok(())
+    }
+
+    fn name(&self, peer: bool) -> IoResult<SocketAddr> {
+        let mut storage: ffi::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<ffi::sockaddr_storage>() as ffi::socklen_t;
+        let ret = unsafe {
+            let ptr = &mut storage as *mut _ as *mut libc::c_void;
+            if peer {
+                ffi::getpeername(self.fd, ptr, &mut len)
+            } else {
+                ffi::getsockname(self.fd, ptr, &mut len)
+            }
+        };
+        if ret < 0 {
+            return Err(IoError::last_error());
+        }
+        from_sockaddr(&storage)
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::close(self.fd);
+        }
+    }
+}
+
+/// Non-blocking TCP listening socket
+pub struct TcpListener {
+    sock: Socket,
+}
+
+impl TcpListener {
+    pub fn bind(addr: &SocketAddr) -> IoResult<TcpListener> {
+        let sock = try!(Socket::new(family_of(&addr.ip), ffi::SOCK_STREAM));
+        try!(sock.set_int_opt(ffi::SOL_SOCKET, ffi::SO_REUSEADDR, 1));
+        try!(sock.bind(addr));
+        if unsafe { ffi::listen(sock.fd, 1024) } < 0 {
+            return Err(IoError::last_error());
+        }
+        Ok(TcpListener { sock: sock })
+    }
+
+    /// Accepts a pending connection, `Ok(None)` if there is none
+    pub fn accept(&self) -> IoResult<Option<(TcpStream, SocketAddr)>> {
+        let mut storage: ffi::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<ffi::sockaddr_storage>() as ffi::socklen_t;
+        let fd = unsafe {
+            ffi::accept4(self.sock.fd, &mut storage as *mut _ as *mut libc::c_void, &mut len,
+                         ffi::SOCK_NONBLOCK | ffi::SOCK_CLOEXEC)
+        };
+        if fd < 0 {
+            if would_block() {
+                return Ok(None);
+            }
+            return Err(IoError::last_error());
+        }
+
+        let stream = TcpStream { sock: Socket { fd: fd } };
+        let _ = stream.sock.set_int_opt(ffi::IPPROTO_TCP, ffi::TCP_NODELAY, 1);
+        Ok(Some((stream, try!(from_sockaddr(&storage)))))
+    }
+
+    /// Stops listening, pending and new connections are refused. The socket is still open until
+    /// it is dropped.
+    pub fn stop_listening(&self) -> IoResult<()> {
+        if unsafe { ffi::shutdown(self.sock.fd, ffi::SHUT_RD) } < 0 {
+            return Err(IoError::last_error());
+        }
+        Ok(())
+    }
+}
+
+impl Evented for TcpListener {
+    fn as_raw_fd(&self) -> libc::c_int {
+        self.sock.fd
+    }
+}
+
+/// Non-blocking TCP stream
+pub struct TcpStream {
+    sock: Socket,
+}
+
+impl TcpStream {
+    /// Starts connecting to `addr`, the stream becomes writable when the connection is established
+    /// or has failed, check the result with `take_socket_error`
+    pub fn connect(addr: &SocketAddr) -> IoResult<TcpStream> {
+        let sock = try!(Socket::new(family_of(&addr.ip), ffi::SOCK_STREAM));
+        let _ = sock.set_int_opt(ffi::IPPROTO_TCP, ffi::TCP_NODELAY, 1);
+        let (storage, len) = to_sockaddr(addr);
+        let ret = unsafe { ffi::connect(sock.fd, &storage as *const _ as *const libc::c_void, len) };
+        if ret < 0 && super::ffi::errno() != ffi::EINPROGRESS {
+            return Err(IoError::last_error());
+        }
+        Ok(TcpStream { sock: sock })
+    }
+
+    /// Returns the pending error of the socket, used for finishing `connect`
+    pub fn take_socket_error(&self) -> IoResult<()> {
+        let mut val: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as ffi::socklen_t;
+        let ret = unsafe {
+            ffi::getsockopt(self.sock.fd, ffi::SOL_SOCKET, ffi::SO_ERROR,
+                            &mut val as *mut _ as *mut libc::c_void, &mut len)
+        };
+        if ret < 0 {
+            return Err(IoError::last_error());
+        }
+        if val != 0 {
+            return Err(IoError::from_errno(val as usize, true));
+        }
+        Ok(())
+    }
+
+    /// Reads into `buf`, `Ok(Some(0))` means EOF
+    pub fn read(&self, buf: &mut [u8]) -> IoResult<Option<usize>> {
+        let n = unsafe {
+            ffi::recv(self.sock.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len() as libc::size_t, 0)
+        };
+        if n < 0 {
+            if would_block() {
+                return Ok(None);
+            }
+            return Err(IoError::last_error());
+        }
+        Ok(Some(n as usize))
+    }
+
+    /// Writes part of `buf`, returns how many bytes have been written
+    pub fn write(&self, buf: &[u8]) -> IoResult<Option<usize>> {
+        let n = unsafe {
+            ffi::send(self.sock.fd, buf.as_ptr() as *const libc::c_void, buf.len() as libc::size_t,
+                      ffi::MSG_NOSIGNAL)
+        };
+        if n < 0 {
+            if would_block() {
+                return Ok(None);
+            }
+            return Err(IoError::last_error());
+        }
+        Ok(Some(n as usize))
+    }
+
+    /// Sends FIN to the peer
+    pub fn shutdown_write(&self) -> IoResult<()> {
+        if unsafe { ffi::shutdown(self.sock.fd, ffi::SHUT_WR) } < 0 {
+            return Err(IoError::last_error());
+        }
+        Ok(())
+    }
+
+    pub fn peer_name(&self) -> IoResult<SocketAddr> {
+        self.sock.name(true)
+    }
+
+    /// Original destination of a connection redirected by `REDIRECT` of iptables or nftables
+    pub fn original_dst(&self) -> IoResult<SocketAddr> {
+        let local = try!(self.socket_name());
+        let (level, name) = match local.ip {
+            Ipv4Addr(..) => (ffi::SOL_IP, ffi::SO_ORIGINAL_DST),
+            Ipv6Addr(..) => (ffi::SOL_IPV6, ffi::IP6T_SO_ORIGINAL_DST),
+        };
+
+        let mut storage: ffi::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<ffi::sockaddr_storage>() as ffi::socklen_t;
+        let ret = unsafe {
+            ffi::getsockopt(self.sock.fd, level, name, &mut storage as *mut _ as *mut libc::c_void, &mut len)
+        };
+        if ret < 0 {
+            return Err(IoError::last_error());
+        }
+        from_sockaddr(&storage)
+    }
+
+    pub fn socket_name(&self) -> IoResult<SocketAddr> {
+        self.sock.name(false)
+    }
+}
+
+impl Evented for TcpStream {
+    fn as_raw_fd(&self) -> libc::c_int {
+        self.sock.fd
+    }
+}
+
+/// Non-blocking UDP socket
+pub struct UdpSocket {
+    sock: Socket,
+}
+
+impl UdpSocket {
+    pub fn bind(addr: &SocketAddr) -> IoResult<UdpSocket> {
+        let sock = try!(Socket::new(family_of(&addr.ip), ffi::SOCK_DGRAM));
+        try!(sock.bind(addr));
+        Ok(UdpSocket { sock: sock })
+    }
+
+    /// Binds a socket for `TPROXY`. It could be bound to a non-local address, and tells the
+    /// original destinations of datagrams by `recv_from_to`.
+    pub fn bind_transparent(addr: &SocketAddr) -> IoResult<UdpSocket> {
+        let sock = try!(Socket::new(family_of(&addr.ip), ffi::SOCK_DGRAM));
+        let (level, transparent, recv_dst) = match addr.ip {
+            Ipv4Addr(..) => (ffi::SOL_IP, ffi::IP_TRANSPARENT, ffi::IP_RECVORIGDSTADDR),
+            Ipv6Addr(..) => (ffi::SOL_IPV6, ffi::IPV6_TRANSPARENT, ffi::IPV6_RECVORIGDSTADDR),
+        };
+        try!(sock.set_int_opt(level, transparent, 1));
+        try!(sock.set_int_opt(level, recv_dst, 1));
+        try!(sock.set_int_opt(ffi::SOL_SOCKET, ffi::SO_REUSEADDR, 1));
+        try!(sock.bind(addr));
+        Ok(UdpSocket { sock: sock })
+    }
+
+    /// Receives one datagram with its source and original destination, the destination is
+    /// only known by sockets created with `bind_transparent`
+    pub fn recv_from_to(&self, buf: &mut [u8]) -> IoResult<Option<(usize, SocketAddr, Option<SocketAddr>)>> {
+        let mut storage: ffi::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut iov = ffi::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len() as libc::size_t,
+        };
+        // Aligned for control message headers
+        let mut control = [0u64; 16];
+        let mut msg = ffi::msghdr {
+            msg_name: &mut storage as *mut _ as *mut libc::c_void,
+            msg_namelen: mem::size_of::<ffi::sockaddr_storage>() as ffi::socklen_t,
+            msg_iov: &mut iov,
+            msg_iovlen: 1,
+            msg_control: control.as_mut_ptr() as *mut libc::c_void,
+            msg_controllen: mem::size_of_val(&control) as libc::size_t,
+            msg_flags: 0,
+        };
+
+        let n = unsafe { ffi::recvmsg(self.sock.fd, &mut msg, 0) };
+        if n < 0 {
+            if would_block() {
+                return Ok(None);
+            }
+            return Err(IoError::last_error());
+        }
+
+        let src = try!(from_sockaddr(&storage));
+        let dst = original_dst_of(&control, msg.msg_controllen as usize);
+        Ok(Some((n as usize, src, dst)))
+    }
+
+    /// Receives one datagram, `Ok(None)` if there is none
+    pub fn recv_from(&self, buf: &mut [u8]) -> IoResult<Option<(usize, SocketAddr)>> {
+        let mut storage: ffi::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<ffi::sockaddr_storage>() as ffi::socklen_t;
+        let n = unsafe {
+            ffi::recvfrom(self.sock.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len() as libc::size_t, 0,
+                          &mut storage as *mut _ as *mut libc::c_void, &mut len)
+        };
+        if n < 0 {
+            if would_block() {
+                return Ok(None);
+            }
+            return Err(IoError::last_error());
+        }
+        Ok(Some((n as usize, try!(from_sockaddr(&storage)))))
+    }
+
+    /// Sends one datagram, `Ok(None)` if the send buffer is full and the datagram is dropped
+    pub fn send_to(&self, buf: &[u8], addr: &SocketAddr) -> IoResult<Option<()>> {
+        let (storage, len) = to_sockaddr(addr);
+        let n = unsafe {
+            ffi::sendto(self.sock.fd, buf.as_ptr() as *const libc::c_void, buf.len() as libc::size_t, 0,
+                        &storage as *const _ as *const libc::c_void, len)
+        };
+        if n < 0 {
+            if would_block() {
+                return Ok(None);
+            }
+            return Err(IoError::last_error());
+        }
+        Ok(Some(()))
+    }
+}
+
+/// Non-blocking Unix domain datagram socket
+pub struct UnixDatagram {
+    sock: Socket,
+}
+
+impl UnixDatagram {
+    /// Binds to `path`, which must not exist
+    pub fn bind(path: &Path) -> IoResult<UnixDatagram> {
+        let sock = try!(Socket::new(ffi::AF_UNIX, ffi::SOCK_DGRAM));
+        let (addr, len) = try!(to_sockaddr_un(path));
+        if unsafe { ffi::bind(sock.fd, &addr as *const _ as *const libc::c_void, len) } < 0 {
+            return Err(IoError::last_error());
+        }
+        Ok(UnixDatagram { sock: sock })
+    }
+
+    /// Receives one datagram with the path of its sender, which is `None` if the sender hasn't
+    /// bound its socket. `Ok(None)` if there is none.
+    pub fn recv_from(&self, buf: &mut [u8]) -> IoResult<Option<(usize, Option<Path>)>> {
+        let mut addr: ffi::sockaddr_un = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<ffi::sockaddr_un>() as ffi::socklen_t;
+        let n = unsafe {
+            ffi::recvfrom(self.sock.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len() as libc::size_t, 0,
+                          &mut addr as *mut _ as *mut libc::c_void, &mut len)
+        };
+        if n < 0 {
+            if would_block() {
+                return Ok(None);
+            }
+            return Err(IoError::last_error());
+        }
+        Ok(Some((n as usize, from_sockaddr_un(&addr, len))))
+    }
+
+    /// Sends one datagram, `Ok(None)` if the send buffer is full and the datagram is dropped
+    pub fn send_to(&self, buf: &[u8], path: &Path) -> IoResult<Option<()>> {
+        let (addr, len) = try!(to_sockaddr_un(path));
+        let n = unsafe {
+            ffi::sendto(self.sock.fd, buf.as_ptr() as *const libc::c_void, buf.len() as libc::size_t, 0,
+                        &addr as *const _ as *const libc::c_void, len)
+        };
+        if n < 0 {
+            if would_block() {
+                return Ok(None);
+            }
+            return Err(IoError::last_error());
+        }
+        Ok(Some(()))
+    }
+}
+
+impl Evented for UnixDatagram {
+    fn as_raw_fd(&self) -> libc::c_int {
+        self.sock.fd
+    }
+}
+
+// Finds the original destination in control messages received by `recvmsg`
+fn original_dst_of(control: &[u64], len: usize) -> Option<SocketAddr> {
+    let align = mem::size_of::<libc::size_t>();
+    let header_len = (mem::size_of::<ffi::cmsghdr>() + align - 1) & !(align - 1);
+    let base = control.as_ptr() as *const u8;
+
+    let mut offset = 0;
+    while offset + header_len <= len {
+        let header = unsafe { &*(base.offset(offset as isize) as *const ffi::cmsghdr) };
+        let cmsg_len = header.cmsg_len as usize;
+        if cmsg_len < header_len || offset + cmsg_len > len {
+            break;
+        }
+
+        let is_dst = (header.cmsg_level == ffi::SOL_IP && header.cmsg_type == ffi::IP_RECVORIGDSTADDR)
+            || (header.cmsg_level == ffi::SOL_IPV6 && header.cmsg_type == ffi::IPV6_RECVORIGDSTADDR);
+        if is_dst {
+            let mut storage: ffi::sockaddr_storage = unsafe { mem::zeroed() };
+            let data_len = ::std::cmp::min(cmsg_len - header_len, mem::size_of::<ffi::sockaddr_storage>());
+            unsafe {
+                ::std::ptr::copy_nonoverlapping_memory(&mut storage as *mut _ as *mut u8,
+                                                       base.offset((offset + header_len) as isize),
+                                                       data_len);
+            }
+            return from_sockaddr(&storage).ok();
+        }
+
+        offset += (cmsg_len + align - 1) & !(align - 1);
+    }
+    None
+}
+
+impl Evented for UdpSocket {
+    fn as_raw_fd(&self) -> libc::c_int {
+        self.sock.fd
+    }
+}
+
+#[cfg(test)]
+mod test_net {
+    use std::io::net::ip::{SocketAddr, Ipv4Addr, Ipv6Addr};
+    use std::mem;
+    use std::ptr;
+    use std::iter::repeat;
+
+    use super::{libc, ffi};
+    use super::{to_sockaddr, from_sockaddr, original_dst_of, to_sockaddr_un, from_sockaddr_un};
+
+    #[test]
+    fn test_sockaddr_conversion() {
+        let addrs = [
+            SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 8388 },
+            SocketAddr { ip: Ipv6Addr(0x2001, 0xdb8, 0, 0, 0, 0, 0xff00, 0x42), port: 1080 },
+        ];
+
+        for addr in addrs.iter() {
+            let (storage, _) = to_sockaddr(addr);
+            assert_eq!(from_sockaddr(&storage).unwrap(), *addr);
+        }
+    }
+
+    #[test]
+    fn test_sockaddr_un_conversion() {
+        let path = Path::new("/tmp/shadowsocks-manager.sock");
+        let (addr, len) = to_sockaddr_un(&path).unwrap();
+        assert_eq!(from_sockaddr_un(&addr, len), Some(path));
+
+        // Unnamed sockets of clients
+        assert_eq!(from_sockaddr_un(&addr, 2), None);
+
+        let too_long = Path::new(format!("/tmp/{}", repeat('s').take(120).collect::<String>()));
+        assert!(to_sockaddr_un(&too_long).is_err());
+    }
+
+    #[test]
+    fn test_original_dst_of() {
+        let dst = SocketAddr { ip: Ipv4Addr(10, 1, 2, 3), port: 53 };
+        let (storage, len) = to_sockaddr(&dst);
+        let header_len = mem::size_of::<ffi::cmsghdr>();
+        let msg_len = header_len + len as usize;
+
+        let mut control = [0u64; 16];
+        unsafe {
+            let header = &mut *(control.as_mut_ptr() as *mut ffi::cmsghdr);
+            header.cmsg_len = msg_len as libc::size_t;
+            header.cmsg_level = ffi::SOL_IP;
+            header.cmsg_type = ffi::IP_RECVORIGDSTADDR;
+            ptr::copy_nonoverlapping_memory((control.as_mut_ptr() as *mut u8).offset(header_len as isize),
+                                            &storage as *const _ as *const u8,
+                                            len as usize);
+        }
+
+        assert_eq!(original_dst_of(&control, msg_len), Some(dst));
+        assert_eq!(original_dst_of(&control, 0), None);
+    }
+}
@@ -0,0 +1,563 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! HTTP/1.1 messages for the HTTP proxy
+//!
+//! Only heads of messages are parsed and rewritten. Bodies are passed through as they are,
+//! their boundaries are tracked by `BodyTracker`, so the requests and responses on a
+//! keep-alive connection could be told apart.
+
+use std::io::{IoResult, IoError, OtherIoError};
+use std::io::net::ip::{IpAddr, Port};
+use std::ascii::AsciiExt;
+use std::cmp;
+use std::str;
+
+use serialize::base64::FromBase64;
+
+use relay::socks5::Address;
+
+/// Heads larger than this are rejected
+pub const MAX_HEAD_SIZE: usize = 65536;
+
+const HEAD_END: &'static [u8] = b"\r\n\r\n";
+
+/// Headers which only make sense for a single connection, they are never forwarded.
+///
+/// `Transfer-Encoding` is kept, since bodies are forwarded without being decoded.
+const HOP_BY_HOP_HEADERS: &'static [&'static str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+pub type Headers = Vec<(String, String)>;
+
+#[inline]
+fn make_io_error(desc: &'static str, detail: Option<String>) -> IoError {
+    IoError {
+        kind: OtherIoError,
+        desc: desc,
+        detail: detail,
+    }
+}
+
+/// Returns the length of the head at the beginning of `buf`, including the empty line
+/// which ends it. Returns `None` if the head hasn't been received completely.
+pub fn head_length(buf: &[u8]) -> Option<usize> {
+    buf.windows(HEAD_END.len()).position(|w| w == HEAD_END).map(|pos| pos + HEAD_END.len())
+}
+
+// Splits a head into its start line and headers
+fn parse_head(head: &[u8]) -> IoResult<(&str, Headers)> {
+    let head = match str::from_utf8(head) {
+        Ok(s) => s,
+        Err(..) => return Err(make_io_error("Message head is not valid UTF-8", None)),
+    };
+
+    let mut lines = head.split_str("\r\n");
+    let start_line = lines.next().unwrap_or("");
+
+    let mut headers: Headers = Vec::new();
+    for line in lines {
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.starts_with(" ") || line.starts_with("\t") {
+            // Obsolete line folding
+            match headers.last_mut() {
+                Some(&mut (_, ref mut value)) => {
+                    value.push(' ');
+                    value.push_str(line.trim());
+                },
+                None => return Err(make_io_error("Malformed header line", Some(line.to_string()))),
+            }
+            continue;
+        }
+
+        match line.find(':') {
+            Some(pos) if pos > 0 => {
+                headers.push((line[..pos].to_string(), line[pos + 1..].trim().to_string()));
+            },
+            _ => return Err(make_io_error("Malformed header line", Some(line.to_string()))),
+        }
+    }
+
+    Ok((start_line, headers))
+}
+
+fn write_headers(headers: &Headers, w: &mut Writer) -> IoResult<()> {
+    for &(ref name, ref value) in headers.iter() {
+        try!(write!(w, "{}: {}\r\n", name, value));
+    }
+    w.write_str("\r\n")
+}
+
+/// Value of the first header called `name`
+pub fn get_header<'a>(headers: &'a Headers, name: &str) -> Option<&'a str> {
+    headers.iter()
+           .find(|&&(ref n, _)| n.as_slice().eq_ignore_ascii_case(name))
+           .map(|&(_, ref v)| v.as_slice())
+}
+
+/// Username and password of the Basic scheme in `Proxy-Authorization`
+pub fn proxy_credentials(headers: &Headers) -> Option<(Vec<u8>, Vec<u8>)> {
+    let value = match get_header(headers, "proxy-authorization") {
+        Some(value) => value.trim(),
+        None => return None,
+    };
+    let pos = match value.find(' ') {
+        Some(pos) if value[..pos].eq_ignore_ascii_case("basic") => pos,
+        _ => return None,
+    };
+    let decoded = match value[pos + 1..].trim().from_base64() {
+        Ok(decoded) => decoded,
+        Err(..) => return None,
+    };
+    decoded.iter().position(|b| *b == b':').map(|colon| (decoded[..colon].to_vec(), decoded[colon + 1..].to_vec()))
+}
+
+/// Whether `token` is listed in any header called `name`, such as `Connection: close`
+pub fn has_token(headers: &Headers, name: &str, token: &str) -> bool {
+    headers.iter()
+           .filter(|&&(ref n, _)| n.as_slice().eq_ignore_ascii_case(name))
+           .any(|&(_, ref v)| v.as_slice().split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
+
+/// Removes hop-by-hop headers, including those listed in `Connection`
+pub fn strip_hop_by_hop(headers: &mut Headers) {
+    let mut listed = Vec::new();
+    for &(ref name, ref value) in headers.iter() {
+        if name.as_slice().eq_ignore_ascii_case("connection")
+                || name.as_slice().eq_ignore_ascii_case("proxy-connection") {
+            for t in value.as_slice().split(',') {
+                listed.push(t.trim().to_ascii_lowercase());
+            }
+        }
+    }
+
+    headers.retain(|&(ref name, _)| {
+        let name = name.to_ascii_lowercase();
+        !HOP_BY_HOP_HEADERS.iter().any(|h| *h == name.as_slice()) && !listed.contains(&name)
+    });
+}
+
+/// Replaces all headers called `name` with a single one
+pub fn set_header(headers: &mut Headers, name: &str, value: String) {
+    headers.retain(|&(ref n, _)| !n.as_slice().eq_ignore_ascii_case(name));
+    headers.push((name.to_string(), value));
+}
+
+// Whether the connection would be closed after this message
+fn wants_close(version: &str, headers: &Headers) -> bool {
+    if version == "HTTP/1.0" {
+        !has_token(headers, "connection", "keep-alive") && !has_token(headers, "proxy-connection", "keep-alive")
+    } else {
+        has_token(headers, "connection", "close") || has_token(headers, "proxy-connection", "close")
+    }
+}
+
+// Length of the body declared by headers, `None` if it is delimited by closing the connection
+fn declared_body_length(headers: &Headers) -> IoResult<Option<BodyLength>> {
+    if let Some(te) = get_header(headers, "transfer-encoding") {
+        let chunked = te.split(',').last().map(|t| t.trim().eq_ignore_ascii_case("chunked")).unwrap_or(false);
+        return Ok(if chunked { Some(BodyLength::Chunked) } else { None });
+    }
+
+    match get_header(headers, "content-length") {
+        Some(len) => match len.parse() {
+            Some(len) => Ok(Some(BodyLength::Length(len))),
+            None => Err(make_io_error("Invalid Content-Length", Some(len.to_string()))),
+        },
+        None => Ok(Some(BodyLength::Length(0))),
+    }
+}
+
+/// Parses `host:port`, `port` could be omitted if `default_port` is given
+pub fn parse_authority(authority: &str, default_port: Option<Port>) -> Option<Address> {
+    let (host, port) = if authority.starts_with("[") {
+        // IPv6 literal
+        let end = match authority.find(']') {
+            Some(end) => end,
+            None => return None,
+        };
+        let rest = &authority[end + 1..];
+        let port = if rest.is_empty() {
+            default_port
+        } else if rest.starts_with(":") {
+            rest[1..].parse()
+        } else {
+            None
+        };
+        (&authority[1..end], port)
+    } else {
+        match authority.rfind(':') {
+            Some(pos) => (&authority[..pos], authority[pos + 1..].parse()),
+            None => (authority, default_port),
+        }
+    };
+
+    let port = match port {
+        Some(port) => port,
+        None => return None,
+    };
+    if host.is_empty() || host.contains(":") && !authority.starts_with("[") {
+        return None;
+    }
+
+    match host.parse::<IpAddr>() {
+        Some(ip) => Some(Address::SocketAddress(ip, port)),
+        None => Some(Address::DomainNameAddress(host.to_string(), port)),
+    }
+}
+
+/// Splits an absolute `http` URI into the address of the origin server, the authority and
+/// the path in origin-form
+pub fn parse_absolute_uri(uri: &str) -> Option<(Address, String, String)> {
+    const SCHEME: &'static str = "http://";
+    if uri.len() < SCHEME.len() || !uri[..SCHEME.len()].eq_ignore_ascii_case(SCHEME) {
+        return None;
+    }
+
+    let rest = &uri[SCHEME.len()..];
+    let end = rest.find(|&: c: char| c == '/' || c == '?' || c == '#').unwrap_or(rest.len());
+    let authority = &rest[..end];
+    // User information is never sent to the origin server
+    let authority = match authority.rfind('@') {
+        Some(pos) => &authority[pos + 1..],
+        None => authority,
+    };
+
+    let path = match &rest[end..] {
+        "" => "/".to_string(),
+        p if p.starts_with("/") => p.to_string(),
+        p => format!("/{}", p),
+    };
+    // Fragments are not part of requests
+    let path = match path.find('#') {
+        Some(pos) => path[..pos].to_string(),
+        None => path,
+    };
+
+    parse_authority(authority, Some(80)).map(|addr| (addr, authority.to_string(), path))
+}
+
+/// Head of a request
+#[derive(Clone, Debug)]
+pub struct RequestHead {
+    pub method: String,
+    pub uri: String,
+    pub version: String,
+    pub headers: Headers,
+}
+
+impl RequestHead {
+    /// Parses a head whose length is returned by `head_length`
+    pub fn parse(head: &[u8]) -> IoResult<RequestHead> {
+        let (start_line, headers) = try!(parse_head(head));
+        let parts: Vec<&str> = start_line.split(' ').collect();
+        if parts.len() != 3 || !parts[2].starts_with("HTTP/1.") {
+            return Err(make_io_error("Malformed request line", Some(start_line.to_string())));
+        }
+
+        Ok(RequestHead {
+            method: parts[0].to_string(),
+            uri: parts[1].to_string(),
+            version: parts[2].to_string(),
+            headers: headers,
+        })
+    }
+
+    pub fn write_to(&self, w: &mut Writer) -> IoResult<()> {
+        try!(write!(w, "{} {} {}\r\n", self.method, self.uri, self.version));
+        write_headers(&self.headers, w)
+    }
+
+    /// The client will close the connection after the response
+    pub fn wants_close(&self) -> bool {
+        wants_close(self.version.as_slice(), &self.headers)
+    }
+
+    pub fn body_length(&self) -> IoResult<BodyLength> {
+        match try!(declared_body_length(&self.headers)) {
+            Some(len) => Ok(len),
+            None => Err(make_io_error("Unsupported Transfer-Encoding of request", None)),
+        }
+    }
+}
+
+/// Head of a response
+#[derive(Clone, Debug)]
+pub struct ResponseHead {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+}
+
+impl ResponseHead {
+    /// Parses a head whose length is returned by `head_length`
+    pub fn parse(head: &[u8]) -> IoResult<ResponseHead> {
+        let (start_line, headers) = try!(parse_head(head));
+        let mut parts = start_line.splitn(2, ' ');
+        let version = parts.next().unwrap_or("");
+        let status = parts.next().and_then(|s| s.parse());
+        let reason = parts.next().unwrap_or("");
+
+        match status {
+            Some(status) if version.starts_with("HTTP/1.") => {
+                Ok(ResponseHead {
+                    version: version.to_string(),
+                    status: status,
+                    reason: reason.to_string(),
+                    headers: headers,
+                })
+            },
+            _ => Err(make_io_error("Malformed status line", Some(start_line.to_string()))),
+        }
+    }
+
+    pub fn write_to(&self, w: &mut Writer) -> IoResult<()> {
+        try!(write!(w, "{} {} {}\r\n", self.version, self.status, self.reason));
+        write_headers(&self.headers, w)
+    }
+
+    /// An informational response, which is followed by the final response
+    pub fn is_interim(&self) -> bool {
+        self.status >= 100 && self.status < 200
+    }
+
+    /// The server will close the connection after this response
+    pub fn wants_close(&self) -> bool {
+        wants_close(self.version.as_slice(), &self.headers)
+    }
+
+    /// Length of the body, `head_request` tells whether it is the response of a `HEAD` request
+    pub fn body_length(&self, head_request: bool) -> IoResult<BodyLength> {
+        if head_request || self.is_interim() || self.status == 204 || self.status == 304 {
+            return Ok(BodyLength::Length(0));
+        }
+
+        declared_body_length(&self.headers).map(|len| len.unwrap_or(BodyLength::UntilClose))
+    }
+}
+
+/// How the end of a body is found
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BodyLength {
+    Length(u64),
+    Chunked,
+    UntilClose,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ChunkState {
+    Size(u64),
+    Extension(u64),
+    SizeLf(u64),
+    Data(u64),
+    DataCr,
+    DataLf,
+    // Length of the current trailer line
+    Trailer(usize),
+    TrailerLf(usize),
+    Done,
+}
+
+/// Finds the end of a body in the data passing through
+pub struct BodyTracker {
+    length: BodyLength,
+    remaining: u64,
+    chunk: ChunkState,
+}
+
+impl BodyTracker {
+    pub fn new(length: BodyLength) -> BodyTracker {
+        BodyTracker {
+            length: length,
+            remaining: match length { BodyLength::Length(len) => len, _ => 0 },
+            chunk: ChunkState::Size(0),
+        }
+    }
+
+    pub fn length(&self) -> BodyLength {
+        self.length
+    }
+
+    /// The whole body has passed
+    pub fn is_done(&self) -> bool {
+        match self.length {
+            BodyLength::Length(..) => self.remaining == 0,
+            BodyLength::Chunked => self.chunk == ChunkState::Done,
+            BodyLength::UntilClose => false,
+        }
+    }
+
+    /// Consumes the beginning of `data` which belongs to the body, returns its length
+    pub fn advance(&mut self, data: &[u8]) -> IoResult<usize> {
+        match self.length {
+            BodyLength::Length(..) => {
+                let n = cmp::min(self.remaining, data.len() as u64);
+                self.remaining -= n;
+                Ok(n as usize)
+            },
+            BodyLength::UntilClose => Ok(data.len()),
+            BodyLength::Chunked => self.advance_chunked(data),
+        }
+    }
+
+    fn advance_chunked(&mut self, data: &[u8]) -> IoResult<usize> {
+        let mut pos = 0;
+        while pos < data.len() && self.chunk != ChunkState::Done {
+            if let ChunkState::Data(remaining) = self.chunk {
+                let n = cmp::min(remaining, (data.len() - pos) as u64);
+                pos += n as usize;
+                self.chunk = if n == remaining { ChunkState::DataCr } else { ChunkState::Data(remaining - n) };
+                continue;
+            }
+
+            let b = data[pos];
+            pos += 1;
+            self.chunk = match (self.chunk, b) {
+                (ChunkState::Size(size), b'\r') => ChunkState::SizeLf(size),
+                (ChunkState::Size(size), b';') => ChunkState::Extension(size),
+                (ChunkState::Size(size), b' ') | (ChunkState::Size(size), b'\t') => ChunkState::Size(size),
+                (ChunkState::Size(size), b) => match (b as char).to_digit(16) {
+                    Some(d) if size < (1 << 56) => ChunkState::Size(size * 16 + d as u64),
+                    _ => return Err(make_io_error("Invalid chunk size", None)),
+                },
+                (ChunkState::Extension(size), b'\r') => ChunkState::SizeLf(size),
+                (ChunkState::Extension(size), _) => ChunkState::Extension(size),
+                (ChunkState::SizeLf(0), b'\n') => ChunkState::Trailer(0),
+                (ChunkState::SizeLf(size), b'\n') => ChunkState::Data(size),
+                (ChunkState::DataCr, b'\r') => ChunkState::DataLf,
+                (ChunkState::DataLf, b'\n') => ChunkState::Size(0),
+                (ChunkState::Trailer(len), b'\r') => ChunkState::TrailerLf(len),
+                (ChunkState::Trailer(len), _) => ChunkState::Trailer(len + 1),
+                (ChunkState::TrailerLf(0), b'\n') => ChunkState::Done,
+                (ChunkState::TrailerLf(_), b'\n') => ChunkState::Trailer(0),
+                _ => return Err(make_io_error("Malformed chunked body", None)),
+            };
+        }
+        Ok(pos)
+    }
+}
+
+#[cfg(test)]
+mod test_http {
+    use std::io::net::ip::Ipv4Addr;
+
+    use relay::socks5::Address;
+    use relay::http::{RequestHead, ResponseHead, BodyTracker, BodyLength};
+    use relay::http::{head_length, strip_hop_by_hop, parse_absolute_uri, parse_authority, proxy_credentials};
+
+    #[test]
+    fn test_request_head() {
+        let raw: &[u8] = b"GET http://user@example.com:8080/index.html?q=1#top HTTP/1.1\r\n\
+                    Host: example.com:8080\r\n\
+                    Proxy-Connection: keep-alive\r\n\
+                    Connection: X-Custom\r\n\
+                    X-Custom: 1\r\n\
+                    Accept: */*\r\n\r\nbody";
+        let len = head_length(raw).unwrap();
+        assert_eq!(&raw[len..], "body".as_bytes());
+        assert!(head_length(&raw[..len - 1]).is_none());
+
+        let mut head = RequestHead::parse(&raw[..len]).unwrap();
+        assert_eq!(head.method.as_slice(), "GET");
+        assert!(!head.wants_close());
+        assert_eq!(head.body_length().unwrap(), BodyLength::Length(0));
+
+        let (addr, authority, path) = parse_absolute_uri(head.uri.as_slice()).unwrap();
+        assert_eq!(addr, Address::DomainNameAddress("example.com".to_string(), 8080));
+        assert_eq!(authority.as_slice(), "example.com:8080");
+        assert_eq!(path.as_slice(), "/index.html?q=1");
+
+        strip_hop_by_hop(&mut head.headers);
+        head.uri = path;
+        let mut out = Vec::new();
+        head.write_to(&mut out).unwrap();
+        assert_eq!(out.as_slice(),
+                   "GET /index.html?q=1 HTTP/1.1\r\nHost: example.com:8080\r\nAccept: */*\r\n\r\n".as_bytes());
+    }
+
+    #[test]
+    fn test_authority() {
+        assert_eq!(parse_authority("127.0.0.1:443", None), Some(Address::SocketAddress(Ipv4Addr(127, 0, 0, 1), 443)));
+        assert_eq!(parse_authority("example.com", Some(80)),
+                   Some(Address::DomainNameAddress("example.com".to_string(), 80)));
+        assert_eq!(parse_authority("example.com", None), None);
+        assert!(parse_authority("[::1]:8080", None).is_some());
+        assert_eq!(parse_authority("::1:8080", None), None);
+        assert_eq!(parse_absolute_uri("https://example.com/"), None);
+        assert_eq!(parse_absolute_uri("/index.html"), None);
+    }
+
+    #[test]
+    fn test_response_body() {
+        let raw = b"HTTP/1.0 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let head = ResponseHead::parse(raw).unwrap();
+        assert_eq!(head.status, 200);
+        assert!(head.wants_close());
+        assert_eq!(head.body_length(true).unwrap(), BodyLength::Length(0));
+        assert_eq!(head.body_length(false).unwrap(), BodyLength::Chunked);
+
+        let body: &[u8] = b"5;ext=1\r\nhello\r\n10\r\n0123456789abcdef\r\n0\r\nX-Trailer: 1\r\n\r\nHTTP/1.1";
+        let mut tracker = BodyTracker::new(BodyLength::Chunked);
+        let mut consumed = 0;
+        // Feed byte by byte to check that the state survives between pieces
+        while !tracker.is_done() {
+            consumed += tracker.advance(&body[consumed..consumed + 1]).unwrap();
+        }
+        assert_eq!(&body[consumed..], "HTTP/1.1".as_bytes());
+
+        let mut tracker = BodyTracker::new(BodyLength::Length(3));
+        assert_eq!(tracker.advance(b"abcdef").unwrap(), 3);
+        assert!(tracker.is_done());
+
+        let mut tracker = BodyTracker::new(BodyLength::Chunked);
+        assert!(tracker.advance(b"xyz\r\n").is_err());
+    }
+
+    #[test]
+    fn test_proxy_credentials() {
+        let header = |value: &str| vec![("Proxy-Authorization".to_string(), value.to_string())];
+
+        // alice:pass:word, the password may have colons
+        assert_eq!(proxy_credentials(&header("Basic YWxpY2U6cGFzczp3b3Jk")),
+                   Some((b"alice".to_vec(), b"pass:word".to_vec())));
+        assert_eq!(proxy_credentials(&header(" basic   Ym9iOg== ")), Some((b"bob".to_vec(), Vec::new())));
+
+        // Without a colon, of another scheme, or not base64
+        assert_eq!(proxy_credentials(&header("Basic YWxpY2U=")), None);
+        assert_eq!(proxy_credentials(&header("Bearer YWxpY2U6cGFzczp3b3Jk")), None);
+        assert_eq!(proxy_credentials(&header("Basic !!!")), None);
+        assert_eq!(proxy_credentials(&header("Basic")), None);
+        assert_eq!(proxy_credentials(&Vec::new()), None);
+    }
+}
@@ -0,0 +1,258 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! Consistent hash load balancing
+//!
+//! Connections with the same key, which is the target host or the client IP, are relayed by the
+//! same server, so that a site sees one egress address. Every server has virtual nodes on a hash
+//! ring in proportion to its weight, and a key belongs to the first node after its hash. Keys of a
+//! skipped server move to the next nodes on the ring until it recovers, other keys stay.
+
+use std::io::net::ip::IpAddr;
+use std::ascii::AsciiExt;
+use std::sync::Arc;
+
+use relay::loadbalancing::server::{LoadBalancer, Failures};
+use relay::eventloop::now_ms;
+use relay::socks5::Address;
+use config::{ServerConfig, HashKey};
+
+/// Virtual nodes of a server for every unit of its weight
+pub const VIRTUAL_NODES: usize = 256;
+
+/// 64 bits FNV-1a, with the finalizer of MurmurHash3 for spreading similar keys over the ring
+pub fn hash(key: &[u8]) -> u64 {
+    let mut h = 0xcbf29ce484222325u64;
+    for b in key.iter() {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+#[derive(Clone)]
+pub struct ConsistentHash {
+    servers: Vec<ServerConfig>,
+    key: HashKey,
+    // Hashes of virtual nodes in order, with indexes of their servers
+    ring: Vec<(u64, usize)>,
+    // Connections without a key are picked in turns
+    index: usize,
+    failures: Arc<Failures>,
+}
+
+impl ConsistentHash {
+    pub fn new(servers: Vec<ServerConfig>, key: HashKey, failures: Arc<Failures>) -> ConsistentHash {
+        let mut ring = Vec::new();
+        for (idx, server) in servers.iter().enumerate() {
+            for i in range(0, VIRTUAL_NODES * server.weight as usize) {
+                let node = format!("{}:{}#{}", server.addr, server.port, i);
+                ring.push((hash(node.as_bytes()), idx));
+            }
+        }
+        ring.sort();
+
+        ConsistentHash {
+            servers: servers,
+            key: key,
+            ring: ring,
+            index: 0,
+            failures: failures,
+        }
+    }
+
+    /// Index of the server for `key`
+    pub fn lookup(&self, key: &str) -> usize {
+        let h = hash(key.as_bytes());
+
+        // The first node which is not before `h`
+        let (mut lo, mut hi) = (0us, self.ring.len());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.ring[mid].0 < h {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+
+        let now = now_ms();
+        for i in range(0, self.ring.len()) {
+            let idx = self.ring[(lo + i) % self.ring.len()].1;
+            if !self.failures.is_skipped(&self.servers[idx], now) {
+                return idx;
+            }
+        }
+        // All servers are skipped
+        self.ring[lo % self.ring.len()].1
+    }
+
+    // Connections to unknown targets are keyed by their clients
+    fn key_of(&self, client: &IpAddr, target: Option<&Address>) -> String {
+        match (self.key, target) {
+            (HashKey::Target, Some(&Address::DomainNameAddress(ref host, _))) => host.to_ascii_lowercase(),
+            (HashKey::Target, Some(&Address::SocketAddress(ref ip, _))) => ip.to_string(),
+            _ => client.to_string(),
+        }
+    }
+}
+
+impl LoadBalancer for ConsistentHash {
+    fn pick_server<'a>(&'a mut self) -> &'a ServerConfig {
+        let now = now_ms();
+        let mut idx = self.index;
+        for i in range(0, self.servers.len()) {
+            let candidate = (self.index + i) % self.servers.len();
+            if !self.failures.is_skipped(&self.servers[candidate], now) {
+                idx = candidate;
+                break;
+            }
+        }
+
+        self.index = (idx + 1) % self.servers.len();
+        &self.servers[idx]
+    }
+
+    fn pick_server_for<'a>(&'a mut self, client: &IpAddr, target: Option<&Address>) -> &'a ServerConfig {
+        let key = self.key_of(client, target);
+        let idx = self.lookup(key.as_slice());
+        &self.servers[idx]
+    }
+
+    fn total(&self) -> usize {
+        self.servers.len()
+    }
+
+    fn report_failure(&mut self, server: &ServerConfig) {
+        self.failures.report_failure(server);
+    }
+
+    fn report_success(&mut self, server: &ServerConfig) {
+        self.failures.report_success(server);
+    }
+}
+
+#[cfg(test)]
+mod test_consistent_hash {
+    use std::iter::repeat;
+    use std::io::net::ip::Ipv4Addr;
+    use std::sync::Arc;
+
+    use config::HashKey;
+    use relay::socks5::Address;
+    use relay::loadbalancing::server::{LoadBalancer, ConsistentHash, Failures, FAILURES_TO_SKIP};
+    use relay::loadbalancing::server::test_servers as servers;
+
+    fn keys() -> Vec<String> {
+        range(0, 3000us).map(|i| format!("host{}.example.com", i)).collect()
+    }
+
+    fn counts(balancer: &ConsistentHash, total: usize) -> Vec<usize> {
+        let mut counts = repeat(0us).take(total).collect::<Vec<usize>>();
+        for key in keys().iter() {
+            counts[balancer.lookup(key.as_slice())] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn test_same_target_same_server() {
+        let mut balancer = ConsistentHash::new(servers(&[1, 1, 1]), HashKey::Target, Arc::new(Failures::new()));
+        let target = Address::DomainNameAddress("www.example.com".to_string(), 443);
+        let port = balancer.pick_server_for(&Ipv4Addr(127, 0, 0, 1), Some(&target)).port;
+        for i in range(0, 10u8) {
+            let client = Ipv4Addr(192, 168, 1, i);
+            assert_eq!(balancer.pick_server_for(&client, Some(&target)).port, port);
+        }
+
+        // Host names are case insensitive, ports are ignored
+        let target = Address::DomainNameAddress("WWW.example.com".to_string(), 80);
+        assert_eq!(balancer.pick_server_for(&Ipv4Addr(127, 0, 0, 1), Some(&target)).port, port);
+    }
+
+    #[test]
+    fn test_same_client_same_server() {
+        let mut balancer = ConsistentHash::new(servers(&[1, 1, 1]), HashKey::Client, Arc::new(Failures::new()));
+        let client = Ipv4Addr(192, 168, 1, 100);
+        let port = balancer.pick_server_for(&client, None).port;
+        for i in range(0, 10u16) {
+            let target = Address::DomainNameAddress(format!("host{}.example.com", i), 443);
+            assert_eq!(balancer.pick_server_for(&client, Some(&target)).port, port);
+        }
+    }
+
+    #[test]
+    fn test_balanced() {
+        let balancer = ConsistentHash::new(servers(&[1, 1, 1]), HashKey::Target, Arc::new(Failures::new()));
+        for &count in counts(&balancer, 3).iter() {
+            assert!(count > 850 && count < 1150, "{} of 3000 keys on a server", count);
+        }
+    }
+
+    #[test]
+    fn test_proportional_to_weights() {
+        let balancer = ConsistentHash::new(servers(&[1, 2]), HashKey::Target, Arc::new(Failures::new()));
+        let heavy = counts(&balancer, 2)[1];
+        assert!(heavy > 1800 && heavy < 2200, "{} of 3000 keys on the heavier server", heavy);
+    }
+
+    #[test]
+    fn test_adding_server_moves_few_keys() {
+        let before = ConsistentHash::new(servers(&[1, 1, 1]), HashKey::Target, Arc::new(Failures::new()));
+        let after = ConsistentHash::new(servers(&[1, 1, 1, 1]), HashKey::Target, Arc::new(Failures::new()));
+
+        let mut moved = 0us;
+        for key in keys().iter() {
+            let idx = after.lookup(key.as_slice());
+            if before.lookup(key.as_slice()) != idx {
+                // Keys only move to the new server
+                assert_eq!(idx, 3);
+                moved += 1;
+            }
+        }
+        assert!(moved < 1050, "{} of 3000 keys moved", moved);
+    }
+
+    #[test]
+    fn test_skip_failing_server() {
+        let servers = servers(&[1, 1, 1]);
+        let mut balancer = ConsistentHash::new(servers.clone(), HashKey::Target, Arc::new(Failures::new()));
+        let before = keys().iter().map(|k| balancer.lookup(k.as_slice())).collect::<Vec<usize>>();
+        for _ in range(0, FAILURES_TO_SKIP) {
+            balancer.report_failure(&servers[1]);
+        }
+
+        for (key, &idx) in keys().iter().zip(before.iter()) {
+            let now = balancer.lookup(key.as_slice());
+            if idx == 1 {
+                assert!(now != 1);
+            } else {
+                assert_eq!(now, idx);
+            }
+        }
+    }
+}
@@ -0,0 +1,468 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! Load balancing with health checks
+//!
+//! `HealthChecker` probes every server periodically in the background, by requesting an HTTP
+//! server through it just like clients of `sslocal` do, and measuring the milliseconds until the
+//! first byte of the response. A server is ejected after failing `fall` probes in a row, and
+//! re-admitted after passing `rise` probes in a row. `LatencyBalancer` picks the healthy server
+//! with the lowest smoothed latency.
+//!
+//! Servers could be replaced while running, statistics of the servers which are kept are not reset.
+
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::io::{IoResult, IoError, OtherIoError};
+use std::io::net::ip::{SocketAddr, Port};
+use std::io::net::tcp::TcpStream;
+use std::io::net::addrinfo::get_host_addresses;
+use std::io::timer::sleep;
+use std::iter::repeat;
+use std::time::Duration;
+
+use config::{ServerConfig, HealthCheckConfig};
+use relay::socks5::Address;
+use relay::eventloop::now_ms;
+use relay::tcprelay::tunnel::Codec;
+use relay::plugin::Plugins;
+use relay::loadbalancing::server::{LoadBalancer, is_same_server};
+
+/// Health of a server, as seen by the latest probes
+#[derive(Clone, Debug)]
+pub struct ServerStat {
+    /// `address:port` of the server
+    pub server: String,
+    pub healthy: bool,
+    /// Smoothed round-trip time in milliseconds, `None` before the first successful probe
+    pub rtt: Option<u64>,
+    /// Round-trip time of the latest successful probe
+    pub last_rtt: Option<u64>,
+    pub probes: u64,
+    pub failures: u64,
+    pub failures_in_row: usize,
+    pub successes_in_row: usize,
+}
+
+/// Requests `target` through `server`, and returns the milliseconds until the first byte of
+/// the response. The server is probed through its plugin if it has one.
+pub fn probe(server: &ServerConfig, plugins: &Plugins, target: &Address, timeout: u64) -> IoResult<u64> {
+    let addr = match try!(plugins.tcp_addr(server)) {
+        Some(addr) => addr,
+        None => match try!(get_host_addresses(server.addr.as_slice())).first() {
+            Some(ip) => SocketAddr {
+                ip: *ip,
+                port: server.port,
+            },
+            None => return Err(IoError {
+                kind: OtherIoError,
+                desc: "Unable to resolve the server",
+                detail: Some(server.addr.clone()),
+            }),
+        },
+    };
+
+    let host = match *target {
+        Address::SocketAddress(ip, _) => ip.to_string(),
+        Address::DomainNameAddress(ref name, _) => name.clone(),
+    };
+    let request = format!("HEAD / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", host);
+    let mut data = Vec::new();
+    let mut codec = try!(Codec::for_request(server, target, &mut data));
+    try!(codec.transform(true, request.as_bytes(), &mut data));
+
+    let start = now_ms();
+    let mut stream = try!(TcpStream::connect_timeout(addr, Duration::milliseconds(timeout as i64)));
+    // The deadline of all following operations
+    stream.set_timeout(Some(timeout));
+    try!(stream.write(data.as_slice()));
+
+    let mut buf: Vec<u8> = repeat(0u8).take(4096).collect();
+    let mut response = Vec::new();
+    while response.is_empty() {
+        let n = try!(stream.read(buf.as_mut_slice()));
+        try!(codec.transform(false, &buf[..n], &mut response));
+    }
+    Ok(now_ms() - start)
+}
+
+// All servers are assumed to be healthy before they are probed
+fn initial_stat(server: &ServerConfig) -> ServerStat {
+    ServerStat {
+        server: format!("{}:{}", server.addr, server.port),
+        healthy: true,
+        rtt: None,
+        last_rtt: None,
+        probes: 0,
+        failures: 0,
+        failures_in_row: 0,
+        successes_in_row: 0,
+    }
+}
+
+/// Probes servers and keeps their health, shared by all load balancers of `sslocal`
+pub struct HealthChecker {
+    // Locked before `stats` if both are locked
+    servers: Mutex<Vec<ServerConfig>>,
+    config: HealthCheckConfig,
+    // Keyed by addresses and ports of servers, so that load balancers with other lists of servers
+    // find the same statistics
+    stats: Mutex<HashMap<(String, Port), ServerStat>>,
+    plugins: Arc<Plugins>,
+}
+
+#[inline]
+fn server_key(server: &ServerConfig) -> (String, Port) {
+    (server.addr.clone(), server.port)
+}
+
+impl HealthChecker {
+    pub fn new(servers: Vec<ServerConfig>, config: HealthCheckConfig, plugins: Arc<Plugins>) -> HealthChecker {
+        let stats = servers.iter().map(|s| (server_key(s), initial_stat(s))).collect();
+        HealthChecker {
+            servers: Mutex::new(servers),
+            config: config,
+            stats: Mutex::new(stats),
+            plugins: plugins,
+        }
+    }
+
+    /// Probes all servers every `interval`, it never returns
+    pub fn run(&self) {
+        loop {
+            for server in self.servers().iter() {
+                let result = probe(server, &*self.plugins, &self.config.target, self.config.timeout);
+                // Servers could have been replaced during the probe
+                self.record(server, result);
+            }
+            sleep(Duration::milliseconds(self.config.interval as i64));
+        }
+    }
+
+    /// Servers being probed, in the order of the configuration
+    pub fn servers(&self) -> Vec<ServerConfig> {
+        self.servers.lock().unwrap().clone()
+    }
+
+    /// Replaces the servers with the ones of a reloaded configuration
+    pub fn set_servers(&self, servers: Vec<ServerConfig>) {
+        let mut current = self.servers.lock().unwrap();
+        let mut stats = self.stats.lock().unwrap();
+        let new_stats = servers.iter().map(|s| {
+            let key = server_key(s);
+            let stat = match stats.remove(&key) {
+                Some(stat) => stat,
+                None => initial_stat(s),
+            };
+            (key, stat)
+        }).collect();
+        *stats = new_stats;
+        *current = servers;
+    }
+
+    fn record(&self, server: &ServerConfig, result: IoResult<u64>) {
+        let mut stats = self.stats.lock().unwrap();
+        let stat = match stats.get_mut(&server_key(server)) {
+            Some(stat) => stat,
+            None => return,
+        };
+        stat.probes += 1;
+
+        match result {
+            Ok(rtt) => {
+                debug!("Server {} responded in {}ms", stat.server, rtt);
+                stat.last_rtt = Some(rtt);
+                stat.rtt = Some(match stat.rtt {
+                    Some(avg) => (avg * 7 + rtt) / 8,
+                    None => rtt,
+                });
+                stat.failures_in_row = 0;
+                stat.successes_in_row += 1;
+                if !stat.healthy && stat.successes_in_row >= self.config.rise {
+                    info!("Server {} is healthy again, responded in {}ms", stat.server, rtt);
+                    stat.healthy = true;
+                }
+            },
+            Err(err) => {
+                debug!("Failed to probe server {}: {}", stat.server, err);
+                self.fail(stat, &err);
+            }
+        }
+    }
+
+    fn fail(&self, stat: &mut ServerStat, err: &IoError) {
+        stat.failures += 1;
+        stat.successes_in_row = 0;
+        stat.failures_in_row += 1;
+        if stat.healthy && stat.failures_in_row >= self.config.fall {
+            error!("Server {} is unhealthy, failed {} times in a row: {}", stat.server, stat.failures_in_row, err);
+            stat.healthy = false;
+            // Latency is measured again after recovering
+            stat.rtt = None;
+        }
+    }
+
+    /// Counts a failed connection of clients through `server` like a failed probe
+    pub fn report_failure(&self, server: &ServerConfig, err: &IoError) {
+        let mut stats = self.stats.lock().unwrap();
+        if let Some(stat) = stats.get_mut(&server_key(server)) {
+            self.fail(stat, err);
+        }
+    }
+
+    /// `server` has responded to a connection of clients. Ejected servers are only re-admitted by
+    /// probes.
+    pub fn report_success(&self, server: &ServerConfig) {
+        let mut stats = self.stats.lock().unwrap();
+        if let Some(stat) = stats.get_mut(&server_key(server)) {
+            if stat.healthy {
+                stat.failures_in_row = 0;
+            }
+        }
+    }
+
+    /// Statistics of all servers, in the order of the configuration
+    pub fn stats(&self) -> Vec<ServerStat> {
+        let servers = self.servers.lock().unwrap();
+        let stats = self.stats.lock().unwrap();
+        servers.iter().filter_map(|s| stats.get(&server_key(s)).map(|stat| stat.clone())).collect()
+    }
+
+    /// Picks the healthy one of `servers` with the lowest latency, except servers in `tried`, and
+    /// returns its index in `servers`. Servers which have not been measured are picked in turns from
+    /// `next` if no server has been measured. Servers which are not probed are taken as healthy.
+    ///
+    /// Returns `None` if all servers are unhealthy or tried.
+    pub fn choose(&self, servers: &[ServerConfig], next: usize, tried: &[ServerConfig]) -> Option<usize> {
+        let stats = self.stats.lock().unwrap();
+        let found: Vec<Option<&ServerStat>> = servers.iter().map(|s| stats.get(&server_key(s))).collect();
+        let available: Vec<usize> = range(0, servers.len()).map(|i| (next + i) % servers.len())
+            .filter(|&idx| found[idx].map_or(true, |stat| stat.healthy))
+            .filter(|&idx| !tried.iter().any(|t| is_same_server(t, &servers[idx])))
+            .collect();
+
+        let fastest = available.iter().filter_map(|&idx| found[idx].and_then(|stat| stat.rtt).map(|rtt| (rtt, idx)))
+                                      .min();
+        match fastest {
+            Some((_, idx)) => Some(idx),
+            None => available.first().map(|idx| *idx),
+        }
+    }
+}
+
+/// Load balancer preferring the healthy server with the lowest latency
+pub struct LatencyBalancer {
+    servers: Vec<ServerConfig>,
+    checker: Arc<HealthChecker>,
+    index: usize,
+}
+
+impl LatencyBalancer {
+    pub fn new(servers: Vec<ServerConfig>, checker: Arc<HealthChecker>) -> LatencyBalancer {
+        LatencyBalancer {
+            servers: servers,
+            checker: checker,
+            index: 0,
+        }
+    }
+}
+
+impl LoadBalancer for LatencyBalancer {
+    fn pick_server<'a>(&'a mut self) -> &'a ServerConfig {
+        // Servers are still tried in turns if all of them are unhealthy
+        let idx = self.checker.choose(self.servers.as_slice(), self.index, &[]).unwrap_or(self.index);
+        self.index = (self.index + 1) % self.servers.len();
+        &self.servers[idx]
+    }
+
+    fn total(&self) -> usize {
+        self.servers.len()
+    }
+
+    fn pick_retry_server(&mut self, tried: &[ServerConfig]) -> Option<ServerConfig> {
+        let servers = self.servers.as_slice();
+        let idx = match self.checker.choose(servers, self.index, tried) {
+            Some(idx) => Some(idx),
+            // Unhealthy servers are tried at last
+            None => range(0, servers.len()).map(|i| (self.index + i) % servers.len())
+                                           .find(|&idx| !tried.iter().any(|t| is_same_server(t, &servers[idx]))),
+        };
+        idx.map(|idx| servers[idx].clone())
+    }
+
+    fn report_failure(&mut self, server: &ServerConfig) {
+        self.checker.report_failure(server, &IoError {
+            kind: OtherIoError,
+            desc: "Connection through the server failed",
+            detail: None,
+        });
+    }
+
+    fn report_success(&mut self, server: &ServerConfig) {
+        self.checker.report_success(server);
+    }
+}
+
+#[cfg(test)]
+mod test_health {
+    use std::sync::Arc;
+    use std::io::{IoError, OtherIoError};
+
+    use config::HealthCheckConfig;
+    use relay::socks5::Address;
+    use relay::loadbalancing::server::{LoadBalancer, test_servers};
+    use relay::loadbalancing::server::health::{HealthChecker, LatencyBalancer};
+    use relay::plugin::Plugins;
+
+    fn checker() -> HealthChecker {
+        HealthChecker::new(test_servers(&[1, 1, 1]), HealthCheckConfig {
+            target: Address::DomainNameAddress("www.example.com".to_string(), 80),
+            interval: 1000,
+            timeout: 1000,
+            fall: 2,
+            rise: 2,
+        }, Arc::new(Plugins::new()))
+    }
+
+    fn failure() -> IoError {
+        IoError {
+            kind: OtherIoError,
+            desc: "probe failed",
+            detail: None,
+        }
+    }
+
+    #[test]
+    fn test_prefer_lowest_latency() {
+        let checker = checker();
+        let servers = checker.servers();
+        assert_eq!(checker.choose(servers.as_slice(), 1, &[]), Some(1));
+
+        checker.record(&servers[0], Ok(300));
+        checker.record(&servers[1], Ok(100));
+        checker.record(&servers[2], Ok(200));
+        assert_eq!(checker.choose(servers.as_slice(), 0, &[]), Some(1));
+
+        // Smoothed, a single slow probe doesn't change the choice
+        checker.record(&servers[1], Ok(250));
+        assert_eq!(checker.stats()[1].rtt, Some(118));
+        assert_eq!(checker.stats()[1].last_rtt, Some(250));
+        assert_eq!(checker.choose(servers.as_slice(), 0, &[]), Some(1));
+
+        // Retrying with another server
+        assert_eq!(checker.choose(servers.as_slice(), 0, &servers[1..2]), Some(2));
+    }
+
+    #[test]
+    fn test_eject_and_readmit() {
+        let checker = checker();
+        let servers = checker.servers();
+        checker.record(&servers[0], Ok(100));
+        checker.record(&servers[1], Ok(200));
+
+        checker.record(&servers[0], Err(failure()));
+        assert!(checker.stats()[0].healthy);
+        checker.record(&servers[0], Err(failure()));
+        assert!(!checker.stats()[0].healthy);
+        assert_eq!(checker.choose(servers.as_slice(), 0, &[]), Some(1));
+
+        checker.record(&servers[0], Ok(50));
+        assert!(!checker.stats()[0].healthy);
+        checker.record(&servers[0], Ok(50));
+        assert!(checker.stats()[0].healthy);
+        assert_eq!(checker.choose(servers.as_slice(), 2, &[]), Some(0));
+
+        let stat = &checker.stats()[0];
+        assert_eq!((stat.probes, stat.failures), (5, 2));
+
+        // Failed connections of clients count as failed probes
+        checker.report_failure(&servers[1], &failure());
+        checker.report_failure(&servers[1], &failure());
+        assert!(!checker.stats()[1].healthy);
+        assert_eq!(checker.stats()[1].probes, 1);
+    }
+
+    #[test]
+    fn test_set_servers() {
+        let checker = checker();
+        let old = checker.servers();
+        checker.record(&old[1], Ok(100));
+
+        let mut servers = old.clone();
+        servers.remove(0);
+        servers[1].port = 9000;
+        checker.set_servers(servers);
+
+        // Statistics of the kept server are not reset
+        let stats = checker.stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].rtt, Some(100));
+        assert_eq!(stats[1].server.as_slice(), "127.0.0.1:9000");
+        assert_eq!(stats[1].probes, 0);
+
+        // Removed servers are ignored
+        checker.record(&old[2], Ok(100));
+        checker.report_failure(&old[0], &failure());
+        assert_eq!(checker.stats().len(), 2);
+        assert_eq!(checker.stats()[1].probes, 0);
+    }
+
+    #[test]
+    fn test_balancer_with_old_servers() {
+        let checker = Arc::new(checker());
+        let old = checker.servers();
+        let mut balancer = LatencyBalancer::new(old.clone(), checker.clone());
+
+        // The first server is removed from the checker before the balancer, statistics of the others
+        // are still found by their addresses
+        let mut servers = old.clone();
+        servers.remove(0);
+        checker.set_servers(servers);
+        checker.record(&old[1], Ok(300));
+        checker.record(&old[2], Ok(100));
+        assert_eq!(balancer.pick_server().port, 8390);
+
+        // Failures are reported to the failed server, not to the one at its index in the checker
+        balancer.report_failure(&old[2]);
+        balancer.report_failure(&old[2]);
+        let stats = checker.stats();
+        assert!(stats[0].healthy);
+        assert!(!stats[1].healthy);
+        assert_eq!(balancer.pick_retry_server(&old[..1]).map(|s| s.port), Some(8389));
+    }
+
+    #[test]
+    fn test_all_unhealthy() {
+        let checker = Arc::new(checker());
+        let servers = checker.servers();
+        for server in servers.iter() {
+            checker.record(server, Err(failure()));
+            checker.record(server, Err(failure()));
+        }
+        assert_eq!(checker.choose(servers.as_slice(), 0, &[]), None);
+
+        // Servers are tried in turns
+        let mut balancer = LatencyBalancer::new(checker.servers(), checker.clone());
+        let ports = range(0, 4us).map(|_| balancer.pick_server().port).collect::<Vec<u16>>();
+        assert_eq!(ports, vec![8388, 8389, 8390, 8388]);
+    }
+}
@@ -20,12 +20,169 @@
 // CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 pub use self::roundrobin::RoundRobin;
+pub use self::weighted::WeightedRoundRobin;
+pub use self::consistent_hash::ConsistentHash;
+pub use self::health::{HealthChecker, LatencyBalancer, ServerStat};
 
-use config::ServerConfig;
+use std::io::net::ip::{IpAddr, SocketAddr, Port};
+use std::io::net::addrinfo::get_host_addresses;
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+
+use config::{ServerConfig, LoadBalancing};
+use relay::socks5::Address;
+use relay::eventloop::now_ms;
 
 pub mod roundrobin;
+pub mod weighted;
+pub mod consistent_hash;
+pub mod health;
+
+/// Servers on ports from 8388 with `weights`, for tests of load balancers
+#[cfg(test)]
+pub fn test_servers(weights: &[u32]) -> Vec<ServerConfig> {
+    use crypto::cipher::CipherType;
+
+    weights.iter().enumerate().map(|(i, &weight)| {
+        let mut server = ServerConfig::new("127.0.0.1".to_string(), 8388 + i as u16, "password".to_string(),
+                                           CipherType::Aes256Cfb);
+        server.weight = weight;
+        server
+    }).collect()
+}
+
+/// Failed connections in a row before a server is skipped
+pub const FAILURES_TO_SKIP: usize = 3;
+
+/// Milliseconds for skipping a failing server
+pub const SKIP_DURATION: u64 = 30000;
 
 pub trait LoadBalancer {
     fn pick_server<'a>(&'a mut self) -> &'a ServerConfig;
     fn total(&self) -> usize;
+
+    /// Picks a server for a connection from `client` to `target`, if the target is known
+    fn pick_server_for<'a>(&'a mut self, _client: &IpAddr, _target: Option<&Address>) -> &'a ServerConfig {
+        self.pick_server()
+    }
+
+    /// Picks a server other than the `tried` ones for retrying a connection, returns `None` if all
+    /// servers have been tried
+    fn pick_retry_server(&mut self, tried: &[ServerConfig]) -> Option<ServerConfig> {
+        for _ in range(0, self.total()) {
+            let server = self.pick_server();
+            if !tried.iter().any(|t| is_same_server(t, server)) {
+                return Some(server.clone());
+            }
+        }
+        None
+    }
+
+    /// Tells that a connection through `server` has failed
+    fn report_failure(&mut self, _server: &ServerConfig) {}
+
+    /// Tells that `server` has responded to a connection
+    fn report_success(&mut self, _server: &ServerConfig) {}
+}
+
+/// Whether `a` and `b` are the same server
+pub fn is_same_server(a: &ServerConfig, b: &ServerConfig) -> bool {
+    a.addr == b.addr && a.port == b.port
+}
+
+/// Failures of servers, a server failing `FAILURES_TO_SKIP` connections in a row is skipped for
+/// `SKIP_DURATION` milliseconds. Load balancers of all workers share it, and it is kept when they
+/// are replaced with new servers.
+pub struct Failures {
+    servers: Mutex<HashMap<(String, Port), ServerFailures>>,
+}
+
+#[derive(Copy)]
+struct ServerFailures {
+    in_row: usize,
+    skip_until: u64,
+}
+
+impl Failures {
+    pub fn new() -> Failures {
+        Failures {
+            servers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `server` is skipped at `now`
+    pub fn is_skipped(&self, server: &ServerConfig, now: u64) -> bool {
+        let servers = self.servers.lock().unwrap();
+        servers.get(&(server.addr.clone(), server.port)).map_or(false, |f| f.skip_until > now)
+    }
+
+    /// Whether all of `servers` are skipped at `now`
+    pub fn all_skipped(&self, servers: &[ServerConfig], now: u64) -> bool {
+        servers.iter().all(|s| self.is_skipped(s, now))
+    }
+
+    pub fn report_failure(&self, server: &ServerConfig) {
+        let mut servers = self.servers.lock().unwrap();
+        let key = (server.addr.clone(), server.port);
+        if !servers.contains_key(&key) {
+            servers.insert(key.clone(), ServerFailures { in_row: 0, skip_until: 0 });
+        }
+
+        let failures = servers.get_mut(&key).unwrap();
+        failures.in_row += 1;
+        if failures.in_row >= FAILURES_TO_SKIP {
+            warn!("Server {}:{} failed {} connections in a row, skipping it for {}s",
+                  server.addr, server.port, failures.in_row, SKIP_DURATION / 1000);
+            failures.in_row = 0;
+            failures.skip_until = now_ms() + SKIP_DURATION;
+        }
+    }
+
+    pub fn report_success(&self, server: &ServerConfig) {
+        let mut servers = self.servers.lock().unwrap();
+        if let Some(failures) = servers.get_mut(&(server.addr.clone(), server.port)) {
+            failures.in_row = 0;
+        }
+    }
+}
+
+/// Resolves the addresses of `servers`, for relays receiving datagrams from them. Returns servers
+/// by their addresses, and addresses by names of servers. Servers which fail to resolve are left out.
+pub fn resolve_servers(servers: &[ServerConfig]) -> (HashMap<SocketAddr, ServerConfig>, HashMap<String, SocketAddr>) {
+    let mut server_set = HashMap::new();
+    let mut server_addr = HashMap::new();
+    for s in servers.iter() {
+        let addrs = match get_host_addresses(s.addr.as_slice()) {
+            Ok(addr) => addr,
+            Err(..) => continue,
+        };
+
+        if !addrs.is_empty() {
+            let addr = SocketAddr {
+                ip: addrs.first().unwrap().clone(),
+                port: s.port,
+            };
+
+            server_set.insert(addr, s.clone());
+            server_addr.insert(s.addr.clone(), addr);
+        }
+    }
+    (server_set, server_addr)
+}
+
+/// Load balancer of `servers` with `strategy`, which prefers healthy servers if they are probed by
+/// `checker`, otherwise it skips servers by `failures`
+pub fn new_load_balancer(servers: Vec<ServerConfig>, strategy: LoadBalancing, checker: Option<Arc<HealthChecker>>,
+                         failures: Arc<Failures>) -> Box<LoadBalancer + Send> {
+    if let Some(checker) = checker {
+        return box LatencyBalancer::new(servers, checker) as Box<LoadBalancer + Send>;
+    }
+
+    match strategy {
+        LoadBalancing::RoundRobin => box RoundRobin::new(servers, failures) as Box<LoadBalancer + Send>,
+        LoadBalancing::WeightedRoundRobin =>
+            box WeightedRoundRobin::new(servers, failures) as Box<LoadBalancer + Send>,
+        LoadBalancing::ConsistentHash(key) =>
+            box ConsistentHash::new(servers, key, failures) as Box<LoadBalancer + Send>,
+    }
 }
@@ -19,20 +19,30 @@
 // IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
 // CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
-use relay::loadbalancing::server::LoadBalancer;
+//! Round robin load balancing
+//!
+//! Servers are picked in turns. A server failing `FAILURES_TO_SKIP` connections in a row is
+//! skipped for `SKIP_DURATION` milliseconds, unless all servers are skipped.
+
+use std::sync::Arc;
+
+use relay::loadbalancing::server::{LoadBalancer, Failures};
+use relay::eventloop::now_ms;
 use config::ServerConfig;
 
 #[derive(Clone)]
 pub struct RoundRobin {
     server: Vec<ServerConfig>,
     index: usize,
+    failures: Arc<Failures>,
 }
 
 impl RoundRobin {
-    pub fn new(config: Vec<ServerConfig>) -> RoundRobin {
+    pub fn new(config: Vec<ServerConfig>, failures: Arc<Failures>) -> RoundRobin {
         RoundRobin {
             server: config,
             index: 0us,
+            failures: failures,
         }
     }
 }
@@ -42,9 +52,18 @@ impl LoadBalancer for RoundRobin {
         match self.server.as_slice() {
             [ref s] => s,
             _ => {
-                let ref s = self.server[self.index];
-                self.index = (self.index + 1) % self.server.len();
-                s
+                let now = now_ms();
+                let mut idx = self.index;
+                for i in range(0, self.server.len()) {
+                    let candidate = (self.index + i) % self.server.len();
+                    if !self.failures.is_skipped(&self.server[candidate], now) {
+                        idx = candidate;
+                        break;
+                    }
+                }
+
+                self.index = (idx + 1) % self.server.len();
+                &self.server[idx]
             }
         }
     }
@@ -52,4 +71,66 @@ impl LoadBalancer for RoundRobin {
     fn total(&self) -> usize {
         self.server.len()
     }
+
+    fn report_failure(&mut self, server: &ServerConfig) {
+        self.failures.report_failure(server);
+    }
+
+    fn report_success(&mut self, server: &ServerConfig) {
+        self.failures.report_success(server);
+    }
+}
+
+#[cfg(test)]
+mod test_roundrobin {
+    use std::sync::Arc;
+
+    use config::ServerConfig;
+    use relay::loadbalancing::server::{LoadBalancer, RoundRobin, Failures, FAILURES_TO_SKIP, test_servers};
+
+    fn servers() -> Vec<ServerConfig> {
+        test_servers(&[1, 1, 1])
+    }
+
+    #[test]
+    fn test_skip_failing_server() {
+        let servers = servers();
+        let mut balancer = RoundRobin::new(servers.clone(), Arc::new(Failures::new()));
+        for _ in range(0, FAILURES_TO_SKIP) {
+            balancer.report_failure(&servers[1]);
+        }
+
+        let ports = range(0, 4us).map(|_| balancer.pick_server().port).collect::<Vec<u16>>();
+        assert_eq!(ports, vec![8388, 8390, 8388, 8390]);
+    }
+
+    #[test]
+    fn test_pick_retry_server() {
+        let servers = servers();
+        let mut balancer = RoundRobin::new(servers.clone(), Arc::new(Failures::new()));
+        let tried = vec![servers[0].clone(), servers[1].clone()];
+        assert_eq!(balancer.pick_retry_server(tried.as_slice()).map(|s| s.port), Some(8390));
+
+        let tried = servers.clone();
+        assert!(balancer.pick_retry_server(tried.as_slice()).is_none());
+    }
+
+    #[test]
+    fn test_shared_failures() {
+        // Load balancers of two workers, and one replaced with other servers
+        let servers = servers();
+        let failures = Arc::new(Failures::new());
+        let mut first = RoundRobin::new(servers.clone(), failures.clone());
+        let mut second = RoundRobin::new(servers.clone(), failures.clone());
+        for _ in range(0, FAILURES_TO_SKIP) {
+            first.report_failure(&servers[0]);
+        }
+
+        let ports = range(0, 4us).map(|_| second.pick_server().port).collect::<Vec<u16>>();
+        assert_eq!(ports, vec![8389, 8390, 8389, 8390]);
+
+        let mut replaced = RoundRobin::new(vec![servers[2].clone(), servers[0].clone()], failures.clone());
+        let ports = range(0, 2us).map(|_| replaced.pick_server().port).collect::<Vec<u16>>();
+        assert_eq!(ports, vec![8390, 8390]);
+    }
 }
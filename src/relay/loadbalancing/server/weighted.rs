@@ -0,0 +1,147 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! Weighted round robin load balancing
+//!
+//! Servers are picked in proportion to their weights, and interleaved as evenly as possible, like
+//! the smooth weighted round robin of nginx. Failing servers are skipped like `RoundRobin` does.
+
+use std::iter::repeat;
+use std::sync::Arc;
+
+use relay::loadbalancing::server::{LoadBalancer, Failures, is_same_server};
+use relay::eventloop::now_ms;
+use config::ServerConfig;
+
+#[derive(Clone)]
+pub struct WeightedRoundRobin {
+    servers: Vec<ServerConfig>,
+    // Current weights, the server with the largest one is picked
+    current: Vec<i64>,
+    failures: Arc<Failures>,
+}
+
+impl WeightedRoundRobin {
+    pub fn new(servers: Vec<ServerConfig>, failures: Arc<Failures>) -> WeightedRoundRobin {
+        let total = servers.len();
+        WeightedRoundRobin {
+            servers: servers,
+            current: repeat(0).take(total).collect(),
+            failures: failures,
+        }
+    }
+}
+
+impl LoadBalancer for WeightedRoundRobin {
+    fn pick_server<'a>(&'a mut self) -> &'a ServerConfig {
+        let now = now_ms();
+        let all_skipped = self.failures.all_skipped(self.servers.as_slice(), now);
+
+        let mut total = 0i64;
+        let mut best = 0us;
+        for idx in range(0, self.servers.len()) {
+            if !all_skipped && self.failures.is_skipped(&self.servers[idx], now) {
+                continue;
+            }
+
+            let weight = self.servers[idx].weight as i64;
+            self.current[idx] += weight;
+            total += weight;
+            if total == weight || self.current[idx] > self.current[best] {
+                best = idx;
+            }
+        }
+
+        self.current[best] -= total;
+        &self.servers[best]
+    }
+
+    fn total(&self) -> usize {
+        self.servers.len()
+    }
+
+    fn pick_retry_server(&mut self, tried: &[ServerConfig]) -> Option<ServerConfig> {
+        // Light servers may not be picked in a round, so the heaviest server which is not skipped is retried
+        let now = now_ms();
+        let failures = &self.failures;
+        self.servers.iter()
+            .filter(|s| !tried.iter().any(|t| is_same_server(t, *s)))
+            .max_by(|s| (!failures.is_skipped(*s, now), s.weight))
+            .map(|s| s.clone())
+    }
+
+    fn report_failure(&mut self, server: &ServerConfig) {
+        self.failures.report_failure(server);
+    }
+
+    fn report_success(&mut self, server: &ServerConfig) {
+        self.failures.report_success(server);
+    }
+}
+
+#[cfg(test)]
+mod test_weighted {
+    use std::sync::Arc;
+
+    use relay::loadbalancing::server::{LoadBalancer, WeightedRoundRobin, Failures, FAILURES_TO_SKIP};
+    use relay::loadbalancing::server::test_servers as servers;
+
+    fn pick(balancer: &mut WeightedRoundRobin, times: usize) -> Vec<u16> {
+        range(0, times).map(|_| balancer.pick_server().port - 8388).collect()
+    }
+
+    #[test]
+    fn test_proportional_to_weights() {
+        let mut balancer = WeightedRoundRobin::new(servers(&[3, 2, 5]), Arc::new(Failures::new()));
+        let picked = pick(&mut balancer, 1000);
+        for (port, &weight) in [3us, 2, 5].iter().enumerate() {
+            let count = picked.iter().filter(|&&p| p as usize == port).count();
+            assert_eq!(count, weight * 100);
+        }
+    }
+
+    #[test]
+    fn test_interleaved() {
+        // The heavy server is not picked 5 times in a row
+        let mut balancer = WeightedRoundRobin::new(servers(&[5, 1, 1]), Arc::new(Failures::new()));
+        assert_eq!(pick(&mut balancer, 7), vec![0, 0, 1, 0, 2, 0, 0]);
+    }
+
+    #[test]
+    fn test_skip_failing_server() {
+        let servers = servers(&[1, 3, 1]);
+        let mut balancer = WeightedRoundRobin::new(servers.clone(), Arc::new(Failures::new()));
+        for _ in range(0, FAILURES_TO_SKIP) {
+            balancer.report_failure(&servers[1]);
+        }
+        assert_eq!(pick(&mut balancer, 4), vec![0, 2, 0, 2]);
+    }
+
+    #[test]
+    fn test_pick_retry_server() {
+        let servers = servers(&[100, 1]);
+        let mut balancer = WeightedRoundRobin::new(servers.clone(), Arc::new(Failures::new()));
+        let tried = vec![servers[0].clone()];
+        assert_eq!(balancer.pick_retry_server(tried.as_slice()).map(|s| s.port), Some(8389));
+        assert!(balancer.pick_retry_server(servers.as_slice()).is_none());
+    }
+}
@@ -21,13 +21,24 @@
 
 //! Local side
 
+use std::sync::Arc;
 use std::thread::Thread;
+use std::io::net::ip::SocketAddr;
 
 use relay::Relay;
+use relay::association::Associations;
+use relay::loadbalancing::server::HealthChecker;
 use relay::tcprelay::local::TcpRelayLocal;
+use relay::dnsrelay::local::DnsRelayLocal;
+use relay::ratelimit::RateLimiter;
+use relay::metrics::{Metrics, MetricsServer};
+use relay::traffic::TrafficStats;
+use relay::reload;
+use relay::shutdown::ShutdownHandle;
+use relay::plugin::{Plugins, StopGuard};
 #[cfg(feature = "enable-udp")]
 use relay::udprelay::local::UdpRelayLocal;
-use config::Config;
+use config::{Config, ConfigType, ServerConfig, Error, ErrorKind};
 
 /// Relay server running under local environment.
 ///
@@ -46,42 +57,174 @@ use config::Config;
 ///     ip: "127.0.0.1".parse().unwrap(),
 ///     port: 1080
 /// });
-/// config.server = vec![ServerConfig {
-///     addr: "127.0.0.1".to_string(),
-///     port: 8388,
-///     password: "server-password".to_string(),
-///     method: CipherType::Aes256Cfb,
-///     timeout: None,
-///     dns_cache_capacity: 1024,
-/// }];
+/// config.server = vec![ServerConfig::new("127.0.0.1".to_string(), 8388, "server-password".to_string(),
+///                                        CipherType::Aes256Cfb)];
 /// RelayLocal::new(config).run();
 /// ```
 #[derive(Clone)]
 pub struct RelayLocal {
     enable_udp: bool,
     tcprelay: TcpRelayLocal,
+    dnsrelay: Option<DnsRelayLocal>,
     #[cfg(feature = "enable-udp")]
     udprelay: UdpRelayLocal,
+    health_checker: Option<Arc<HealthChecker>>,
+    metrics: Arc<Metrics>,
+    // Traffic of listening ports, which is only counted for metrics
+    traffic: Arc<TrafficStats>,
+    metrics_addr: Option<SocketAddr>,
+    drain_timeout: u64,
+    plugins: Arc<Plugins>,
 }
 
 impl RelayLocal {
     #[cfg(feature = "enable-udp")]
     pub fn new(config: Config) -> RelayLocal {
-        let tcprelay = TcpRelayLocal::new(config.clone());
-        let udprelay = UdpRelayLocal::new(config.clone());
+        let associations = Associations::new();
+        let plugins = RelayLocal::start_plugins(&config);
+        let health_checker = RelayLocal::health_checker_of(&config, plugins.clone());
+        let rate_limiter = Arc::new(RateLimiter::new(&config));
+        let metrics = Arc::new(Metrics::new());
+        let traffic = Arc::new(TrafficStats::new());
+        let tcprelay = TcpRelayLocal::new(config.clone(), associations.clone(), health_checker.clone(),
+                                          rate_limiter.clone(), metrics.clone(), traffic.clone(), plugins.clone());
+        let udprelay = UdpRelayLocal::new(config.clone(), associations, health_checker.clone(), rate_limiter,
+                                          metrics.clone());
         RelayLocal {
             tcprelay: tcprelay,
+            dnsrelay: config.local_dns.as_ref().map(|_| {
+                DnsRelayLocal::new(config.clone(), health_checker.clone(), plugins.clone())
+            }),
             udprelay: udprelay,
-            enable_udp: config.enable_udp,
+            health_checker: health_checker,
+            metrics: metrics,
+            traffic: traffic,
+            metrics_addr: config.metrics,
+            drain_timeout: config.drain_timeout,
+            plugins: plugins,
+            // UDP is relayed for the local address and tunnels, but not for the HTTP proxy
+            enable_udp: config.enable_udp && (config.local.is_some() || !config.tunnels.is_empty()),
         }
     }
 
     #[cfg(not(feature = "enable-udp"))]
     pub fn new(config: Config) -> RelayLocal {
-        let tcprelay = TcpRelayLocal::new(config.clone());
+        let plugins = RelayLocal::start_plugins(&config);
+        let health_checker = RelayLocal::health_checker_of(&config, plugins.clone());
+        let rate_limiter = Arc::new(RateLimiter::new(&config));
+        let metrics = Arc::new(Metrics::new());
+        let traffic = Arc::new(TrafficStats::new());
+        let tcprelay = TcpRelayLocal::new(config.clone(), Associations::new(), health_checker.clone(), rate_limiter,
+                                          metrics.clone(), traffic.clone(), plugins.clone());
         RelayLocal {
             tcprelay: tcprelay,
+            dnsrelay: config.local_dns.as_ref().map(|_| {
+                DnsRelayLocal::new(config.clone(), health_checker.clone(), plugins.clone())
+            }),
             enable_udp: config.enable_udp,
+            health_checker: health_checker,
+            metrics: metrics,
+            traffic: traffic,
+            metrics_addr: config.metrics,
+            drain_timeout: config.drain_timeout,
+            plugins: plugins,
+        }
+    }
+
+    // Plugins of servers are started before relaying, and run until `run` returns
+    fn start_plugins(config: &Config) -> Arc<Plugins> {
+        let plugins = Arc::new(Plugins::new());
+        plugins.set_servers(config.server.as_slice());
+        plugins
+    }
+
+    fn health_checker_of(config: &Config, plugins: Arc<Plugins>) -> Option<Arc<HealthChecker>> {
+        config.health_check.clone().map(|c| Arc::new(HealthChecker::new(config.server.clone(), c, plugins)))
+    }
+
+    /// The health checker shared by all relays if health checks are enabled, for querying the
+    /// latency and health of servers
+    pub fn health_checker(&self) -> Option<Arc<HealthChecker>> {
+        self.health_checker.clone()
+    }
+
+    /// Counters of connections, failures and caches of all relays
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Replaces the servers with the ones of a reloaded configuration. New connections, datagrams
+    /// and DNS queries are relayed through the new servers, established connections are kept.
+    /// Plugins of new servers are started, and plugins of removed servers are killed.
+    ///
+    /// Other parts of the configuration are only changed by restarting.
+    pub fn reload(&self, config: Config) -> Result<(), Error> {
+        if config.server.is_empty() {
+            return Err(Error::new(ErrorKind::MissingField, "no servers in the configuration", None));
+        }
+
+        self.plugins.set_servers(config.server.as_slice());
+        if let Some(ref checker) = self.health_checker {
+            checker.set_servers(config.server.clone());
+        }
+        self.tcprelay.set_servers(config.server.clone());
+        if let Some(ref dnsrelay) = self.dnsrelay {
+            dnsrelay.set_servers(config.server.clone());
+        }
+        self.set_udp_servers(config.server.clone());
+        info!("Relaying through {} servers", config.server.len());
+        Ok(())
+    }
+
+    /// Reloads the configuration file `path` whenever SIGHUP is received, see `reload`. `extra_servers`
+    /// given on the command line are added to the reloaded configuration.
+    pub fn reload_on_hangup(&self, path: String, extra_servers: Vec<ServerConfig>) {
+        let local = self.clone();
+        reload::reload_on_hangup(path, ConfigType::Local, extra_servers, move |config| local.reload(config));
+    }
+
+    /// A handle shutting the relays down with the drain timeout of the configuration
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle::new(self.clone(), self.drain_timeout)
+    }
+
+    #[cfg(feature = "enable-udp")]
+    fn set_udp_servers(&self, servers: Vec<ServerConfig>) {
+        self.udprelay.set_servers(servers);
+    }
+
+    #[cfg(not(feature = "enable-udp"))]
+    fn set_udp_servers(&self, _: Vec<ServerConfig>) {}
+
+    #[cfg(feature = "enable-udp")]
+    fn shutdown_udp(&self) {
+        self.udprelay.shutdown(0);
+    }
+
+    #[cfg(not(feature = "enable-udp"))]
+    fn shutdown_udp(&self) {}
+
+    // Relays keep running if the metrics server fails
+    fn start_metrics(&self) {
+        if let Some(addr) = self.metrics_addr {
+            let mut server = MetricsServer::new(addr, self.metrics.clone(), self.traffic.clone());
+            if let Some(ref checker) = self.health_checker {
+                server.set_health_checker(checker.clone());
+            }
+            Thread::spawn(move || {
+                if let Err(err) = server.run() {
+                    error!("Metrics server exited: {}", err);
+                }
+            });
+        }
+    }
+
+    // Probes servers in a detached thread, which runs as long as the process
+    fn start_health_checker(&self) {
+        if let Some(ref checker) = self.health_checker {
+            let checker = checker.clone();
+            Thread::spawn(move || checker.run());
+            info!("Enabled health checks of servers");
         }
     }
 }
@@ -89,18 +232,38 @@ impl RelayLocal {
 impl Relay for RelayLocal {
     #[cfg(not(feature = "enable-udp"))]
     fn run(&self) {
+        // Plugins are killed after returning, or while unwinding a panic
+        let _plugins = StopGuard::new(self.plugins.clone());
+
         if self.enable_udp {
             warn!("UDP relay feature is disabled, recompile with feature=\"enable-udp\" to enable this feature");
         }
+        self.start_health_checker();
+        self.start_metrics();
+
         let tcprelay = self.tcprelay.clone();
         let tcp_thread = Thread::scoped(move || tcprelay.run());
         info!("Enabled TCP relay");
 
+        let dns_thread = self.dnsrelay.clone().map(|dnsrelay| {
+            info!("Enabled DNS forwarder");
+            Thread::scoped(move || dnsrelay.run())
+        });
+
         tcp_thread.join().ok().expect("A thread failed and exited");
+        if let Some(dns_thread) = dns_thread {
+            dns_thread.join().ok().expect("A thread failed and exited");
+        }
     }
 
     #[cfg(feature = "enable-udp")]
     fn run(&self) {
+        // Plugins are killed after returning, or while unwinding a panic
+        let _plugins = StopGuard::new(self.plugins.clone());
+
+        self.start_health_checker();
+        self.start_metrics();
+
         let mut threads = Vec::with_capacity(2);
 
         let tcprelay = self.tcprelay.clone();
@@ -115,8 +278,22 @@ impl Relay for RelayLocal {
             info!("Enabled UDP relay");
         }
 
+        if let Some(ref dnsrelay) = self.dnsrelay {
+            let dnsrelay = dnsrelay.clone();
+            threads.push(Thread::scoped(move || dnsrelay.run()));
+            info!("Enabled DNS forwarder");
+        }
+
         for fut in threads.into_iter() {
             fut.join().ok().expect("A thread failed and exited");
         }
     }
+
+    fn shutdown(&self, drain_timeout: u64) {
+        self.tcprelay.shutdown(drain_timeout);
+        if let Some(ref dnsrelay) = self.dnsrelay {
+            dnsrelay.shutdown(drain_timeout);
+        }
+        self.shutdown_udp();
+    }
 }
@@ -0,0 +1,462 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! Manager of `ssserver`, compatible with `ss-manager` of shadowsocks-libev
+//!
+//! Commands are plain text datagrams received on UDP or a Unix datagram socket:
+//!
+//! ```plain
+//! add: {"server_port": 8001, "password": "7cd308cc059", "method": "aes-256-gcm"}
+//! remove: {"server_port": 8001}
+//! ping
+//! stat
+//! limit: {"server_port": 8001, "upload": 1048576, "download": 4194304}
+//! ```
+//!
+//! `add`, `remove` and `limit` are answered with `ok` or `err`, `ping` with `pong`, and `stat`
+//! with the traffic of all ports, such as `stat: {"8001": 11370}`, which is bytes transferred in
+//! both directions since the port was added. Traffic is also reported to the sender of the latest
+//! command periodically.
+//!
+//! `limit` changes rate limits in bytes per second of a port, of each connection with
+//! `"connection": true`, or of the process without either key. A rate of 0 means unlimited, and
+//! a missing rate is left unchanged.
+
+use std::io::{IoResult, fs};
+use std::io::net::ip::{SocketAddr, Port};
+use std::iter::repeat;
+use std::str;
+
+use serialize::json;
+
+use config::{ManagerConfig, ManagerAddress, ServerConfig, RateLimitConfig};
+use crypto::cipher::CipherType;
+use relay::server::RelayServer;
+use relay::eventloop::{EventLoop, Handler, Token, Interest, Ready, UdpSocket, UnixDatagram};
+
+const SOCKET_TOKEN: Token = Token(0);
+const REPORT_TOKEN: Token = Token(1);
+
+/// A command of the manager protocol
+pub enum Command {
+    /// Starts a server on the port
+    Add {
+        port: Port,
+        password: String,
+        method: Option<CipherType>,
+    },
+    /// Stops the server on the port
+    Remove(Port),
+    Ping,
+    /// Asks for the traffic of all ports
+    Stat,
+    /// Changes rate limits, `None` leaves a rate unchanged and `Some(0)` means unlimited
+    Limit {
+        scope: LimitScope,
+        upload: Option<usize>,
+        download: Option<usize>,
+    },
+}
+
+/// Rate limits changed by a `limit` command
+#[derive(Copy, Debug, PartialEq, Eq)]
+pub enum LimitScope {
+    Process,
+    /// Each connection
+    Connection,
+    Port(Port),
+}
+
+fn port_of(value: &json::Json) -> Option<Port> {
+    let port = match value.as_u64() {
+        Some(port) => Some(port),
+        // Some managers send ports as strings
+        None => value.as_string().and_then(|s| s.parse::<u64>()),
+    };
+    match port {
+        Some(port) if port > 0 && port <= 65535 => Some(port as Port),
+        _ => None,
+    }
+}
+
+/// Parses a command, such as `add: {"server_port": 8001, "password": "7cd308cc059"}`
+pub fn parse_command(s: &str) -> Result<Command, String> {
+    // Some clients terminate commands with NUL
+    let s = s.trim_right_matches('\0').trim();
+    let (name, args) = match s.find(':') {
+        Some(pos) => (s[..pos].trim(), Some(s[pos + 1..].trim())),
+        None => (s, None),
+    };
+
+    match name {
+        "ping" => return Ok(Command::Ping),
+        "stat" => return Ok(Command::Stat),
+        "add" | "remove" | "limit" => {},
+        _ => return Err(format!("unknown command `{}`", name)),
+    }
+
+    let args = match args.map(|args| json::Json::from_str(args)) {
+        Some(Ok(args)) => args,
+        Some(Err(err)) => return Err(format!("invalid arguments of `{}`: {:?}", name, err)),
+        None => return Err(format!("`{}` needs arguments", name)),
+    };
+    if name == "limit" {
+        return parse_limit(&args);
+    }
+
+    let port = match args.find("server_port").and_then(port_of) {
+        Some(port) => port,
+        None => return Err(format!("`server_port` of `{}` should be a port", name)),
+    };
+    if name == "remove" {
+        return Ok(Command::Remove(port));
+    }
+
+    let password = match args.find("password").and_then(|p| p.as_string()) {
+        Some(password) => password.to_string(),
+        None => return Err("`password` of `add` should be a string".to_string()),
+    };
+    let method = match args.find("method") {
+        Some(method) => match method.as_string().and_then(|m| m.parse::<CipherType>()) {
+            Some(method) => Some(method),
+            None => return Err(format!("`method` of `add` is not supported: {}", method)),
+        },
+        None => None,
+    };
+
+    Ok(Command::Add {
+        port: port,
+        password: password,
+        method: method,
+    })
+}
+
+fn parse_limit(args: &json::Json) -> Result<Command, String> {
+    let scope = match args.find("server_port") {
+        Some(port) => match port_of(port) {
+            Some(port) => LimitScope::Port(port),
+            None => return Err("`server_port` of `limit` should be a port".to_string()),
+        },
+        None if args.find("connection").and_then(|c| c.as_boolean()) == Some(true) => LimitScope::Connection,
+        None => LimitScope::Process,
+    };
+
+    let rate = |key: &str| match args.find(key) {
+        Some(rate) => match rate.as_u64() {
+            Some(rate) => Ok(Some(rate as usize)),
+            None => Err(format!("`{}` of `limit` should be bytes per second", key)),
+        },
+        None => Ok(None),
+    };
+    Ok(Command::Limit {
+        scope: scope,
+        upload: try!(rate("upload")),
+        download: try!(rate("download")),
+    })
+}
+
+/// Formats the traffic of ports as `stat: {"8001": 11370}`
+pub fn format_stat(traffic: &[(Port, usize)]) -> String {
+    let ports = traffic.iter().map(|&(port, bytes)| format!("\"{}\":{}", port, bytes)).collect::<Vec<String>>();
+    format!("stat: {{{}}}", ports.connect(","))
+}
+
+enum Socket {
+    Udp(UdpSocket),
+    Unix(UnixDatagram),
+}
+
+/// Sender of commands
+#[derive(Clone)]
+enum Peer {
+    Udp(SocketAddr),
+    Unix(Path),
+}
+
+impl Socket {
+    fn bind(addr: &ManagerAddress) -> IoResult<Socket> {
+        match *addr {
+            ManagerAddress::SocketAddress(ref addr) => Ok(Socket::Udp(try!(UdpSocket::bind(addr)))),
+            ManagerAddress::UnixSocket(ref path) => {
+                // Left by the last run
+                let _ = fs::unlink(path);
+                Ok(Socket::Unix(try!(UnixDatagram::bind(path))))
+            }
+        }
+    }
+
+    fn register(&self, event_loop: &mut EventLoop<Manager>) -> IoResult<()> {
+        match *self {
+            Socket::Udp(ref socket) => event_loop.register(socket, SOCKET_TOKEN, Interest::readable()),
+            Socket::Unix(ref socket) => event_loop.register(socket, SOCKET_TOKEN, Interest::readable()),
+        }
+    }
+
+    // Senders on Unix sockets without bound paths couldn't be answered
+    fn recv_from(&self, buf: &mut [u8]) -> IoResult<Option<(usize, Option<Peer>)>> {
+        match *self {
+            Socket::Udp(ref socket) => {
+                Ok(try!(socket.recv_from(buf)).map(|(n, addr)| (n, Some(Peer::Udp(addr)))))
+            },
+            Socket::Unix(ref socket) => {
+                Ok(try!(socket.recv_from(buf)).map(|(n, path)| (n, path.map(Peer::Unix))))
+            },
+        }
+    }
+
+    fn send_to(&self, data: &[u8], peer: &Peer) {
+        let result = match (self, peer) {
+            (&Socket::Udp(ref socket), &Peer::Udp(ref addr)) => socket.send_to(data, addr),
+            (&Socket::Unix(ref socket), &Peer::Unix(ref path)) => socket.send_to(data, path),
+            _ => return,
+        };
+        if let Err(err) = result {
+            error!("Failed to send to the manager: {}", err);
+        }
+    }
+}
+
+/// Handles commands of the manager, and reports traffic periodically
+pub struct Manager {
+    config: ManagerConfig,
+    server: RelayServer,
+    socket: Option<Socket>,
+    // Where traffic is reported to
+    reporter: Option<Peer>,
+    buf: Vec<u8>,
+}
+
+impl Manager {
+    /// Manages servers of `server`, which should be running
+    pub fn new(config: ManagerConfig, server: RelayServer) -> Manager {
+        Manager {
+            config: config,
+            server: server,
+            socket: None,
+            reporter: None,
+            buf: repeat(0u8).take(65536).collect(),
+        }
+    }
+
+    /// Receives commands until the event loop fails
+    pub fn run(mut self) -> IoResult<()> {
+        let mut event_loop = try!(EventLoop::new());
+        let socket = try!(Socket::bind(&self.config.addr));
+        try!(socket.register(&mut event_loop));
+        self.socket = Some(socket);
+        info!("Manager listening on {}", self.config.addr);
+
+        event_loop.timeout_ms(REPORT_TOKEN, self.config.report_interval);
+        event_loop.run(&mut self)
+    }
+
+    /// Handles a command and returns the answer
+    pub fn handle(&mut self, cmd: &str) -> String {
+        let cmd = match parse_command(cmd) {
+            Ok(cmd) => cmd,
+            Err(err) => {
+                error!("Invalid manager command: {}", err);
+                return "err".to_string();
+            }
+        };
+
+        match cmd {
+            Command::Add { port, password, method } => {
+                match self.add(port, password, method) {
+                    Ok(..) => {
+                        info!("Manager added server on port {}", port);
+                        "ok".to_string()
+                    },
+                    Err(err) => {
+                        error!("Manager failed to add server on port {}: {}", port, err);
+                        "err".to_string()
+                    }
+                }
+            },
+            Command::Remove(port) => {
+                // Removing a port which doesn't exist is not an error, like ss-manager
+                if self.server.stop_server(port) {
+                    info!("Manager removed server on port {}", port);
+                }
+                self.server.traffic().remove(port);
+                self.server.rate_limiter().remove(port);
+                "ok".to_string()
+            },
+            Command::Ping => "pong".to_string(),
+            Command::Stat => format_stat(self.server.traffic().totals().as_slice()),
+            Command::Limit { scope, upload, download } => {
+                self.limit(scope, upload, download);
+                info!("Manager changed rate limits of {:?}", scope);
+                "ok".to_string()
+            },
+        }
+    }
+
+    fn limit(&self, scope: LimitScope, upload: Option<usize>, download: Option<usize>) {
+        let rate_limiter = self.server.rate_limiter();
+        let port_buckets;
+        let buckets = match scope {
+            LimitScope::Process => rate_limiter.process(),
+            LimitScope::Connection => rate_limiter.connection(),
+            LimitScope::Port(port) => {
+                port_buckets = rate_limiter.port(port);
+                &*port_buckets
+            }
+        };
+
+        let rates = buckets.rates();
+        let merge = |rate: Option<usize>, old: Option<usize>| match rate {
+            Some(0) => None,
+            Some(rate) => Some(rate),
+            None => old,
+        };
+        buckets.set_rates(&RateLimitConfig {
+            upload: merge(upload, rates.upload),
+            download: merge(download, rates.download),
+        });
+    }
+
+    fn add(&mut self, port: Port, password: String, method: Option<CipherType>) -> Result<(), String> {
+        let method = match method.or(self.config.method) {
+            Some(method) => method,
+            None => return Err("`method` is required, there is no default method".to_string()),
+        };
+
+        let mut config = ServerConfig::new(self.config.server_addr.clone(), port, password, method);
+        config.timeout = self.config.timeout;
+        if let Err(err) = config.validate() {
+            return Err(format!("{:?}", err));
+        }
+        self.server.start_server(&config).map_err(|err| err.to_string())
+    }
+
+    fn report(&self) {
+        if let (&Some(ref socket), &Some(ref peer)) = (&self.socket, &self.reporter) {
+            let stat = format_stat(self.server.traffic().totals().as_slice());
+            socket.send_to(stat.as_bytes(), peer);
+        }
+    }
+}
+
+impl Handler for Manager {
+    type Message = ();
+
+    fn ready(&mut self, _: &mut EventLoop<Manager>, _: Token, _: Ready) {
+        loop {
+            let result = match self.socket {
+                Some(ref socket) => socket.recv_from(self.buf.as_mut_slice()),
+                None => return,
+            };
+            let (len, peer) = match result {
+                Ok(Some(r)) => r,
+                Ok(None) => break,
+                Err(err) => {
+                    error!("Manager failed to receive: {}", err);
+                    break;
+                }
+            };
+
+            let cmd = str::from_utf8(&self.buf[..len]).ok().map(|cmd| cmd.to_string());
+            let answer = match cmd {
+                Some(cmd) => self.handle(cmd.as_slice()),
+                None => "err".to_string(),
+            };
+
+            if let Some(peer) = peer {
+                if let Some(ref socket) = self.socket {
+                    socket.send_to(answer.as_bytes(), &peer);
+                }
+                self.reporter = Some(peer);
+            }
+        }
+    }
+
+    fn timeout(&mut self, event_loop: &mut EventLoop<Manager>, _: Token) {
+        self.report();
+        event_loop.timeout_ms(REPORT_TOKEN, self.config.report_interval);
+    }
+}
+
+#[cfg(test)]
+mod test_manager {
+    use relay::manager::{parse_command, format_stat, Command, LimitScope};
+
+    #[test]
+    fn test_parse_command() {
+        match parse_command("add: {\"server_port\": 8001, \"password\": \"7cd308cc059\"}\0") {
+            Ok(Command::Add { port, password, method }) => {
+                assert_eq!(port, 8001);
+                assert_eq!(password.as_slice(), "7cd308cc059");
+                assert!(method.is_none());
+            },
+            _ => panic!("`add` is not parsed"),
+        }
+
+        match parse_command("remove: {\"server_port\": \"8001\"}") {
+            Ok(Command::Remove(port)) => assert_eq!(port, 8001),
+            _ => panic!("`remove` is not parsed"),
+        }
+
+        assert!(match parse_command("ping") { Ok(Command::Ping) => true, _ => false });
+        assert!(match parse_command(" stat: {} ") { Ok(Command::Stat) => true, _ => false });
+
+        assert!(parse_command("add: {\"server_port\": 8001}").is_err());
+        assert!(parse_command("add: {\"server_port\": 70000, \"password\": \"p\"}").is_err());
+        assert!(parse_command("add: {\"server_port\": 8001, \"password\": \"p\", \"method\": \"rot13\"}").is_err());
+        assert!(parse_command("remove").is_err());
+        assert!(parse_command("list").is_err());
+    }
+
+    #[test]
+    fn test_parse_limit() {
+        match parse_command("limit: {\"server_port\": 8001, \"upload\": 1024, \"download\": 0}") {
+            Ok(Command::Limit { scope, upload, download }) => {
+                assert_eq!(scope, LimitScope::Port(8001));
+                assert_eq!(upload, Some(1024));
+                assert_eq!(download, Some(0));
+            },
+            _ => panic!("`limit` of a port is not parsed"),
+        }
+
+        match parse_command("limit: {\"connection\": true, \"download\": 2048}") {
+            Ok(Command::Limit { scope, upload, download }) => {
+                assert_eq!(scope, LimitScope::Connection);
+                assert_eq!(upload, None);
+                assert_eq!(download, Some(2048));
+            },
+            _ => panic!("`limit` of connections is not parsed"),
+        }
+
+        assert!(match parse_command("limit: {\"upload\": 1024}") {
+            Ok(Command::Limit { scope: LimitScope::Process, .. }) => true,
+            _ => false,
+        });
+        assert!(parse_command("limit: {\"upload\": \"fast\"}").is_err());
+        assert!(parse_command("limit").is_err());
+    }
+
+    #[test]
+    fn test_format_stat() {
+        assert_eq!(format_stat(&[]).as_slice(), "stat: {}");
+        assert_eq!(format_stat(&[(8001, 11370), (8002, 0)]).as_slice(), "stat: {\"8001\":11370,\"8002\":0}");
+    }
+}
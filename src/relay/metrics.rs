@@ -0,0 +1,397 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Metrics of `sslocal` and `ssserver` in the Prometheus text format
+//!
+//! Relays count connections, failures and cache lookups in a `Metrics` shared by all of them.
+//! `MetricsServer` serves these counters, together with traffic of ports, health of servers and
+//! counters of filters, to `GET /metrics` requests in a thread of its own:
+//!
+//! ```plain
+//! # HELP shadowsocks_active_connections TCP connections being relayed
+//! # TYPE shadowsocks_active_connections gauge
+//! shadowsocks_active_connections 12
+//! # HELP shadowsocks_bytes_total Bytes relayed by a port
+//! # TYPE shadowsocks_bytes_total counter
+//! shadowsocks_bytes_total{port="8388",direction="upload"} 11370
+//! ```
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::io::{IoResult, Listener, Acceptor};
+use std::io::net::tcp::{TcpListener, TcpStream};
+use std::io::net::ip::SocketAddr;
+use std::iter::repeat;
+use std::hash::Hash;
+
+use collect::LruCache;
+
+use relay::http::head_length;
+use relay::traffic::TrafficStats;
+use relay::replay_filter::ReplayFilter;
+use relay::outbound_filter::OutboundFilter;
+use relay::loadbalancing::server::HealthChecker;
+
+/// Milliseconds to wait for the request of a scraper
+const REQUEST_TIMEOUT: u64 = 5000;
+
+/// Caches whose evictions are counted
+#[derive(Copy, Debug, PartialEq, Eq)]
+pub enum Cache {
+    /// Resolved addresses of `CachedDns`
+    Dns,
+    /// Clients and targets of UDP relays
+    Udp,
+}
+
+/// Counters shared by all relays of a process
+pub struct Metrics {
+    connections: AtomicUsize,
+    active_connections: AtomicUsize,
+    handshake_failures: AtomicUsize,
+    decryption_failures: AtomicUsize,
+    dns_hits: AtomicUsize,
+    dns_misses: AtomicUsize,
+    udp_associations: AtomicUsize,
+    dns_evictions: AtomicUsize,
+    udp_evictions: AtomicUsize,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            connections: AtomicUsize::new(0),
+            active_connections: AtomicUsize::new(0),
+            handshake_failures: AtomicUsize::new(0),
+            decryption_failures: AtomicUsize::new(0),
+            dns_hits: AtomicUsize::new(0),
+            dns_misses: AtomicUsize::new(0),
+            udp_associations: AtomicUsize::new(0),
+            dns_evictions: AtomicUsize::new(0),
+            udp_evictions: AtomicUsize::new(0),
+        }
+    }
+
+    /// A client has failed the handshake, such as sending a malformed or replayed request
+    pub fn add_handshake_failure(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Data from the other end of a cipher has failed to be decrypted
+    pub fn add_decryption_failure(&self) {
+        self.decryption_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_dns_lookup(&self, hit: bool) {
+        let counter = if hit { &self.dns_hits } else { &self.dns_misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A UDP relay has seen a new pair of a client and a target
+    pub fn add_udp_association(&self) {
+        self.udp_associations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Inserts into `lru`, and counts the entry evicted to make room for it.
+    ///
+    /// Returns `true` if `k` hasn't been in `lru`.
+    pub fn insert_lru<K: Hash + Eq, V>(&self, cache: Cache, lru: &mut LruCache<K, V>, k: K, v: V) -> bool {
+        let full = lru.len() >= lru.capacity();
+        let inserted = lru.insert(k, v).is_none();
+        if inserted && full {
+            let evictions = match cache {
+                Cache::Dns => &self.dns_evictions,
+                Cache::Udp => &self.udp_evictions,
+            };
+            evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        inserted
+    }
+
+    /// TCP connections accepted since the start
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// TCP connections which haven't been closed
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+}
+
+/// An accepted TCP connection, which is active until it is dropped
+pub struct ActiveConnection {
+    metrics: Arc<Metrics>,
+}
+
+impl ActiveConnection {
+    pub fn new(metrics: Arc<Metrics>) -> ActiveConnection {
+        metrics.connections.fetch_add(1, Ordering::Relaxed);
+        metrics.active_connections.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection {
+            metrics: metrics,
+        }
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.metrics.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Label values are quoted, with backslashes, quotes and line feeds escaped
+fn escape_label(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Writes a metric family with its samples, each of them is labels such as `port="8388"`
+/// and a value
+fn write_family(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, u64)]) {
+    if samples.is_empty() {
+        return;
+    }
+
+    out.push_str(format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind).as_slice());
+    for &(ref labels, value) in samples.iter() {
+        if labels.is_empty() {
+            out.push_str(format!("{} {}\n", name, value).as_slice());
+        } else {
+            out.push_str(format!("{}{{{}}} {}\n", name, labels, value).as_slice());
+        }
+    }
+}
+
+fn write_single(out: &mut String, name: &str, kind: &str, help: &str, value: usize) {
+    write_family(out, name, kind, help, &[(String::new(), value as u64)]);
+}
+
+/// HTTP server of metrics, serving one scraper at a time
+pub struct MetricsServer {
+    addr: SocketAddr,
+    metrics: Arc<Metrics>,
+    traffic: Arc<TrafficStats>,
+    health_checker: Option<Arc<HealthChecker>>,
+    replay_filter: Option<Arc<ReplayFilter>>,
+    outbound_filter: Option<Arc<OutboundFilter>>,
+}
+
+impl MetricsServer {
+    pub fn new(addr: SocketAddr, metrics: Arc<Metrics>, traffic: Arc<TrafficStats>) -> MetricsServer {
+        MetricsServer {
+            addr: addr,
+            metrics: metrics,
+            traffic: traffic,
+            health_checker: None,
+            replay_filter: None,
+            outbound_filter: None,
+        }
+    }
+
+    /// Serves health of servers probed by `health_checker`
+    pub fn set_health_checker(&mut self, health_checker: Arc<HealthChecker>) {
+        self.health_checker = Some(health_checker);
+    }
+
+    /// Serves counters of filters of `ssserver`
+    pub fn set_filters(&mut self, replay_filter: Arc<ReplayFilter>, outbound_filter: Arc<OutboundFilter>) {
+        self.replay_filter = Some(replay_filter);
+        self.outbound_filter = Some(outbound_filter);
+    }
+
+    /// All metrics in the text format
+    pub fn render(&self) -> String {
+        let metrics = &*self.metrics;
+        let load = |counter: &AtomicUsize| counter.load(Ordering::Relaxed);
+        let mut out = String::new();
+
+        write_single(&mut out, "shadowsocks_connections_total", "counter",
+                     "TCP connections accepted", metrics.connections());
+        write_single(&mut out, "shadowsocks_active_connections", "gauge",
+                     "TCP connections being relayed", metrics.active_connections());
+        write_single(&mut out, "shadowsocks_handshake_failures_total", "counter",
+                     "Clients failed the handshake", load(&metrics.handshake_failures));
+        write_single(&mut out, "shadowsocks_decryption_failures_total", "counter",
+                     "Data failed to be decrypted", load(&metrics.decryption_failures));
+        write_single(&mut out, "shadowsocks_dns_cache_hits_total", "counter",
+                     "Domain names resolved from the cache", load(&metrics.dns_hits));
+        write_single(&mut out, "shadowsocks_dns_cache_misses_total", "counter",
+                     "Domain names not found in the cache", load(&metrics.dns_misses));
+        write_single(&mut out, "shadowsocks_udp_associations_total", "counter",
+                     "Pairs of UDP clients and targets", load(&metrics.udp_associations));
+        write_family(&mut out, "shadowsocks_lru_evictions_total", "counter", "Entries evicted from full caches",
+                     &[("cache=\"dns\"".to_string(), load(&metrics.dns_evictions) as u64),
+                       ("cache=\"udp\"".to_string(), load(&metrics.udp_evictions) as u64)]);
+
+        let mut bytes = Vec::new();
+        for (port, upload, download) in self.traffic.ports().into_iter() {
+            bytes.push((format!("port=\"{}\",direction=\"upload\"", port), upload as u64));
+            bytes.push((format!("port=\"{}\",direction=\"download\"", port), download as u64));
+        }
+        write_family(&mut out, "shadowsocks_bytes_total", "counter", "Bytes relayed by a port", bytes.as_slice());
+
+        if let Some(ref checker) = self.health_checker {
+            let stats = checker.stats();
+            let label = |server: &str| format!("server=\"{}\"", escape_label(server));
+            let up = stats.iter().map(|s| (label(s.server.as_slice()), s.healthy as u64)).collect::<Vec<_>>();
+            let rtt = stats.iter().filter_map(|s| s.rtt.map(|rtt| (label(s.server.as_slice()), rtt)))
+                                  .collect::<Vec<_>>();
+            let failures = stats.iter().map(|s| (label(s.server.as_slice()), s.failures)).collect::<Vec<_>>();
+            write_family(&mut out, "shadowsocks_server_up", "gauge",
+                         "Whether the server is healthy", up.as_slice());
+            write_family(&mut out, "shadowsocks_server_rtt_milliseconds", "gauge",
+                         "Smoothed round-trip time of health checks", rtt.as_slice());
+            write_family(&mut out, "shadowsocks_server_failures_total", "counter",
+                         "Failed health checks and connections", failures.as_slice());
+        }
+
+        if let Some(ref filter) = self.replay_filter {
+            write_single(&mut out, "shadowsocks_replay_filter_checks_total", "counter",
+                         "IVs and salts checked for replays", filter.checked());
+            write_single(&mut out, "shadowsocks_replay_filter_hits_total", "counter",
+                         "Replayed requests rejected", filter.hits());
+        }
+        if let Some(ref filter) = self.outbound_filter {
+            write_single(&mut out, "shadowsocks_outbound_denied_total", "counter",
+                         "Connections to refused targets", filter.denied());
+        }
+        out
+    }
+
+    /// Serves requests until it fails to listen, it never returns otherwise
+    pub fn run(&self) -> IoResult<()> {
+        let mut acceptor = try!(try!(TcpListener::bind(self.addr)).listen());
+        info!("Serving metrics on http://{}/metrics", self.addr);
+        for stream in acceptor.incoming() {
+            let result = match stream {
+                Ok(stream) => self.serve(stream),
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                debug!("Failed to serve metrics: {}", err);
+            }
+        }
+        Ok(())
+    }
+
+    fn serve(&self, mut stream: TcpStream) -> IoResult<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT));
+
+        let mut buf: Vec<u8> = repeat(0u8).take(4096).collect();
+        let mut head = Vec::new();
+        while head_length(head.as_slice()).is_none() && head.len() < buf.len() {
+            let n = try!(stream.read(buf.as_mut_slice()));
+            head.push_all(&buf[..n]);
+        }
+
+        let response = if head.starts_with(b"GET /metrics ") || head.starts_with(b"GET /metrics?") {
+            let body = self.render();
+            format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+        } else {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+        };
+        stream.write(response.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test_metrics {
+    use std::sync::Arc;
+
+    use collect::LruCache;
+
+    use relay::metrics::{Metrics, MetricsServer, ActiveConnection, Cache, escape_label};
+    use relay::traffic::TrafficStats;
+
+    fn server(metrics: Arc<Metrics>, traffic: Arc<TrafficStats>) -> MetricsServer {
+        MetricsServer::new("127.0.0.1:9100".parse().unwrap(), metrics, traffic)
+    }
+
+    #[test]
+    fn test_active_connections() {
+        let metrics = Arc::new(Metrics::new());
+        let first = ActiveConnection::new(metrics.clone());
+        {
+            let _second = ActiveConnection::new(metrics.clone());
+            assert_eq!(metrics.active_connections(), 2);
+        }
+        assert_eq!(metrics.active_connections(), 1);
+        drop(first);
+        assert_eq!(metrics.active_connections(), 0);
+        assert_eq!(metrics.connections(), 2);
+    }
+
+    #[test]
+    fn test_lru_evictions() {
+        let metrics = Metrics::new();
+        let mut lru = LruCache::new(2);
+        assert!(metrics.insert_lru(Cache::Udp, &mut lru, 1us, "a"));
+        assert!(metrics.insert_lru(Cache::Udp, &mut lru, 2us, "b"));
+        assert!(!metrics.insert_lru(Cache::Udp, &mut lru, 2us, "c"));
+        assert!(metrics.insert_lru(Cache::Udp, &mut lru, 3us, "d"));
+
+        let text = server(Arc::new(metrics), Arc::new(TrafficStats::new())).render();
+        assert!(text.contains("shadowsocks_lru_evictions_total{cache=\"udp\"} 1\n"));
+        assert!(text.contains("shadowsocks_lru_evictions_total{cache=\"dns\"} 0\n"));
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.add_handshake_failure();
+        metrics.add_dns_lookup(true);
+        metrics.add_dns_lookup(false);
+        metrics.add_dns_lookup(false);
+        let traffic = Arc::new(TrafficStats::new());
+        traffic.port(8388).add_upload(11370);
+        traffic.port(8388).add_download(42);
+
+        let _conn = ActiveConnection::new(metrics.clone());
+        let text = server(metrics, traffic).render();
+        assert!(text.contains("# TYPE shadowsocks_active_connections gauge\nshadowsocks_active_connections 1\n"));
+        assert!(text.contains("# TYPE shadowsocks_connections_total counter\n"));
+        assert!(text.contains("shadowsocks_handshake_failures_total 1\n"));
+        assert!(text.contains("shadowsocks_decryption_failures_total 0\n"));
+        assert!(text.contains("shadowsocks_dns_cache_hits_total 1\n"));
+        assert!(text.contains("shadowsocks_dns_cache_misses_total 2\n"));
+        assert!(text.contains("shadowsocks_bytes_total{port=\"8388\",direction=\"upload\"} 11370\n"));
+        assert!(text.contains("shadowsocks_bytes_total{port=\"8388\",direction=\"download\"} 42\n"));
+        // Absent without a health checker or filters
+        assert!(!text.contains("shadowsocks_server_up"));
+        assert!(!text.contains("shadowsocks_replay_filter"));
+    }
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape_label("example.com:8388").as_slice(), "example.com:8388");
+        assert_eq!(escape_label("a\"b\\c\nd").as_slice(), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -23,15 +23,46 @@
 
 pub use self::local::RelayLocal;
 pub use self::server::RelayServer;
+pub use self::shutdown::ShutdownHandle;
 
 mod tcprelay;
 #[cfg(feature = "enable-udp")]
 mod udprelay;
+mod dnsrelay;
 pub mod local;
 pub mod server;
-mod loadbalancing;
+pub mod loadbalancing;
+#[cfg(target_os = "linux")]
+mod eventloop;
+// Relays run on the epoll event loop, the build fails with this message on other systems
+#[cfg(not(target_os = "linux"))]
+mod eventloop {
+    relays_run_on_epoll_which_is_only_available_on_linux!();
+}
+mod cached_dns;
+mod parse;
+mod http;
+mod association;
+pub mod socks4;
 pub mod socks5;
+pub mod replay_filter;
+pub mod acl;
+pub mod outbound_filter;
+pub mod users;
+pub mod traffic;
+pub mod manager;
+pub mod ratelimit;
+pub mod metrics;
+pub mod signal;
+pub mod reload;
+pub mod shutdown;
+pub mod plugin;
 
 pub trait Relay {
     fn run(&self);
+
+    /// Stops accepting connections, and lets `run` return after established connections have been
+    /// finished or `drain_timeout` milliseconds have passed. It could be called from any thread,
+    /// before or after `run`.
+    fn shutdown(&self, drain_timeout: u64);
 }
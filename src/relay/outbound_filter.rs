@@ -0,0 +1,221 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! Outbound filter of servers
+//!
+//! Servers connect to whatever targets clients send, so anyone knowing a password could reach
+//! services which only listen on the server's own network, such as `127.0.0.1` or the metadata
+//! service of cloud providers at `169.254.169.254`. Unless disabled, targets in loopback, private,
+//! link-local, shared, multicast and reserved ranges are refused. Names are checked after they
+//! have been resolved, so names resolving to such addresses are refused too.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::io::net::ip::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use relay::socks5::Address;
+use relay::acl::AccessControl;
+
+/// Whether `ip` is an address which servers should not connect to for clients
+pub fn is_private(ip: &IpAddr) -> bool {
+    match *ip {
+        Ipv4Addr(a, b, _, _) => {
+            a == 0                                   // 0.0.0.0/8, this network
+            || a == 10                               // 10.0.0.0/8, private
+            || (a == 100 && b & 0xc0 == 64)          // 100.64.0.0/10, shared address space
+            || a == 127                              // 127.0.0.0/8, loopback
+            || (a == 169 && b == 254)                // 169.254.0.0/16, link-local and metadata services
+            || (a == 172 && b & 0xf0 == 16)          // 172.16.0.0/12, private
+            || (a == 192 && b == 168)                // 192.168.0.0/16, private
+            || (a == 198 && b & 0xfe == 18)          // 198.18.0.0/15, benchmarking
+            || a >= 224                              // multicast, reserved and broadcast
+        },
+        Ipv6Addr(a, b, c, d, e, f, g, h) => {
+            match (a, b, c, d, e, f) {
+                // IPv4-compatible addresses, including the unspecified and loopback addresses, IPv4-mapped
+                // addresses, and the NAT64 prefix 64:ff9b::/96
+                (0, 0, 0, 0, 0, 0) | (0, 0, 0, 0, 0, 0xffff) | (0x64, 0xff9b, 0, 0, 0, 0) => {
+                    is_private(&embedded_ipv4(g, h))
+                },
+                // 6to4, 2002::/16 with the IPv4 address following the prefix
+                (0x2002, _, _, _, _, _) => is_private(&embedded_ipv4(b, c)),
+                // Teredo, 2001::/32 with the inverted IPv4 address of the client at the end
+                (0x2001, 0, _, _, _, _) => is_private(&embedded_ipv4(!g, !h)),
+                _ => {
+                    a & 0xfe00 == 0xfc00                 // fc00::/7, unique local
+                    || a & 0xffc0 == 0xfe80              // fe80::/10, link-local
+                    || a & 0xff00 == 0xff00              // ff00::/8, multicast
+                }
+            }
+        }
+    }
+}
+
+// The IPv4 address in two segments of an IPv6 address
+fn embedded_ipv4(hi: u16, lo: u16) -> IpAddr {
+    Ipv4Addr((hi >> 8) as u8, hi as u8, (lo >> 8) as u8, lo as u8)
+}
+
+/// Decides which targets servers refuse to connect to, shared by all relays of a server
+pub struct OutboundFilter {
+    block_private: bool,
+    acl: Option<Arc<AccessControl>>,
+    denied: AtomicUsize,
+}
+
+impl OutboundFilter {
+    /// Creates a filter refusing private addresses if `block_private` is set, and addresses in the
+    /// outbound block list of `acl`
+    pub fn new(block_private: bool, acl: Option<Arc<AccessControl>>) -> OutboundFilter {
+        OutboundFilter {
+            block_private: block_private,
+            acl: acl,
+            denied: AtomicUsize::new(0),
+        }
+    }
+
+    fn is_allowed(&self, addr: &Address) -> bool {
+        if let Address::SocketAddress(ref ip, _) = *addr {
+            if self.block_private && is_private(ip) {
+                return false;
+            }
+        }
+        !self.acl.as_ref().map_or(false, |acl| acl.check_outbound_blocked(addr))
+    }
+
+    fn deny(&self, addr: &Address) {
+        let denied = self.denied.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!("Refused to connect to {}, {} targets have been refused", addr, denied);
+    }
+
+    /// Checks the target sent by a client. Names are only checked by the ACL here, and should be
+    /// checked again by `filter_resolved` after they have been resolved.
+    ///
+    /// Returns `false` if the target is refused.
+    pub fn check(&self, addr: &Address) -> bool {
+        if self.is_allowed(addr) {
+            true
+        } else {
+            self.deny(addr);
+            false
+        }
+    }
+
+    /// Removes refused addresses from `ips`, which `addr` has been resolved to
+    pub fn filter_resolved(&self, addr: &Address, ips: Vec<IpAddr>) -> Vec<IpAddr> {
+        let port = match *addr {
+            Address::SocketAddress(_, port) | Address::DomainNameAddress(_, port) => port,
+        };
+
+        let total = ips.len();
+        let allowed: Vec<IpAddr> = ips.into_iter().filter(|ip| {
+            self.is_allowed(&Address::SocketAddress(*ip, port))
+        }).collect();
+
+        if total > 0 && allowed.is_empty() {
+            self.deny(addr);
+        } else if allowed.len() < total {
+            debug!("{} of {} addresses of {} are refused", total - allowed.len(), total, addr);
+        }
+        allowed
+    }
+
+    /// Number of targets which have been refused
+    pub fn denied(&self) -> usize {
+        self.denied.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test_outbound_filter {
+    use std::sync::Arc;
+    use std::io::net::ip::{IpAddr, Ipv4Addr};
+
+    use relay::socks5::Address;
+    use relay::acl::AccessControl;
+    use relay::outbound_filter::{OutboundFilter, is_private};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_is_private() {
+        for s in ["0.0.0.0", "10.1.2.3", "100.100.100.200", "127.0.0.1", "169.254.169.254", "172.31.0.1",
+                  "192.168.1.1", "198.19.0.1", "224.0.0.1", "255.255.255.255",
+                  "::", "::1", "::ffff:127.0.0.1", "64:ff9b::a00:1", "fd00:ec2::254", "fe80::1", "ff02::1"].iter() {
+            assert!(is_private(&ip(*s)), "{} should be private", s);
+        }
+
+        for s in ["8.8.8.8", "100.128.0.1", "172.32.0.1", "192.169.0.1", "::ffff:8.8.8.8", "2001:4860::8888"].iter() {
+            assert!(!is_private(&ip(*s)), "{} should not be private", s);
+        }
+    }
+
+    #[test]
+    fn test_is_private_embedded_ipv4() {
+        // IPv4-compatible, 6to4 and Teredo addresses of 127.0.0.1, 10.0.0.1 and 192.168.1.1
+        for s in ["::127.0.0.1", "::10.0.0.1", "2002:7f00:1::1", "2002:a00:1:1::", "2002:c0a8:101::1",
+                  "2001:0:4136:e378:8000:63bf:80ff:fffe", "2001:0:4136:e378:8000:63bf:f5ff:fffe",
+                  "2001:0:4136:e378:8000:63bf:3f57:fefe"].iter() {
+            assert!(is_private(&ip(*s)), "{} should be private", s);
+        }
+
+        // The same of 8.8.8.8
+        for s in ["::8.8.8.8", "2002:808:808::1", "2001:0:4136:e378:8000:63bf:f7f7:f7f7",
+                  "2001:db8::8"].iter() {
+            assert!(!is_private(&ip(*s)), "{} should not be private", s);
+        }
+    }
+
+    #[test]
+    fn test_outbound_filter() {
+        let filter = OutboundFilter::new(true, None);
+        assert!(!filter.check(&Address::SocketAddress(ip("127.0.0.1"), 80)));
+        assert!(filter.check(&Address::SocketAddress(ip("8.8.8.8"), 53)));
+
+        // Names are checked after resolving
+        let addr = Address::DomainNameAddress("rebind.example.com".to_string(), 80);
+        assert!(filter.check(&addr));
+        assert!(filter.filter_resolved(&addr, vec![ip("169.254.169.254")]).is_empty());
+        assert_eq!(filter.filter_resolved(&addr, vec![ip("10.0.0.1"), ip("93.184.216.34")]),
+                   vec![ip("93.184.216.34")]);
+        assert_eq!(filter.denied(), 2);
+
+        let filter = OutboundFilter::new(false, None);
+        assert!(filter.check(&Address::SocketAddress(Ipv4Addr(127, 0, 0, 1), 80)));
+        assert_eq!(filter.denied(), 0);
+    }
+
+    #[test]
+    fn test_outbound_filter_acl() {
+        let acl = AccessControl::parse("[outbound_block_list]\n1.2.3.0/24\n||blocked.com\n").unwrap();
+        let filter = OutboundFilter::new(false, Some(Arc::new(acl)));
+        assert!(!filter.check(&Address::SocketAddress(ip("1.2.3.4"), 80)));
+        assert!(!filter.check(&Address::DomainNameAddress("www.blocked.com".to_string(), 80)));
+
+        let addr = Address::DomainNameAddress("example.com".to_string(), 80);
+        assert!(filter.check(&addr));
+        assert!(filter.filter_resolved(&addr, vec![ip("1.2.3.5")]).is_empty());
+        assert_eq!(filter.denied(), 3);
+    }
+}
@@ -0,0 +1,131 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! Parsing messages out of partially received data
+//!
+//! Sockets in the event loop deliver data in arbitrary pieces. Instead of rewriting every
+//! parser as a state machine, the blocking parsers (such as `socks5::Address::read_from`) are
+//! run on all data received so far, and the result is thrown away if the parser has tried
+//! to read beyond it. The parser will be run again after more data arrives.
+
+use std::io::{IoResult, IoError, EndOfFile};
+use std::cmp;
+use std::slice;
+
+/// Reader over received data, which remembers whether it has run out of data
+pub struct PartialReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    exhausted: bool,
+}
+
+impl<'a> PartialReader<'a> {
+    pub fn new(buf: &'a [u8]) -> PartialReader<'a> {
+        PartialReader {
+            buf: buf,
+            pos: 0,
+            exhausted: false,
+        }
+    }
+
+    /// Whether a read has been cut short by the end of data
+    pub fn is_exhausted(&self) -> bool {
+        self.exhausted
+    }
+
+    /// Number of bytes read
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+}
+
+impl<'a> Reader for PartialReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if self.pos == self.buf.len() {
+            self.exhausted = true;
+            return Err(IoError {
+                kind: EndOfFile,
+                desc: "Message is incomplete",
+                detail: None,
+            });
+        }
+
+        let nread = cmp::min(buf.len(), self.buf.len() - self.pos);
+        if nread < buf.len() {
+            self.exhausted = true;
+        }
+        slice::bytes::copy_memory(buf, &self.buf[self.pos..self.pos + nread]);
+        self.pos += nread;
+        Ok(nread)
+    }
+}
+
+/// Runs `parser` on `buf`.
+///
+/// Returns `None` if `buf` does not contain the whole message yet, otherwise the result
+/// of `parser` and the number of bytes it has consumed.
+pub fn parse_partial<T, F>(buf: &[u8], parser: F) -> Option<(T, usize)>
+        where F: FnOnce(&mut PartialReader) -> T {
+    let mut reader = PartialReader::new(buf);
+    let result = parser(&mut reader);
+    if reader.is_exhausted() {
+        None
+    } else {
+        Some((result, reader.position()))
+    }
+}
+
+#[cfg(test)]
+mod test_parse {
+    use std::io::net::ip::Ipv4Addr;
+
+    use relay::parse::parse_partial;
+    use relay::socks5::{Address, TcpRequestHeader, Command};
+
+    #[test]
+    fn test_parse_partial() {
+        let header = TcpRequestHeader::new(Command::TcpConnect,
+                                           Address::DomainNameAddress("example.com".to_string(), 443));
+        let mut buf = Vec::new();
+        header.write_to(&mut buf).unwrap();
+        buf.push_all(b"payload");
+
+        for len in range(0, header.len()) {
+            assert!(parse_partial(&buf[..len], |r| TcpRequestHeader::read_from(r)).is_none());
+        }
+
+        let (parsed, consumed) = parse_partial(buf.as_slice(), |r| TcpRequestHeader::read_from(r)).unwrap();
+        assert_eq!(consumed, header.len());
+        match parsed.unwrap().address {
+            Address::DomainNameAddress(ref name, port) => {
+                assert_eq!(name.as_slice(), "example.com");
+                assert_eq!(port, 443);
+            },
+            _ => panic!("Unexpected address"),
+        }
+
+        let v4 = [1u8, 127, 0, 0, 1, 0x1f, 0x90];
+        let (addr, consumed) = parse_partial(&v4, |r| Address::read_from(r)).unwrap();
+        assert_eq!(consumed, v4.len());
+        assert_eq!(addr.unwrap(), Address::SocketAddress(Ipv4Addr(127, 0, 0, 1), 8080));
+    }
+}
@@ -0,0 +1,450 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! SIP003 plugins
+//!
+//! A server could be wrapped by a plugin, which is a separate process obfuscating the TCP
+//! traffic between `sslocal` and `ssserver`, such as `simple-obfs` or `v2ray-plugin`. The plugin
+//! of `sslocal` listens on a free loopback port, which relays connect to instead of the server,
+//! and talks to the server. The plugin of `ssserver` listens on the address of the server, and
+//! talks to the relay listening on a free loopback port. UDP is relayed without plugins.
+//!
+//! Plugins are told both addresses by `SS_REMOTE_HOST`, `SS_REMOTE_PORT`, `SS_LOCAL_HOST` and
+//! `SS_LOCAL_PORT`, and their options by `SS_PLUGIN_OPTIONS`. A plugin which exits is restarted
+//! with the same addresses, and plugins are killed by a `StopGuard` before `sslocal` and
+//! `ssserver` exit, even if they exit by a panic.
+
+extern crate libc;
+
+use std::collections::HashMap;
+use std::io::{IoResult, IoError, OtherIoError};
+use std::io::net::ip::{SocketAddr, Ipv4Addr, Port};
+use std::io::net::tcp::TcpListener;
+use std::io::process::{Command, Process, StdioContainer, PleaseExitSignal};
+use std::io::timer::sleep;
+use std::sync::{Arc, Mutex};
+use std::thread::Thread;
+use std::time::Duration;
+
+use config::ServerConfig;
+
+/// Milliseconds before restarting a plugin which has exited
+const RESTART_DELAY: i64 = 1000;
+
+#[inline]
+fn make_io_error(desc: &'static str, detail: Option<String>) -> IoError {
+    IoError {
+        kind: OtherIoError,
+        desc: desc,
+        detail: detail,
+    }
+}
+
+// A loopback address which is free now, it is bound by the plugin or the relay later
+fn free_local_addr() -> IoResult<SocketAddr> {
+    let mut listener = try!(TcpListener::bind(SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 0 }));
+    listener.socket_name()
+}
+
+/// The plugin process of a server, which is restarted by its supervisor until it is stopped
+struct Plugin {
+    plugin: String,
+    plugin_opts: Option<String>,
+    remote_host: String,
+    remote_port: Port,
+    local_addr: SocketAddr,
+    state: Mutex<State>,
+}
+
+struct State {
+    // Id of the running process
+    pid: Option<libc::pid_t>,
+    stopped: bool,
+}
+
+impl Plugin {
+    // Whether this is the plugin of `server`, with the same options
+    fn serves(&self, server: &ServerConfig) -> bool {
+        self.remote_host == server.addr && self.remote_port == server.port
+            && server.plugin.as_ref() == Some(&self.plugin) && server.plugin_opts == self.plugin_opts
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(self.plugin.as_slice());
+        command.env("SS_REMOTE_HOST", self.remote_host.as_slice())
+               .env("SS_REMOTE_PORT", self.remote_port.to_string())
+               .env("SS_LOCAL_HOST", self.local_addr.ip.to_string())
+               .env("SS_LOCAL_PORT", self.local_addr.port.to_string())
+               .stdin(StdioContainer::Ignored)
+               .stdout(StdioContainer::InheritFd(libc::STDOUT_FILENO))
+               .stderr(StdioContainer::InheritFd(libc::STDERR_FILENO))
+               // In its own process group, SIGINT of the terminal is left to `stop`
+               .detached();
+        if let Some(ref opts) = self.plugin_opts {
+            command.env("SS_PLUGIN_OPTIONS", opts.as_slice());
+        }
+        command
+    }
+
+    // Runs the plugin again whenever it exits, until it is stopped
+    fn supervise(&self) {
+        loop {
+            match self.command().spawn() {
+                Ok(process) => self.wait(process),
+                Err(err) => error!("Failed to start plugin {}: {}", self.plugin, err),
+            }
+
+            if self.state.lock().unwrap().stopped {
+                return;
+            }
+            sleep(Duration::milliseconds(RESTART_DELAY));
+        }
+    }
+
+    // The process could be killed by `stop` while it is waited for
+    fn wait(&self, mut process: Process) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.stopped {
+                // The process is waited for when it is dropped
+                let _ = process.signal_exit();
+                return;
+            }
+            state.pid = Some(process.id());
+        }
+        info!("Started plugin {} of server {}:{} on {}", self.plugin, self.remote_host, self.remote_port,
+              self.local_addr);
+
+        let status = process.wait();
+        let mut state = self.state.lock().unwrap();
+        state.pid = None;
+        if !state.stopped {
+            match status {
+                Ok(status) => error!("Plugin {} exited with {}, restarting it", self.plugin, status),
+                Err(err) => error!("Failed to wait for plugin {}: {}", self.plugin, err),
+            }
+        }
+    }
+
+    fn stop(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.stopped = true;
+        if let Some(pid) = state.pid {
+            if let Err(err) = Process::kill(pid, PleaseExitSignal) {
+                error!("Failed to kill plugin {}: {}", self.plugin, err);
+            }
+        }
+    }
+}
+
+/// Running plugins of servers, keyed by addresses and ports of the servers
+pub struct Plugins {
+    running: Mutex<HashMap<(String, Port), Arc<Plugin>>>,
+}
+
+impl Plugins {
+    pub fn new() -> Plugins {
+        Plugins {
+            running: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Starts the plugin of `server` in a detached thread supervising it, and returns the free
+    /// loopback address given to the plugin as its local address. A running plugin of the server
+    /// is stopped.
+    pub fn start(&self, server: &ServerConfig) -> IoResult<SocketAddr> {
+        let name = match server.plugin {
+            Some(ref plugin) => plugin.clone(),
+            None => return Err(make_io_error("The server has no plugin",
+                                             Some(format!("{}:{}", server.addr, server.port)))),
+        };
+
+        let plugin = Arc::new(Plugin {
+            plugin: name,
+            plugin_opts: server.plugin_opts.clone(),
+            remote_host: server.addr.clone(),
+            remote_port: server.port,
+            local_addr: try!(free_local_addr()),
+            state: Mutex::new(State {
+                pid: None,
+                stopped: false,
+            }),
+        });
+        let local_addr = plugin.local_addr;
+
+        let supervised = plugin.clone();
+        Thread::spawn(move || supervised.supervise());
+        if let Some(old) = self.running.lock().unwrap().insert((server.addr.clone(), server.port), plugin) {
+            old.stop();
+        }
+        Ok(local_addr)
+    }
+
+    /// Kills the plugin of the server at `addr:port`, returns `false` if there is none
+    pub fn stop(&self, addr: &str, port: Port) -> bool {
+        match self.running.lock().unwrap().remove(&(addr.to_string(), port)) {
+            Some(plugin) => {
+                plugin.stop();
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Starts plugins of `servers` which are not running with the same options, and stops plugins
+    /// of other servers
+    pub fn set_servers(&self, servers: &[ServerConfig]) {
+        let stale = self.running.lock().unwrap().iter()
+                        .filter(|&(_, plugin)| !servers.iter().any(|s| plugin.serves(s)))
+                        .map(|(key, _)| key.clone()).collect::<Vec<(String, Port)>>();
+        for &(ref addr, port) in stale.iter() {
+            self.stop(addr.as_slice(), port);
+        }
+
+        for server in servers.iter().filter(|s| s.plugin.is_some()) {
+            if self.running.lock().unwrap().contains_key(&(server.addr.clone(), server.port)) {
+                continue;
+            }
+            if let Err(err) = self.start(server) {
+                error!("Failed to start plugin of server {}:{}: {}", server.addr, server.port, err);
+            }
+        }
+    }
+
+    /// Kills all plugins, before exiting
+    pub fn stop_all(&self) {
+        for (_, plugin) in self.running.lock().unwrap().drain() {
+            plugin.stop();
+        }
+    }
+
+    // Id of the running process of the plugin of the server at `addr:port`
+    #[cfg(test)]
+    fn pid(&self, addr: &str, port: Port) -> Option<libc::pid_t> {
+        self.running.lock().unwrap().get(&(addr.to_string(), port)).and_then(|p| p.state.lock().unwrap().pid)
+    }
+
+    /// The local address of the plugin which TCP connections to `server` go through, `None` if
+    /// the server has no plugin. It fails if the plugin of the server is not running.
+    pub fn tcp_addr(&self, server: &ServerConfig) -> IoResult<Option<SocketAddr>> {
+        if server.plugin.is_none() {
+            return Ok(None);
+        }
+
+        match self.running.lock().unwrap().get(&(server.addr.clone(), server.port)) {
+            Some(plugin) if plugin.serves(server) => Ok(Some(plugin.local_addr)),
+            _ => Err(make_io_error("The plugin of the server is not running",
+                                   Some(format!("{}:{}", server.addr, server.port)))),
+        }
+    }
+}
+
+/// Kills all plugins when it is dropped, also while unwinding a panic, so that no plugin is left
+/// running after exiting
+pub struct StopGuard {
+    plugins: Arc<Plugins>,
+}
+
+impl StopGuard {
+    pub fn new(plugins: Arc<Plugins>) -> StopGuard {
+        StopGuard {
+            plugins: plugins,
+        }
+    }
+}
+
+impl Drop for StopGuard {
+    fn drop(&mut self) {
+        self.plugins.stop_all();
+    }
+}
+
+#[cfg(test)]
+mod test_plugin {
+    use std::io::{File, TempDir, Listener, Acceptor, USER_RWX};
+    use std::io::fs::{self, PathExtensions};
+    use std::io::net::ip::{SocketAddr, Port};
+    use std::io::net::tcp::{TcpListener, TcpStream};
+    use std::io::process::{Process, MustDieSignal};
+    use std::io::timer::sleep;
+    use std::os;
+    use std::thread::Thread;
+    use std::time::Duration;
+
+    use config::ServerConfig;
+    use crypto::cipher::CipherType;
+    use relay::plugin::Plugins;
+    use super::libc;
+
+    fn server(plugin: Option<String>) -> ServerConfig {
+        let mut server = ServerConfig::new("127.0.0.1".to_string(), 8388, "server-password".to_string(),
+                                           CipherType::Aes256Cfb);
+        server.plugin = plugin;
+        server.plugin_opts = Some("mode=passthrough".to_string());
+        server
+    }
+
+    #[test]
+    fn test_tcp_addr() {
+        let plugins = Plugins::new();
+        assert_eq!(plugins.tcp_addr(&server(None)).unwrap(), None);
+        assert!(plugins.tcp_addr(&server(Some("obfs-local".to_string()))).is_err());
+    }
+
+    #[test]
+    fn test_start_plugin() {
+        let dir = TempDir::new("shadowsocks-plugin").unwrap();
+        let output = dir.path().join("env");
+        let script = dir.path().join("plugin.sh");
+        File::create(&script).write_str(format!(
+            "#!/bin/sh\necho \"$SS_REMOTE_HOST $SS_REMOTE_PORT $SS_LOCAL_HOST $SS_LOCAL_PORT $SS_PLUGIN_OPTIONS\" \
+             > {}\n", output.display()).as_slice()).unwrap();
+        fs::chmod(&script, USER_RWX).unwrap();
+
+        let plugins = Plugins::new();
+        let server = server(Some(script.display().to_string()));
+        let local_addr = plugins.start(&server).unwrap();
+        assert_eq!(plugins.tcp_addr(&server).unwrap(), Some(local_addr));
+
+        for _ in range(0, 50) {
+            if output.exists() {
+                break;
+            }
+            sleep(Duration::milliseconds(100));
+        }
+        let env = File::open(&output).read_to_string().unwrap();
+        assert_eq!(env.as_slice().trim(),
+                   format!("127.0.0.1 8388 127.0.0.1 {} mode=passthrough", local_addr.port).as_slice());
+
+        assert!(plugins.stop("127.0.0.1", 8388));
+        assert!(plugins.tcp_addr(&server).is_err());
+        plugins.stop_all();
+    }
+
+    // Echoes what is received by each connection, returns the port it listens on
+    fn echo_server() -> Port {
+        let mut listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.socket_name().unwrap().port;
+        let mut acceptor = listener.listen().unwrap();
+        Thread::spawn(move || {
+            for stream in acceptor.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buf = [0u8; 1024];
+                while let Ok(n) = stream.read(buf.as_mut_slice()) {
+                    stream.write(&buf[..n]).unwrap();
+                }
+            }
+        });
+        port
+    }
+
+    // Sends `msg` through the plugin listening on `addr`, which could still be starting
+    fn relay(addr: SocketAddr, msg: &[u8]) -> Vec<u8> {
+        for _ in range(0, 50) {
+            if let Ok(mut stream) = TcpStream::connect(addr) {
+                stream.write(msg).unwrap();
+                return stream.read_exact(msg.len()).unwrap();
+            }
+            sleep(Duration::milliseconds(100));
+        }
+        panic!("The plugin is not listening on {}", addr);
+    }
+
+    // Waits for a process of the plugin other than `previous`
+    fn running_pid(plugins: &Plugins, port: Port, previous: Option<libc::pid_t>) -> libc::pid_t {
+        for _ in range(0, 50) {
+            match plugins.pid("127.0.0.1", port) {
+                Some(pid) if Some(pid) != previous => return pid,
+                _ => sleep(Duration::milliseconds(100)),
+            }
+        }
+        panic!("The plugin is not restarted");
+    }
+
+    fn copy(from: &mut TcpStream, to: &mut TcpStream) {
+        let mut buf = [0u8; 1024];
+        while let Ok(n) = from.read(buf.as_mut_slice()) {
+            if to.write(&buf[..n]).is_err() {
+                break;
+            }
+        }
+    }
+
+    // The plugin of `test_supervise_plugin`, run by the test binary itself. It relays one connection
+    // from its local address to its remote address, and exits after that.
+    #[test]
+    #[ignore]
+    fn passthrough_helper() {
+        let (local, remote) = match (os::getenv("SS_LOCAL_HOST"), os::getenv("SS_LOCAL_PORT"),
+                                     os::getenv("SS_REMOTE_HOST"), os::getenv("SS_REMOTE_PORT")) {
+            (Some(lhost), Some(lport), Some(rhost), Some(rport)) =>
+                (format!("{}:{}", lhost, lport), format!("{}:{}", rhost, rport)),
+            // Not started as a plugin
+            _ => return,
+        };
+
+        let mut acceptor = TcpListener::bind(local.as_slice()).unwrap().listen().unwrap();
+        let mut client = acceptor.accept().unwrap();
+        let mut remote = TcpStream::connect(remote.as_slice()).unwrap();
+        let (mut client_reader, mut remote_writer) = (client.clone(), remote.clone());
+        Thread::spawn(move || {
+            copy(&mut client_reader, &mut remote_writer);
+            let _ = remote_writer.close_write();
+        });
+        copy(&mut remote, &mut client);
+    }
+
+    #[test]
+    fn test_supervise_plugin() {
+        let dir = TempDir::new("shadowsocks-plugin").unwrap();
+        let script = dir.path().join("passthrough.sh");
+        File::create(&script).write_str(format!("#!/bin/sh\nexec {} --ignored passthrough_helper\n",
+                                                os::self_exe_name().unwrap().display()).as_slice()).unwrap();
+        fs::chmod(&script, USER_RWX).unwrap();
+
+        let mut server = server(Some(script.display().to_string()));
+        server.port = echo_server();
+        let plugins = Plugins::new();
+        let local_addr = plugins.start(&server).unwrap();
+        let first = running_pid(&plugins, server.port, None);
+        assert_eq!(relay(local_addr, b"hello"), b"hello".to_vec());
+
+        // The plugin has exited after relaying the connection, and it is restarted after being killed
+        let restarted = running_pid(&plugins, server.port, Some(first));
+        Process::kill(restarted, MustDieSignal).unwrap();
+        let pid = running_pid(&plugins, server.port, Some(restarted));
+        assert_eq!(relay(local_addr, b"again"), b"again".to_vec());
+
+        // Killed, and not restarted any more
+        let pid = running_pid(&plugins, server.port, Some(pid));
+        plugins.stop_all();
+        for _ in range(0, 50) {
+            if Process::kill(pid, 0).is_err() {
+                break;
+            }
+            sleep(Duration::milliseconds(100));
+        }
+        assert!(Process::kill(pid, 0).is_err());
+        sleep(Duration::milliseconds(2 * super::RESTART_DELAY));
+        assert!(TcpStream::connect(local_addr).is_err());
+    }
+}
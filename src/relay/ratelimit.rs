@@ -0,0 +1,426 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! Rate limiting of relayed traffic
+//!
+//! Traffic is limited by token buckets, which are filled at their rates in bytes per second and
+//! hold at most a second of tokens. A connection is limited by buckets of three levels: its own
+//! buckets, the buckets of its port and the buckets of the process. Uploading and downloading
+//! have their own buckets.
+//!
+//! TCP connections stop reading while any of their buckets is empty, UDP packets are dropped if
+//! there aren't enough tokens for them. Rates could be changed while running, connections follow
+//! the new rates at once.
+
+use std::cmp;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::BTreeMap;
+use std::io::net::ip::Port;
+
+use config::{Config, RateLimitConfig};
+use relay::eventloop::now_ms;
+
+/// Reading waits until a bucket has this many bytes, or 50ms of its rate if that is less, so that
+/// data is not relayed in tiny pieces
+const MIN_READ_SIZE: usize = 4096;
+
+// Bytes to wait for before reading at `rate`
+fn read_size(rate: usize) -> usize {
+    cmp::max(1, cmp::min(rate / 20, MIN_READ_SIZE))
+}
+
+struct BucketState {
+    tokens: f64,
+    // When tokens were filled, milliseconds
+    filled_at: u64,
+}
+
+/// A token bucket limiting one direction
+pub struct TokenBucket {
+    // Bytes per second, 0 if it is unlimited. Buckets of connections share it with a template.
+    rate: Arc<AtomicUsize>,
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Option<usize>) -> TokenBucket {
+        TokenBucket::with_rate(Arc::new(AtomicUsize::new(rate.unwrap_or(0))))
+    }
+
+    // A full bucket
+    fn with_rate(rate: Arc<AtomicUsize>) -> TokenBucket {
+        let tokens = rate.load(Ordering::Relaxed) as f64;
+        TokenBucket {
+            rate: rate,
+            state: Mutex::new(BucketState {
+                tokens: tokens,
+                filled_at: now_ms(),
+            }),
+        }
+    }
+
+    /// Bytes per second, `None` if it is unlimited
+    pub fn rate(&self) -> Option<usize> {
+        match self.rate.load(Ordering::Relaxed) {
+            0 => None,
+            rate => Some(rate),
+        }
+    }
+
+    pub fn set_rate(&self, rate: Option<usize>) {
+        self.rate.store(rate.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    // Locks the state after filling it up to `now`, returns `None` if it is unlimited
+    fn fill<F, T>(&self, now: u64, f: F) -> Option<T> where F: FnOnce(&mut BucketState, usize) -> T {
+        let rate = match self.rate() {
+            Some(rate) => rate,
+            None => return None,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        if now > state.filled_at {
+            let tokens = state.tokens + (now - state.filled_at) as f64 * rate as f64 / 1000.0;
+            state.tokens = if tokens > rate as f64 { rate as f64 } else { tokens };
+            state.filled_at = now;
+        }
+        Some(f(&mut *state, rate))
+    }
+
+    /// Bytes which could be read now, 0 if reading should wait, `None` if it is unlimited
+    pub fn available(&self, now: u64) -> Option<usize> {
+        self.fill(now, |state, rate| {
+            if state.tokens >= read_size(rate) as f64 { state.tokens as usize } else { 0 }
+        })
+    }
+
+    /// Takes tokens of bytes which have been read, tokens may be owed if other connections have
+    /// taken them in the meantime
+    pub fn consume(&self, n: usize, now: u64) {
+        self.fill(now, |state, _| state.tokens -= n as f64);
+    }
+
+    /// Whether there are enough tokens of a packet of `n` bytes
+    pub fn has_tokens(&self, n: usize, now: u64) -> bool {
+        self.fill(now, |state, _| state.tokens >= n as f64).unwrap_or(true)
+    }
+
+    /// Milliseconds to wait until reading could go on
+    pub fn delay(&self, now: u64) -> u64 {
+        self.fill(now, |state, rate| {
+            let wanted = read_size(rate) as f64;
+            if state.tokens >= wanted {
+                0
+            } else {
+                ((wanted - state.tokens) * 1000.0 / rate as f64) as u64 + 1
+            }
+        }).unwrap_or(0)
+    }
+}
+
+/// Buckets of both directions
+pub struct Buckets {
+    upload: TokenBucket,
+    download: TokenBucket,
+}
+
+impl Buckets {
+    pub fn new(config: &RateLimitConfig) -> Buckets {
+        Buckets {
+            upload: TokenBucket::new(config.upload),
+            download: TokenBucket::new(config.download),
+        }
+    }
+
+    // New buckets following the rates of `template`
+    fn sharing_rates(template: &Buckets) -> Buckets {
+        Buckets {
+            upload: TokenBucket::with_rate(template.upload.rate.clone()),
+            download: TokenBucket::with_rate(template.download.rate.clone()),
+        }
+    }
+
+    pub fn rates(&self) -> RateLimitConfig {
+        RateLimitConfig {
+            upload: self.upload.rate(),
+            download: self.download.rate(),
+        }
+    }
+
+    pub fn set_rates(&self, config: &RateLimitConfig) {
+        self.upload.set_rate(config.upload);
+        self.download.set_rate(config.download);
+    }
+
+    /// The bucket of uploading or downloading
+    pub fn get(&self, upload: bool) -> &TokenBucket {
+        if upload { &self.upload } else { &self.download }
+    }
+}
+
+/// Buckets limiting a TCP connection
+pub struct ConnectionLimiter {
+    own: Buckets,
+    port: Option<Arc<Buckets>>,
+    process: Arc<Buckets>,
+}
+
+impl ConnectionLimiter {
+    fn buckets(&self, upload: bool) -> Vec<&TokenBucket> {
+        let mut buckets = vec![self.own.get(upload), self.process.get(upload)];
+        if let Some(ref port) = self.port {
+            buckets.push(port.get(upload));
+        }
+        buckets
+    }
+
+    /// Bytes which could be read now, 0 if reading should wait, `None` if it is unlimited
+    pub fn available(&self, upload: bool, now: u64) -> Option<usize> {
+        self.buckets(upload).iter().fold(None, |min, bucket| {
+            match (min, bucket.available(now)) {
+                (Some(a), Some(b)) => Some(cmp::min(a, b)),
+                (a, b) => a.or(b),
+            }
+        })
+    }
+
+    pub fn consume(&self, upload: bool, n: usize, now: u64) {
+        for bucket in self.buckets(upload).iter() {
+            bucket.consume(n, now);
+        }
+    }
+
+    /// Milliseconds to wait until reading could go on
+    pub fn delay(&self, upload: bool, now: u64) -> u64 {
+        self.buckets(upload).iter().map(|bucket| bucket.delay(now)).max().unwrap_or(0)
+    }
+}
+
+/// Rate limits of a process, shared by all relays
+pub struct RateLimiter {
+    process: Arc<Buckets>,
+    // Rates of buckets of every connection, its own tokens are never used
+    connection: Buckets,
+    ports: RwLock<BTreeMap<Port, Arc<Buckets>>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &Config) -> RateLimiter {
+        RateLimiter {
+            process: Arc::new(Buckets::new(&config.rate_limit)),
+            connection: Buckets::new(&config.connection_rate_limit),
+            ports: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Buckets of the whole process
+    pub fn process(&self) -> &Buckets {
+        &*self.process
+    }
+
+    /// Rates of every connection, changing them changes the rates of established connections too
+    pub fn connection(&self) -> &Buckets {
+        &self.connection
+    }
+
+    /// Buckets of `port`, which are unlimited for a new port
+    pub fn port(&self, port: Port) -> Arc<Buckets> {
+        if let Some(buckets) = self.ports.read().unwrap().get(&port) {
+            return buckets.clone();
+        }
+
+        let mut ports = self.ports.write().unwrap();
+        if let Some(buckets) = ports.get(&port) {
+            return buckets.clone();
+        }
+        let buckets = Arc::new(Buckets::new(&Default::default()));
+        ports.insert(port, buckets.clone());
+        buckets
+    }
+
+    /// Forgets the buckets of a removed port
+    pub fn remove(&self, port: Port) {
+        self.ports.write().unwrap().remove(&port);
+    }
+
+    /// Buckets of a new connection, which is also limited by `port` if it is given
+    pub fn for_connection(&self, port: Option<Arc<Buckets>>) -> ConnectionLimiter {
+        ConnectionLimiter {
+            own: Buckets::sharing_rates(&self.connection),
+            port: port,
+            process: self.process.clone(),
+        }
+    }
+
+    /// Takes tokens of a UDP packet from the buckets of the process and `port`, returns `false`
+    /// if the packet should be dropped
+    pub fn take_packet(&self, port: Option<&Buckets>, upload: bool, n: usize) -> bool {
+        let now = now_ms();
+        let mut buckets = vec![self.process.get(upload)];
+        if let Some(port) = port {
+            buckets.push(port.get(upload));
+        }
+
+        // A dropped packet takes no tokens from any bucket
+        if !buckets.iter().all(|bucket| bucket.has_tokens(n, now)) {
+            return false;
+        }
+        for bucket in buckets.iter() {
+            bucket.consume(n, now);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test_ratelimit {
+    use std::io::timer::sleep;
+    use std::time::Duration;
+    use std::sync::Arc;
+    use std::num::Float;
+
+    use config::{Config, RateLimitConfig};
+    use relay::eventloop::now_ms;
+    use relay::ratelimit::{TokenBucket, Buckets, RateLimiter};
+
+    // Reads as much as the bucket allows every millisecond for `ms`, returns the bytes read
+    fn simulate(bucket: &TokenBucket, start: u64, ms: u64) -> usize {
+        let mut total = 0;
+        for now in range(start, start + ms) {
+            let n = bucket.available(now).unwrap();
+            bucket.consume(n, now);
+            total += n;
+        }
+        total
+    }
+
+    fn assert_within(actual: usize, expected: usize, tolerance: f64) {
+        let error = (actual as f64 - expected as f64).abs() / expected as f64;
+        assert!(error <= tolerance, "{} is not within {} of {}", actual, tolerance, expected);
+    }
+
+    #[test]
+    fn test_throughput() {
+        for &rate in [1000us, 65536, 10485760].iter() {
+            let bucket = TokenBucket::new(Some(rate));
+            let start = now_ms();
+            // A full bucket at first
+            assert_eq!(bucket.available(start), Some(rate));
+            bucket.consume(rate, start);
+
+            assert_within(simulate(&bucket, start, 10000), rate * 10, 0.01);
+        }
+    }
+
+    #[test]
+    fn test_throughput_in_real_time() {
+        let rate = 1048576;
+        let bucket = TokenBucket::new(Some(rate));
+        let start = now_ms();
+        bucket.consume(rate, start);
+
+        let mut total = 0;
+        while now_ms() - start < 500 {
+            let now = now_ms();
+            match bucket.available(now).unwrap() {
+                0 => sleep(Duration::milliseconds(bucket.delay(now) as i64)),
+                n => {
+                    bucket.consume(n, now);
+                    total += n;
+                }
+            }
+        }
+        let elapsed = (now_ms() - start) as usize;
+        assert_within(total, rate * elapsed / 1000, 0.1);
+    }
+
+    #[test]
+    fn test_waiting() {
+        let bucket = TokenBucket::new(Some(20000));
+        let now = now_ms();
+        bucket.consume(20000, now);
+
+        // Waits for 1000 bytes, 50ms of the rate
+        assert_eq!(bucket.available(now + 49), Some(0));
+        assert!(bucket.delay(now + 49) > 0);
+        assert_eq!(bucket.available(now + 50), Some(1000));
+        assert_eq!(bucket.delay(now + 50), 0);
+
+        assert!(!bucket.has_tokens(1500, now + 50));
+        assert!(bucket.has_tokens(1000, now + 50));
+
+        assert_eq!(TokenBucket::new(None).available(now), None);
+        assert!(TokenBucket::new(None).has_tokens(65536, now));
+    }
+
+    #[test]
+    fn test_levels() {
+        let mut config = Config::new();
+        config.rate_limit = RateLimitConfig { upload: Some(3000), download: None };
+        config.connection_rate_limit = RateLimitConfig { upload: Some(2000), download: None };
+        let limiter = RateLimiter::new(&config);
+        limiter.port(8388).set_rates(&RateLimitConfig { upload: Some(1000), download: None });
+
+        let now = now_ms();
+        let conn = limiter.for_connection(Some(limiter.port(8388)));
+        assert_eq!(conn.available(true, now), Some(1000));
+        assert_eq!(conn.available(false, now), None);
+
+        conn.consume(true, 1000, now);
+        assert_eq!(conn.available(true, now), Some(0));
+        assert_eq!(limiter.process().get(true).available(now), Some(2000));
+
+        // Changed while running
+        limiter.connection().set_rates(&RateLimitConfig { upload: None, download: Some(500) });
+        assert_eq!(conn.available(false, now + 1000), Some(500));
+
+        let other = limiter.for_connection(None);
+        assert_eq!(other.available(true, now), Some(2000));
+        assert!(limiter.take_packet(None, true, 2000));
+        assert!(!limiter.take_packet(None, true, 1000));
+        assert!(limiter.take_packet(Some(&*Arc::new(Buckets::new(&Default::default()))), false, 1));
+    }
+
+    #[test]
+    fn test_dropped_packet() {
+        let mut config = Config::new();
+        config.rate_limit = RateLimitConfig { upload: Some(3000), download: None };
+        let limiter = RateLimiter::new(&config);
+        let port = limiter.port(8388);
+        port.set_rates(&RateLimitConfig { upload: Some(1000), download: None });
+
+        // Dropped by the port, the process keeps its tokens
+        let now = now_ms();
+        assert!(!limiter.take_packet(Some(&*port), true, 2000));
+        assert_eq!(limiter.process().get(true).available(now), Some(3000));
+
+        // Dropped by the process, the port keeps its tokens
+        assert!(limiter.take_packet(None, true, 2500));
+        assert!(!limiter.take_packet(Some(&*port), true, 1000));
+        assert_eq!(port.get(true).available(now), Some(1000));
+
+        assert!(limiter.take_packet(Some(&*port), true, 500));
+        assert_eq!(port.get(true).available(now), Some(500));
+        assert_eq!(limiter.process().get(true).available(now), Some(0));
+    }
+}
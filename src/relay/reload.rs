@@ -0,0 +1,98 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! Reloading the configuration file on SIGHUP
+//!
+//! `sslocal` and `ssserver` read their configuration file again when they receive SIGHUP, and
+//! apply the servers in it without dropping established connections. A file which fails to be
+//! parsed or validated is rejected, and the running configuration is kept. Servers given on the
+//! command line are added to every configuration loaded again, as they have been at start.
+
+use std::io::timer::sleep;
+use std::thread::Thread;
+use std::time::Duration;
+
+use config::{Config, ConfigType, ServerConfig, Error};
+use relay::signal;
+
+/// Milliseconds between two checks of SIGHUP
+const POLL_INTERVAL: i64 = 500;
+
+/// Loads `path` again whenever SIGHUP is received, and applies the configuration with `apply`,
+/// in a detached thread. `extra_servers` are the servers given on the command line.
+pub fn reload_on_hangup<F>(path: String, config_type: ConfigType, extra_servers: Vec<ServerConfig>, mut apply: F)
+        where F: FnMut(Config) -> Result<(), Error> + Send + 'static {
+    signal::catch_hangup();
+    Thread::spawn(move || {
+        loop {
+            sleep(Duration::milliseconds(POLL_INTERVAL));
+            if !signal::take_hangup() {
+                continue;
+            }
+
+            info!("Reloading configuration from {}", path);
+            let result = Config::load_from_file(path.as_slice(), config_type)
+                .and_then(|config| apply(with_servers(config, extra_servers.as_slice())));
+            if let Err(err) = result {
+                error!("Rejected configuration from {}, keeping the running one: {:?}", path, err);
+            }
+        }
+    });
+}
+
+// Adds the servers given on the command line to a configuration loaded from the file
+fn with_servers(mut config: Config, servers: &[ServerConfig]) -> Config {
+    config.server.push_all(servers);
+    config
+}
+
+#[cfg(test)]
+mod test_reload {
+    use config::{Config, ConfigType, ServerConfig};
+    use crypto::cipher::CipherType;
+
+    use super::with_servers;
+
+    fn cli_server() -> ServerConfig {
+        ServerConfig::new("127.0.0.2".to_string(), 8389, "cli-password".to_string(), CipherType::Aes256Cfb)
+    }
+
+    #[test]
+    fn test_keeps_cli_servers() {
+        let config = Config::load_from_str(r#"{"server": "127.0.0.1", "server_port": 8388,
+                                               "password": "server-password", "method": "aes-256-cfb"}"#,
+                                           ConfigType::Local).unwrap();
+        let config = with_servers(config, &[cli_server()]);
+
+        let servers: Vec<(&str, u16)> = config.server.iter().map(|s| (s.addr.as_slice(), s.port)).collect();
+        assert_eq!(servers, vec![("127.0.0.1", 8388), ("127.0.0.2", 8389)]);
+        assert_eq!(config.server[1].password.as_slice(), "cli-password");
+    }
+
+    #[test]
+    fn test_cli_servers_only() {
+        // A configuration file may only define other settings, such as `local_port`
+        let config = with_servers(Config::new(), &[cli_server()]);
+        assert_eq!(config.server.len(), 1);
+        assert_eq!(config.server[0].port, 8389);
+    }
+}
@@ -0,0 +1,205 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Replay filter for IVs and salts received by servers
+//!
+//! Every IV (or salt) should be used only once, so a server which sees the same IV twice
+//! is receiving a replayed request. IVs are remembered by a rotating pair of bloom filters:
+//! new IVs are added to the current filter, and when it is full, the older one is cleared
+//! and becomes the current one. So at least `capacity` recent IVs are always remembered.
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::num::Float;
+use std::iter::repeat;
+
+/// Default number of IVs in each bloom filter
+pub const DEFAULT_REPLAY_FILTER_CAPACITY: usize = 1000000;
+
+/// False positive rate of each bloom filter
+pub const REPLAY_FILTER_FALSE_POSITIVE_RATE: f64 = 1e-6;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+fn fnv1a(seed: u64, data: &[u8]) -> u64 {
+    let mut hash = seed;
+    for b in data.iter() {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u64,
+    count: usize,
+}
+
+impl BloomFilter {
+    fn new(capacity: usize, fp_rate: f64) -> BloomFilter {
+        let ln2 = 2.0f64.ln();
+        let num_bits = (-(capacity as f64) * fp_rate.ln() / (ln2 * ln2)).ceil().max(64.0) as u64;
+        let num_hashes = ((num_bits as f64 / capacity as f64) * ln2).round().max(1.0) as u64;
+
+        BloomFilter {
+            bits: repeat(0u64).take(((num_bits + 63) / 64) as usize).collect(),
+            num_bits: num_bits,
+            num_hashes: num_hashes,
+            count: 0,
+        }
+    }
+
+    // Double hashing, `h1 + i * h2`
+    fn indexes(&self, data: &[u8]) -> Vec<u64> {
+        let h1 = fnv1a(FNV_OFFSET_BASIS, data);
+        let h2 = fnv1a(h1 ^ FNV_OFFSET_BASIS, data) | 1;
+        range(0, self.num_hashes).map(|i| h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits).collect()
+    }
+
+    fn contains(&self, data: &[u8]) -> bool {
+        self.indexes(data).iter().all(|&idx| self.bits[(idx / 64) as usize] & (1 << (idx % 64) as usize) != 0)
+    }
+
+    fn insert(&mut self, data: &[u8]) {
+        for idx in self.indexes(data).into_iter() {
+            self.bits[(idx / 64) as usize] |= 1 << (idx % 64) as usize;
+        }
+        self.count += 1;
+    }
+
+    fn clear(&mut self) {
+        for b in self.bits.iter_mut() {
+            *b = 0;
+        }
+        self.count = 0;
+    }
+}
+
+struct FilterPair {
+    filters: [BloomFilter; 2],
+    current: usize,
+}
+
+/// Replay filter shared by all relays in a server
+pub struct ReplayFilter {
+    inner: Mutex<FilterPair>,
+    capacity: usize,
+    checked: AtomicUsize,
+    hits: AtomicUsize,
+}
+
+impl ReplayFilter {
+    /// Creates a filter remembering at least `capacity` IVs
+    pub fn new(capacity: usize) -> ReplayFilter {
+        ReplayFilter {
+            inner: Mutex::new(FilterPair {
+                filters: [BloomFilter::new(capacity, REPLAY_FILTER_FALSE_POSITIVE_RATE),
+                          BloomFilter::new(capacity, REPLAY_FILTER_FALSE_POSITIVE_RATE)],
+                current: 0,
+            }),
+            capacity: capacity,
+            checked: AtomicUsize::new(0),
+            hits: AtomicUsize::new(0),
+        }
+    }
+
+    /// Checks whether `iv` has been seen, and remembers it.
+    ///
+    /// Returns `false` if `iv` is a replay. Ciphers without IVs, such as table and rc4, could not be
+    /// protected, an empty `iv` is always accepted and not counted.
+    pub fn check_and_insert(&self, iv: &[u8]) -> bool {
+        if iv.is_empty() {
+            return true;
+        }
+        self.checked.fetch_add(1, Ordering::Relaxed);
+
+        let mut pair = self.inner.lock().unwrap();
+        if pair.filters[0].contains(iv) || pair.filters[1].contains(iv) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        if pair.filters[pair.current].count >= self.capacity {
+            pair.current = 1 - pair.current;
+            let current = pair.current;
+            pair.filters[current].clear();
+        }
+        let current = pair.current;
+        pair.filters[current].insert(iv);
+        true
+    }
+
+    /// Number of IVs which have been checked
+    pub fn checked(&self) -> usize {
+        self.checked.load(Ordering::Relaxed)
+    }
+
+    /// Number of replayed IVs which have been rejected
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test_replay_filter {
+    use relay::replay_filter::ReplayFilter;
+
+    #[test]
+    fn test_replay_filter() {
+        let filter = ReplayFilter::new(100);
+        let ivs = range(0, 100us).map(|i| format!("iv-{}", i)).collect::<Vec<String>>();
+
+        for iv in ivs.iter() {
+            assert!(filter.check_and_insert(iv.as_bytes()));
+        }
+        for iv in ivs.iter() {
+            assert!(!filter.check_and_insert(iv.as_bytes()));
+        }
+        assert_eq!(filter.checked(), 200);
+        assert_eq!(filter.hits(), 100);
+    }
+
+    #[test]
+    fn test_replay_filter_rotate() {
+        let filter = ReplayFilter::new(10);
+        for i in range(0, 30us) {
+            assert!(filter.check_and_insert(format!("iv-{}", i).as_bytes()));
+        }
+
+        // The last `capacity` IVs are always remembered
+        for i in range(20, 30us) {
+            assert!(!filter.check_and_insert(format!("iv-{}", i).as_bytes()));
+        }
+    }
+
+    #[test]
+    fn test_empty_iv() {
+        let filter = ReplayFilter::new(10);
+        for _ in range(0, 3us) {
+            assert!(filter.check_and_insert(&[]));
+        }
+        assert_eq!(filter.checked(), 0);
+        assert_eq!(filter.hits(), 0);
+    }
+}
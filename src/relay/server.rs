@@ -21,13 +21,29 @@
 
 //! Server side
 
+use std::sync::{Arc, Mutex};
 use std::thread::Thread;
+use std::io::IoResult;
+use std::io::net::ip::{Port, SocketAddr};
+use std::io::fs::PathExtensions;
+use std::io::timer::sleep;
+use std::time::Duration;
 
 #[cfg(feature = "enable-udp")]
 use relay::udprelay::server::UdpRelayServer;
 use relay::tcprelay::server::TcpRelayServer;
+use relay::replay_filter::ReplayFilter;
+use relay::outbound_filter::OutboundFilter;
+use relay::users::UserTables;
+use relay::traffic::TrafficStats;
+use relay::ratelimit::RateLimiter;
+use relay::metrics::{Metrics, MetricsServer};
+use relay::manager::Manager;
+use relay::reload;
+use relay::shutdown::ShutdownHandle;
+use relay::plugin::{Plugins, StopGuard};
 use relay::Relay;
-use config::Config;
+use config::{Config, ConfigType, ServerConfig, ManagerConfig, Error, ErrorKind};
 
 /// Relay server running on server side.
 ///
@@ -42,14 +58,8 @@ use config::Config;
 /// use shadowsocks::crypto::cipher::CipherType;
 ///
 /// let mut config = Config::new();
-/// config.server = vec![ServerConfig {
-///     addr: "127.0.0.1".to_string(),
-///     port: 8388,
-///     password: "server-password".to_string(),
-///     method: CipherType::Aes256Cfb,
-///     timeout: None,
-///     dns_cache_capacity: 1024,
-/// }];
+/// config.server = vec![ServerConfig::new("127.0.0.1".to_string(), 8388, "server-password".to_string(),
+///                                        CipherType::Aes256Cfb)];
 /// RelayServer::new(config).run();
 /// ```
 ///
@@ -59,26 +69,372 @@ pub struct RelayServer {
     tcprelay: TcpRelayServer,
     #[cfg(feature = "enable-udp")]
     udprelay: UdpRelayServer,
+    replay_filter: Arc<ReplayFilter>,
+    outbound_filter: Arc<OutboundFilter>,
+    users: Arc<UserTables>,
+    traffic: Arc<TrafficStats>,
+    rate_limiter: Arc<RateLimiter>,
+    metrics: Arc<Metrics>,
+    plugins: Arc<Plugins>,
+    manager: Option<ManagerConfig>,
+    traffic_state_file: Option<Path>,
+    metrics_addr: Option<SocketAddr>,
+    drain_timeout: u64,
+    // Servers of the configuration, which are replaced by reloading it. Servers added by the
+    // manager are not in it.
+    configured: Arc<Mutex<Vec<ServerConfig>>>,
+}
+
+/// Seconds between two saves of the traffic state file
+const TRAFFIC_SAVE_INTERVAL: i64 = 60;
+
+/// What reloading the configuration does to a server
+#[derive(Copy, Debug, PartialEq, Eq)]
+enum Change {
+    Keep,
+    /// New connections are served with the new configuration
+    Update,
+    /// The listening address or the plugin has been changed
+    Restart,
+    Start,
+    Stop,
+}
+
+// Compares the servers of a reloaded configuration with the running ones by their ports, removed
+// servers come first
+fn diff_servers(old: &[ServerConfig], new: &[ServerConfig]) -> Vec<(Port, Change)> {
+    let mut changes = old.iter().filter(|o| !new.iter().any(|s| s.port == o.port))
+                                .map(|o| (o.port, Change::Stop)).collect::<Vec<(Port, Change)>>();
+    for server in new.iter() {
+        let change = match old.iter().find(|o| o.port == server.port) {
+            Some(old) if old == server => Change::Keep,
+            Some(old) if old.addr == server.addr && old.plugin == server.plugin
+                            && old.plugin_opts == server.plugin_opts => Change::Update,
+            Some(..) => Change::Restart,
+            None => Change::Start,
+        };
+        changes.push((server.port, change));
+    }
+    changes
+}
+
+// Restores traffic saved by the last run, so that quotas are kept
+fn load_traffic(config: &Config) -> TrafficStats {
+    match config.traffic_state_file {
+        Some(ref path) if path.exists() => match TrafficStats::load(path) {
+            Ok(traffic) => traffic,
+            Err(err) => panic!("Failed to load traffic state file: {}", err),
+        },
+        _ => TrafficStats::new(),
+    }
 }
 
 impl RelayServer {
     #[cfg(feature = "enable-udp")]
     pub fn new(config: Config) -> RelayServer {
-        let tcprelay = TcpRelayServer::new(config.clone());
-        let udprelay = UdpRelayServer::new(config.clone());
+        let replay_filter = Arc::new(ReplayFilter::new(config.replay_filter_capacity));
+        let outbound_filter = Arc::new(OutboundFilter::new(config.block_private_outbound, config.acl.clone()));
+        let traffic = Arc::new(load_traffic(&config));
+        let users = Arc::new(UserTables::new(config.server.as_slice(), traffic.clone()));
+        let rate_limiter = Arc::new(RateLimiter::new(&config));
+        let metrics = Arc::new(Metrics::new());
+        let plugins = Arc::new(Plugins::new());
+        let tcprelay = TcpRelayServer::new(config.clone(), replay_filter.clone(), outbound_filter.clone(),
+                                           users.clone(), traffic.clone(), rate_limiter.clone(), metrics.clone(),
+                                           plugins.clone());
+        let udprelay = UdpRelayServer::new(config.clone(), replay_filter.clone(), outbound_filter.clone(),
+                                           users.clone(), traffic.clone(), rate_limiter.clone(), metrics.clone());
         RelayServer {
             tcprelay: tcprelay,
             udprelay: udprelay,
             enable_udp: config.enable_udp,
+            replay_filter: replay_filter,
+            outbound_filter: outbound_filter,
+            users: users,
+            traffic: traffic,
+            rate_limiter: rate_limiter,
+            metrics: metrics,
+            plugins: plugins,
+            manager: config.manager,
+            traffic_state_file: config.traffic_state_file,
+            metrics_addr: config.metrics,
+            drain_timeout: config.drain_timeout,
+            configured: Arc::new(Mutex::new(config.server)),
         }
     }
 
     #[cfg(not(feature = "enable-udp"))]
     pub fn new(config: Config) -> RelayServer {
-        let tcprelay = TcpRelayServer::new(config.clone());
+        let replay_filter = Arc::new(ReplayFilter::new(config.replay_filter_capacity));
+        let outbound_filter = Arc::new(OutboundFilter::new(config.block_private_outbound, config.acl.clone()));
+        let traffic = Arc::new(load_traffic(&config));
+        let users = Arc::new(UserTables::new(config.server.as_slice(), traffic.clone()));
+        let rate_limiter = Arc::new(RateLimiter::new(&config));
+        let metrics = Arc::new(Metrics::new());
+        let plugins = Arc::new(Plugins::new());
+        let tcprelay = TcpRelayServer::new(config.clone(), replay_filter.clone(), outbound_filter.clone(),
+                                           users.clone(), traffic.clone(), rate_limiter.clone(), metrics.clone(),
+                                           plugins.clone());
         RelayServer {
             tcprelay: tcprelay,
             enable_udp: config.enable_udp,
+            replay_filter: replay_filter,
+            outbound_filter: outbound_filter,
+            users: users,
+            traffic: traffic,
+            rate_limiter: rate_limiter,
+            metrics: metrics,
+            plugins: plugins,
+            manager: config.manager,
+            traffic_state_file: config.traffic_state_file,
+            metrics_addr: config.metrics,
+            drain_timeout: config.drain_timeout,
+            configured: Arc::new(Mutex::new(config.server)),
+        }
+    }
+
+    /// The replay filter shared by TCP and UDP relays, for monitoring its counters
+    pub fn replay_filter(&self) -> Arc<ReplayFilter> {
+        self.replay_filter.clone()
+    }
+
+    /// The outbound filter shared by TCP and UDP relays, for monitoring refused targets
+    pub fn outbound_filter(&self) -> Arc<OutboundFilter> {
+        self.outbound_filter.clone()
+    }
+
+    /// Users of multi-user servers, for adding, removing and disabling users while running
+    pub fn users(&self) -> Arc<UserTables> {
+        self.users.clone()
+    }
+
+    /// Bytes transferred by each port, counted by both TCP and UDP relays
+    pub fn traffic(&self) -> Arc<TrafficStats> {
+        self.traffic.clone()
+    }
+
+    /// Rate limits of ports, connections and the process, which could be changed while running
+    pub fn rate_limiter(&self) -> Arc<RateLimiter> {
+        self.rate_limiter.clone()
+    }
+
+    /// Counters of connections, failures and caches of all relays
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Starts a server while running, its TCP relay is stopped if its UDP relay fails to start
+    pub fn start_server(&self, config: &ServerConfig) -> IoResult<()> {
+        try!(self.tcprelay.start_server(config));
+        if let Err(err) = self.start_udp_server(config) {
+            self.tcprelay.stop_server(config.port);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Stops the server on `port`, returns `false` if there is no such server
+    pub fn stop_server(&self, port: Port) -> bool {
+        let stopped = self.tcprelay.stop_server(port);
+        self.stop_udp_server(port) || stopped
+    }
+
+    /// Stops accepting connections of the server on `port` and its plugin, established TCP connections
+    /// are relayed until they are finished. Returns `false` if there is no such server.
+    pub fn stop_listening(&self, port: Port) -> bool {
+        let stopped = self.tcprelay.stop_listening(port);
+        self.stop_udp_server(port) || stopped
+    }
+
+    /// Serves new connections of the server on the port of `config` with `config`, established
+    /// connections are kept. Returns `false` if there is no such server.
+    pub fn update_server(&self, config: &ServerConfig) -> bool {
+        let updated = self.tcprelay.update_server(config);
+        self.update_udp_server(config) || updated
+    }
+
+    /// Applies the servers of a reloaded configuration. Servers which have been removed from it are
+    /// stopped, new ones are started, and changed ones serve new connections with their new
+    /// configuration while established connections are kept. A server whose listening address has
+    /// been changed is restarted, and so is a server whose plugin has been changed, its established
+    /// TCP connections are kept with the old configuration. Servers added by the manager are not
+    /// touched.
+    ///
+    /// Servers which fail to start are reported in the returned error, a restarted server keeps
+    /// running with its old configuration in that case.
+    ///
+    /// Other parts of the configuration are only changed by restarting.
+    pub fn reload(&self, config: Config) -> Result<(), Error> {
+        if config.server.is_empty() && self.manager.is_none() {
+            return Err(Error::new(ErrorKind::MissingField, "no servers in the configuration", None));
+        }
+        for (idx, server) in config.server.iter().enumerate() {
+            if config.server[..idx].iter().any(|s| s.port == server.port) {
+                return Err(Error::new(ErrorKind::Invalid, "servers should have different ports",
+                                      Some(server.port.to_string())));
+            }
+        }
+
+        let mut configured = self.configured.lock().unwrap();
+        let mut applied = Vec::with_capacity(config.server.len());
+        let mut failed = Vec::new();
+        for (port, change) in diff_servers(configured.as_slice(), config.server.as_slice()).into_iter() {
+            let old = configured.iter().find(|s| s.port == port);
+            let new = config.server.iter().find(|s| s.port == port);
+            match change {
+                Change::Stop => {
+                    self.stop_server(port);
+                    self.users.remove(port);
+                    self.traffic.remove(port);
+                    self.rate_limiter.remove(port);
+                    info!("Stopped server on port {}, which has been removed", port);
+                },
+                Change::Keep => applied.push(new.unwrap().clone()),
+                Change::Update => {
+                    let new = new.unwrap();
+                    self.users.set(new);
+                    self.update_server(new);
+                    info!("Updated server on port {}", port);
+                    applied.push(new.clone());
+                },
+                Change::Restart => {
+                    // The port is held by the old server until it has stopped listening, which is
+                    // started again if the new one fails. Established connections are kept.
+                    let (old, new) = (old.unwrap(), new.unwrap());
+                    self.stop_listening(port);
+                    self.users.set(new);
+                    match self.start_server(new) {
+                        Ok(..) => applied.push(new.clone()),
+                        Err(err) => {
+                            failed.push(format!("{}:{}: {}", new.addr, port, err));
+                            self.users.set(old);
+                            match self.start_server(old) {
+                                Ok(..) => applied.push(old.clone()),
+                                Err(err) => error!("Failed to restore server on port {}: {}", port, err),
+                            }
+                        }
+                    }
+                },
+                Change::Start => {
+                    let new = new.unwrap();
+                    self.users.set(new);
+                    match self.start_server(new) {
+                        Ok(..) => applied.push(new.clone()),
+                        Err(err) => {
+                            failed.push(format!("{}:{}: {}", new.addr, port, err));
+                            self.users.remove(port);
+                        }
+                    }
+                },
+            }
+        }
+        *configured = applied;
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::new(ErrorKind::IoError, "failed to start servers, other servers have been applied",
+                           Some(failed.connect("; "))))
+        }
+    }
+
+    /// Reloads the configuration file `path` whenever SIGHUP is received, see `reload`. `extra_servers`
+    /// given on the command line are added to the reloaded configuration.
+    pub fn reload_on_hangup(&self, path: String, extra_servers: Vec<ServerConfig>) {
+        let server = self.clone();
+        reload::reload_on_hangup(path, ConfigType::Server, extra_servers, move |config| server.reload(config));
+    }
+
+    /// A handle shutting the server down with the drain timeout of the configuration
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle::new(self.clone(), self.drain_timeout)
+    }
+
+    #[cfg(feature = "enable-udp")]
+    fn start_udp_server(&self, config: &ServerConfig) -> IoResult<()> {
+        if self.enable_udp { self.udprelay.start_server(config) } else { Ok(()) }
+    }
+
+    #[cfg(not(feature = "enable-udp"))]
+    fn start_udp_server(&self, _: &ServerConfig) -> IoResult<()> {
+        Ok(())
+    }
+
+    #[cfg(feature = "enable-udp")]
+    fn stop_udp_server(&self, port: Port) -> bool {
+        self.enable_udp && self.udprelay.stop_server(port)
+    }
+
+    #[cfg(not(feature = "enable-udp"))]
+    fn stop_udp_server(&self, _: Port) -> bool {
+        false
+    }
+
+    #[cfg(feature = "enable-udp")]
+    fn update_udp_server(&self, config: &ServerConfig) -> bool {
+        self.enable_udp && self.udprelay.update_server(config)
+    }
+
+    #[cfg(not(feature = "enable-udp"))]
+    fn update_udp_server(&self, _: &ServerConfig) -> bool {
+        false
+    }
+
+    #[cfg(feature = "enable-udp")]
+    fn shutdown_udp(&self) {
+        self.udprelay.shutdown(0);
+    }
+
+    #[cfg(not(feature = "enable-udp"))]
+    fn shutdown_udp(&self) {}
+
+    // Traffic counted since the last save is kept for the next run
+    fn save_traffic(&self) {
+        if let Some(ref path) = self.traffic_state_file {
+            match self.traffic.save(path) {
+                Ok(..) => info!("Saved traffic to {}", path.display()),
+                Err(err) => error!("Failed to save traffic to {}: {}", path.display(), err),
+            }
+        }
+    }
+
+    fn run_traffic_saver(&self) {
+        if let Some(ref path) = self.traffic_state_file {
+            let (traffic, path) = (self.traffic.clone(), path.clone());
+            Thread::spawn(move || {
+                loop {
+                    sleep(Duration::seconds(TRAFFIC_SAVE_INTERVAL));
+                    if let Err(err) = traffic.save(&path) {
+                        error!("Failed to save traffic to {}: {}", path.display(), err);
+                    }
+                }
+            });
+        }
+    }
+
+    // Servers keep running if the metrics server fails
+    fn run_metrics(&self) {
+        if let Some(addr) = self.metrics_addr {
+            let mut server = MetricsServer::new(addr, self.metrics.clone(), self.traffic.clone());
+            server.set_filters(self.replay_filter.clone(), self.outbound_filter.clone());
+            Thread::spawn(move || {
+                if let Err(err) = server.run() {
+                    error!("Metrics server exited: {}", err);
+                }
+            });
+        }
+    }
+
+    // Servers keep running if the manager fails
+    fn run_manager(&self) {
+        if let Some(ref config) = self.manager {
+            let manager = Manager::new(config.clone(), self.clone());
+            Thread::spawn(move || {
+                if let Err(err) = manager.run() {
+                    error!("Manager exited: {}", err);
+                }
+            });
+            info!("Enabled manager");
         }
     }
 }
@@ -86,6 +442,9 @@ impl RelayServer {
 impl Relay for RelayServer {
     #[cfg(feature = "enable-udp")]
     fn run(&self) {
+        // Plugins are killed after returning, or while unwinding a panic
+        let _plugins = StopGuard::new(self.plugins.clone());
+
         let mut threads = Vec::with_capacity(2);
 
         let tcprelay = self.tcprelay.clone();
@@ -99,13 +458,21 @@ impl Relay for RelayServer {
             info!("Enabled UDP relay");
         }
 
+        self.run_manager();
+        self.run_traffic_saver();
+        self.run_metrics();
+
         for fut in threads.into_iter() {
             fut.join().ok().expect("A relay thread failed and exited");
         }
+        self.save_traffic();
     }
 
     #[cfg(not(feature = "enable-udp"))]
     fn run(&self) {
+        // Plugins are killed after returning, or while unwinding a panic
+        let _plugins = StopGuard::new(self.plugins.clone());
+
         if self.enable_udp {
             warn!("UDP relay feature is disabled, recompile with feature=\"enable-udp\" to enable this feature");
         }
@@ -114,6 +481,80 @@ impl Relay for RelayServer {
         let tcp_thread = Thread::scoped(move || tcprelay.run());
         info!("Enabled TCP relay");
 
+        self.run_manager();
+        self.run_traffic_saver();
+        self.run_metrics();
+
         tcp_thread.join().ok().expect("TCP relay thread failed and exited");
+        self.save_traffic();
+    }
+
+    fn shutdown(&self, drain_timeout: u64) {
+        self.tcprelay.shutdown(drain_timeout);
+        self.shutdown_udp();
+    }
+}
+
+#[cfg(test)]
+mod test_server {
+    use std::io::net::tcp::TcpStream;
+    use std::io::timer::sleep;
+    use std::thread::Thread;
+    use std::time::Duration;
+
+    use config::{Config, ServerConfig};
+    use crypto::cipher::CipherType;
+    use relay::Relay;
+    use super::{diff_servers, Change, RelayServer};
+
+    fn server(port: u16) -> ServerConfig {
+        ServerConfig::new("127.0.0.1".to_string(), port, "server-password".to_string(), CipherType::Aes256Cfb)
+    }
+
+    #[test]
+    fn test_diff_servers() {
+        let old = vec![server(8001), server(8002), server(8003), server(8004), server(8005)];
+
+        let mut updated = server(8002);
+        updated.password = "new-password".to_string();
+        let mut moved = server(8003);
+        moved.addr = "0.0.0.0".to_string();
+        let mut plugged = server(8004);
+        plugged.plugin = Some("obfs-server".to_string());
+        let new = vec![server(8001), updated, moved, plugged, server(8006)];
+
+        assert_eq!(diff_servers(old.as_slice(), new.as_slice()),
+                   vec![(8005, Change::Stop), (8001, Change::Keep), (8002, Change::Update),
+                        (8003, Change::Restart), (8004, Change::Restart), (8006, Change::Start)]);
+    }
+
+    #[test]
+    fn test_diff_nothing() {
+        let servers = vec![server(8001)];
+        assert_eq!(diff_servers(servers.as_slice(), servers.as_slice()), vec![(8001, Change::Keep)]);
+        assert_eq!(diff_servers(&[], servers.as_slice()), vec![(8001, Change::Start)]);
+        assert_eq!(diff_servers(servers.as_slice(), &[]), vec![(8001, Change::Stop)]);
+    }
+
+    #[test]
+    fn test_restart_on_same_port() {
+        let mut config = Config::new();
+        config.server = vec![server(28406)];
+        config.workers = 2;
+        let relay = RelayServer::new(config);
+        let running = relay.clone();
+        Thread::spawn(move || running.run());
+        sleep(Duration::milliseconds(200));
+
+        // Workers may not have dropped the stopped listener yet
+        for _ in range(0, 10us) {
+            assert!(relay.stop_server(28406));
+            relay.start_server(&server(28406)).unwrap();
+        }
+        assert!(TcpStream::connect(("127.0.0.1", 28406)).is_ok());
+
+        assert!(relay.stop_server(28406));
+        assert!(!relay.stop_server(28406));
+        relay.shutdown(0);
     }
 }
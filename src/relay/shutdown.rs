@@ -0,0 +1,203 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! Shutting relays down gracefully
+//!
+//! A relay which is shut down stops accepting connections at once, and established TCP
+//! connections are relayed until they are finished, or the drain timeout has expired, when the
+//! rest of them are closed. UDP relays and the DNS forwarder have nothing to wait for, their
+//! associations are flushed at once. `Relay::run` returns after all of them have exited.
+//!
+//! `sslocal` and `ssserver` are shut down this way when they receive SIGTERM or SIGINT, and a
+//! second signal closes the remaining connections without waiting any longer.
+
+use std::io::timer::sleep;
+use std::thread::Thread;
+use std::time::Duration;
+
+use relay::Relay;
+use relay::signal;
+
+/// Milliseconds between two checks of SIGTERM and SIGINT
+const POLL_INTERVAL: i64 = 500;
+
+/// Shuts a relay down from another thread
+pub struct ShutdownHandle {
+    relay: Box<Relay + Send>,
+    drain_timeout: u64,
+}
+
+impl ShutdownHandle {
+    /// A handle of `relay`, whose connections are given `drain_timeout` milliseconds to finish
+    pub fn new<R: Relay + Send + 'static>(relay: R, drain_timeout: u64) -> ShutdownHandle {
+        ShutdownHandle {
+            relay: Box::new(relay) as Box<Relay + Send>,
+            drain_timeout: drain_timeout,
+        }
+    }
+
+    /// Stops accepting connections, `run` of the relay returns after established connections have
+    /// been finished or the drain timeout has expired
+    pub fn shutdown(&self) {
+        info!("Shutting down, waiting {}ms for connections to finish", self.drain_timeout);
+        self.relay.shutdown(self.drain_timeout);
+    }
+
+    /// Closes all connections without waiting for them
+    pub fn shutdown_now(&self) {
+        info!("Shutting down, closing all connections");
+        self.relay.shutdown(0);
+    }
+
+    /// Shuts the relay down when SIGTERM or SIGINT is received, in a detached thread. The first
+    /// signal drains connections, the second one closes them.
+    pub fn shutdown_on_terminate(self) {
+        signal::catch_terminate();
+        Thread::spawn(move || {
+            let mut draining = false;
+            loop {
+                sleep(Duration::milliseconds(POLL_INTERVAL));
+                if !signal::take_terminate() {
+                    continue;
+                }
+
+                if draining {
+                    self.shutdown_now();
+                    break;
+                }
+                self.shutdown();
+                draining = true;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test_shutdown {
+    use std::io::{Reader, TimedOut};
+    use std::io::net::ip::Port;
+    use std::io::net::tcp::TcpStream;
+    use std::io::timer::sleep;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread::Thread;
+    use std::time::Duration;
+
+    use config::{Config, ServerConfig};
+    use crypto::cipher::CipherType;
+    use relay::Relay;
+    use relay::server::RelayServer;
+    use relay::shutdown::ShutdownHandle;
+
+    fn relay(port: Port, enable_udp: bool) -> RelayServer {
+        let mut config = Config::new();
+        config.server = vec![ServerConfig::new("127.0.0.1".to_string(), port, "server-password".to_string(),
+                                               CipherType::Aes256Cfb)];
+        config.enable_udp = enable_udp;
+        config.workers = 1;
+        RelayServer::new(config)
+    }
+
+    // Runs the relay in another thread, which sends a message after `run` has returned
+    fn run(relay: &RelayServer) -> Receiver<()> {
+        let (tx, rx) = channel();
+        let relay = relay.clone();
+        Thread::spawn(move || {
+            relay.run();
+            let _ = tx.send(());
+        });
+        rx
+    }
+
+    fn returns_within(returned: &Receiver<()>, ms: i64) -> bool {
+        for _ in range(0, ms / 10) {
+            if returned.try_recv().is_ok() {
+                return true;
+            }
+            sleep(Duration::milliseconds(10));
+        }
+        returned.try_recv().is_ok()
+    }
+
+    // A client which never finishes its handshake, the server keeps it until it is closed
+    fn connect(port: Port) -> TcpStream {
+        for _ in range(0, 50us) {
+            if let Ok(stream) = TcpStream::connect(("127.0.0.1", port)) {
+                // Gives the worker time to accept it
+                sleep(Duration::milliseconds(200));
+                return stream;
+            }
+            sleep(Duration::milliseconds(100));
+        }
+        panic!("Failed to connect to the relay on port {}", port);
+    }
+
+    #[test]
+    fn test_returns_after_last_connection() {
+        let relay = relay(28401, false);
+        let returned = run(&relay);
+        let client = connect(28401);
+
+        ShutdownHandle::new(relay.clone(), 60000).shutdown();
+        assert!(!returns_within(&returned, 500));
+        assert!(TcpStream::connect(("127.0.0.1", 28401)).is_err());
+
+        drop(client);
+        assert!(returns_within(&returned, 2000));
+    }
+
+    #[test]
+    fn test_closes_connections_at_deadline() {
+        let relay = relay(28402, false);
+        let returned = run(&relay);
+        let mut client = connect(28402);
+
+        ShutdownHandle::new(relay.clone(), 300).shutdown();
+        assert!(returns_within(&returned, 2000));
+
+        // Closed by the server, not timed out
+        client.set_read_timeout(Some(1000));
+        let mut buf = [0u8; 16];
+        let err = client.read(&mut buf).unwrap_err();
+        assert!(err.kind != TimedOut);
+    }
+
+    #[test]
+    fn test_shutdown_before_run() {
+        let relay = relay(28403, false);
+        ShutdownHandle::new(relay.clone(), 60000).shutdown();
+
+        let returned = run(&relay);
+        assert!(returns_within(&returned, 1000));
+    }
+
+    #[cfg(feature = "enable-udp")]
+    #[test]
+    fn test_udp_exits_at_once() {
+        let relay = relay(28404, true);
+        let returned = run(&relay);
+        // Both relays are running
+        drop(connect(28404));
+
+        ShutdownHandle::new(relay.clone(), 60000).shutdown();
+        assert!(returns_within(&returned, 1000));
+    }
+}
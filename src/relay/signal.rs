@@ -0,0 +1,109 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! Signals caught by `sslocal` and `ssserver`
+//!
+//! A signal handler could do little more than setting a flag, so signals are caught into flags
+//! here, which are polled by the threads acting on them.
+
+extern crate libc;
+
+use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+
+mod ffi {
+    extern crate libc;
+
+    pub const SIGHUP: libc::c_int = 1;
+    pub const SIGINT: libc::c_int = 2;
+    pub const SIGTERM: libc::c_int = 15;
+
+    extern {
+        pub fn signal(signum: libc::c_int, handler: libc::size_t) -> libc::size_t;
+        pub fn raise(signum: libc::c_int) -> libc::c_int;
+    }
+}
+
+static HANGUP: AtomicBool = ATOMIC_BOOL_INIT;
+static TERMINATE: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn on_hangup(_: libc::c_int) {
+    HANGUP.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn on_terminate(_: libc::c_int) {
+    TERMINATE.store(true, Ordering::SeqCst);
+}
+
+/// Catches SIGHUP, which terminates the process by default
+pub fn catch_hangup() {
+    unsafe {
+        ffi::signal(ffi::SIGHUP, on_hangup as libc::size_t);
+    }
+}
+
+/// Whether SIGHUP has been caught since the last call
+pub fn take_hangup() -> bool {
+    HANGUP.swap(false, Ordering::SeqCst)
+}
+
+/// Catches SIGTERM and SIGINT, which terminate the process by default
+pub fn catch_terminate() {
+    unsafe {
+        ffi::signal(ffi::SIGTERM, on_terminate as libc::size_t);
+        ffi::signal(ffi::SIGINT, on_terminate as libc::size_t);
+    }
+}
+
+/// Whether SIGTERM or SIGINT has been caught since the last call
+pub fn take_terminate() -> bool {
+    TERMINATE.swap(false, Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod test_signal {
+    use relay::signal::{catch_hangup, take_hangup, catch_terminate, take_terminate, ffi};
+
+    #[test]
+    fn test_hangup() {
+        catch_hangup();
+        assert!(!take_hangup());
+
+        unsafe {
+            ffi::raise(ffi::SIGHUP);
+        }
+        assert!(take_hangup());
+        assert!(!take_hangup());
+    }
+
+    #[test]
+    fn test_terminate() {
+        catch_terminate();
+        assert!(!take_terminate());
+
+        unsafe {
+            ffi::raise(ffi::SIGTERM);
+            ffi::raise(ffi::SIGINT);
+        }
+        assert!(take_terminate());
+        assert!(!take_terminate());
+    }
+}
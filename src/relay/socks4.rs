@@ -0,0 +1,174 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! SOCKS4 and SOCKS4a protocol
+//!
+//! Requests are parsed into `socks5::Address`, so they could be relayed the same way as SOCKS5.
+
+use std::io::{Reader, IoResult, IoError, OtherIoError};
+use std::io::net::ip::Ipv4Addr;
+
+use relay::socks5::{Address, Command, Reply};
+
+pub const SOCKS4_VERSION : u8 = 0x04;
+
+const SOCKS4_CMD_TCP_CONNECT : u8 = 0x01;
+const SOCKS4_CMD_TCP_BIND    : u8 = 0x02;
+
+const SOCKS4_REPLY_GRANTED  : u8 = 0x5a;
+const SOCKS4_REPLY_REJECTED : u8 = 0x5b;
+
+// Longest user id or domain name accepted
+const MAX_FIELD_LENGTH : usize = 255;
+
+#[inline]
+fn make_io_error(desc: &'static str) -> IoError {
+    IoError {
+        kind: OtherIoError,
+        desc: desc,
+        detail: None,
+    }
+}
+
+fn read_null_terminated(reader: &mut Reader) -> IoResult<Vec<u8>> {
+    let mut buf = Vec::new();
+    loop {
+        match try!(reader.read_byte()) {
+            0 => return Ok(buf),
+            b if buf.len() < MAX_FIELD_LENGTH => buf.push(b),
+            _ => return Err(make_io_error("Field of SOCKS4 request is too long")),
+        }
+    }
+}
+
+// +----+----+----+----+----+----+----+----+----+----+....+----+
+// | VN | CD | DSTPORT |      DSTIP        | USERID       |NULL|
+// +----+----+----+----+----+----+----+----+----+----+....+----+
+// | 1  | 1  |    2    |        4          | Variable     | 1  |
+// +----+----+----+----+----+----+----+----+----+----+....+----+
+//
+// SOCKS4a sets DSTIP to 0.0.0.x (x != 0), and appends a domain name terminated by NULL
+#[derive(Clone, Debug)]
+pub struct TcpRequestHeader {
+    pub command: Command,
+    pub address: Address,
+    pub user_id: Vec<u8>,
+}
+
+impl TcpRequestHeader {
+    pub fn read_from(reader: &mut Reader) -> IoResult<TcpRequestHeader> {
+        let ver = try!(reader.read_byte());
+        if ver != SOCKS4_VERSION {
+            return Err(make_io_error("Invalid Socks4 version"));
+        }
+
+        let command = match try!(reader.read_byte()) {
+            SOCKS4_CMD_TCP_CONNECT => Command::TcpConnect,
+            SOCKS4_CMD_TCP_BIND => Command::TcpBind,
+            _ => return Err(make_io_error("Unsupported Socks4 command")),
+        };
+
+        let port = try!(reader.read_be_u16());
+        let mut ip = [0u8; 4];
+        try!(reader.read_at_least(4, &mut ip));
+        let user_id = try!(read_null_terminated(reader));
+
+        let address = if ip[0] == 0 && ip[1] == 0 && ip[2] == 0 && ip[3] != 0 {
+            let domain = try!(read_null_terminated(reader));
+            match String::from_utf8(domain) {
+                Ok(domain) => Address::DomainNameAddress(domain, port),
+                Err(..) => return Err(make_io_error("Invalid domain name in Socks4a request")),
+            }
+        } else {
+            Address::SocketAddress(Ipv4Addr(ip[0], ip[1], ip[2], ip[3]), port)
+        };
+
+        Ok(TcpRequestHeader {
+            command: command,
+            address: address,
+            user_id: user_id,
+        })
+    }
+}
+
+// +----+----+----+----+----+----+----+----+
+// | VN | CD | DSTPORT |      DSTIP        |
+// +----+----+----+----+----+----+----+----+
+// | 1  | 1  |    2    |        4          |
+// +----+----+----+----+----+----+----+----+
+#[derive(Clone, Debug, Copy)]
+pub struct TcpResponseHeader {
+    pub reply: Reply,
+}
+
+impl TcpResponseHeader {
+    pub fn new(reply: Reply) -> TcpResponseHeader {
+        TcpResponseHeader {
+            reply: reply,
+        }
+    }
+
+    pub fn write_to(&self, writer: &mut Writer) -> IoResult<()> {
+        // SOCKS4 has only one code for all failures
+        let code = match self.reply {
+            Reply::Succeeded => SOCKS4_REPLY_GRANTED,
+            _ => SOCKS4_REPLY_REJECTED,
+        };
+
+        // DSTPORT and DSTIP are ignored by clients
+        try!(writer.write(&[0x00, code, 0, 0, 0, 0, 0, 0]));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_socks4 {
+    use std::io::BufReader;
+    use std::io::net::ip::Ipv4Addr;
+
+    use relay::socks4::{TcpRequestHeader, TcpResponseHeader};
+    use relay::socks5::{Address, Reply};
+
+    #[test]
+    fn test_request() {
+        let socks4 = [4u8, 1, 0x1f, 0x90, 10, 0, 0, 1, b'u', 0];
+        let header = TcpRequestHeader::read_from(&mut BufReader::new(&socks4)).unwrap();
+        assert_eq!(header.address, Address::SocketAddress(Ipv4Addr(10, 0, 0, 1), 8080));
+        assert_eq!(header.user_id, vec![b'u']);
+
+        let socks4a = b"\x04\x01\x00\x50\x00\x00\x00\x01\x00example.com\x00";
+        let header = TcpRequestHeader::read_from(&mut BufReader::new(socks4a)).unwrap();
+        assert_eq!(header.address, Address::DomainNameAddress("example.com".to_string(), 80));
+
+        let unknown_command = [4u8, 3, 0, 80, 10, 0, 0, 1, 0];
+        assert!(TcpRequestHeader::read_from(&mut BufReader::new(&unknown_command)).is_err());
+    }
+
+    #[test]
+    fn test_response() {
+        let mut buf = Vec::new();
+        TcpResponseHeader::new(Reply::Succeeded).write_to(&mut buf).unwrap();
+        TcpResponseHeader::new(Reply::HostUnreachable).write_to(&mut buf).unwrap();
+        assert_eq!(buf, vec![0, 0x5a, 0, 0, 0, 0, 0, 0, 0, 0x5b, 0, 0, 0, 0, 0, 0]);
+    }
+}
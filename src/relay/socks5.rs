@@ -22,6 +22,7 @@
 #![allow(dead_code)]
 
 use std::fmt::{self, Debug, Formatter};
+use std::collections::BTreeMap;
 use std::io::net::ip::{IpAddr, Port};
 use std::io::net::ip::{Ipv4Addr, Ipv6Addr};
 use std::io::{Reader, IoResult, IoError, OtherIoError};
@@ -33,6 +34,11 @@ pub const SOCKS5_AUTH_METHOD_GSSAPI          : u8 = 0x01;
 pub const SOCKS5_AUTH_METHOD_PASSWORD        : u8 = 0x02;
 pub const SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE  : u8 = 0xff;
 
+const SOCKS5_PASSWD_AUTH_VERSION : u8 = 0x01;
+
+pub const SOCKS5_PASSWD_AUTH_SUCCEEDED : u8 = 0x00;
+pub const SOCKS5_PASSWD_AUTH_FAILED    : u8 = 0x01;
+
 const SOCKS5_CMD_TCP_CONNECT   : u8 = 0x01;
 const SOCKS5_CMD_TCP_BIND      : u8 = 0x02;
 const SOCKS5_CMD_UDP_ASSOCIATE : u8 = 0x03;
@@ -489,6 +495,119 @@ impl HandshakeResponse {
     }
 }
 
+// Username/Password authentication request, RFC 1929
+// +----+------+----------+------+----------+
+// |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+// +----+------+----------+------+----------+
+// | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+// +----+------+----------+------+----------+
+#[derive(Clone)]
+pub struct PasswdAuthRequest {
+    pub uname: Vec<u8>,
+    pub passwd: Vec<u8>,
+}
+
+impl PasswdAuthRequest {
+    pub fn new(uname: Vec<u8>, passwd: Vec<u8>) -> PasswdAuthRequest {
+        PasswdAuthRequest {
+            uname: uname,
+            passwd: passwd,
+        }
+    }
+
+    pub fn read_from(stream: &mut Reader) -> IoResult<PasswdAuthRequest> {
+        let ver = try!(stream.read_byte());
+        if ver != SOCKS5_PASSWD_AUTH_VERSION {
+            return Err(IoError {
+                kind: OtherIoError,
+                desc: "Invalid username/password authentication version",
+                detail: None,
+            });
+        }
+
+        let ulen = try!(stream.read_byte());
+        let uname = try!(stream.read_exact(ulen as usize));
+        let plen = try!(stream.read_byte());
+        let passwd = try!(stream.read_exact(plen as usize));
+
+        Ok(PasswdAuthRequest::new(uname, passwd))
+    }
+
+    /// Whether the username and password belong to one of `users`. Passwords are compared in
+    /// constant time, so that the time of a failure tells nothing about the password.
+    pub fn check(&self, users: &BTreeMap<String, String>) -> bool {
+        let uname = String::from_utf8_lossy(self.uname.as_slice()).into_owned();
+        match users.get(&uname) {
+            Some(passwd) => constant_time_eq(passwd.as_bytes(), self.passwd.as_slice()),
+            None => false,
+        }
+    }
+
+    pub fn write_to(&self, stream: &mut Writer) -> IoResult<()> {
+        try!(stream.write(&[SOCKS5_PASSWD_AUTH_VERSION, self.uname.len() as u8]));
+        try!(stream.write(self.uname.as_slice()));
+        try!(stream.write(&[self.passwd.len() as u8]));
+        try!(stream.write(self.passwd.as_slice()));
+
+        Ok(())
+    }
+}
+
+// Only the length of `a` and `b` could be told by timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |diff, (x, y)| diff | (*x ^ *y)) == 0
+}
+
+// Password should never be logged
+impl Debug for PasswdAuthRequest {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "PasswdAuthRequest {{ uname: {:?} }}", String::from_utf8_lossy(self.uname.as_slice()))
+    }
+}
+
+// +----+--------+
+// |VER | STATUS |
+// +----+--------+
+// | 1  |   1    |
+// +----+--------+
+#[derive(Clone, Debug, Copy)]
+pub struct PasswdAuthResponse {
+    pub status: u8,
+}
+
+impl PasswdAuthResponse {
+    pub fn new(status: u8) -> PasswdAuthResponse {
+        PasswdAuthResponse {
+            status: status,
+        }
+    }
+
+    pub fn read_from(stream: &mut Reader) -> IoResult<PasswdAuthResponse> {
+        let mut buf = [0; 2];
+        try!(stream.read_at_least(2, &mut buf));
+        let [ver, status] = buf;
+
+        if ver != SOCKS5_PASSWD_AUTH_VERSION {
+            return Err(IoError {
+                kind: OtherIoError,
+                desc: "Invalid username/password authentication version",
+                detail: None,
+            });
+        }
+
+        Ok(PasswdAuthResponse::new(status))
+    }
+
+    pub fn write_to(&self, stream: &mut Writer) -> IoResult<()> {
+        try!(stream.write(&[SOCKS5_PASSWD_AUTH_VERSION, self.status]));
+
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct UdpAssociateHeader {
     pub frag: u8,
@@ -528,3 +647,49 @@ impl UdpAssociateHeader {
         3 + self.address.len()
     }
 }
+
+#[cfg(test)]
+mod test_socks5 {
+    use std::collections::BTreeMap;
+    use std::io::BufReader;
+
+    use relay::socks5::{PasswdAuthRequest, PasswdAuthResponse, SOCKS5_PASSWD_AUTH_FAILED};
+
+    fn users() -> BTreeMap<String, String> {
+        let mut users = BTreeMap::new();
+        users.insert("alice".to_string(), "alice-password".to_string());
+        users.insert("bob".to_string(), "bob-password".to_string());
+        users
+    }
+
+    #[test]
+    fn test_passwd_auth() {
+        let mut buf = Vec::new();
+        PasswdAuthRequest::new(b"alice".to_vec(), b"alice-password".to_vec()).write_to(&mut buf).unwrap();
+        assert_eq!(buf, b"\x01\x05alice\x0ealice-password".to_vec());
+
+        let req = PasswdAuthRequest::read_from(&mut BufReader::new(buf.as_slice())).unwrap();
+        assert!(req.check(&users()));
+
+        // Truncated, or of another version
+        assert!(PasswdAuthRequest::read_from(&mut BufReader::new(&buf[..buf.len() - 1])).is_err());
+        assert!(PasswdAuthRequest::read_from(&mut BufReader::new(b"\x05\x01a\x01b")).is_err());
+
+        let mut buf = Vec::new();
+        PasswdAuthResponse::new(SOCKS5_PASSWD_AUTH_FAILED).write_to(&mut buf).unwrap();
+        let resp = PasswdAuthResponse::read_from(&mut BufReader::new(buf.as_slice())).unwrap();
+        assert_eq!(resp.status, SOCKS5_PASSWD_AUTH_FAILED);
+    }
+
+    #[test]
+    fn test_check_passwd() {
+        let users = users();
+        assert!(PasswdAuthRequest::new(b"bob".to_vec(), b"bob-password".to_vec()).check(&users));
+
+        // Passwords of other users, prefixes and extensions are all wrong
+        for passwd in ["alice-password", "bob-passwor", "bob-password!", "bob-passworD", ""].iter() {
+            assert!(!PasswdAuthRequest::new(b"bob".to_vec(), passwd.as_bytes().to_vec()).check(&users));
+        }
+        assert!(!PasswdAuthRequest::new(b"carol".to_vec(), b"bob-password".to_vec()).check(&users));
+    }
+}
@@ -0,0 +1,230 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Request and response headers of TCP streams in the Shadowsocks 2022 edition (SIP022)
+//!
+//! ```plain
+//! Request:  salt | sealed(type 0, timestamp, length of variable header)
+//!                | sealed(address, padding length, padding, initial payload) | chunks...
+//! Response: salt | sealed(type 1, timestamp, request salt, length of first chunk)
+//!                | sealed(first chunk) | chunks...
+//! ```
+
+use std::io::{IoResult, IoError, OtherIoError, BufReader, MemReader};
+use std::collections::HashMap;
+use std::iter::repeat;
+use std::rand::{self, Rng};
+
+use time;
+
+use crypto::aead2022::is_timestamp_valid;
+use relay::socks5::Address;
+use relay::tcprelay::stream::{Decryptor, EncryptedWriter};
+
+pub const HEADER_TYPE_CLIENT_STREAM: u8 = 0;
+pub const HEADER_TYPE_SERVER_STREAM: u8 = 1;
+
+/// Maximum length of random padding in request header
+pub const MAX_PADDING_SIZE: usize = 900;
+
+/// Salts have to be remembered for at least twice of `aead2022::MAX_TIME_DIFF`
+pub const SALT_WINDOW: i64 = 60;
+
+// type (1) + timestamp (8) + length (2)
+const REQUEST_FIXED_HEADER_SIZE: usize = 11;
+
+#[inline]
+fn make_io_error(desc: &'static str, detail: Option<String>) -> IoError {
+    IoError {
+        kind: OtherIoError,
+        desc: desc,
+        detail: detail,
+    }
+}
+
+fn check_timestamp(timestamp: u64) -> IoResult<()> {
+    if !is_timestamp_valid(timestamp) {
+        return Err(make_io_error("Timestamp in header is out of the allowed window",
+                                 Some(format!("timestamp {}, local time {}", timestamp, time::get_time().sec))));
+    }
+    Ok(())
+}
+
+/// Writes the request header with a random padding
+pub fn write_request_header<W: Writer>(w: &mut EncryptedWriter<W>, addr: &Address) -> IoResult<()> {
+    let mut rng = rand::thread_rng();
+    let padding_len = rng.gen_range(1us, MAX_PADDING_SIZE + 1);
+
+    let mut var_header = Vec::with_capacity(addr.len() + 2 + padding_len);
+    try!(addr.write_to(&mut var_header));
+    try!(var_header.write_be_u16(padding_len as u16));
+    let mut padding: Vec<u8> = repeat(0u8).take(padding_len).collect();
+    rng.fill_bytes(padding.as_mut_slice());
+    var_header.push_all(padding.as_slice());
+
+    let mut fixed_header = Vec::with_capacity(REQUEST_FIXED_HEADER_SIZE);
+    try!(fixed_header.write_u8(HEADER_TYPE_CLIENT_STREAM));
+    try!(fixed_header.write_be_u64(time::get_time().sec as u64));
+    try!(fixed_header.write_be_u16(var_header.len() as u16));
+
+    try!(w.write_sealed(fixed_header.as_slice()));
+    w.write_sealed(var_header.as_slice())
+}
+
+/// Reads the request header and returns the target address, or `None` if the header
+/// hasn't been completely received.
+///
+/// `var_header_len` keeps the length read from the fixed header between calls, it should
+/// be `None` at first. The initial payload in the header will be put back into `d`.
+pub fn read_request_header(d: &mut Decryptor, var_header_len: &mut Option<usize>) -> IoResult<Option<Address>> {
+    if var_header_len.is_none() {
+        let fixed_header = match try!(d.read_sealed(REQUEST_FIXED_HEADER_SIZE)) {
+            Some(h) => h,
+            None => return Ok(None),
+        };
+        let mut reader = BufReader::new(fixed_header.as_slice());
+        if try!(reader.read_u8()) != HEADER_TYPE_CLIENT_STREAM {
+            return Err(make_io_error("Invalid request header type", None));
+        }
+        try!(check_timestamp(try!(reader.read_be_u64())));
+        *var_header_len = Some(try!(reader.read_be_u16()) as usize);
+    }
+
+    let var_header = match try!(d.read_sealed(var_header_len.unwrap())) {
+        Some(h) => h,
+        None => return Ok(None),
+    };
+    let mut reader = MemReader::new(var_header);
+    let addr = try!(Address::read_from(&mut reader).map_err(|err| {
+        make_io_error("Invalid address in request header", Some(err.to_string()))
+    }));
+    let padding_len = try!(reader.read_be_u16()) as usize;
+    if padding_len > MAX_PADDING_SIZE {
+        return Err(make_io_error("Padding in request header is too long", None));
+    }
+    try!(reader.read_exact(padding_len));
+
+    let initial_payload = try!(reader.read_to_end());
+    if initial_payload.is_empty() && padding_len == 0 {
+        return Err(make_io_error("Request header without padding and initial payload", None));
+    }
+    d.unread(initial_payload.as_slice());
+
+    Ok(Some(addr))
+}
+
+/// Sets the response header, which will be sent with the first chunk
+pub fn set_response_header<W: Writer>(w: &mut EncryptedWriter<W>, request_salt: &[u8]) {
+    let mut header = Vec::with_capacity(1 + 8 + request_salt.len() + 2);
+    header.write_u8(HEADER_TYPE_SERVER_STREAM).unwrap();
+    header.write_be_u64(time::get_time().sec as u64).unwrap();
+    header.push_all(request_salt);
+    w.set_pending_header(header);
+}
+
+/// Reads the response header and verifies the request salt in it, returns `false` if the
+/// header hasn't been completely received.
+///
+/// The first chunk will be decrypted by the following `Decryptor::decrypt` of `d`.
+pub fn read_response_header(d: &mut Decryptor, request_salt: &[u8]) -> IoResult<bool> {
+    let header = match try!(d.read_sealed(1 + 8 + request_salt.len() + 2)) {
+        Some(h) => h,
+        None => return Ok(false),
+    };
+    let mut reader = BufReader::new(header.as_slice());
+    if try!(reader.read_u8()) != HEADER_TYPE_SERVER_STREAM {
+        return Err(make_io_error("Invalid response header type", None));
+    }
+    try!(check_timestamp(try!(reader.read_be_u64())));
+    if try!(reader.read_exact(request_salt.len())).as_slice() != request_salt {
+        return Err(make_io_error("Request salt mismatched in response header", None));
+    }
+    d.set_payload_len(try!(reader.read_be_u16()) as usize);
+    Ok(true)
+}
+
+/// Remembers salts of requests in the last `SALT_WINDOW` seconds to reject replayed requests
+pub struct SaltReplayWindow {
+    salts: HashMap<Vec<u8>, i64>,
+    last_purge: i64,
+}
+
+impl SaltReplayWindow {
+    pub fn new() -> SaltReplayWindow {
+        SaltReplayWindow {
+            salts: HashMap::new(),
+            last_purge: time::get_time().sec,
+        }
+    }
+
+    /// Returns `false` if the salt has been seen in the window
+    pub fn check_and_insert(&mut self, salt: &[u8]) -> bool {
+        let now = time::get_time().sec;
+        if now - self.last_purge > SALT_WINDOW {
+            let expired = self.salts.iter()
+                                    .filter(|&(_, t)| now - *t > SALT_WINDOW)
+                                    .map(|(s, _)| s.clone())
+                                    .collect::<Vec<Vec<u8>>>();
+            for s in expired.iter() {
+                self.salts.remove(s);
+            }
+            self.last_purge = now;
+        }
+
+        if self.salts.contains_key(salt) {
+            return false;
+        }
+        self.salts.insert(salt.to_vec(), now);
+        true
+    }
+}
+
+#[cfg(all(test, feature = "cipher-2022-blake3-aes-256-gcm"))]
+mod test_aead2022 {
+    use std::iter::repeat;
+
+    use crypto::cipher::CipherType;
+    use relay::tcprelay::aead2022::{set_response_header, read_response_header};
+    use relay::tcprelay::stream::{EncryptedWriter, Decryptor};
+
+    #[test]
+    fn test_empty_response() {
+        let t = CipherType::Blake3Aes256Gcm;
+        let key = repeat(7u8).take(t.key_size()).collect::<Vec<u8>>();
+        let request_salt = repeat(1u8).take(t.salt_size()).collect::<Vec<u8>>();
+        let salt = repeat(2u8).take(t.salt_size()).collect::<Vec<u8>>();
+
+        // The target has closed without sending anything
+        let mut writer = EncryptedWriter::with_type(Vec::new(), t, key.as_slice(), salt.as_slice());
+        set_response_header(&mut writer, request_salt.as_slice());
+        writer.finalize().unwrap();
+
+        let mut decryptor = Decryptor::new(t, key.as_slice());
+        decryptor.feed(salt.as_slice());
+        decryptor.feed(writer.get_ref().as_slice());
+        assert!(read_response_header(&mut decryptor, request_salt.as_slice()).unwrap());
+
+        let mut out = Vec::new();
+        decryptor.decrypt(&mut out).unwrap();
+        assert!(out.is_empty());
+        assert!(!decryptor.has_incomplete());
+    }
+}
@@ -0,0 +1,799 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! HTTP proxy front-end of sslocal
+//!
+//! `CONNECT` requests are answered after the connection to the shadowsocks server has been
+//! established, then the connection becomes a plain `Tunnel`.
+//!
+//! Other requests must have absolute URIs. They are rewritten into origin-form without
+//! hop-by-hop headers, and sent through the same kind of encrypted connection as `CONNECT`.
+//! Requests of a keep-alive connection share one remote connection while they go to the same
+//! origin server. A request to another origin server waits until all responses from the
+//! current one have been received, then a new remote connection is made for it.
+//!
+//! A request which needs a new remote connection through the shadowsocks server waits for the worker
+//! to pick a server for its target, and to resolve the domain name of the server. A request to a
+//! domain name which the ACL bypasses waits for the worker to resolve it.
+//!
+//! If connecting through the server fails, or the server closes the connection before responding,
+//! the request is sent again through another server picked by the worker. It is answered with `502`
+//! after all attempts have failed, or if the target of a direct connection couldn't be reached.
+//!
+//! If SOCKS5 users are defined, every request must carry the credentials of one of them in
+//! `Proxy-Authorization` with the Basic scheme, otherwise it is answered with `407`.
+
+use std::io::{IoResult, IoError, OtherIoError};
+use std::io::net::ip::{SocketAddr, IpAddr};
+use std::collections::{BTreeMap, RingBuf};
+use std::sync::Arc;
+use std::mem;
+
+use config::ServerConfig;
+
+use relay::socks5::{Address, PasswdAuthRequest};
+use relay::acl::AccessControl;
+use relay::http::{self, RequestHead, ResponseHead, BodyTracker, BodyLength, MAX_HEAD_SIZE};
+use relay::eventloop::{EventLoop, Handler, Ready, TcpStream};
+use relay::tcprelay::tunnel::{Endpoint, Tunnel, Codec, MAX_PENDING_SIZE};
+use relay::tcprelay::tunnel::{client_token, remote_token, resolved_target, resolved_server, keep_for_replay};
+use relay::tcprelay::tunnel::{Lookup, Attempts, Routed};
+
+#[inline]
+fn make_io_error(desc: &'static str, detail: Option<String>) -> IoError {
+    IoError {
+        kind: OtherIoError,
+        desc: desc,
+        detail: detail,
+    }
+}
+
+/// Connection to one origin server, through the shadowsocks server or directly
+struct Upstream {
+    remote: Endpoint,
+    codec: Codec,
+    addr: Address,
+    /// The origin server hasn't asked to close the connection
+    reusable: bool,
+    through_server: bool,
+    /// Data sent through the server, kept until it responds for sending it again through another
+    /// server. `None` if there is too much, or for direct connections.
+    replay: Option<Vec<u8>>,
+    responded: bool,
+}
+
+impl Upstream {
+    // Sends data from the client
+    fn send(&mut self, data: &[u8]) -> IoResult<()> {
+        keep_for_replay(&mut self.replay, data);
+        self.codec.transform(true, data, &mut self.remote.out)
+    }
+}
+
+/// A request whose response hasn't been forwarded completely
+struct Pending {
+    head_request: bool,
+    /// Close the client connection after the response
+    close: bool,
+}
+
+enum Request {
+    Connect(Address),
+    Forward(Address, RequestHead, BodyLength),
+}
+
+/// A connection of the HTTP proxy, until it becomes a `Tunnel` by `CONNECT`
+pub struct HttpProxy {
+    pub client: Endpoint,
+    pub peer: SocketAddr,
+    id: usize,
+    acl: Option<Arc<AccessControl>>,
+    users: Arc<BTreeMap<String, String>>,
+    /// Target of a direct connection or server which is being resolved, the request waits for it
+    pub lookup: Lookup,
+    /// Target of the request which the worker should pick a server for, the request waits for it
+    pub pick: Option<Address>,
+    /// Server picked for the request, which is taken by the new remote connection
+    server: Option<(ServerConfig, SocketAddr)>,
+    /// Server picked for the request, while its domain name is being resolved
+    resolving: Option<ServerConfig>,
+    /// Servers tried for the last request which has needed a server
+    pub attempts: Attempts,
+    /// Connecting through the server has failed, the worker should retry or give up
+    failed: Option<IoError>,
+    /// Target of the remote connection which has failed before responding, and data sent to it,
+    /// while the worker picks another server
+    retry: Option<(Address, Vec<u8>)>,
+    /// The server has responded since `take_responded`
+    responded: bool,
+    upstream: Option<Upstream>,
+    /// Target of the `CONNECT` request, while connecting to the shadowsocks server
+    connect: Option<Address>,
+    /// Data from the client which hasn't been forwarded
+    received: Vec<u8>,
+    /// Body of the request being forwarded
+    request: Option<BodyTracker>,
+    pending: RingBuf<Pending>,
+    /// Decrypted data from the remote which hasn't been forwarded
+    decrypted: Vec<u8>,
+    /// Body of the response being forwarded
+    response: Option<BodyTracker>,
+    /// No more requests will be read, the connection will be closed after the last response
+    closing: bool,
+}
+
+impl HttpProxy {
+    pub fn new(stream: TcpStream, peer: SocketAddr, id: usize, acl: Option<Arc<AccessControl>>,
+               users: Arc<BTreeMap<String, String>>) -> HttpProxy {
+        HttpProxy {
+            client: Endpoint::new(stream, client_token(id)),
+            peer: peer,
+            id: id,
+            acl: acl,
+            users: users,
+            lookup: Lookup::Idle,
+            pick: None,
+            server: None,
+            resolving: None,
+            attempts: Attempts::new(),
+            failed: None,
+            retry: None,
+            responded: false,
+            upstream: None,
+            connect: None,
+            received: Vec::new(),
+            request: None,
+            pending: RingBuf::new(),
+            decrypted: Vec::new(),
+            response: None,
+            closing: false,
+        }
+    }
+
+    /// Handles readiness of the client
+    pub fn client_ready(&mut self, ready: Ready, buf: &mut [u8]) -> IoResult<()> {
+        if ready.error {
+            try!(self.client.stream.take_socket_error());
+        }
+
+        if ready.readable || ready.hangup {
+            while self.can_read_client() {
+                match try!(self.client.read(buf)) {
+                    None | Some(0) => break,
+                    Some(n) => {
+                        self.received.push_all(&buf[..n]);
+                        try!(self.process_requests());
+                    },
+                }
+            }
+        }
+
+        if ready.hangup {
+            self.client.hang_up();
+        }
+
+        self.flush()
+    }
+
+    /// Continues the request waiting for its target or its server to be resolved
+    pub fn resolved(&mut self, addrs: Option<Vec<IpAddr>>) -> IoResult<()> {
+        if let Some(server) = self.resolving.take() {
+            self.lookup = Lookup::Idle;
+            let server_addr = resolved_server(&server, addrs).map(Some);
+            return self.connect_server(server, server_addr);
+        }
+
+        self.lookup = Lookup::Done(addrs);
+        try!(self.process_requests());
+        self.flush()
+    }
+
+    /// Whether the server has responded since the last call
+    pub fn take_responded(&mut self) -> bool {
+        mem::replace(&mut self.responded, false)
+    }
+
+    // Continues the request waiting for a server after the worker has picked `server`, or sends the
+    // request again through `server` after the previous server has failed
+    fn connect_server(&mut self, server: ServerConfig, server_addr: IoResult<Option<SocketAddr>>) -> IoResult<()> {
+        match server_addr {
+            Ok(Some(server_addr)) => {
+                match self.retry.take() {
+                    Some((addr, data)) => try!(self.reconnect(&server, server_addr, addr, data)),
+                    None => self.server = Some((server, server_addr)),
+                }
+                try!(self.process_requests());
+            },
+            Ok(None) => {
+                self.lookup = Lookup::Requested(server.addr.clone());
+                self.resolving = Some(server);
+            },
+            Err(err) => self.failed = Some(err),
+        }
+        self.flush()
+    }
+
+    // Connects to `addr` through `server` at `server_addr`, and sends `data` which has been sent
+    // through the failed server
+    fn reconnect(&mut self, server: &ServerConfig, server_addr: SocketAddr, addr: Address, data: Vec<u8>)
+                 -> IoResult<()> {
+        let stream = match TcpStream::connect(&server_addr) {
+            Ok(stream) => stream,
+            Err(err) => {
+                self.retry = Some((addr, data));
+                self.failed = Some(err);
+                return Ok(());
+            }
+        };
+
+        let mut remote = Endpoint::connecting(stream, remote_token(self.id));
+        let codec = try!(Codec::for_request(server, &addr, &mut remote.out));
+        let mut up = Upstream {
+            remote: remote,
+            codec: codec,
+            addr: addr,
+            reusable: true,
+            through_server: true,
+            replay: Some(Vec::new()),
+            responded: false,
+        };
+        try!(up.send(data.as_slice()));
+        self.upstream = Some(up);
+        Ok(())
+    }
+
+    /// Handles readiness of the remote.
+    ///
+    /// Returns `true` if the `CONNECT` request has succeeded, the connection should be turned
+    /// into a tunnel by `into_tunnel`.
+    pub fn remote_ready(&mut self, ready: Ready, buf: &mut [u8]) -> IoResult<bool> {
+        let result = match self.upstream {
+            Some(ref mut up) => {
+                if up.remote.connecting {
+                    up.remote.finish_connect()
+                } else if ready.error {
+                    up.remote.stream.take_socket_error()
+                } else {
+                    Ok(())
+                }
+            },
+            None => return Ok(false),
+        };
+
+        if let Err(err) = result {
+            try!(self.upstream_failed(err));
+            return self.flush().map(|_| false);
+        }
+
+        if self.connect.is_some() {
+            self.client.out.push_all(b"HTTP/1.1 200 Connection Established\r\n\r\n");
+            return Ok(true);
+        }
+
+        if ready.readable || ready.hangup {
+            try!(self.read_remote(ready.hangup, buf));
+        }
+
+        self.flush().map(|_| false)
+    }
+
+    // Reads responses until the remote blocks, the client has too much pending data or EOF.
+    // Everything is read if the remote has hung up.
+    fn read_remote(&mut self, drain: bool, buf: &mut [u8]) -> IoResult<()> {
+        loop {
+            if !drain && self.client.out.len() >= MAX_PENDING_SIZE {
+                return Ok(());
+            }
+
+            let eof = {
+                let up = match self.upstream {
+                    Some(ref mut up) => up,
+                    None => return Ok(()),
+                };
+                if up.remote.connecting {
+                    return Ok(());
+                }
+
+                match up.remote.read(buf) {
+                    Ok(None) => return Ok(()),
+                    Ok(Some(0)) => {
+                        try!(up.codec.finish(false, &mut self.decrypted));
+                        Ok(true)
+                    },
+                    Ok(Some(n)) => {
+                        if !up.responded {
+                            up.responded = true;
+                            up.replay = None;
+                            self.responded = up.through_server;
+                        }
+                        try!(up.codec.transform(false, &buf[..n], &mut self.decrypted));
+                        Ok(false)
+                    },
+                    Err(err) => Err(err),
+                }
+            };
+            let eof = match eof {
+                Ok(eof) => eof,
+                Err(err) => return self.upstream_failed(err),
+            };
+
+            try!(self.process_responses());
+            if eof {
+                try!(self.upstream_closed());
+            }
+
+            // A request may be waiting for all responses
+            if self.pending.is_empty() {
+                try!(self.process_requests());
+            }
+            if eof {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Turns the connection into a tunnel after the `CONNECT` request has succeeded, with the servers
+    /// which have been tried if the target is connected through a server
+    pub fn into_tunnel(mut self) -> IoResult<(Tunnel, Address, Option<Attempts>)> {
+        let up = self.upstream.take().unwrap();
+        let mut tunnel = Tunnel::new(self.client, up.remote, up.codec);
+        let attempts = if up.through_server {
+            tunnel.keep_for_retry();
+            Some(self.attempts)
+        } else {
+            None
+        };
+
+        if self.received.is_empty() {
+            try!(tunnel.flush());
+        } else {
+            // Data sent by the client right after the request
+            try!(tunnel.feed_client(self.received.as_slice()));
+        }
+        Ok((tunnel, up.addr, attempts))
+    }
+
+    fn can_read_client(&self) -> bool {
+        let remote_full = match self.upstream {
+            Some(ref up) => up.remote.out.len() >= MAX_PENDING_SIZE,
+            None => false,
+        };
+        !self.client.read_closed && !self.closing && self.connect.is_none()
+            && self.received.len() < MAX_HEAD_SIZE && !remote_full
+    }
+
+    // Forwards requests in `received` as far as possible
+    fn process_requests(&mut self) -> IoResult<()> {
+        loop {
+            if self.waits_for_server() {
+                return Ok(());
+            }
+
+            if let Some(mut tracker) = self.request.take() {
+                let n = try!(tracker.advance(self.received.as_slice()));
+                if let Some(ref mut up) = self.upstream {
+                    try!(up.send(&self.received[..n]));
+                }
+                self.received = self.received[n..].to_vec();
+
+                if !tracker.is_done() {
+                    self.request = Some(tracker);
+                    return Ok(());
+                }
+                continue;
+            }
+
+            if self.closing || self.connect.is_some() || self.received.is_empty() || self.lookup.is_waiting() {
+                return Ok(());
+            }
+
+            let len = match http::head_length(self.received.as_slice()) {
+                Some(len) => len,
+                None => {
+                    if self.received.len() >= MAX_HEAD_SIZE {
+                        self.reject("431 Request Header Fields Too Large");
+                    }
+                    return Ok(());
+                }
+            };
+
+            let req = match self.parse_request(len) {
+                Ok(req) => req,
+                Err(status) => {
+                    self.reject(status);
+                    return Ok(());
+                }
+            };
+
+            match req {
+                Request::Connect(addr) => {
+                    // Responses of previous requests come first
+                    if !self.pending.is_empty() {
+                        return Ok(());
+                    }
+
+                    // The request is kept while its server is being picked or its target is being resolved
+                    if self.connect_upstream(addr.clone()) {
+                        info!("CONNECT {}", addr);
+                        self.received = self.received[len..].to_vec();
+                        self.connect = Some(addr);
+                    }
+                    return Ok(());
+                },
+                Request::Forward(addr, head, length) => {
+                    let reusable = match self.upstream {
+                        Some(ref up) => up.reusable && up.addr == addr,
+                        None => false,
+                    };
+                    if !reusable {
+                        if !self.pending.is_empty() {
+                            return Ok(());
+                        }
+                        if !self.connect_upstream(addr) {
+                            return Ok(());
+                        }
+                    }
+
+                    self.received = self.received[len..].to_vec();
+                    try!(self.forward_request(head, length));
+                }
+            }
+        }
+    }
+
+    // Waiting for the worker to pick a server, or to retry through another server
+    fn waits_for_server(&self) -> bool {
+        self.pick.is_some() || self.failed.is_some() || self.retry.is_some()
+    }
+
+    fn parse_request(&self, len: usize) -> Result<Request, &'static str> {
+        let head = match RequestHead::parse(&self.received[..len]) {
+            Ok(head) => head,
+            Err(err) => {
+                debug!("{} sent a bad request: {}", self.peer, err);
+                return Err("400 Bad Request");
+            }
+        };
+
+        if !self.users.is_empty() {
+            match http::proxy_credentials(&head.headers) {
+                Some((uname, passwd)) => {
+                    let req = PasswdAuthRequest::new(uname, passwd);
+                    if !req.check(&*self.users) {
+                        warn!("{} failed to authenticate as {:?}", self.peer, req);
+                        return Err("407 Proxy Authentication Required");
+                    }
+                },
+                None => {
+                    debug!("{} sent a request without credentials", self.peer);
+                    return Err("407 Proxy Authentication Required");
+                }
+            }
+        }
+
+        if head.method.as_slice() == "CONNECT" {
+            return match http::parse_authority(head.uri.as_slice(), None) {
+                Some(addr) => Ok(Request::Connect(addr)),
+                None => Err("400 Bad Request"),
+            };
+        }
+
+        let (addr, authority, path) = match http::parse_absolute_uri(head.uri.as_slice()) {
+            Some(target) => target,
+            None => {
+                debug!("{} requested a URI which is not absolute: {}", self.peer, head.uri);
+                return Err("400 Bad Request");
+            }
+        };
+        let length = match head.body_length() {
+            Ok(length) => length,
+            Err(..) => return Err("501 Not Implemented"),
+        };
+
+        let mut head = head;
+        http::strip_hop_by_hop(&mut head.headers);
+        http::set_header(&mut head.headers, "Host", authority);
+        head.uri = path;
+        Ok(Request::Forward(addr, head, length))
+    }
+
+    fn forward_request(&mut self, head: RequestHead, length: BodyLength) -> IoResult<()> {
+        info!("{} {}{}", head.method, self.upstream.as_ref().unwrap().addr, head.uri);
+
+        let close = head.wants_close();
+        self.pending.push_back(Pending {
+            head_request: head.method.as_slice() == "HEAD",
+            close: close,
+        });
+        // Nothing from the client would be handled after this request
+        self.closing = close;
+
+        let mut data = Vec::new();
+        try!(head.write_to(&mut data));
+        try!(self.upstream.as_mut().unwrap().send(data.as_slice()));
+
+        if length != BodyLength::Length(0) {
+            self.request = Some(BodyTracker::new(length));
+        }
+        Ok(())
+    }
+
+    // Forwards responses in `decrypted` as far as possible
+    fn process_responses(&mut self) -> IoResult<()> {
+        loop {
+            if let Some(mut tracker) = self.response.take() {
+                let n = try!(tracker.advance(self.decrypted.as_slice()));
+                self.client.out.push_all(&self.decrypted[..n]);
+                self.decrypted = self.decrypted[n..].to_vec();
+
+                if !tracker.is_done() {
+                    self.response = Some(tracker);
+                    return Ok(());
+                }
+                self.finish_response();
+                continue;
+            }
+
+            if self.decrypted.is_empty() {
+                return Ok(());
+            }
+
+            let len = match http::head_length(self.decrypted.as_slice()) {
+                Some(len) => len,
+                None if self.decrypted.len() >= MAX_HEAD_SIZE => {
+                    return Err(make_io_error("Response head is too large", None));
+                },
+                None => return Ok(()),
+            };
+
+            let mut head = try!(ResponseHead::parse(&self.decrypted[..len]));
+            self.decrypted = self.decrypted[len..].to_vec();
+
+            if head.status == 101 {
+                return Err(make_io_error("Protocol switching is not supported", None));
+            }
+
+            let (head_request, close) = match self.pending.front() {
+                Some(p) => (p.head_request, p.close),
+                None => return Err(make_io_error("Received a response without request", None)),
+            };
+
+            let length = try!(head.body_length(head_request));
+            // The client could only find the end of this body by EOF
+            let close = close || length == BodyLength::UntilClose;
+
+            if head.wants_close() {
+                if let Some(ref mut up) = self.upstream {
+                    up.reusable = false;
+                }
+            }
+
+            http::strip_hop_by_hop(&mut head.headers);
+            http::set_header(&mut head.headers, "Connection",
+                             if close { "close".to_string() } else { "keep-alive".to_string() });
+            try!(head.write_to(&mut self.client.out));
+
+            if !head.is_interim() {
+                self.pending.front_mut().unwrap().close = close;
+                self.response = Some(BodyTracker::new(length));
+            }
+        }
+    }
+
+    fn finish_response(&mut self) {
+        let pending = self.pending.pop_front().unwrap();
+        if pending.close {
+            self.closing = true;
+            self.received.clear();
+            self.request = None;
+        }
+    }
+
+    fn upstream_closed(&mut self) -> IoResult<()> {
+        if let Some(tracker) = self.response.take() {
+            if tracker.length() != BodyLength::UntilClose {
+                return Err(make_io_error("Remote closed in the middle of a response", None));
+            }
+            self.finish_response();
+        }
+
+        if !self.pending.is_empty() {
+            let err = make_io_error("Remote closed without responding", None);
+            return match self.upstream {
+                Some(Upstream { replay: Some(..), .. }) => self.upstream_failed(err),
+                _ => Err(err),
+            };
+        }
+
+        // Following requests need a new remote connection
+        self.upstream = None;
+        Ok(())
+    }
+
+    fn upstream_failed(&mut self, err: IoError) -> IoResult<()> {
+        // The worker picks another server to send the request again, if the server has failed before
+        // responding
+        if let Some(Upstream { addr, replay: Some(data), .. }) = self.upstream.take() {
+            self.retry = Some((addr, data));
+            self.failed = Some(err);
+            return Ok(());
+        }
+
+        if self.connect.is_none() && self.pending.is_empty() {
+            return Ok(());
+        }
+
+        if self.response.is_some() {
+            return Err(err);
+        }
+
+        error!("Failed to connect remote server: {}", err);
+        self.connect = None;
+        self.pending.clear();
+        self.reject("502 Bad Gateway");
+        Ok(())
+    }
+
+    // Starts a new remote connection to `addr`, replies `502` if it fails. Returns `false` also if a
+    // server should be picked or the target should be resolved first, the request is processed again
+    // after that.
+    fn connect_upstream(&mut self, addr: Address) -> bool {
+        self.upstream = None;
+
+        let direct = self.acl.as_ref().map_or(false, |acl| acl.check_bypass(&addr));
+        let server = if direct {
+            None
+        } else {
+            match self.server.take() {
+                Some(server) => Some(server),
+                None => {
+                    self.pick = Some(addr);
+                    return false;
+                }
+            }
+        };
+
+        let target = match server {
+            Some((_, server_addr)) => Ok(server_addr),
+            None => match addr {
+                Address::SocketAddress(ip, port) => Ok(SocketAddr { ip: ip, port: port }),
+                Address::DomainNameAddress(ref name, _) => match self.lookup.take_done() {
+                    Some(addrs) => resolved_target(&addr, addrs),
+                    None => {
+                        self.lookup = Lookup::Requested(name.clone());
+                        return false;
+                    }
+                },
+            },
+        };
+
+        let mut remote = match target.and_then(|target| TcpStream::connect(&target)) {
+            Ok(s) => Endpoint::connecting(s, remote_token(self.id)),
+            Err(err) if server.is_some() => {
+                // The worker retries through another server, the request is processed again after that
+                self.failed = Some(err);
+                return false;
+            },
+            Err(err) => {
+                error!("Failed to connect remote server: {}", err);
+                self.reject("502 Bad Gateway");
+                return false;
+            }
+        };
+
+        let codec = match server {
+            Some((ref server, _)) => Codec::for_request(server, &addr, &mut remote.out),
+            None => Ok(Codec::plain()),
+        };
+        match codec {
+            Ok(codec) => {
+                self.upstream = Some(Upstream {
+                    remote: remote,
+                    codec: codec,
+                    addr: addr,
+                    reusable: true,
+                    through_server: server.is_some(),
+                    replay: if server.is_some() { Some(Vec::new()) } else { None },
+                    responded: false,
+                });
+                true
+            },
+            Err(err) => {
+                error!("Failed to encrypt request header: {}", err);
+                self.reject("502 Bad Gateway");
+                false
+            }
+        }
+    }
+
+    // Replies an error, the connection will be closed after that.
+    // The reply waits for responses of previous requests.
+    fn reject(&mut self, status: &str) {
+        if !self.pending.is_empty() {
+            return;
+        }
+
+        // Clients are asked for credentials of the Basic scheme
+        let challenge = if status.starts_with("407") { "Proxy-Authenticate: Basic realm=\"sslocal\"\r\n" } else { "" };
+        let reply = format!("HTTP/1.1 {}\r\n{}Connection: close\r\nContent-Length: 0\r\n\r\n", status, challenge);
+        self.client.out.push_all(reply.as_bytes());
+        self.closing = true;
+        self.received.clear();
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        try!(self.client.flush());
+        match self.upstream {
+            Some(ref mut up) => up.remote.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// The connection could be dropped
+    pub fn is_finished(&self) -> bool {
+        if self.client.write_closed {
+            return true;
+        }
+        (self.closing || self.client.read_closed) && self.connect.is_none() && !self.waits_for_server()
+            && !self.lookup.is_waiting() && self.pending.is_empty() && self.client.out.is_empty()
+    }
+
+    pub fn update_interest<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) -> IoResult<()> {
+        let client_readable = self.can_read_client();
+        try!(self.client.update_interest(event_loop, client_readable));
+
+        let remote_readable = self.client.out.len() < MAX_PENDING_SIZE && !self.client.write_closed;
+        match self.upstream {
+            Some(ref mut up) => up.remote.update_interest(event_loop, remote_readable),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Routed for HttpProxy {
+    fn take_pending(&mut self) -> Option<(IpAddr, Address)> {
+        let peer = self.peer.ip;
+        self.pick.take().map(|addr| (peer, addr))
+    }
+
+    fn attempts(&mut self) -> &mut Attempts {
+        &mut self.attempts
+    }
+
+    fn take_failed(&mut self) -> Option<IoError> {
+        self.failed.take()
+    }
+
+    fn connect_through(&mut self, _id: usize, server: ServerConfig, server_addr: IoResult<Option<SocketAddr>>)
+                       -> IoResult<()> {
+        self.connect_server(server, server_addr)
+    }
+
+    fn give_up(&mut self, err: &IoError) -> IoResult<()> {
+        error!("Failed to connect remote server: {}", err);
+        self.retry = None;
+        self.connect = None;
+        self.pending.clear();
+        self.reject("502 Bad Gateway");
+        self.flush()
+    }
+}
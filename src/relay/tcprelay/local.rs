@@ -20,39 +20,77 @@
 // CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 //! TcpRelay server that running on local environment
+//!
+//! Connections are accepted and relayed by a pool of workers, each of them runs an event loop
+//! in its own thread. Clients could talk either SOCKS5 or HTTP, the latter is served on another
+//! listening address.
+//!
+//! Targets which the ACL bypasses are connected directly. Domain names of these targets and of
+//! servers are resolved in the task pool of `CachedDns` so that workers are not blocked.
+//!
+//! Servers could be replaced while the workers are running, established connections keep using
+//! the servers they have connected to.
+//!
+//! Shutting the relay down stops all listeners, and every worker exits after its connections have
+//! been finished, or closes them when the drain timeout expires.
 
-use std::io::{Listener, TcpListener, Acceptor, TcpStream};
-use std::io::{
-    IoResult,
-    IoError,
-    EndOfFile,
-    ConnectionFailed,
-    ConnectionRefused,
-    ConnectionReset,
-    ConnectionAborted,
-    BrokenPipe,
-    OtherIoError,
-};
+use std::io::{IoResult, IoError, OtherIoError};
+use std::io::{ConnectionFailed, ConnectionRefused, ConnectionReset, ConnectionAborted};
 use std::io::net::ip::{SocketAddr, IpAddr};
-use std::io::net::addrinfo::get_host_addresses;
-use std::io::{self, BufferedStream};
 use std::thread::Thread;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::iter::repeat;
 
-use config::Config;
+use config::{Config, ServerConfig, LocalMode, LoadBalancing, DEFAULT_DNS_CACHE_CAPACITY};
 
 use relay::Relay;
+use relay::socks4;
 use relay::socks5;
-use relay::loadbalancing::server::{LoadBalancer, RoundRobin};
-use relay::tcprelay::stream::{EncryptedWriter, DecryptedReader};
+use relay::association::{Associations, Association};
+use relay::acl::AccessControl;
+use relay::cached_dns::CachedDns;
+use relay::loadbalancing::server::{LoadBalancer, HealthChecker, Failures, new_load_balancer};
+use relay::eventloop::{EventLoop, Handler, Token, Interest, Ready, Timeout, TcpListener, TcpStream, Notifier, now_ms};
+use relay::parse::parse_partial;
+use relay::ratelimit::RateLimiter;
+use relay::metrics::{Metrics, ActiveConnection};
+use relay::plugin::Plugins;
+use relay::traffic::{TrafficStats, Traffic};
+use relay::tcprelay::tunnel::{Endpoint, Tunnel, Codec, RELAY_BUFFER_SIZE};
+use relay::tcprelay::tunnel::{client_token, remote_token, parse_token, log_error, resolved_target, resolved_server};
+use relay::tcprelay::tunnel::{Lookup, Attempts, Routed};
+use relay::tcprelay::tunnel::{THROTTLE_TOKEN, DRAIN_TOKEN, Throttled};
+use relay::tcprelay::http_proxy::HttpProxy;
 
-use crypto::cipher;
-use crypto::cipher::CipherType;
-use crypto::CryptoMode;
+const LISTENER_TOKEN: Token = Token(0);
+const HTTP_LISTENER_TOKEN: Token = Token(1);
+// Listener of the i-th tunnel has `Token(TUNNEL_LISTENER_TOKEN + i)`
+const TUNNEL_LISTENER_TOKEN: usize = 2;
+
+/// Servers and the workers using them, shared by clones of a `TcpRelayLocal`
+struct Servers {
+    list: Vec<ServerConfig>,
+    workers: Vec<Notifier<Message>>,
+    // When connections are closed after the relay has been shut down
+    deadline: Option<u64>,
+}
 
 #[derive(Clone)]
 pub struct TcpRelayLocal {
     config: Config,
+    associations: Associations,
+    health_checker: Option<Arc<HealthChecker>>,
+    // Failures of servers reported by all workers
+    failures: Arc<Failures>,
+    rate_limiter: Arc<RateLimiter>,
+    metrics: Arc<Metrics>,
+    // Traffic of listening ports
+    traffic: Arc<TrafficStats>,
+    plugins: Arc<Plugins>,
+    // Resolves targets of direct connections for all workers
+    dns: Arc<CachedDns>,
+    servers: Arc<Mutex<Servers>>,
 }
 
 #[inline]
@@ -64,279 +102,1211 @@ fn make_io_error(desc: &'static str, detail: Option<String>) -> IoError {
     }
 }
 
-macro_rules! try_result{
-    ($res:expr) => ({
-        let res = $res;
-        match res {
-            Ok(r) => { r },
-            Err(err) => {
-                error!("{}", err);
-                return;
-            }
+impl TcpRelayLocal {
+    pub fn new(c: Config, associations: Associations, health_checker: Option<Arc<HealthChecker>>,
+               rate_limiter: Arc<RateLimiter>, metrics: Arc<Metrics>, traffic: Arc<TrafficStats>,
+               plugins: Arc<Plugins>) -> TcpRelayLocal {
+        if c.server.is_empty()
+                || (c.local.is_none() && c.local_http.is_none() && c.tunnels.is_empty() && c.local_dns.is_none()) {
+            panic!("You have to provide configuration for server and local");
         }
-    });
-    ($res:expr, prefix: $prefix:expr) => ({
-        let res = $res;
-        let prefix = $prefix;
-        match res {
-            Ok(r) => { r },
-            Err(err) => {
-                error!("{} {}", prefix, err);
-                return;
-            }
+
+        let servers = Servers {
+            list: c.server.clone(),
+            workers: Vec::new(),
+            deadline: None,
+        };
+        let dns = Arc::new(CachedDns::with_capacity(DEFAULT_DNS_CACHE_CAPACITY, metrics.clone()));
+        TcpRelayLocal {
+            config: c,
+            associations: associations,
+            health_checker: health_checker,
+            failures: Arc::new(Failures::new()),
+            rate_limiter: rate_limiter,
+            metrics: metrics,
+            traffic: traffic,
+            plugins: plugins,
+            dns: dns,
+            servers: Arc::new(Mutex::new(servers)),
         }
-    });
-    ($res:expr, message: $message:expr) => ({
-        let res = $res;
-        let message = $message;
-        match res {
-            Ok(r) => { r },
-            Err(..) => {
-                error!("{}", message);
-                return;
-            }
+    }
+
+    /// Replaces the servers which new connections are relayed through.
+    ///
+    /// It could be called before or after `run`.
+    pub fn set_servers(&self, servers: Vec<ServerConfig>) {
+        let mut guard = self.servers.lock().unwrap();
+        for worker in guard.workers.iter() {
+            // The worker has exited if it fails
+            let _ = worker.notify(Message::Servers(servers.clone()));
         }
-    });
+        guard.list = servers;
+    }
 }
 
-impl TcpRelayLocal {
-    pub fn new(c: Config) -> TcpRelayLocal {
-        if c.server.is_empty() || c.local.is_none() {
-            panic!("You have to provide configuration for server and local");
+#[derive(Copy, PartialEq, Eq)]
+enum Stage {
+    /// Reading the method selection message, or the request of SOCKS4
+    Handshake,
+    /// Reading the username/password authentication request
+    Authenticate,
+    /// Reading the request
+    Request,
+    /// Connecting to the shadowsocks server
+    Connecting,
+    /// Holding a UDP association until the client closes the connection
+    Associated,
+    /// Writing the last reply, the connection will be closed after that
+    Closing,
+}
+
+#[derive(Copy, PartialEq, Eq)]
+enum Protocol {
+    Socks5,
+    /// SOCKS4 or SOCKS4a
+    Socks4,
+    /// Redirected by netfilter or accepted by a tunnel, the client expects no reply
+    Transparent,
+}
+
+/// A connection which hasn't been established
+struct Handshake {
+    client: Endpoint,
+    remote: Option<Endpoint>,
+    stage: Stage,
+    received: Vec<u8>,
+    peer: IpAddr,
+    // Picked by the worker after the request has been received
+    server: Option<ServerConfig>,
+    addr: Option<socks5::Address>,
+    association: Option<Association>,
+    protocol: Protocol,
+    acl: Option<Arc<AccessControl>>,
+    /// Connecting to the target without the server, as told by the ACL
+    direct: bool,
+    /// The domain name of a direct target or of the server being resolved
+    lookup: Lookup,
+    /// Waiting for the worker to pick a server for the request
+    pending: bool,
+    attempts: Attempts,
+    /// Connecting through the server has failed, the worker should retry or give up
+    failed: Option<IoError>,
+}
+
+impl Handshake {
+    fn new(stream: TcpStream, id: usize, peer: IpAddr, acl: Option<Arc<AccessControl>>) -> Handshake {
+        Handshake {
+            client: Endpoint::new(stream, client_token(id)),
+            remote: None,
+            stage: Stage::Handshake,
+            received: Vec::new(),
+            peer: peer,
+            server: None,
+            addr: None,
+            association: None,
+            protocol: Protocol::Socks5,
+            acl: acl,
+            direct: false,
+            lookup: Lookup::Idle,
+            pending: false,
+            attempts: Attempts::new(),
+            failed: None,
         }
+    }
 
-        TcpRelayLocal {
-            config: c,
+    fn reply(&mut self, reply: socks5::Reply, addr: socks5::Address) -> IoResult<()> {
+        match self.protocol {
+            Protocol::Socks5 => socks5::TcpResponseHeader::new(reply, addr).write_to(&mut self.client.out),
+            Protocol::Socks4 => socks4::TcpResponseHeader::new(reply).write_to(&mut self.client.out),
+            Protocol::Transparent => Ok(()),
         }
     }
 
-    fn do_handshake(stream: &mut TcpStream) -> IoResult<()> {
+    fn handle_socks4_request(&mut self, id: usize, users: &BTreeMap<String, String>) -> IoResult<()> {
+        let (header, consumed) = match parse_partial(self.received.as_slice(), |r| socks4::TcpRequestHeader::read_from(r)) {
+            Some((header, consumed)) => (try!(header), consumed),
+            None => return Ok(()),
+        };
+        self.received = self.received[consumed..].to_vec();
+        self.protocol = Protocol::Socks4;
+
+        let addr = header.address;
+        if !users.is_empty() {
+            warn!("SOCKS4 is disabled since authentication is required");
+            try!(self.reply(socks5::Reply::ConnectionNotAllowed, addr));
+            self.stage = Stage::Closing;
+            return Ok(());
+        }
+
+        match header.command {
+            socks5::Command::TcpConnect => self.connect(id, addr),
+            _ => {
+                warn!("BIND is not supported");
+                try!(self.reply(socks5::Reply::CommandNotSupported, addr));
+                self.stage = Stage::Closing;
+                Ok(())
+            }
+        }
+    }
+
+    fn do_handshake(&mut self, users: &BTreeMap<String, String>) -> IoResult<()> {
         // Read the handshake header
-        let req = try!(socks5::HandshakeRequest::read_from(stream));
+        let (req, consumed) = match parse_partial(self.received.as_slice(), |r| socks5::HandshakeRequest::read_from(r)) {
+            Some((req, consumed)) => (try!(req), consumed),
+            None => return Ok(()),
+        };
+        self.received = self.received[consumed..].to_vec();
 
-        if !req.methods.contains(&socks5::SOCKS5_AUTH_METHOD_NONE) {
+        let method = if users.is_empty() {
+            socks5::SOCKS5_AUTH_METHOD_NONE
+        } else {
+            socks5::SOCKS5_AUTH_METHOD_PASSWORD
+        };
+
+        if !req.methods.contains(&method) {
             let resp = socks5::HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE);
-            try!(resp.write_to(stream));
-            warn!("Currently shadowsocks-rust does not support authentication");
-            return Err(make_io_error("Currently shadowsocks-rust does not support authentication", None));
+            try!(resp.write_to(&mut self.client.out));
+            warn!("Client does not support the required authentication method {}", method);
+            self.stage = Stage::Closing;
+            return Ok(());
         }
 
         // Reply to client
-        let resp = socks5::HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NONE);
-        try!(resp.write_to(stream));
+        let resp = socks5::HandshakeResponse::new(method);
+        try!(resp.write_to(&mut self.client.out));
+        self.stage = if users.is_empty() { Stage::Request } else { Stage::Authenticate };
+        Ok(())
+    }
+
+    fn authenticate(&mut self, users: &BTreeMap<String, String>) -> IoResult<()> {
+        let (req, consumed) = match parse_partial(self.received.as_slice(), |r| socks5::PasswdAuthRequest::read_from(r)) {
+            Some((req, consumed)) => (try!(req), consumed),
+            None => return Ok(()),
+        };
+        self.received = self.received[consumed..].to_vec();
+
+        let uname = String::from_utf8_lossy(req.uname.as_slice()).into_owned();
+        if req.check(users) {
+            debug!("User `{}` has been authenticated", uname);
+            try!(socks5::PasswdAuthResponse::new(socks5::SOCKS5_PASSWD_AUTH_SUCCEEDED).write_to(&mut self.client.out));
+            self.stage = Stage::Request;
+        } else {
+            warn!("Authentication failed for user `{}`", uname);
+            try!(socks5::PasswdAuthResponse::new(socks5::SOCKS5_PASSWD_AUTH_FAILED).write_to(&mut self.client.out));
+            self.stage = Stage::Closing;
+        }
         Ok(())
     }
 
-    fn handle_udp_associate_local(stream: &mut TcpStream, _: &socks5::Address) -> IoResult<()> {
-        let sockname = try!(stream.socket_name());
+    fn handle_request(&mut self, id: usize, associations: Option<&Associations>) -> IoResult<()> {
+        let sockname = try!(self.client.stream.socket_name());
 
-        let reply = socks5::TcpResponseHeader::new(socks5::Reply::Succeeded,
-                                                   socks5::Address::SocketAddress(sockname.ip, sockname.port));
-        try!(reply.write_to(stream));
+        let header = match parse_partial(self.received.as_slice(), |r| socks5::TcpRequestHeader::read_from(r)) {
+            Some((Ok(h), consumed)) => {
+                self.received = self.received[consumed..].to_vec();
+                h
+            },
+            Some((Err(err), _)) => {
+                return Err(make_io_error("Failed to read request header", Some(err.to_string())));
+            },
+            None => return Ok(()),
+        };
 
-        // TODO: record this client's information for udprelay local server to validate
-        //       whether the client has already authenticated
+        let addr = header.address;
+
+        match header.command {
+            socks5::Command::TcpConnect => try!(self.connect(id, addr)),
+            socks5::Command::TcpBind => {
+                warn!("BIND is not supported");
+                try!(self.reply(socks5::Reply::CommandNotSupported, addr));
+                self.stage = Stage::Closing;
+            },
+            socks5::Command::UdpAssociate => {
+                let peername = try!(self.client.stream.peer_name());
+                info!("{} requests for UDP ASSOCIATE", peername);
+                match associations {
+                    Some(associations) if cfg!(feature = "enable-udp") => {
+                        // The UDP relay accepts datagrams from this client while the connection is alive
+                        self.association = Some(associations.associate(peername.ip));
+                        try!(self.reply(socks5::Reply::Succeeded,
+                                        socks5::Address::SocketAddress(sockname.ip, sockname.port)));
+                        self.stage = Stage::Associated;
+                    },
+                    _ => {
+                        warn!("UDP ASSOCIATE is disabled");
+                        try!(self.reply(socks5::Reply::CommandNotSupported, addr));
+                        self.stage = Stage::Closing;
+                    }
+                }
+            }
+        }
 
         Ok(())
     }
 
-    fn handle_client(mut stream: TcpStream,
-                     server_addr: SocketAddr,
-                     password: Vec<u8>,
-                     encrypt_method: CipherType,
-                     enable_udp: bool) {
-        try_result!(TcpRelayLocal::do_handshake(&mut stream), prefix: "Error occurs while doing handshake:");
+    fn connect(&mut self, id: usize, addr: socks5::Address) -> IoResult<()> {
+        self.direct = self.acl.as_ref().map_or(false, |acl| acl.check_bypass(&addr));
+        if self.direct {
+            info!("CONNECT {} directly", addr);
+            self.addr = Some(addr.clone());
+            match addr {
+                socks5::Address::SocketAddress(ip, port) => self.connect_to(id, Ok(SocketAddr { ip: ip, port: port })),
+                socks5::Address::DomainNameAddress(name, _) => {
+                    // Connects in `resolved`
+                    self.lookup = Lookup::Requested(name);
+                    self.stage = Stage::Connecting;
+                    Ok(())
+                }
+            }
+        } else {
+            info!("CONNECT {}", addr);
+            self.addr = Some(addr);
+            self.stage = Stage::Connecting;
+            self.pending = true;
+            Ok(())
+        }
+    }
+
+    /// Connects after the domain name of the direct target or of the server has been resolved
+    fn resolved(&mut self, id: usize, addrs: Option<Vec<IpAddr>>) -> IoResult<()> {
+        self.lookup = Lookup::Idle;
+        let target = if self.direct {
+            resolved_target(self.addr.as_ref().unwrap(), addrs)
+        } else {
+            resolved_server(self.server.as_ref().unwrap(), addrs)
+        };
+        self.connect_to(id, target)
+    }
+
+    fn connect_to(&mut self, id: usize, target: IoResult<SocketAddr>) -> IoResult<()> {
+        match target.and_then(|target| TcpStream::connect(&target)) {
+            Ok(s) => {
+                self.remote = Some(Endpoint::connecting(s, remote_token(id)));
+                self.stage = Stage::Connecting;
+                Ok(())
+            },
+            Err(err) => self.connect_failed(err),
+        }
+    }
+
+    // Connections through servers are left to the worker for retrying
+    fn connect_failed(&mut self, err: IoError) -> IoResult<()> {
+        if self.direct {
+            self.give_up(&err)
+        } else {
+            self.stage = Stage::Connecting;
+            self.failed = Some(err);
+            Ok(())
+        }
+    }
+
+    fn reply_connect_error(&mut self, err: &IoError, addr: socks5::Address) -> IoResult<()> {
+        self.stage = Stage::Closing;
+        match err.kind {
+            ConnectionAborted | ConnectionReset | ConnectionRefused | ConnectionFailed => {
+                self.reply(socks5::Reply::HostUnreachable, addr)
+            },
+            _ => self.reply(socks5::Reply::NetworkUnreachable, addr),
+        }
+    }
+
+    /// Handles readiness of the client before the tunnel is established
+    fn client_ready(&mut self, id: usize, ready: Ready, buf: &mut [u8],
+                    users: &BTreeMap<String, String>, associations: Option<&Associations>) -> IoResult<()> {
+        if ready.error {
+            try!(self.client.stream.take_socket_error());
+        }
+
+        if self.stage == Stage::Associated {
+            // Anything sent by the client is ignored, the association ends with the connection
+            if ready.readable || ready.hangup {
+                loop {
+                    match try!(self.client.read(buf)) {
+                        None => break,
+                        Some(0) => {
+                            self.stage = Stage::Closing;
+                            break;
+                        },
+                        Some(..) => {},
+                    }
+                }
+            }
+            if ready.hangup {
+                self.client.hang_up();
+                self.stage = Stage::Closing;
+            }
+            return self.client.flush();
+        }
+
+        if (ready.readable || ready.hangup) && self.is_reading() {
+            loop {
+                match try!(self.client.read(buf)) {
+                    None => break,
+                    Some(0) => return Err(make_io_error("Client closed before sending request", None)),
+                    Some(n) => self.received.push_all(&buf[..n]),
+                }
+            }
+
+            if self.stage == Stage::Handshake {
+                // SOCKS4 clients send the request directly
+                if self.received.first() == Some(&socks4::SOCKS4_VERSION) {
+                    try!(self.handle_socks4_request(id, users));
+                } else {
+                    try!(self.do_handshake(users));
+                }
+            }
+            if self.stage == Stage::Authenticate {
+                try!(self.authenticate(users));
+            }
+            if self.stage == Stage::Request {
+                try!(self.handle_request(id, associations));
+            }
+        } else if ready.hangup {
+            return Err(make_io_error("Client has closed the connection", None));
+        }
+
+        self.client.flush()
+    }
+
+    fn is_reading(&self) -> bool {
+        match self.stage {
+            Stage::Handshake | Stage::Authenticate | Stage::Request | Stage::Associated => true,
+            Stage::Connecting | Stage::Closing => false,
+        }
+    }
+
+    /// Handles readiness of the remote while connecting.
+    ///
+    /// Returns the remote endpoint and the codec after the connection has been established.
+    fn remote_ready(&mut self) -> IoResult<Option<(Endpoint, Codec)>> {
+        let result = match self.remote {
+            Some(ref mut remote) => remote.finish_connect(),
+            None => return Ok(None),
+        };
+
+        let addr = self.addr.clone().unwrap();
+        if let Err(err) = result {
+            self.remote = None;
+            try!(self.connect_failed(err));
+            return Ok(None);
+        }
+
+        let sockname = try!(self.client.stream.socket_name());
+        try!(self.reply(socks5::Reply::Succeeded, socks5::Address::SocketAddress(sockname.ip, sockname.port)));
+
+        let mut remote = self.remote.take().unwrap();
+        let codec = if self.direct {
+            Codec::plain()
+        } else {
+            try!(Codec::for_request(self.server.as_ref().unwrap(), &addr, &mut remote.out))
+        };
+        Ok(Some((remote, codec)))
+    }
+
+    /// Establishes the tunnel, with the servers which have been tried if the target is connected
+    /// through a server
+    fn into_tunnel(self, remote: Endpoint, codec: Codec) -> IoResult<(Tunnel, Option<Attempts>)> {
+        let received = self.received;
+        let mut tunnel = Tunnel::new(self.client, remote, codec);
+        let attempts = if self.direct {
+            None
+        } else {
+            tunnel.keep_for_retry();
+            Some(self.attempts)
+        };
+
+        if received.is_empty() {
+            try!(tunnel.flush());
+        } else {
+            // Data sent by the client right after the request
+            try!(tunnel.feed_client(received.as_slice()));
+        }
+        Ok((tunnel, attempts))
+    }
+
+    fn update_interest(&mut self, event_loop: &mut EventLoop<LocalWorker>) -> IoResult<()> {
+        let reading = self.is_reading();
+        try!(self.client.update_interest(event_loop, reading));
+        match self.remote {
+            Some(ref mut remote) => remote.update_interest(event_loop, false),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Routed for Handshake {
+    fn take_pending(&mut self) -> Option<(IpAddr, socks5::Address)> {
+        if !self.pending {
+            return None;
+        }
+        self.pending = false;
+        Some((self.peer, self.addr.clone().unwrap()))
+    }
 
-        let sockname = try_result!(stream.socket_name(), prefix: "Failed to get socket name:");
+    fn attempts(&mut self) -> &mut Attempts {
+        &mut self.attempts
+    }
 
-        let header = match socks5::TcpRequestHeader::read_from(&mut stream) {
-            Ok(h) => { h },
+    fn take_failed(&mut self) -> Option<IoError> {
+        self.failed.take()
+    }
+
+    fn connect_through(&mut self, id: usize, server: ServerConfig, server_addr: IoResult<Option<SocketAddr>>)
+                       -> IoResult<()> {
+        match server_addr {
+            Ok(Some(server_addr)) => {
+                self.server = Some(server);
+                self.connect_to(id, Ok(server_addr))
+            },
+            Ok(None) => {
+                // Connects in `resolved`
+                self.lookup = Lookup::Requested(server.addr.clone());
+                self.server = Some(server);
+                Ok(())
+            },
             Err(err) => {
-                socks5::TcpResponseHeader::new(err.reply,
-                                               socks5::Address::SocketAddress(sockname.ip, sockname.port));
-                error!("Failed to read request header: {}", err);
-                return;
+                self.server = Some(server);
+                self.connect_failed(err)
             }
+        }
+    }
+
+    fn give_up(&mut self, err: &IoError) -> IoResult<()> {
+        error!("Failed to connect remote server: {}", err);
+        let addr = self.addr.clone().unwrap();
+        try!(self.reply_connect_error(err, addr));
+        self.client.flush()
+    }
+}
+
+enum Connection {
+    Handshaking(Handshake),
+    Http(HttpProxy),
+    /// Servers which have been tried are kept until the server responds, for retrying
+    Relaying(Tunnel, socks5::Address, Option<Attempts>),
+    /// The server has failed before responding, the domain name of the last tried server is being
+    /// resolved for retrying through it
+    Retrying(Tunnel, socks5::Address, Attempts, Lookup),
+}
+
+#[derive(Copy, PartialEq, Eq)]
+enum Listener {
+    Local,
+    Http,
+    /// Index of the tunnel
+    Tunnel(usize),
+}
+
+struct Entry {
+    conn: Connection,
+    last_active: u64,
+    timer: Option<Timeout>,
+    // Traffic of the port which has accepted the connection
+    traffic: Arc<Traffic>,
+    // Counted as an active connection until the entry is dropped
+    _active: ActiveConnection,
+}
+
+/// Messages to workers
+enum Message {
+    /// Replaces the servers of the load balancer
+    Servers(Vec<ServerConfig>),
+    /// Stops accepting, and exits after connections have been finished or the deadline has passed
+    Drain(u64),
+    /// The target of a direct connection has been resolved
+    Resolved(usize, Option<Vec<IpAddr>>),
+}
+
+/// Accepts and relays connections in one event loop
+struct LocalWorker {
+    listener: Option<Arc<TcpListener>>,
+    http_listener: Option<Arc<TcpListener>>,
+    tunnels: Vec<(Arc<TcpListener>, socks5::Address)>,
+    load_balancer: Box<LoadBalancer + Send>,
+    load_balancing: LoadBalancing,
+    health_checker: Option<Arc<HealthChecker>>,
+    failures: Arc<Failures>,
+    mode: LocalMode,
+    users: Arc<BTreeMap<String, String>>,
+    acl: Option<Arc<AccessControl>>,
+    // Registry of UDP associations, if the UDP relay is enabled
+    associations: Option<Associations>,
+    timeout: Option<u64>,
+    retry_attempts: usize,
+    retry_timeout: u64,
+    rate_limiter: Arc<RateLimiter>,
+    metrics: Arc<Metrics>,
+    traffic: Arc<TrafficStats>,
+    plugins: Arc<Plugins>,
+    dns: Arc<CachedDns>,
+    conns: HashMap<usize, Entry>,
+    // Connections waiting for tokens
+    throttled: Throttled,
+    // When the remaining connections are closed, after the worker has started draining
+    deadline: Option<u64>,
+    next_id: usize,
+    buf: Vec<u8>,
+}
+
+impl LocalWorker {
+    fn new(listener: Option<Arc<TcpListener>>, http_listener: Option<Arc<TcpListener>>,
+           tunnels: Vec<(Arc<TcpListener>, socks5::Address)>,
+           config: &Config, servers: Vec<ServerConfig>, associations: Associations,
+           health_checker: Option<Arc<HealthChecker>>, failures: Arc<Failures>, rate_limiter: Arc<RateLimiter>,
+           metrics: Arc<Metrics>, traffic: Arc<TrafficStats>, plugins: Arc<Plugins>,
+           dns: Arc<CachedDns>) -> LocalWorker {
+        // Tokens of the first client must not be listener tokens
+        let next_id = TUNNEL_LISTENER_TOKEN / 2 + tunnels.len();
+        LocalWorker {
+            listener: listener,
+            http_listener: http_listener,
+            tunnels: tunnels,
+            load_balancer: new_load_balancer(servers, config.load_balancing, health_checker.clone(), failures.clone()),
+            load_balancing: config.load_balancing,
+            health_checker: health_checker,
+            failures: failures,
+            mode: config.local_mode,
+            users: Arc::new(config.local_users.clone()),
+            acl: config.acl.clone(),
+            associations: if config.enable_udp { Some(associations) } else { None },
+            timeout: config.timeout,
+            retry_attempts: config.retry_attempts,
+            retry_timeout: config.retry_timeout,
+            rate_limiter: rate_limiter,
+            metrics: metrics,
+            traffic: traffic,
+            plugins: plugins,
+            dns: dns,
+            conns: HashMap::new(),
+            throttled: Throttled::new(),
+            deadline: None,
+            next_id: next_id,
+            buf: repeat(0u8).take(RELAY_BUFFER_SIZE).collect(),
+        }
+    }
+
+    // Address to connect to `server` at, `Ok(None)` if the domain name of the server should be resolved
+    // first
+    fn server_addr(&self, server: &ServerConfig) -> IoResult<Option<SocketAddr>> {
+        // Connections go through the plugin of the server, if it has one
+        if let Some(addr) = try!(self.plugins.tcp_addr(server)) {
+            debug!("Using proxy `{}:{}` through plugin on `{}`", server.addr, server.port, addr);
+            return Ok(Some(addr));
+        }
+
+        Ok(server.addr.parse::<IpAddr>().map(|ip| SocketAddr { ip: ip, port: server.port }))
+    }
+
+    // Reports the failure of the last server, and picks another server if the connection could still
+    // be retried
+    fn next_server(&mut self, attempts: &mut Attempts) -> Option<ServerConfig> {
+        if let Some(server) = attempts.tried.last() {
+            self.load_balancer.report_failure(server);
+        }
+
+        if attempts.tried.len() >= self.retry_attempts || now_ms() >= attempts.started + self.retry_timeout {
+            return None;
+        }
+        let server = self.load_balancer.pick_retry_server(attempts.tried.as_slice());
+        if let Some(ref server) = server {
+            attempts.tried.push(server.clone());
+        }
+        server
+    }
+
+    // Connects through the server picked for the request once it has been received, and through
+    // other servers if connecting has failed
+    fn connect_through_server<C: Routed>(&mut self, id: usize, conn: &mut C) -> IoResult<()> {
+        if let Some((client, target)) = conn.take_pending() {
+            let server = self.load_balancer.pick_server_for(&client, Some(&target)).clone();
+            {
+                // Attempts of every request start when its server is picked
+                let attempts = conn.attempts();
+                *attempts = Attempts::new();
+                attempts.tried.push(server.clone());
+            }
+            let server_addr = self.server_addr(&server);
+            try!(conn.connect_through(id, server, server_addr));
+        }
+        self.retry_if_failed(id, conn)
+    }
+
+    // Connects through other servers if connecting through the server has failed
+    fn retry_if_failed<C: Routed>(&mut self, id: usize, conn: &mut C) -> IoResult<()> {
+        while let Some(err) = conn.take_failed() {
+            match self.next_server(conn.attempts()) {
+                Some(server) => {
+                    info!("Retrying through server {}:{} after: {}", server.addr, server.port, err);
+                    let server_addr = self.server_addr(&server);
+                    try!(conn.connect_through(id, server, server_addr));
+                },
+                None => try!(conn.give_up(&err)),
+            }
+        }
+        Ok(())
+    }
+
+    // Sends what the client has sent again through another server, after the server has failed
+    // before responding
+    fn retry_tunnel(&mut self, event_loop: &mut EventLoop<LocalWorker>, id: usize, mut tunnel: Tunnel,
+                    addr: socks5::Address, mut attempts: Attempts, mut err: IoError) -> Option<Connection> {
+        loop {
+            let server = match self.next_server(&mut attempts) {
+                Some(server) => server,
+                None => {
+                    log_error(&addr, &err);
+                    return None;
+                }
+            };
+
+            info!("Retrying CONNECT {} through server {}:{} after: {}", addr, server.addr, server.port, err);
+            let result = match self.server_addr(&server) {
+                Ok(Some(server_addr)) => reconnect(event_loop, id, &mut tunnel, &addr, &server, server_addr),
+                Ok(None) => {
+                    // Retries in `process` after the server has been resolved, nothing is relayed until then
+                    return match tunnel.pause(event_loop) {
+                        Ok(..) => Some(Connection::Retrying(tunnel, addr, attempts, Lookup::Requested(server.addr))),
+                        Err(err) => {
+                            log_error(&addr, &err);
+                            None
+                        }
+                    };
+                },
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(..) => return Some(Connection::Relaying(tunnel, addr, Some(attempts))),
+                Err(e) => err = e,
+            }
+        }
+    }
+
+    fn listener_of(&self, token: Token) -> Option<Listener> {
+        if token == LISTENER_TOKEN {
+            Some(Listener::Local)
+        } else if token == HTTP_LISTENER_TOKEN {
+            Some(Listener::Http)
+        } else if token.0 >= TUNNEL_LISTENER_TOKEN && token.0 < TUNNEL_LISTENER_TOKEN + self.tunnels.len() {
+            Some(Listener::Tunnel(token.0 - TUNNEL_LISTENER_TOKEN))
+        } else {
+            None
+        }
+    }
+
+    fn accept(&mut self, event_loop: &mut EventLoop<LocalWorker>, listener: Listener) {
+        loop {
+            let accepted = match listener {
+                Listener::Local => self.listener.as_ref().unwrap().accept(),
+                Listener::Http => self.http_listener.as_ref().unwrap().accept(),
+                Listener::Tunnel(i) => self.tunnels[i].0.accept(),
+            };
+            let (stream, peer) = match accepted {
+                Ok(Some(accepted)) => accepted,
+                // Taken by another worker, or no more pending connections
+                Ok(None) => break,
+                Err(err) => {
+                    error!("Failed to accept: {}", err);
+                    break;
+                }
+            };
+            // Traffic is counted by the port which the client has connected to
+            let traffic = match stream.socket_name() {
+                Ok(addr) => self.traffic.port(addr.port),
+                Err(err) => {
+                    error!("Failed to get the local address of client: {}", err);
+                    continue;
+                }
+            };
+
+            let id = self.next_id;
+            self.next_id += 1;
+
+            let forward = match listener {
+                Listener::Local if self.mode == LocalMode::Redir => {
+                    match stream.original_dst() {
+                        Ok(dst) => Some(socks5::Address::SocketAddress(dst.ip, dst.port)),
+                        Err(err) => {
+                            error!("Failed to get the original destination: {}", err);
+                            continue;
+                        }
+                    }
+                },
+                Listener::Tunnel(i) => Some(self.tunnels[i].1.clone()),
+                _ => None,
+            };
+
+            let result = if listener == Listener::Http {
+                // Servers are picked after requests have been parsed
+                let mut proxy = HttpProxy::new(stream, peer, id, self.acl.clone(), self.users.clone());
+                proxy.update_interest(event_loop).map(|_| Connection::Http(proxy))
+            } else if let Some(addr) = forward {
+                // Connects to `addr` without any handshake
+                let mut handshake = Handshake::new(stream, id, peer.ip, self.acl.clone());
+                handshake.protocol = Protocol::Transparent;
+                let result = match handshake.connect(id, addr) {
+                    Ok(..) => self.connect_through_server(id, &mut handshake),
+                    Err(err) => Err(err),
+                };
+                match result {
+                    // Failed to connect, which has been logged
+                    Ok(..) if handshake.stage == Stage::Closing => continue,
+                    Ok(..) => handshake.update_interest(event_loop).map(|_| Connection::Handshaking(handshake)),
+                    Err(err) => Err(err),
+                }
+            } else {
+                let mut handshake = Handshake::new(stream, id, peer.ip, self.acl.clone());
+                handshake.update_interest(event_loop).map(|_| Connection::Handshaking(handshake))
+            };
+            let mut conn = match result {
+                Ok(conn) => conn,
+                Err(err) => {
+                    error!("Failed to register client: {}", err);
+                    continue;
+                }
+            };
+            self.lookup(event_loop, id, &mut conn);
+
+            let timer = self.timeout.map(|t| event_loop.timeout_ms(client_token(id), t));
+            self.conns.insert(id, Entry {
+                conn: conn,
+                last_active: now_ms(),
+                timer: timer,
+                traffic: traffic,
+                _active: ActiveConnection::new(self.metrics.clone()),
+            });
+        }
+    }
+
+    // Listeners are shared by all workers, stopping them more than once fails harmlessly
+    fn drain(&mut self, event_loop: &mut EventLoop<LocalWorker>, deadline: u64) {
+        let mut listeners = self.tunnels.drain().map(|(listener, _)| listener).collect::<Vec<Arc<TcpListener>>>();
+        listeners.extend(self.listener.take().into_iter());
+        listeners.extend(self.http_listener.take().into_iter());
+        for listener in listeners.iter() {
+            if let Err(err) = event_loop.deregister(&**listener) {
+                error!("Failed to deregister listener: {}", err);
+            }
+            let _ = listener.stop_listening();
+        }
+
+        // UDP associations are flushed with the UDP relay, so their control connections are closed
+        let associated = self.conns.iter().filter(|&(_, entry)| match entry.conn {
+            Connection::Handshaking(ref h) => h.stage == Stage::Associated,
+            _ => false,
+        }).map(|(id, _)| *id).collect::<Vec<usize>>();
+        for id in associated.iter() {
+            self.conns.remove(id);
+        }
+
+        let now = now_ms();
+        event_loop.timeout_ms(DRAIN_TOKEN, if deadline > now { deadline - now } else { 0 });
+        self.deadline = Some(deadline);
+        debug!("Draining {} connections", self.conns.len());
+    }
+
+    fn close_all(&mut self, event_loop: &mut EventLoop<LocalWorker>) {
+        if !self.conns.is_empty() {
+            info!("Closed {} connections which were not finished in time", self.conns.len());
+        }
+        self.conns.clear();
+        event_loop.shutdown();
+    }
+
+    fn exit_if_drained(&self, event_loop: &mut EventLoop<LocalWorker>) {
+        if self.deadline.is_some() && self.conns.is_empty() {
+            event_loop.shutdown();
+        }
+    }
+
+    fn handle(&mut self, event_loop: &mut EventLoop<LocalWorker>, id: usize, from_client: bool, ready: Ready) {
+        let mut entry = match self.conns.remove(&id) {
+            Some(entry) => entry,
+            None => return,
         };
 
-        let addr = header.address;
+        match self.process(event_loop, id, from_client, ready, entry.conn, &*entry.traffic) {
+            Some(mut conn) => {
+                if let Connection::Relaying(ref tunnel, _, _) = conn {
+                    if let Some(delay) = tunnel.throttle_delay() {
+                        self.throttled.throttle(event_loop, id, delay);
+                    }
+                }
+                self.lookup(event_loop, id, &mut conn);
+                entry.conn = conn;
+                entry.last_active = now_ms();
+                self.conns.insert(id, entry);
+            },
+            None => {
+                if let Some(timer) = entry.timer {
+                    event_loop.clear_timeout(timer);
+                }
+            }
+        }
+    }
 
-        match header.command {
-            socks5::Command::TcpConnect => {
-                info!("CONNECT {}", addr);
+    // Resolves the target of a direct connection, or the server of a connection, in the task pool of
+    // `dns`. The connection continues in `resolved` with the result.
+    fn lookup(&self, event_loop: &mut EventLoop<LocalWorker>, id: usize, conn: &mut Connection) {
+        let name = match *conn {
+            Connection::Handshaking(ref mut handshake) => handshake.lookup.take_requested(),
+            Connection::Http(ref mut proxy) => proxy.lookup.take_requested(),
+            Connection::Relaying(..) => None,
+            Connection::Retrying(_, _, _, ref mut lookup) => lookup.take_requested(),
+        };
+        if let Some(name) = name {
+            let notifier = event_loop.notifier();
+            self.dns.resolve_async(name.as_slice(), move |addrs| {
+                // The worker has exited if it fails
+                let _ = notifier.notify(Message::Resolved(id, addrs));
+            });
+        }
+    }
 
-                let mut remote_stream = match TcpStream::connect((server_addr.ip, server_addr.port)) {
-                    Err(err) => {
-                        match err.kind {
-                            ConnectionAborted | ConnectionReset | ConnectionRefused | ConnectionFailed => {
-                                socks5::TcpResponseHeader::new(socks5::Reply::HostUnreachable, addr.clone())
-                                    .write_to(&mut stream).unwrap();
-                            },
-                            _ => {
-                                socks5::TcpResponseHeader::new(socks5::Reply::NetworkUnreachable, addr.clone())
-                                    .write_to(&mut stream).unwrap();
+    fn resolved(&mut self, event_loop: &mut EventLoop<LocalWorker>, id: usize, addrs: Option<Vec<IpAddr>>) {
+        let result = match self.conns.get_mut(&id) {
+            Some(entry) => match entry.conn {
+                Connection::Handshaking(ref mut handshake) => handshake.resolved(id, addrs),
+                Connection::Http(ref mut proxy) => proxy.resolved(addrs),
+                Connection::Relaying(..) => Ok(()),
+                Connection::Retrying(_, _, _, ref mut lookup) => {
+                    *lookup = Lookup::Done(addrs);
+                    Ok(())
+                },
+            },
+            // Closed while resolving
+            None => return,
+        };
+        if let Err(err) = result {
+            error!("Error occurs while connecting: {}", err);
+            self.conns.remove(&id);
+            return;
+        }
+
+        // Registers the connection again, as if the client was ready
+        let nothing = Ready { readable: false, writable: false, hangup: false, error: false };
+        self.handle(event_loop, id, true, nothing);
+    }
+
+    // Returns the connection if it is still alive
+    fn process(&mut self, event_loop: &mut EventLoop<LocalWorker>, id: usize, from_client: bool, ready: Ready,
+               conn: Connection, traffic: &Traffic) -> Option<Connection> {
+        match conn {
+            Connection::Handshaking(mut handshake) => {
+                let result = if from_client {
+                    let buf = self.buf.as_mut_slice();
+                    handshake.client_ready(id, ready, buf, &*self.users, self.associations.as_ref()).map(|_| None)
+                } else {
+                    handshake.remote_ready()
+                };
+                if from_client && result.is_err() {
+                    self.metrics.add_handshake_failure();
+                }
+                let result = match result {
+                    Ok(remote) => self.connect_through_server(id, &mut handshake).map(|_| remote),
+                    Err(err) => Err(err),
+                };
+
+                match result {
+                    Ok(Some((remote, codec))) => {
+                        let addr = handshake.addr.clone().unwrap();
+                        let limiter = self.rate_limiter.for_connection(None);
+                        let result = handshake.into_tunnel(remote, codec).and_then(|(mut tunnel, attempts)| {
+                            tunnel.set_limiter(limiter);
+                            tunnel.update_interest(event_loop).map(|_| (tunnel, attempts))
+                        });
+                        match result {
+                            Ok((tunnel, attempts)) => Some(Connection::Relaying(tunnel, addr, attempts)),
+                            Err(err) => {
+                                log_error(&addr, &err);
+                                None
+                            }
+                        }
+                    },
+                    Ok(None) => {
+                        if handshake.stage == Stage::Closing && handshake.client.out.is_empty() {
+                            return None;
+                        }
+                        match handshake.update_interest(event_loop) {
+                            Ok(..) => Some(Connection::Handshaking(handshake)),
+                            Err(err) => {
+                                error!("Error occurs while doing handshake: {}", err);
+                                None
                             }
                         }
-                        error!("Failed to connect remote server: {}", err);
-                        return;
                     },
-                    Ok(s) => { s },
+                    Err(err) => {
+                        error!("Error occurs while doing handshake: {}", err);
+                        None
+                    }
+                }
+            },
+            Connection::Http(mut proxy) => {
+                let result = {
+                    let buf = self.buf.as_mut_slice();
+                    if from_client {
+                        proxy.client_ready(ready, buf).map(|_| false)
+                    } else {
+                        proxy.remote_ready(ready, buf)
+                    }
                 };
-
-                let mut buffered_local_stream = BufferedStream::new(stream.clone());
-
-                let iv = encrypt_method.gen_init_vec();
-                let encryptor = cipher::with_type(encrypt_method,
-                                                  password.as_slice(),
-                                                  iv.as_slice(),
-                                                  CryptoMode::Encrypt);
-                try_result!(remote_stream.write(iv.as_slice()));
-                let mut encrypt_stream = EncryptedWriter::new(remote_stream.clone(), encryptor);
-
-                {
-                    try_result!(socks5::TcpResponseHeader::new(
-                                                    socks5::Reply::Succeeded,
-                                                    socks5::Address::SocketAddress(sockname.ip, sockname.port))
-                                .write_to(&mut buffered_local_stream),
-                        prefix: "Error occurs while writing header to local stream:");
-                    try_result!(buffered_local_stream.flush());
-                    try_result!(addr.write_to(&mut encrypt_stream));
+                let result = match result {
+                    Ok(connected) => self.connect_through_server(id, &mut proxy).map(|_| connected),
+                    Err(err) => Err(err),
+                };
+                if proxy.take_responded() {
+                    // The server works, there is no need to retry
+                    self.load_balancer.report_success(proxy.attempts.tried.last().unwrap());
                 }
 
-                let addr_cloned = addr.clone();
-                let mut remote_stream_cloned = remote_stream.clone();
-                let mut local_stream_cloned = stream.clone();
-                Thread::spawn(move || {
-                    match io::util::copy(&mut buffered_local_stream, &mut encrypt_stream) {
-                        Ok(..) => {},
-                        Err(err) => {
-                            match err.kind {
-                                EndOfFile | BrokenPipe => {
-                                    debug!("{} relay from local to remote stream: {}", addr_cloned, err)
-                                },
-                                _ => {
-                                    error!("{} relay from local to remote stream: {}", addr_cloned, err)
-                                }
+                match result {
+                    Ok(true) => {
+                        let limiter = self.rate_limiter.for_connection(None);
+                        let result = proxy.into_tunnel().and_then(|(mut tunnel, addr, attempts)| {
+                            tunnel.set_limiter(limiter);
+                            tunnel.update_interest(event_loop).map(|_| (tunnel, addr, attempts))
+                        });
+                        match result {
+                            Ok((tunnel, addr, attempts)) => Some(Connection::Relaying(tunnel, addr, attempts)),
+                            Err(err) => {
+                                error!("Error occurs while establishing HTTP tunnel: {}", err);
+                                None
                             }
-                            remote_stream_cloned.close_write().or(Ok(())).unwrap();
-                            local_stream_cloned.close_read().or(Ok(())).unwrap();
                         }
+                    },
+                    Ok(false) if proxy.is_finished() => None,
+                    Ok(false) => match proxy.update_interest(event_loop) {
+                        Ok(..) => Some(Connection::Http(proxy)),
+                        Err(err) => {
+                            log_error(&proxy.peer, &err);
+                            None
+                        }
+                    },
+                    Err(err) => {
+                        log_error(&proxy.peer, &err);
+                        None
+                    }
+                }
+            },
+            Connection::Relaying(mut tunnel, addr, mut attempts) => {
+                let result = tunnel.ready(from_client, ready, self.buf.as_mut_slice())
+                                   .and_then(|_| tunnel.update_interest(event_loop));
+
+                if tunnel.has_response() {
+                    // The server works, there is no need to retry
+                    if let Some(attempts) = attempts.take() {
+                        self.load_balancer.report_success(attempts.tried.last().unwrap());
                     }
-                });
-
-                let remote_iv = try_result!(remote_stream.read_exact(encrypt_method.block_size()));
-                let decryptor = cipher::with_type(encrypt_method,
-                                                  password.as_slice(),
-                                                  remote_iv.as_slice(),
-                                                  CryptoMode::Decrypt);
-                let mut decrypt_stream = DecryptedReader::new(remote_stream.clone(), decryptor);
-                match io::util::copy(&mut decrypt_stream, &mut stream) {
+                }
+                let (upload, download) = tunnel.take_transferred();
+                traffic.add_upload(upload);
+                traffic.add_download(download);
+
+                match result {
                     Err(err) => {
-                        match err.kind {
-                            EndOfFile | BrokenPipe => {
-                                debug!("{} relay from local to remote stream: {}", addr, err)
-                            },
-                            _ => {
-                                error!("{} relay from local to remote stream: {}", addr, err)
-                            }
+                        if !from_client && tunnel.can_retry() && attempts.is_some() {
+                            return self.retry_tunnel(event_loop, id, tunnel, addr, attempts.unwrap(), err);
                         }
-                        remote_stream.close_write().or(Ok(())).unwrap();
-                        stream.close_read().or(Ok(())).unwrap();
+                        if tunnel.is_decryption_failed() {
+                            self.metrics.add_decryption_failure();
+                        }
+                        log_error(&addr, &err);
+                        None
                     },
-                    Ok(..) => {},
+                    Ok(..) if tunnel.is_finished() => None,
+                    Ok(..) => Some(Connection::Relaying(tunnel, addr, attempts)),
                 }
             },
-            socks5::Command::TcpBind => {
-                warn!("BIND is not supported");
-                try_result!(socks5::TcpResponseHeader::new(socks5::Reply::CommandNotSupported, addr)
-                    .write_to(&mut stream),
-                    prefix: "Failed to write BIND response:");
-            },
-            socks5::Command::UdpAssociate => {
-                let sockname = stream.peer_name().unwrap();
-                info!("{} requests for UDP ASSOCIATE", sockname);
-                if cfg!(feature = "enable-udp") && enable_udp {
-                    try_result!(TcpRelayLocal::handle_udp_associate_local(&mut stream, &addr),
-                                prefix: "Failed to write UDP ASSOCIATE response:");
-                } else {
-                    warn!("UDP ASSOCIATE is disabled");
-                    try_result!(socks5::TcpResponseHeader::new(socks5::Reply::CommandNotSupported, addr)
-                        .write_to(&mut stream),
-                        prefix: "Failed to write UDP ASSOCIATE response:");
+            Connection::Retrying(mut tunnel, addr, attempts, mut lookup) => {
+                let addrs = match lookup.take_done() {
+                    Some(addrs) => addrs,
+                    None if ready.hangup || ready.error => {
+                        debug!("{} relay: Client has closed the connection while retrying", addr);
+                        return None;
+                    },
+                    None => return Some(Connection::Retrying(tunnel, addr, attempts, lookup)),
+                };
+
+                let result = {
+                    let server = attempts.tried.last().unwrap();
+                    resolved_server(server, addrs).and_then(|server_addr| {
+                        reconnect(event_loop, id, &mut tunnel, &addr, server, server_addr)
+                    })
+                };
+                match result {
+                    Ok(..) => Some(Connection::Relaying(tunnel, addr, Some(attempts))),
+                    Err(err) => self.retry_tunnel(event_loop, id, tunnel, addr, attempts, err),
                 }
             }
         }
     }
 }
 
-impl Relay for TcpRelayLocal {
-    fn run(&self) {
-        let mut server_load_balancer = RoundRobin::new(self.config.server.clone());
+// Connects `tunnel` to `server` at `server_addr`, after the previous server has failed before responding
+fn reconnect(event_loop: &mut EventLoop<LocalWorker>, id: usize, tunnel: &mut Tunnel, addr: &socks5::Address,
+             server: &ServerConfig, server_addr: SocketAddr) -> IoResult<()> {
+    let stream = try!(TcpStream::connect(&server_addr));
+    let mut remote = Endpoint::connecting(stream, remote_token(id));
+    let codec = try!(Codec::for_request(server, addr, &mut remote.out));
+    try!(tunnel.retry(remote, codec));
+    tunnel.update_interest(event_loop)
+}
 
-        let local_conf = self.config.local.expect("need local configuration");
+impl Handler for LocalWorker {
+    type Message = Message;
+
+    fn ready(&mut self, event_loop: &mut EventLoop<LocalWorker>, token: Token, ready: Ready) {
+        if let Some(listener) = self.listener_of(token) {
+            self.accept(event_loop, listener);
+            return;
+        }
 
-        let mut acceptor = match TcpListener::bind(
-                format!("{}:{}", local_conf.ip, local_conf.port).as_slice()).listen() {
-            Ok(acpt) => acpt,
-            Err(e) => {
-                panic!("Error occurs while listening local address: {}", e.to_string());
+        let (id, from_client) = parse_token(token);
+        self.handle(event_loop, id, from_client, ready);
+        self.exit_if_drained(event_loop);
+    }
+
+    fn notify(&mut self, event_loop: &mut EventLoop<LocalWorker>, msg: Message) {
+        match msg {
+            Message::Servers(servers) => {
+                debug!("Relaying new connections through {} servers", servers.len());
+                self.load_balancer = new_load_balancer(servers, self.load_balancing, self.health_checker.clone(),
+                                                       self.failures.clone());
+            },
+            Message::Drain(deadline) => self.drain(event_loop, deadline),
+            Message::Resolved(id, addrs) => self.resolved(event_loop, id, addrs),
+        }
+        self.exit_if_drained(event_loop);
+    }
+
+    fn timeout(&mut self, event_loop: &mut EventLoop<LocalWorker>, token: Token) {
+        if token == DRAIN_TOKEN {
+            self.close_all(event_loop);
+            return;
+        }
+
+        if token == THROTTLE_TOKEN {
+            let nothing = Ready { readable: false, writable: false, hangup: false, error: false };
+            for id in self.throttled.resume().into_iter() {
+                self.handle(event_loop, id, true, nothing);
             }
+        } else {
+            self.expire(event_loop, token);
+        }
+        self.exit_if_drained(event_loop);
+    }
+}
+
+impl LocalWorker {
+    // Timeout of an idle connection
+    fn expire(&mut self, event_loop: &mut EventLoop<LocalWorker>, token: Token) {
+        let (id, _) = parse_token(token);
+        let timeout = match self.timeout {
+            Some(t) => t,
+            None => return,
         };
 
-        info!("Shadowsocks listening on {}", local_conf);
-
-        let mut cached_proxy: BTreeMap<String, Vec<IpAddr>> = BTreeMap::new();
-
-        for s in acceptor.incoming() {
-            let mut stream = s.unwrap();
-            stream.set_timeout(self.config.timeout);
-
-            let mut succeed = false;
-            for _ in range(0, server_load_balancer.total()) {
-                let ref server_cfg = server_load_balancer.pick_server();
-                let addrs = {
-                    match cached_proxy.get(server_cfg.addr.as_slice()).map(|x| x.clone()) {
-                        Some(addr) => addr,
-                        None => {
-                            match get_host_addresses(server_cfg.addr.as_slice()) {
-                                Ok(addr) => {
-                                    if addr.is_empty() {
-                                        error!("cannot resolve proxy server `{}`", server_cfg.addr);
-                                        continue;
-                                    }
-                                    cached_proxy.insert(server_cfg.addr.clone(), addr.clone());
-                                    addr
-                                },
-                                Err(err) => {
-                                    error!("cannot resolve proxy server `{}`: {}", server_cfg.addr, err);
-                                    continue;
-                                }
-                            }
-                        }
+        let idle = match self.conns.get(&id) {
+            // UDP associations last as long as their control connections
+            Some(&Entry { conn: Connection::Handshaking(ref h), .. }) if h.stage == Stage::Associated => 0,
+            Some(entry) => now_ms() - entry.last_active,
+            None => return,
+        };
+
+        if idle >= timeout {
+            debug!("Connection timed out after {}ms", idle);
+            self.conns.remove(&id);
+        } else {
+            let timer = event_loop.timeout_ms(token, timeout - idle);
+            self.conns.get_mut(&id).unwrap().timer = Some(timer);
+        }
+    }
+}
+
+fn bind(addr: &SocketAddr, kind: &str) -> Arc<TcpListener> {
+    match TcpListener::bind(addr) {
+        Ok(listener) => {
+            info!("Shadowsocks {} listening on {}", kind, addr);
+            Arc::new(listener)
+        },
+        Err(e) => {
+            panic!("Error occurs while listening local address: {}", e.to_string());
+        }
+    }
+}
+
+impl Relay for TcpRelayLocal {
+    fn run(&self) {
+        let kind = match self.config.local_mode {
+            LocalMode::Socks5 => "SOCKS5",
+            LocalMode::Redir => "redir",
+        };
+        let listener = self.config.local.as_ref().map(|addr| bind(addr, kind));
+        let http_listener = self.config.local_http.as_ref().map(|addr| bind(addr, "HTTP proxy"));
+        let tunnels: Vec<(Arc<TcpListener>, socks5::Address)> = self.config.tunnels.iter().map(|t| {
+            (bind(&t.local, format!("tunnel to {}", t.forward).as_slice()), t.forward.clone())
+        }).collect();
+
+        let mut workers = Vec::new();
+        for _ in range(0, self.config.workers) {
+            let listener = listener.clone();
+            let http_listener = http_listener.clone();
+            let tunnels = tunnels.clone();
+            let config = self.config.clone();
+            let associations = self.associations.clone();
+            let health_checker = self.health_checker.clone();
+            let failures = self.failures.clone();
+            let rate_limiter = self.rate_limiter.clone();
+            let metrics = self.metrics.clone();
+            let traffic = self.traffic.clone();
+            let plugins = self.plugins.clone();
+            let dns = self.dns.clone();
+            let servers = self.servers.clone();
+            workers.push(Thread::scoped(move || {
+                let mut event_loop = EventLoop::new().ok().expect("Failed to create event loop");
+                if let Some(ref listener) = listener {
+                    event_loop.register(&**listener, LISTENER_TOKEN, Interest::readable())
+                              .ok().expect("Failed to register listener");
+                }
+                if let Some(ref listener) = http_listener {
+                    event_loop.register(&**listener, HTTP_LISTENER_TOKEN, Interest::readable())
+                              .ok().expect("Failed to register listener");
+                }
+                for (i, &(ref listener, _)) in tunnels.iter().enumerate() {
+                    event_loop.register(&**listener, Token(TUNNEL_LISTENER_TOKEN + i), Interest::readable())
+                              .ok().expect("Failed to register listener");
+                }
+
+                // Servers replaced from now on are sent to the worker, which has nothing to do if the relay
+                // has been shut down
+                let list = {
+                    let mut servers = servers.lock().unwrap();
+                    if servers.deadline.is_some() {
+                        return;
                     }
+                    servers.workers.push(event_loop.notifier());
+                    servers.list.clone()
                 };
 
-                let server_addr = SocketAddr {
-                    ip: addrs.first().unwrap().clone(),
-                    port: server_cfg.port,
-                };
-                debug!("Using proxy `{}:{}` (`{}`)", server_cfg.addr, server_cfg.port, server_addr);
-                let encrypt_method = server_cfg.method.clone();
-                let pwd = encrypt_method.bytes_to_key(server_cfg.password.as_bytes());
-                let enable_udp = self.config.enable_udp;
-
-                Thread::spawn(move ||
-                    TcpRelayLocal::handle_client(stream,
-                                                 server_addr,
-                                                 pwd,
-                                                 encrypt_method,
-                                                 enable_udp));
-                succeed = true;
-                break;
-            }
-            if !succeed {
-                panic!("All proxy servers are failed!");
-            }
+                let mut worker = LocalWorker::new(listener, http_listener, tunnels, &config, list, associations,
+                                                  health_checker, failures, rate_limiter, metrics, traffic, plugins,
+                                                  dns);
+                if let Err(err) = event_loop.run(&mut worker) {
+                    error!("Event loop exited: {}", err);
+                }
+            }));
+        }
+
+        for worker in workers.into_iter() {
+            worker.join().ok().expect("A worker failed and exited");
+        }
+    }
+
+    fn shutdown(&self, drain_timeout: u64) {
+        let deadline = now_ms() + drain_timeout;
+        let mut servers = self.servers.lock().unwrap();
+        for worker in servers.workers.iter() {
+            let _ = worker.notify(Message::Drain(deadline));
         }
+        servers.deadline = Some(deadline);
     }
 }
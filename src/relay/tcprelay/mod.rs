@@ -21,7 +21,9 @@
 
 //! TcpRelay implementation
 
-mod cached_dns;
+mod aead2022;
+mod http_proxy;
 pub mod local;
 pub mod server;
 mod stream;
+pub mod tunnel;
@@ -20,207 +20,1067 @@
 // CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 //! TcpRelay server that running on the server side
+//!
+//! Connections are accepted and relayed by a pool of workers, each of them runs an event loop
+//! in its own thread. Domain names are resolved in the task pool of `CachedDns`.
+//!
+//! Servers could be started and stopped while the workers are running, every worker accepts
+//! connections of all running servers. Stopping a server closes its listener and connections.
+//! Updating a server keeps its listener, and only new connections are served with the new
+//! configuration.
+//!
+//! Clients of a multi-user server are identified before their requests are decrypted, see
+//! `relay::users`.
+//!
+//! Connections waiting for tokens of their rate limits are woken up together by a timer of the
+//! worker, see `relay::ratelimit`.
+//!
+//! Shutting the relay down stops all listeners, and every worker exits after its connections have
+//! been finished, or closes them when the drain timeout expires.
 
-use std::sync::Arc;
-use std::io::{Listener, TcpListener, Acceptor, TcpStream};
-use std::io::{EndOfFile, BrokenPipe};
-use std::io::net::ip::SocketAddr;
-use std::io::{BufferedStream, self};
-use std::time::duration::Duration;
+use std::sync::{Arc, Mutex};
+use std::io::{IoResult, IoError, OtherIoError};
+use std::io::net::ip::{SocketAddr, IpAddr, Port};
+use std::io::net::addrinfo::get_host_addresses;
 use std::thread::Thread;
+use std::collections::HashMap;
+use std::iter::repeat;
 
 use config::{Config, ServerConfig};
+use crypto::cipher::CipherCategory;
 use relay::Relay;
-use relay::socks5::{Address, self};
-use relay::tcprelay::cached_dns::CachedDns;
-use relay::tcprelay::stream::{DecryptedReader, EncryptedWriter};
-use crypto::cipher;
-use crypto::CryptoMode;
-
-macro_rules! try_result{
-    ($res:expr) => ({
-        let res = $res;
-        match res {
-            Ok(r) => { r },
-            Err(err) => {
-                error!("{}", err);
-                return;
-            }
-        }
-    });
-    ($res:expr, prefix: $prefix:expr) => ({
-        let res = $res;
-        let prefix = $prefix;
-        match res {
-            Ok(r) => { r },
-            Err(err) => {
-                error!("{} {}", prefix, err);
-                return;
-            }
-        }
-    });
-    ($res:expr, message: $message:expr) => ({
-        let res = $res;
-        let message = $message;
-        match res {
-            Ok(r) => { r },
-            Err(..) => {
-                error!("{}", message);
-                return;
-            }
-        }
-    });
+use relay::socks5::Address;
+use relay::outbound_filter::OutboundFilter;
+use relay::replay_filter::ReplayFilter;
+use relay::users::{UserTables, UserTable, ActiveUser};
+use relay::traffic::{TrafficStats, Traffic};
+use relay::ratelimit::{RateLimiter, Buckets, ConnectionLimiter};
+use relay::metrics::{Metrics, ActiveConnection};
+use relay::plugin::Plugins;
+use relay::cached_dns::CachedDns;
+use relay::eventloop::{EventLoop, Handler, Token, Interest, Ready, Timeout, TcpListener, TcpStream, Notifier, now_ms};
+use relay::parse::parse_partial;
+use relay::tcprelay::aead2022::{self, SaltReplayWindow};
+use relay::tcprelay::stream::{EncryptedWriter, Decryptor};
+use relay::tcprelay::tunnel::{Endpoint, Tunnel, Codec, Throttled, RELAY_BUFFER_SIZE, MAX_PENDING_SIZE};
+use relay::tcprelay::tunnel::{client_token, remote_token, parse_token, log_error, THROTTLE_TOKEN, DRAIN_TOKEN};
+
+/// Milliseconds for connecting to one address of the target
+const CONNECT_TIMEOUT: u64 = 30000;
+
+#[inline]
+fn make_io_error(desc: &'static str, detail: Option<String>) -> IoError {
+    IoError {
+        kind: OtherIoError,
+        desc: desc,
+        detail: detail,
+    }
+}
+
+/// Running servers and the workers serving them, shared by clones of a `TcpRelayServer`
+struct Servers {
+    running: Vec<Arc<ServerContext>>,
+    workers: Vec<Notifier<Message>>,
+    // When connections are closed after the relay has been shut down
+    deadline: Option<u64>,
 }
 
 #[derive(Clone)]
 pub struct TcpRelayServer {
     config: Config,
+    replay_filter: Arc<ReplayFilter>,
+    outbound_filter: Arc<OutboundFilter>,
+    users: Arc<UserTables>,
+    traffic: Arc<TrafficStats>,
+    rate_limiter: Arc<RateLimiter>,
+    metrics: Arc<Metrics>,
+    plugins: Arc<Plugins>,
+    servers: Arc<Mutex<Servers>>,
 }
 
 impl TcpRelayServer {
-    pub fn new(c: Config) -> TcpRelayServer {
-        if c.server.is_empty() {
+    pub fn new(c: Config, replay_filter: Arc<ReplayFilter>, outbound_filter: Arc<OutboundFilter>,
+               users: Arc<UserTables>, traffic: Arc<TrafficStats>, rate_limiter: Arc<RateLimiter>,
+               metrics: Arc<Metrics>, plugins: Arc<Plugins>) -> TcpRelayServer {
+        if c.server.is_empty() && c.manager.is_none() {
             panic!("You have to provide a server configuration");
         }
         TcpRelayServer {
             config: c,
+            replay_filter: replay_filter,
+            outbound_filter: outbound_filter,
+            users: users,
+            traffic: traffic,
+            rate_limiter: rate_limiter,
+            metrics: metrics,
+            plugins: plugins,
+            servers: Arc::new(Mutex::new(Servers {
+                running: Vec::new(),
+                workers: Vec::new(),
+                deadline: None,
+            })),
         }
     }
 
-    fn accept_loop(s: ServerConfig) {
-        let mut acceptor = try_result!(TcpListener::bind((s.addr.as_slice(), s.port)).listen(),
-                                       prefix: "Failed to bind: ");
+    /// Starts accepting connections of a server, in addition to the running ones.
+    ///
+    /// It could be called before or after `run`. The plugin of the server is started to listen on
+    /// the address of the server, and connections are accepted from it on a loopback address.
+    pub fn start_server(&self, config: &ServerConfig) -> IoResult<()> {
+        let local_addr = match config.plugin {
+            Some(..) => Some(try!(self.plugins.start(config))),
+            None => None,
+        };
 
-        info!("Shadowsocks listening on {}", s.addr);
+        let result = self.start_context(config, local_addr);
+        if result.is_err() && local_addr.is_some() {
+            self.plugins.stop(config.addr.as_slice(), config.port);
+        }
+        result
+    }
 
-        let dnscache_arc = Arc::new(CachedDns::with_capacity(s.dns_cache_capacity));
+    fn start_context(&self, config: &ServerConfig, local_addr: Option<SocketAddr>) -> IoResult<()> {
+        let ctx = try!(ServerContext::bind(config, local_addr, &*self.users, &*self.traffic, &*self.rate_limiter,
+                                           self.metrics.clone()));
+        let ctx = Arc::new(ctx);
 
-        let pwd = s.method.bytes_to_key(s.password.as_bytes());
-        let timeout = s.timeout;
-        let method = s.method;
-        for s in acceptor.incoming() {
-            let mut stream = s.unwrap();
-            stream.set_timeout(timeout);
+        let mut servers = self.servers.lock().unwrap();
+        if servers.deadline.is_some() {
+            return Err(make_io_error("The relay has been shut down", None));
+        }
+        for worker in servers.workers.iter() {
+            // The worker has exited if it fails
+            let _ = worker.notify(Message::Start(ctx.clone()));
+        }
+        servers.running.push(ctx);
+        Ok(())
+    }
 
-            let pwd = pwd.clone();
-            let encrypt_method = method;
-            let dnscache = dnscache_arc.clone();
+    /// Serves new connections of the server on the port of `config` with `config`, established
+    /// connections keep the configuration they were accepted with. The listening address is not
+    /// changed. Returns `false` if there is no such server.
+    pub fn update_server(&self, config: &ServerConfig) -> bool {
+        let mut servers = self.servers.lock().unwrap();
+        let pos = match servers.running.iter().position(|ctx| ctx.config.port == config.port) {
+            Some(pos) => pos,
+            None => return false,
+        };
 
-            Thread::spawn(move || {
-                let remote_iv = try_result!(stream.read_exact(encrypt_method.block_size()));
-                let decryptor = cipher::with_type(encrypt_method,
-                                                  pwd.as_slice(),
-                                                  remote_iv.as_slice(),
-                                                  CryptoMode::Decrypt);
+        let ctx = {
+            let old = &servers.running[pos];
+            ServerContext::with_listener(config, old.listener.clone(), old.salt_window.clone(), &*self.users,
+                                         &*self.traffic, &*self.rate_limiter, self.metrics.clone())
+        };
+        let ctx = Arc::new(ctx);
+        for worker in servers.workers.iter() {
+            let _ = worker.notify(Message::Update(ctx.clone()));
+        }
+        servers.running[pos] = ctx;
+        true
+    }
 
-                let buffered_client_stream = BufferedStream::new(stream.clone());
-                let mut decrypt_stream = DecryptedReader::new(buffered_client_stream, decryptor);
+    /// Stops the server on `port`, its listener, connections and plugin are closed.
+    /// Returns `false` if there is no such server.
+    pub fn stop_server(&self, port: Port) -> bool {
+        self.remove_server(port, true)
+    }
 
-                let addr = try_result!(socks5::Address::read_from(&mut decrypt_stream),
-                     prefix: "Error occurs while parsing request header, maybe wrong crypto method or password: "
-                );
+    /// Stops accepting connections of the server on `port`, its listener and plugin are closed.
+    /// Established connections are relayed with the configuration they were accepted with until they
+    /// are finished, so that the server could be started again with another listening address or
+    /// plugin. Returns `false` if there is no such server.
+    pub fn stop_listening(&self, port: Port) -> bool {
+        self.remove_server(port, false)
+    }
 
-                info!("Connecting to {}", addr);
-                let remote_stream = match addr {
-                    Address::SocketAddress(ip, port) => {
-                        try_result!(TcpStream::connect_timeout(SocketAddr {ip: ip, port: port}, Duration::seconds(30)),
-                                prefix: format!("Unable to connect {}:", addr)
-                        )
-                    },
-                    Address::DomainNameAddress(ref name, ref port) => {
-                        let ipaddrs = {
-                            // Cannot fail inside, which will cause other tasks fail, too.
-                            match dnscache.resolve(name.as_slice()) {
-                                Some(v) => { v },
-                                None => {
-                                    error!("Unable to resolve {}:{}", name, port);
-                                    return;
-                                }
-                            }
-                        };
-
-                        let connector = |&:| {
-                            for ipaddr in ipaddrs.iter() {
-                                let result = TcpStream::connect_timeout(SocketAddr {
-                                                                ip: *ipaddr,
-                                                                port: *port
-                                                            },
-                                                            Duration::seconds(30));
-                                match result {
-                                    Err(err) => debug!("{} trying {}: {}", addr, ipaddr, err),
-                                    Ok(host) => {
-                                        debug!("{} trying {}: succeed", addr, ipaddr);
-                                        return host;
-                                    }
-                                }
-                            }
-                            panic!("Unable to connect {}", addr);
-                        };
-                        connector()
+    fn remove_server(&self, port: Port, close_connections: bool) -> bool {
+        let mut servers = self.servers.lock().unwrap();
+        let pos = servers.running.iter().position(|ctx| ctx.config.port == port);
+        let ctx = match pos {
+            Some(pos) => servers.running.remove(pos),
+            None => return false,
+        };
+        self.plugins.stop(ctx.config.addr.as_slice(), port);
+
+        // Workers drop the listener later, but the port is freed at once, so that the server could
+        // be started again right after
+        if let Err(err) = ctx.listener.stop_listening() {
+            error!("Failed to stop listening on port {}: {}", port, err);
+        }
+        for worker in servers.workers.iter() {
+            let msg = if close_connections { Message::Stop(port) } else { Message::StopListening(port) };
+            let _ = worker.notify(msg);
+        }
+        true
+    }
+}
+
+/// States of a server which are shared by all workers
+struct ServerContext {
+    config: ServerConfig,
+    key: Vec<u8>,
+    listener: Arc<TcpListener>,
+    dns: CachedDns,
+    salt_window: Arc<Mutex<SaltReplayWindow>>,
+    // Users of a multi-user server
+    users: Option<Arc<UserTable>>,
+    traffic: Arc<Traffic>,
+    rate_limit: Arc<Buckets>,
+    metrics: Arc<Metrics>,
+}
+
+impl ServerContext {
+    // Listens on `local_addr` instead of the address of the server if it is behind a plugin
+    fn bind(config: &ServerConfig, local_addr: Option<SocketAddr>, users: &UserTables, traffic: &TrafficStats,
+            rate_limiter: &RateLimiter, metrics: Arc<Metrics>) -> IoResult<ServerContext> {
+        let addr = match local_addr {
+            Some(addr) => addr,
+            None => {
+                let ip = match config.addr.parse::<IpAddr>() {
+                    Some(ip) => ip,
+                    None => {
+                        let addrs = try!(get_host_addresses(config.addr.as_slice()));
+                        match addrs.first() {
+                            Some(ip) => *ip,
+                            None => return Err(make_io_error("Unable to resolve listening address",
+                                                             Some(config.addr.clone()))),
+                        }
                     }
                 };
+                SocketAddr { ip: ip, port: config.port }
+            }
+        };
+
+        let listener = try!(TcpListener::bind(&addr));
+        let salt_window = Mutex::new(SaltReplayWindow::new());
+        Ok(ServerContext::with_listener(config, Arc::new(listener), Arc::new(salt_window), users, traffic,
+                                        rate_limiter, metrics))
+    }
+
+    // Updated servers keep their listeners and salts
+    fn with_listener(config: &ServerConfig, listener: Arc<TcpListener>, salt_window: Arc<Mutex<SaltReplayWindow>>,
+                     users: &UserTables, traffic: &TrafficStats, rate_limiter: &RateLimiter, metrics: Arc<Metrics>)
+            -> ServerContext {
+        let traffic = traffic.port(config.port);
+        traffic.set_quota(config.quota);
+        let rate_limit = rate_limiter.port(config.port);
+        rate_limit.set_rates(&config.rate_limit);
+
+        ServerContext {
+            config: config.clone(),
+            key: config.key(),
+            listener: listener,
+            dns: CachedDns::with_capacity(config.dns_cache_capacity, metrics.clone()),
+            salt_window: salt_window,
+            users: users.get(config.addr.as_slice(), config.port),
+            traffic: traffic,
+            rate_limit: rate_limit,
+            metrics: metrics,
+        }
+    }
+}
+
+#[derive(Copy, PartialEq, Eq)]
+enum Stage {
+    /// Reading the IV and the request header
+    ReadHeader,
+    /// Resolving the domain name of the target
+    Resolving,
+    /// Connecting to the target
+    Connecting,
+}
+
+/// A connection which hasn't been established
+struct Handshake {
+    client: Endpoint,
+    remote: Option<Endpoint>,
+    stage: Stage,
+    decryptor: Decryptor,
+    salt_checked: bool,
+    var_header_len: Option<usize>,
+    received: usize,
+    // Data decrypted after the header
+    plain: Vec<u8>,
+    addr: Option<Address>,
+    // Addresses of the target which haven't been tried
+    ips: Vec<IpAddr>,
+    connect_timer: Option<Timeout>,
+    // Data received from a client of a multi-user server before it has been identified
+    head: Vec<u8>,
+    // Key of the connection, which is the key of the user for multi-user servers
+    key: Vec<u8>,
+    user: Option<ActiveUser>,
+}
+
+impl Handshake {
+    fn new(client: Endpoint, ctx: &ServerContext) -> Handshake {
+        Handshake {
+            client: client,
+            remote: None,
+            stage: Stage::ReadHeader,
+            decryptor: Decryptor::new(ctx.config.method, ctx.key.as_slice()),
+            salt_checked: false,
+            var_header_len: None,
+            received: 0,
+            plain: Vec::new(),
+            addr: None,
+            ips: Vec::new(),
+            connect_timer: None,
+            head: Vec::new(),
+            key: ctx.key.clone(),
+            user: None,
+        }
+    }
+
+    fn is_identified(&self, ctx: &ServerContext) -> bool {
+        ctx.users.is_none() || self.user.is_some()
+    }
+
+    /// Identifies the user of a multi-user server by `head`, then decrypts with the key of the user
+    fn identify(&mut self, ctx: &ServerContext) -> IoResult<bool> {
+        let users = match ctx.users {
+            Some(ref users) if self.user.is_none() => users,
+            _ => return Ok(true),
+        };
+
+        let user = match try!(users.identify_stream(ctx.key.as_slice(), self.head.as_slice())) {
+            Some(user) => user,
+            None => return Ok(false),
+        };
+        if !user.is_enabled() {
+            return Err(make_io_error("User is disabled", Some(user.name().to_string())));
+        }
+        if user.traffic().is_exceeded() {
+            return Err(make_io_error("User has exceeded the quota", Some(user.name().to_string())));
+        }
+        debug!("Identified user {}", user.name());
+
+        // The identity header of the 2022 edition is not a part of the stream
+        let salt_size = ctx.config.method.iv_size();
+        self.decryptor = Decryptor::new(ctx.config.method, user.key());
+        self.decryptor.feed(&self.head[..salt_size]);
+        self.decryptor.feed(&self.head[salt_size + users.identity_header_size()..]);
+        self.head = Vec::new();
+        self.key = user.key().to_vec();
+        self.user = Some(ActiveUser::new(user));
+        Ok(true)
+    }
+
+    /// Handles readiness of the client, returns the target address after the header has been read
+    fn client_ready(&mut self, ctx: &ServerContext, replay_filter: &ReplayFilter, ready: Ready, buf: &mut [u8])
+            -> IoResult<Option<Address>> {
+        if ready.error {
+            try!(self.client.stream.take_socket_error());
+        }
+
+        if self.stage != Stage::ReadHeader {
+            if ready.hangup {
+                return Err(make_io_error("Client has closed the connection", None));
+            }
+            return Ok(None);
+        }
+
+        while self.received < MAX_PENDING_SIZE {
+            match try!(self.client.read(buf)) {
+                None => break,
+                Some(0) => return Err(make_io_error("Client closed before sending request", None)),
+                Some(n) => {
+                    if self.is_identified(ctx) {
+                        self.decryptor.feed(&buf[..n]);
+                    } else {
+                        self.head.push_all(&buf[..n]);
+                    }
+                    self.received += n;
+                }
+            }
+        }
+
+        if !try!(self.identify(ctx)) {
+            return Ok(None);
+        }
+
+        self.read_header(ctx, replay_filter).map_err(|err| {
+            make_io_error("Error occurs while parsing request header, maybe wrong crypto method or password",
+                          Some(err.to_string()))
+        })
+    }
+
+    fn read_header(&mut self, ctx: &ServerContext, replay_filter: &ReplayFilter) -> IoResult<Option<Address>> {
+        let iv = match self.decryptor.iv() {
+            Some(iv) => iv.to_vec(),
+            None => return Ok(None),
+        };
+        let is_aead2022 = ctx.config.method.category() == CipherCategory::Aead2022;
+
+        if is_aead2022 && !self.salt_checked {
+            self.salt_checked = true;
+            if !ctx.salt_window.lock().unwrap().check_and_insert(iv.as_slice()) {
+                let peer = self.client.stream.peer_name().ok().map(|a| a.to_string())
+                                                              .unwrap_or("unknown".to_string());
+                return Err(make_io_error("Replayed request salt", Some(format!("from {}", peer))));
+            }
+        }
+
+        let addr = if is_aead2022 {
+            let addr = match try!(aead2022::read_request_header(&mut self.decryptor, &mut self.var_header_len)) {
+                Some(addr) => addr,
+                None => return Ok(None),
+            };
+            try!(self.decryptor.decrypt(&mut self.plain));
+            addr
+        } else {
+            try!(self.decryptor.decrypt(&mut self.plain));
+            match parse_partial(self.plain.as_slice(), |r| Address::read_from(r)) {
+                None => return Ok(None),
+                Some((Err(err), _)) => return Err(make_io_error("Invalid address", Some(err.to_string()))),
+                Some((Ok(addr), consumed)) => {
+                    self.plain = self.plain[consumed..].to_vec();
+                    addr
+                }
+            }
+        };
+
+        // Checks after the header has been decrypted, so that random probes won't fill the filter
+        if !replay_filter.check_and_insert(iv.as_slice()) {
+            return Err(make_io_error("Replayed request",
+                                     Some(format!("to {}, rejected {} of {} requests",
+                                                  addr, replay_filter.hits(), replay_filter.checked()))));
+        }
+
+        Ok(Some(addr))
+    }
+
+    /// Connects to the next address of the target
+    fn connect_next(&mut self, event_loop: &mut EventLoop<ServerWorker>, id: usize) -> IoResult<()> {
+        let addr = self.addr.clone().unwrap();
+        let port = match addr {
+            Address::SocketAddress(_, port) => port,
+            Address::DomainNameAddress(_, port) => port,
+        };
+
+        while !self.ips.is_empty() {
+            let ip = self.ips.remove(0);
+            match TcpStream::connect(&SocketAddr { ip: ip, port: port }) {
+                Ok(stream) => {
+                    self.remote = Some(Endpoint::connecting(stream, remote_token(id)));
+                    self.stage = Stage::Connecting;
+                    self.connect_timer = Some(event_loop.timeout_ms(remote_token(id), CONNECT_TIMEOUT));
+                    return Ok(());
+                },
+                Err(err) => debug!("{} trying {}: {}", addr, ip, err),
+            }
+        }
+
+        Err(make_io_error("Unable to connect", Some(addr.to_string())))
+    }
+
+    /// Handles readiness of the remote while connecting, returns it after it has been connected
+    fn remote_ready(&mut self, event_loop: &mut EventLoop<ServerWorker>, id: usize) -> IoResult<Option<Endpoint>> {
+        let result = match self.remote {
+            Some(ref mut remote) => remote.finish_connect(),
+            None => return Ok(None),
+        };
+
+        if let Some(timer) = self.connect_timer.take() {
+            event_loop.clear_timeout(timer);
+        }
+
+        let addr = self.addr.clone().unwrap();
+        match result {
+            Ok(..) => {
+                debug!("{} trying {}: succeed", addr,
+                       self.remote.as_ref().unwrap().stream.peer_name().ok().map(|a| a.to_string())
+                                                                       .unwrap_or("unknown".to_string()));
+                Ok(self.remote.take())
+            },
+            Err(err) => {
+                debug!("{} trying next address: {}", addr, err);
+                self.remote = None;
+                try!(self.connect_next(event_loop, id));
+                Ok(None)
+            }
+        }
+    }
+
+    /// The current address of the target hasn't been connected in time
+    fn connect_timeout(&mut self, event_loop: &mut EventLoop<ServerWorker>, id: usize) -> IoResult<()> {
+        self.connect_timer = None;
+        if self.stage != Stage::Connecting || self.remote.is_none() {
+            return Ok(());
+        }
+
+        debug!("{} connect timed out", self.addr.clone().unwrap());
+        self.remote = None;
+        self.connect_next(event_loop, id)
+    }
+
+    fn into_tunnel(self, remote: Endpoint, ctx: &ServerContext, limiter: ConnectionLimiter) -> IoResult<Tunnel> {
+        let method = ctx.config.method;
+        let request_salt = self.decryptor.iv().unwrap().to_vec();
+
+        let iv = method.gen_init_vec();
+        let mut encryptor = EncryptedWriter::with_type(Vec::new(), method, self.key.as_slice(), iv.as_slice());
+        if method.category() == CipherCategory::Aead2022 {
+            aead2022::set_response_header(&mut encryptor, request_salt.as_slice());
+        }
+
+        let mut client = self.client;
+        client.out.push_all(iv.as_slice());
+        let mut remote = remote;
+        remote.out = self.plain;
+
+        let mut tunnel = Tunnel::new(client, remote, Codec::server(encryptor, self.decryptor));
+        tunnel.set_limiter(limiter);
+        try!(tunnel.flush());
+        Ok(tunnel)
+    }
+
+    fn update_interest(&mut self, event_loop: &mut EventLoop<ServerWorker>) -> IoResult<()> {
+        let reading = self.stage == Stage::ReadHeader && self.received < MAX_PENDING_SIZE;
+        try!(self.client.update_interest(event_loop, reading));
+        match self.remote {
+            Some(ref mut remote) => remote.update_interest(event_loop, false),
+            None => Ok(()),
+        }
+    }
+}
+
+enum Connection {
+    Handshaking(Handshake),
+    /// Relaying with the user of a multi-user server
+    Relaying(Tunnel, Address, Option<ActiveUser>),
+}
+
+// Counts traffic of the server, and of the user of a multi-user server
+fn count_traffic(ctx: &ServerContext, user: &Option<ActiveUser>, upload: usize, download: usize) {
+    ctx.traffic.add_upload(upload);
+    ctx.traffic.add_download(download);
+    if let Some(ref user) = *user {
+        user.user().traffic().add_upload(upload);
+        user.user().traffic().add_download(download);
+    }
+}
+
+struct Entry {
+    conn: Connection,
+    // Id of the listener of the server
+    server: usize,
+    // Configuration of the server when the connection was accepted
+    ctx: Arc<ServerContext>,
+    last_active: u64,
+    timer: Option<Timeout>,
+    // Counted as an active connection until the entry is dropped
+    _active: ActiveConnection,
+}
+
+/// Result of resolving the target of a connection
+struct Resolved {
+    id: usize,
+    addrs: Option<Vec<IpAddr>>,
+}
+
+/// Messages to workers
+enum Message {
+    Resolved(Resolved),
+    /// Starts accepting connections of a server
+    Start(Arc<ServerContext>),
+    /// Stops the server on the port
+    Stop(Port),
+    /// Stops accepting connections of the server on the port, established ones are kept
+    StopListening(Port),
+    /// Serves new connections of the server on the same port with the new context
+    Update(Arc<ServerContext>),
+    /// Stops accepting, and exits after connections have been finished or the deadline has passed
+    Drain(u64),
+}
+
+/// Accepts and relays connections of all servers in one event loop
+struct ServerWorker {
+    // Running servers, keyed by ids of their listeners
+    servers: HashMap<usize, Arc<ServerContext>>,
+    replay_filter: Arc<ReplayFilter>,
+    outbound_filter: Arc<OutboundFilter>,
+    rate_limiter: Arc<RateLimiter>,
+    conns: HashMap<usize, Entry>,
+    // Connections waiting for tokens
+    throttled: Throttled,
+    // When the remaining connections are closed, after the worker has started draining
+    deadline: Option<u64>,
+    next_id: usize,
+    buf: Vec<u8>,
+}
+
+impl ServerWorker {
+    fn new(replay_filter: Arc<ReplayFilter>, outbound_filter: Arc<OutboundFilter>, rate_limiter: Arc<RateLimiter>)
+            -> ServerWorker {
+        ServerWorker {
+            servers: HashMap::new(),
+            replay_filter: replay_filter,
+            outbound_filter: outbound_filter,
+            rate_limiter: rate_limiter,
+            conns: HashMap::new(),
+            throttled: Throttled::new(),
+            deadline: None,
+            next_id: 0,
+            buf: repeat(0u8).take(RELAY_BUFFER_SIZE).collect(),
+        }
+    }
+
+    // Listeners and connections take ids from the same sequence, a listener has the client token of its id
+    fn start(&mut self, event_loop: &mut EventLoop<ServerWorker>, ctx: Arc<ServerContext>) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        match event_loop.register(&*ctx.listener, client_token(id), Interest::readable()) {
+            Ok(..) => { self.servers.insert(id, ctx); },
+            Err(err) => error!("Failed to register listener: {}", err),
+        }
+    }
+
+    // Connections of the server keep the context they were accepted with. Returns ids of the listeners.
+    fn stop_listening(&mut self, event_loop: &mut EventLoop<ServerWorker>, port: Port) -> Vec<usize> {
+        let stopped = self.servers.iter().filter(|&(_, ctx)| ctx.config.port == port)
+                                         .map(|(id, _)| *id).collect::<Vec<usize>>();
+        for id in stopped.iter() {
+            let ctx = self.servers.remove(id).unwrap();
+            if let Err(err) = event_loop.deregister(&*ctx.listener) {
+                error!("Failed to deregister listener: {}", err);
+            }
+        }
+        debug!("Stopped listening on port {}", port);
+        stopped
+    }
+
+    fn stop(&mut self, event_loop: &mut EventLoop<ServerWorker>, port: Port) {
+        let stopped = self.stop_listening(event_loop, port);
+        let closed = self.conns.iter().filter(|&(_, entry)| stopped.contains(&entry.server))
+                                      .map(|(id, _)| *id).collect::<Vec<usize>>();
+        debug!("Stopped server on port {}, closed {} connections", port, closed.len());
+        for id in closed.iter() {
+            let entry = self.conns.remove(id).unwrap();
+            if let Some(timer) = entry.timer {
+                event_loop.clear_timeout(timer);
+            }
+        }
+    }
+
+    // The listener is still registered with the same id
+    fn update(&mut self, ctx: Arc<ServerContext>) {
+        let port = ctx.config.port;
+        for (_, running) in self.servers.iter_mut() {
+            if running.config.port == port {
+                *running = ctx.clone();
+            }
+        }
+        debug!("Updated server on port {}", port);
+    }
+
+    // Listeners are shared by all workers, stopping them more than once fails harmlessly
+    fn drain(&mut self, event_loop: &mut EventLoop<ServerWorker>, deadline: u64) {
+        for (_, ctx) in self.servers.drain() {
+            if let Err(err) = event_loop.deregister(&*ctx.listener) {
+                error!("Failed to deregister listener: {}", err);
+            }
+            let _ = ctx.listener.stop_listening();
+        }
 
-                let mut remote_stream_cloned = remote_stream.clone();
-                let addr_cloned = addr.clone();
-                Thread::spawn(move || {
-                    match io::util::copy(&mut decrypt_stream, &mut remote_stream_cloned) {
-                        Ok(..) => {},
+        let now = now_ms();
+        event_loop.timeout_ms(DRAIN_TOKEN, if deadline > now { deadline - now } else { 0 });
+        self.deadline = Some(deadline);
+        debug!("Draining {} connections", self.conns.len());
+    }
+
+    fn close_all(&mut self, event_loop: &mut EventLoop<ServerWorker>) {
+        if !self.conns.is_empty() {
+            info!("Closed {} connections which were not finished in time", self.conns.len());
+        }
+        self.conns.clear();
+        event_loop.shutdown();
+    }
+
+    fn exit_if_drained(&self, event_loop: &mut EventLoop<ServerWorker>) {
+        if self.deadline.is_some() && self.conns.is_empty() {
+            event_loop.shutdown();
+        }
+    }
+
+    fn accept(&mut self, event_loop: &mut EventLoop<ServerWorker>, server: usize) {
+        let ctx = match self.servers.get(&server) {
+            Some(ctx) => ctx.clone(),
+            None => return,
+        };
+        loop {
+            let stream = match ctx.listener.accept() {
+                Ok(Some((stream, _))) => stream,
+                // Taken by another worker, or no more pending connections
+                Ok(None) => break,
+                Err(err) => {
+                    error!("Failed to accept: {}", err);
+                    break;
+                }
+            };
+            if ctx.traffic.is_exceeded() {
+                debug!("Refused connection to port {}, which has exceeded the quota", ctx.config.port);
+                continue;
+            }
+
+            let id = self.next_id;
+            self.next_id += 1;
+
+            let mut handshake = Handshake::new(Endpoint::new(stream, client_token(id)), &*ctx);
+            if let Err(err) = handshake.update_interest(event_loop) {
+                error!("Failed to register client: {}", err);
+                continue;
+            }
+
+            let timer = ctx.config.timeout.map(|t| event_loop.timeout_ms(client_token(id), t));
+            self.conns.insert(id, Entry {
+                conn: Connection::Handshaking(handshake),
+                server: server,
+                ctx: ctx.clone(),
+                last_active: now_ms(),
+                timer: timer,
+                _active: ActiveConnection::new(ctx.metrics.clone()),
+            });
+        }
+    }
+
+    // Starts connecting to the target after the header has been read
+    fn start_connect(event_loop: &mut EventLoop<ServerWorker>, ctx: &ServerContext, filter: &OutboundFilter,
+                     id: usize, handshake: &mut Handshake, addr: Address) -> IoResult<()> {
+        if !filter.check(&addr) {
+            return Err(make_io_error("Refused to connect", Some(addr.to_string())));
+        }
+
+        info!("Connecting to {}", addr);
+        handshake.addr = Some(addr.clone());
+
+        match addr {
+            Address::SocketAddress(ip, _) => {
+                handshake.ips = vec![ip];
+                handshake.connect_next(event_loop, id)
+            },
+            Address::DomainNameAddress(ref name, _) => {
+                handshake.stage = Stage::Resolving;
+                let notifier = event_loop.notifier();
+                ctx.dns.resolve_async(name.as_slice(), move |addrs| {
+                    // The worker has exited if it fails
+                    let _ = notifier.notify(Message::Resolved(Resolved { id: id, addrs: addrs }));
+                });
+                Ok(())
+            }
+        }
+    }
+
+    // Returns the connection if it is still alive
+    fn process(&mut self, event_loop: &mut EventLoop<ServerWorker>, id: usize, ctx: &ServerContext,
+               from_client: bool, ready: Ready, conn: Connection) -> Option<Connection> {
+        let buf = self.buf.as_mut_slice();
+
+        match conn {
+            Connection::Handshaking(mut handshake) => {
+                let result = if from_client {
+                    match handshake.client_ready(ctx, &*self.replay_filter, ready, buf) {
+                        Ok(Some(addr)) => {
+                            let filter = &*self.outbound_filter;
+                            ServerWorker::start_connect(event_loop, ctx, filter, id, &mut handshake, addr).map(|_| None)
+                        },
+                        Ok(None) => Ok(None),
                         Err(err) => {
-                            match err.kind {
-                                EndOfFile | BrokenPipe => {
-                                    debug!("{} relay from local to remote stream: {}", addr_cloned, err)
-                                },
-                                _ => {
-                                    error!("{} relay from local to remote stream: {}", addr_cloned, err)
-                                }
+                            ctx.metrics.add_handshake_failure();
+                            Err(err)
+                        }
+                    }
+                } else {
+                    handshake.remote_ready(event_loop, id)
+                };
+
+                match result {
+                    Ok(Some(remote)) => {
+                        let addr = handshake.addr.clone().unwrap();
+                        let user = handshake.user.take();
+                        count_traffic(ctx, &user, handshake.received, 0);
+                        let limiter = self.rate_limiter.for_connection(Some(ctx.rate_limit.clone()));
+                        let result = handshake.into_tunnel(remote, ctx, limiter).and_then(|mut tunnel| {
+                            tunnel.update_interest(event_loop).map(|_| tunnel)
+                        });
+                        match result {
+                            Ok(tunnel) => Some(Connection::Relaying(tunnel, addr, user)),
+                            Err(err) => {
+                                log_error(&addr, &err);
+                                None
                             }
-                            remote_stream_cloned.close_write().or(Ok(())).unwrap();
-                            decrypt_stream.get_mut().get_mut().close_read().or(Ok(())).unwrap();
                         }
+                    },
+                    Ok(None) => ServerWorker::keep_handshaking(event_loop, handshake),
+                    Err(err) => {
+                        error!("{}", err);
+                        None
                     }
-                });
+                }
+            },
+            Connection::Relaying(mut tunnel, addr, user) => {
+                if let Some(ref user) = user {
+                    if !user.user().is_enabled() {
+                        info!("Closing connection of disabled user {} to {}", user.user().name(), addr);
+                        return None;
+                    }
+                    if user.user().traffic().is_exceeded() {
+                        info!("Closing connection of user {} to {}, who has exceeded the quota",
+                              user.user().name(), addr);
+                        return None;
+                    }
+                }
+                if ctx.traffic.is_exceeded() {
+                    info!("Closing connection to {}, port {} has exceeded the quota", addr, ctx.config.port);
+                    return None;
+                }
 
-                let iv = encrypt_method.gen_init_vec();
-                let encryptor = cipher::with_type(encrypt_method,
-                                                  pwd.as_slice(),
-                                                  iv.as_slice(),
-                                                  CryptoMode::Encrypt);
-                try_result!(stream.write(iv.as_slice()));
-                let mut buffered_remote_stream = BufferedStream::new(remote_stream.clone());
-                let mut encrypt_stream = EncryptedWriter::new(stream.clone(), encryptor);
-                match io::util::copy(&mut buffered_remote_stream, &mut encrypt_stream) {
-                    Ok(..) => {},
+                let result = tunnel.ready(from_client, ready, buf).and_then(|_| tunnel.update_interest(event_loop));
+                let (upload, download) = tunnel.take_transferred();
+                count_traffic(ctx, &user, upload, download);
+                match result {
                     Err(err) => {
-                        match err.kind {
-                            EndOfFile | BrokenPipe => {
-                                debug!("{} relay from remote to local stream: {}", addr, err)
-                            },
-                            _ => {
-                                error!("{} relay from remote to local stream: {}", addr, err)
-                            }
+                        if tunnel.is_decryption_failed() {
+                            ctx.metrics.add_decryption_failure();
                         }
-                        encrypt_stream.get_mut().close_write().or(Ok(())).unwrap();
-                        buffered_remote_stream.get_mut().close_read().or(Ok(())).unwrap();
+                        log_error(&addr, &err);
+                        None
+                    },
+                    Ok(..) if tunnel.is_finished() => None,
+                    Ok(..) => Some(Connection::Relaying(tunnel, addr, user)),
+                }
+            }
+        }
+    }
+
+    fn keep_handshaking(event_loop: &mut EventLoop<ServerWorker>, mut handshake: Handshake) -> Option<Connection> {
+        match handshake.update_interest(event_loop) {
+            Ok(..) => Some(Connection::Handshaking(handshake)),
+            Err(err) => {
+                error!("{}", err);
+                None
+            }
+        }
+    }
+}
+
+impl ServerWorker {
+    fn handle(&mut self, event_loop: &mut EventLoop<ServerWorker>, id: usize, from_client: bool, ready: Ready) {
+        let mut entry = match self.conns.remove(&id) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        let ctx = entry.ctx.clone();
+        match self.process(event_loop, id, &*ctx, from_client, ready, entry.conn) {
+            Some(conn) => {
+                if let Connection::Relaying(ref tunnel, _, _) = conn {
+                    if let Some(delay) = tunnel.throttle_delay() {
+                        self.throttled.throttle(event_loop, id, delay);
                     }
                 }
-            });
+                entry.conn = conn;
+                entry.last_active = now_ms();
+                self.conns.insert(id, entry);
+            },
+            None => {
+                if let Some(timer) = entry.timer {
+                    event_loop.clear_timeout(timer);
+                }
+            }
+        }
+    }
+
+    fn resolved(&mut self, event_loop: &mut EventLoop<ServerWorker>, msg: Resolved) {
+        let mut entry = match self.conns.remove(&msg.id) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        let conn = match entry.conn {
+            Connection::Handshaking(mut handshake) => {
+                let addr = handshake.addr.clone().unwrap();
+                let result = match msg.addrs {
+                    Some(addrs) => {
+                        // Names may resolve to refused addresses
+                        let addrs = self.outbound_filter.filter_resolved(&addr, addrs);
+                        if addrs.is_empty() {
+                            Err(make_io_error("Refused to connect", Some(addr.to_string())))
+                        } else {
+                            handshake.ips = addrs;
+                            handshake.connect_next(event_loop, msg.id)
+                        }
+                    },
+                    None => Err(make_io_error("Unable to resolve", Some(addr.to_string()))),
+                };
+
+                match result {
+                    Ok(..) => ServerWorker::keep_handshaking(event_loop, handshake),
+                    Err(err) => {
+                        error!("{}", err);
+                        None
+                    }
+                }
+            },
+            conn => Some(conn),
+        };
+
+        match conn {
+            Some(conn) => {
+                entry.conn = conn;
+                self.conns.insert(msg.id, entry);
+            },
+            None => {
+                if let Some(timer) = entry.timer {
+                    event_loop.clear_timeout(timer);
+                }
+            }
+        }
+    }
+}
+
+impl Handler for ServerWorker {
+    type Message = Message;
+
+    fn ready(&mut self, event_loop: &mut EventLoop<ServerWorker>, token: Token, ready: Ready) {
+        let (id, from_client) = parse_token(token);
+        if from_client && self.servers.contains_key(&id) {
+            self.accept(event_loop, id);
+            return;
+        }
+        self.handle(event_loop, id, from_client, ready);
+        self.exit_if_drained(event_loop);
+    }
+
+    fn notify(&mut self, event_loop: &mut EventLoop<ServerWorker>, msg: Message) {
+        match msg {
+            Message::Resolved(msg) => self.resolved(event_loop, msg),
+            Message::Start(ctx) => self.start(event_loop, ctx),
+            Message::Stop(port) => self.stop(event_loop, port),
+            Message::StopListening(port) => { self.stop_listening(event_loop, port); },
+            Message::Update(ctx) => self.update(ctx),
+            Message::Drain(deadline) => self.drain(event_loop, deadline),
+        }
+        self.exit_if_drained(event_loop);
+    }
+
+    fn timeout(&mut self, event_loop: &mut EventLoop<ServerWorker>, token: Token) {
+        if token == DRAIN_TOKEN {
+            self.close_all(event_loop);
+            return;
+        }
+
+        if token == THROTTLE_TOKEN {
+            let nothing = Ready { readable: false, writable: false, hangup: false, error: false };
+            for id in self.throttled.resume().into_iter() {
+                self.handle(event_loop, id, true, nothing);
+            }
+        } else {
+            self.expire(event_loop, token);
+        }
+        self.exit_if_drained(event_loop);
+    }
+}
+
+impl ServerWorker {
+    // Timeout of connecting, or of an idle connection
+    fn expire(&mut self, event_loop: &mut EventLoop<ServerWorker>, token: Token) {
+        let (id, from_client) = parse_token(token);
+        let mut entry = match self.conns.remove(&id) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        if !from_client {
+            // Timeout of connecting
+            let conn = match entry.conn {
+                Connection::Handshaking(mut handshake) => {
+                    match handshake.connect_timeout(event_loop, id) {
+                        Ok(..) => ServerWorker::keep_handshaking(event_loop, handshake),
+                        Err(err) => {
+                            error!("{}", err);
+                            None
+                        }
+                    }
+                },
+                conn => Some(conn),
+            };
+
+            match conn {
+                Some(conn) => {
+                    entry.conn = conn;
+                    self.conns.insert(id, entry);
+                },
+                None => {
+                    if let Some(timer) = entry.timer {
+                        event_loop.clear_timeout(timer);
+                    }
+                }
+            }
+            return;
+        }
+
+        let timeout = entry.ctx.config.timeout.unwrap();
+        let idle = now_ms() - entry.last_active;
+        if idle >= timeout {
+            debug!("Connection timed out after {}ms", idle);
+        } else {
+            entry.timer = Some(event_loop.timeout_ms(token, timeout - idle));
+            self.conns.insert(id, entry);
         }
     }
 }
 
 impl Relay for TcpRelayServer {
     fn run(&self) {
-        let mut threads = Vec::new();
         for s in self.config.server.iter() {
-            let s = s.clone();
-            let fut = Thread::scoped(move || {
-                TcpRelayServer::accept_loop(s);
-            });
-            threads.push(fut);
+            match self.start_server(s) {
+                Ok(..) => info!("Shadowsocks listening on {}:{}", s.addr, s.port),
+                Err(err) => error!("Failed to bind: {}", err),
+            }
+        }
+
+        let mut workers = Vec::new();
+        for _ in range(0, self.config.workers) {
+            let servers = self.servers.clone();
+            let replay_filter = self.replay_filter.clone();
+            let outbound_filter = self.outbound_filter.clone();
+            let rate_limiter = self.rate_limiter.clone();
+            workers.push(Thread::scoped(move || {
+                let mut event_loop = EventLoop::new().ok().expect("Failed to create event loop");
+                let mut worker = ServerWorker::new(replay_filter, outbound_filter, rate_limiter);
+
+                // Servers started from now on are sent to the worker, which has nothing to do if the relay
+                // has been shut down
+                {
+                    let mut servers = servers.lock().unwrap();
+                    if servers.deadline.is_some() {
+                        return;
+                    }
+                    servers.workers.push(event_loop.notifier());
+                    for ctx in servers.running.iter() {
+                        worker.start(&mut event_loop, ctx.clone());
+                    }
+                }
+
+                if let Err(err) = event_loop.run(&mut worker) {
+                    error!("Event loop exited: {}", err);
+                }
+            }));
+        }
+
+        for worker in workers.into_iter() {
+            worker.join().ok().expect("A worker failed and exited");
         }
+    }
 
-        for fut in threads.into_iter() {
-            fut.join().ok().expect("A thread failed and exited");
+    fn shutdown(&self, drain_timeout: u64) {
+        let deadline = now_ms() + drain_timeout;
+        let mut servers = self.servers.lock().unwrap();
+        for worker in servers.workers.iter() {
+            let _ = worker.notify(Message::Drain(deadline));
         }
+        servers.deadline = Some(deadline);
     }
 }
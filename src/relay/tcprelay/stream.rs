@@ -23,145 +23,320 @@
 
 use std::io::{IoResult, IoError, IoErrorKind};
 use std::cmp;
-use std::slice;
 
-use crypto::cipher::Cipher;
+use crypto::cipher::{self, Cipher, CipherType, CipherCategory};
+use crypto::aead::{self, AeadCipher};
+use crypto::aead2022;
+use crypto::CryptoMode;
 
-pub struct DecryptedReader<R: Reader> {
-    reader: R,
-    buffer: Vec<u8>,
-    cipher: Box<Cipher + Send>,
-    pos: usize,
-    sent_final: bool,
+enum DecryptCipher {
+    Stream(Box<Cipher + Send>),
+    Aead(Box<AeadCipher + Send>),
+    Aead2022(Box<AeadCipher + Send>),
 }
 
-const BUFFER_SIZE: usize = 2048;
+enum EncryptCipher {
+    Stream(Box<Cipher + Send>),
+    Aead(Box<AeadCipher + Send>),
+    // The optional header will be sealed together with the length of the first chunk
+    Aead2022(Box<AeadCipher + Send>, Option<Vec<u8>>),
+}
 
-impl<R: Reader> DecryptedReader<R> {
-    pub fn new(r: R, cipher: Box<Cipher + Send>) -> DecryptedReader<R> {
-        DecryptedReader {
-            reader: r,
-            buffer: Vec::new(),
-            cipher: cipher,
-            pos: 0,
+#[inline]
+fn make_cipher_error(err: cipher::Error) -> IoError {
+    IoError {
+        kind: IoErrorKind::OtherIoError,
+        desc: err.desc,
+        detail: err.detail,
+    }
+}
+
+// [header][length of the first chunk][tag][first chunk][tag]
+fn seal_with_header(cipher: &mut Box<AeadCipher + Send>, mut header: Vec<u8>, chunk: &[u8]) -> IoResult<Vec<u8>> {
+    header.push((chunk.len() >> 8) as u8);
+    header.push(chunk.len() as u8);
+
+    let mut data = try!(cipher.encrypt(header.as_slice()).map_err(make_cipher_error));
+    data.push_all(try!(cipher.encrypt(chunk).map_err(make_cipher_error)).as_slice());
+    Ok(data)
+}
+
+/// Decrypts a stream which arrives in pieces.
+///
+/// Received data is pushed with `feed`, the IV or salt is collected from the beginning of it,
+/// and everything which could be decrypted is taken out with `decrypt`.
+pub struct Decryptor {
+    cipher_type: CipherType,
+    key: Vec<u8>,
+    iv: Vec<u8>,
+    cipher: Option<DecryptCipher>,
+    incoming: Vec<u8>,
+    // Decrypted data which has been put back by `unread`
+    pending: Vec<u8>,
+    // Length of the next AEAD payload, if its length block has been decrypted
+    payload_len: Option<usize>,
+    sent_final: bool,
+}
+
+impl Decryptor {
+    /// Creates a decryptor by the category of `t`, `key` is the master key
+    pub fn new(t: CipherType, key: &[u8]) -> Decryptor {
+        Decryptor {
+            cipher_type: t,
+            key: key.to_vec(),
+            iv: Vec::with_capacity(t.iv_size()),
+            cipher: None,
+            incoming: Vec::new(),
+            pending: Vec::new(),
+            payload_len: None,
             sent_final: false,
         }
     }
 
-    pub fn get_ref(&self) -> &R {
-        &self.reader
+    /// Appends received data
+    pub fn feed(&mut self, data: &[u8]) {
+        let mut data = data;
+
+        if self.cipher.is_none() {
+            let iv_len = cmp::min(self.cipher_type.iv_size() - self.iv.len(), data.len());
+            self.iv.push_all(&data[..iv_len]);
+            data = &data[iv_len..];
+
+            if self.iv.len() == self.cipher_type.iv_size() {
+                let t = self.cipher_type;
+                let (key, iv) = (self.key.as_slice(), self.iv.as_slice());
+                self.cipher = Some(match t.category() {
+                    CipherCategory::Stream =>
+                        DecryptCipher::Stream(cipher::with_type(t, key, iv, CryptoMode::Decrypt)),
+                    CipherCategory::Aead => DecryptCipher::Aead(aead::with_type(t, key, iv)),
+                    CipherCategory::Aead2022 => DecryptCipher::Aead2022(aead::with_type(t, key, iv)),
+                });
+            }
+        }
+
+        self.incoming.push_all(data);
+    }
+
+    /// The IV or salt, `None` if it hasn't been completely received
+    pub fn iv(&self) -> Option<&[u8]> {
+        match self.cipher {
+            Some(..) => Some(self.iv.as_slice()),
+            None => None,
+        }
     }
 
-    /// Gets a mutable reference to the underlying reader.
-    ///
-    /// # Warning
+    /// Whether there is received data which hasn't been decrypted
+    pub fn has_incomplete(&self) -> bool {
+        !self.incoming.is_empty() || self.payload_len.is_some() || (self.cipher.is_none() && !self.iv.is_empty())
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.incoming = self.incoming[len..].to_vec();
+    }
+
+    /// Takes one sealed block whose plaintext is exactly `len` bytes, without the length prefix.
     ///
-    /// It is inadvisable to read directly from or write directly to the
-    /// underlying reader.
-    pub fn get_mut(&mut self) -> &mut R {
-        &mut self.reader
+    /// Returns `None` if the whole block hasn't been received. This is used for reading headers
+    /// of the 2022 edition.
+    pub fn read_sealed(&mut self, len: usize) -> IoResult<Option<Vec<u8>>> {
+        let result = match self.cipher {
+            Some(DecryptCipher::Aead2022(ref mut c)) => {
+                if self.incoming.len() < len + c.tag_size() {
+                    return Ok(None);
+                }
+                (len + c.tag_size(), try!(c.decrypt(&self.incoming[..len + c.tag_size()]).map_err(make_cipher_error)))
+            },
+            None => return Ok(None),
+            _ => panic!("Sealed blocks are only available for ciphers of the 2022 edition"),
+        };
+
+        let (consumed, data) = result;
+        self.consume(consumed);
+        Ok(Some(data))
     }
 
-    /// Unwraps this `DecryptedReader`, returning the underlying reader.
+    /// Sets the length of the next payload chunk, whose length block has been read in other ways.
     ///
-    /// The internal buffer is flushed before returning the reader. Any leftover
-    /// data in the read buffer is lost.
-    pub fn into_inner(self) -> R {
-        self.reader
+    /// This is used for the first chunk of responses in the 2022 edition.
+    pub fn set_payload_len(&mut self, len: usize) {
+        self.payload_len = Some(len);
     }
-}
 
-impl<R: Reader> Buffer for DecryptedReader<R> {
-    fn fill_buf<'b>(&'b mut self) -> IoResult<&'b [u8]> {
-        if self.pos == self.buffer.len() {
-            let mut incoming = [0u8; BUFFER_SIZE];
-            match self.reader.read(&mut incoming) {
-                Ok(l) => {
-                    self.buffer = match self.cipher.update(&incoming[0..l]) {
-                        Ok(ret) => ret,
-                        Err(err) => return Err(IoError {
-                                        kind: IoErrorKind::OtherIoError,
-                                        desc: err.desc,
-                                        detail: err.detail,
-                                    }),
-                    }
-                },
-                Err(err) => {
-                    match err.kind {
-                        IoErrorKind::EndOfFile => {
-                            if self.sent_final {
-                                return Err(err);
-                            }
+    /// Puts decrypted data back, it will be returned by the next `decrypt`
+    pub fn unread(&mut self, data: &[u8]) {
+        let mut pending = data.to_vec();
+        pending.push_all(self.pending.as_slice());
+        self.pending = pending;
+    }
 
-                            self.sent_final = true;
-                            self.buffer = match self.cipher.finalize() {
-                                Ok(ret) => ret,
-                                Err(err) => return Err(IoError {
-                                    kind: IoErrorKind::OtherIoError,
-                                    desc: err.desc,
-                                    detail: err.detail,
-                                }),
-                            };
+    /// Decrypts all received data which could be decrypted and appends it into `out`
+    pub fn decrypt(&mut self, out: &mut Vec<u8>) -> IoResult<()> {
+        out.push_all(self.pending.as_slice());
+        self.pending.clear();
+
+        let consumed = match self.cipher {
+            None => 0,
+            Some(DecryptCipher::Stream(ref mut cipher)) => {
+                let start = out.len();
+                out.resize(start + self.incoming.len(), 0u8);
+                let len = try!(cipher.update_into(self.incoming.as_slice(), &mut out[start..])
+                                     .map_err(make_cipher_error));
+                out.truncate(start + len);
+                self.incoming.len()
+            },
+            Some(DecryptCipher::Aead(ref mut cipher)) | Some(DecryptCipher::Aead2022(ref mut cipher)) => {
+                let max_payload_size = match self.cipher_type.category() {
+                    CipherCategory::Aead2022 => aead2022::MAX_PAYLOAD_SIZE,
+                    _ => aead::MAX_PAYLOAD_SIZE,
+                };
+                let tag_size = cipher.tag_size();
 
-                            if self.buffer.len() == 0 {
-                                return Err(err);
+                // [encrypted payload length][length tag][encrypted payload][payload tag]
+                let mut pos = 0;
+                loop {
+                    let payload_len = match self.payload_len {
+                        Some(len) => len,
+                        None => {
+                            if self.incoming.len() - pos < 2 + tag_size {
+                                break;
                             }
-                        },
-                        _ => return Err(err),
+                            let len_buf = try!(cipher.decrypt(&self.incoming[pos..pos + 2 + tag_size])
+                                                     .map_err(make_cipher_error));
+                            pos += 2 + tag_size;
+                            let len = ((len_buf[0] as usize) << 8) | (len_buf[1] as usize);
+                            if len > max_payload_size {
+                                // The stream could not be resynchronized
+                                return Err(IoError {
+                                    kind: IoErrorKind::OtherIoError,
+                                    desc: "Invalid length of payload chunk",
+                                    detail: Some(format!("{} is larger than {}", len, max_payload_size)),
+                                });
+                            }
+                            self.payload_len = Some(len);
+                            len
+                        }
+                    };
+
+                    if self.incoming.len() - pos < payload_len + tag_size {
+                        break;
                     }
+                    let payload = try!(cipher.decrypt(&self.incoming[pos..pos + payload_len + tag_size])
+                                             .map_err(make_cipher_error));
+                    pos += payload_len + tag_size;
+                    self.payload_len = None;
+                    out.push_all(payload.as_slice());
                 }
-            };
+                pos
+            }
+        };
 
-            self.pos = 0;
+        if consumed > 0 {
+            self.consume(consumed);
         }
-
-        Ok(&self.buffer[self.pos..self.buffer.len()])
+        Ok(())
     }
 
-    fn consume(&mut self, amt: usize) {
-        self.pos += amt;
-        assert!(self.pos <= self.buffer.len());
-    }
-}
+    /// Finishes the stream after the peer has closed it, appends the remaining data into `out`
+    pub fn finalize(&mut self, out: &mut Vec<u8>) -> IoResult<()> {
+        if self.sent_final {
+            return Ok(());
+        }
+        self.sent_final = true;
 
-impl<R: Reader> Reader for DecryptedReader<R> {
-    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
-        let nread = {
-            let available = try!(self.fill_buf());
-            let nread = cmp::min(available.len(), buf.len());
-            slice::bytes::copy_memory(buf, &available[0..nread]);
-            nread
-        };
-        self.pos += nread;
-        Ok(nread)
+        if let Some(DecryptCipher::Stream(ref mut cipher)) = self.cipher {
+            out.push_all(try!(cipher.finalize().map_err(make_cipher_error)).as_slice());
+        }
+        Ok(())
     }
 }
 
 pub struct EncryptedWriter<W: Writer> {
     writer: W,
-    cipher: Box<Cipher + Send>,
+    cipher: EncryptCipher,
+    buffer: Vec<u8>,
+    finalized: bool,
 }
 
 impl<W: Writer> EncryptedWriter<W> {
     pub fn new(w: W, cipher: Box<Cipher + Send>) -> EncryptedWriter<W> {
+        EncryptedWriter::with_cipher(w, EncryptCipher::Stream(cipher))
+    }
+
+    /// Creates a writer which writes chunks defined in the shadowsocks AEAD specification
+    pub fn new_aead(w: W, cipher: Box<AeadCipher + Send>) -> EncryptedWriter<W> {
+        EncryptedWriter::with_cipher(w, EncryptCipher::Aead(cipher))
+    }
+
+    /// Creates a writer by the category of `t`.
+    ///
+    /// `key` is the master key and `iv` is the IV or salt which has been written to `w`.
+    pub fn with_type(w: W, t: CipherType, key: &[u8], iv: &[u8]) -> EncryptedWriter<W> {
+        match t.category() {
+            CipherCategory::Stream =>
+                EncryptedWriter::new(w, cipher::with_type(t, key, iv, CryptoMode::Encrypt)),
+            CipherCategory::Aead =>
+                EncryptedWriter::new_aead(w, aead::with_type(t, key, iv)),
+            CipherCategory::Aead2022 =>
+                EncryptedWriter::with_cipher(w, EncryptCipher::Aead2022(aead::with_type(t, key, iv), None)),
+        }
+    }
+
+    fn with_cipher(w: W, cipher: EncryptCipher) -> EncryptedWriter<W> {
         EncryptedWriter {
             writer: w,
             cipher: cipher,
+            buffer: Vec::new(),
+            finalized: false,
         }
     }
 
+    /// Writes one sealed block without the length prefix.
+    ///
+    /// This is used for writing headers of the 2022 edition.
+    pub fn write_sealed(&mut self, data: &[u8]) -> IoResult<()> {
+        let cipher = match self.cipher {
+            EncryptCipher::Aead2022(ref mut c, _) => c,
+            _ => panic!("Sealed blocks are only available for ciphers of the 2022 edition"),
+        };
+
+        let encrypted = try!(cipher.encrypt(data).map_err(make_cipher_error));
+        self.writer.write(encrypted.as_slice())
+    }
+
+    /// Sets a header which will be sealed together with the length of the first chunk.
+    ///
+    /// This is used for the response header of the 2022 edition, which has to contain the
+    /// length of the first payload chunk.
+    pub fn set_pending_header(&mut self, header: Vec<u8>) {
+        match self.cipher {
+            EncryptCipher::Aead2022(_, ref mut pending) => *pending = Some(header),
+            _ => panic!("Pending header is only available for ciphers of the 2022 edition"),
+        }
+    }
+
+    /// Writes the final block of stream ciphers, or the pending header of the 2022 edition with an
+    /// empty first chunk if nothing has been written. Only the first call takes effect.
     pub fn finalize(&mut self) -> IoResult<()> {
-        match self.cipher.finalize() {
-            Ok(fin) => {
+        if self.finalized {
+            return Ok(());
+        }
+        self.finalized = true;
+
+        match self.cipher {
+            EncryptCipher::Stream(ref mut cipher) => {
+                let fin = try!(cipher.finalize().map_err(make_cipher_error));
                 self.writer.write(fin.as_slice())
             },
-            Err(err) => {
-                Err(IoError {
-                    kind: IoErrorKind::OtherIoError,
-                    desc: err.desc,
-                    detail: err.detail,
-                })
-            }
+            EncryptCipher::Aead2022(ref mut cipher, ref mut pending_header) => match pending_header.take() {
+                // The peer is waiting for the header even if there is nothing to respond
+                Some(header) => {
+                    let data = try!(seal_with_header(cipher, header, &[]));
+                    self.writer.write(data.as_slice())
+                },
+                None => Ok(()),
+            },
+            // Every chunk has already been sealed in `write`
+            EncryptCipher::Aead(..) => Ok(()),
         }
     }
 
@@ -182,16 +357,42 @@ impl<W: Writer> EncryptedWriter<W> {
 
 impl<W: Writer> Writer for EncryptedWriter<W> {
     fn write(&mut self, buf: &[u8]) -> IoResult<()> {
-        match self.cipher.update(buf) {
-            Ok(ret) => {
-                self.writer.write(ret.as_slice())
+        match self.cipher {
+            EncryptCipher::Stream(ref mut cipher) => {
+                if self.buffer.len() < buf.len() {
+                    self.buffer.resize(buf.len(), 0u8);
+                }
+
+                let len = try!(cipher.update_into(buf, self.buffer.as_mut_slice()).map_err(make_cipher_error));
+                self.writer.write(&self.buffer[..len])
             },
-            Err(err) => {
-                Err(IoError {
-                    kind: IoErrorKind::OtherIoError,
-                    desc: err.desc,
-                    detail: err.detail,
-                })
+            EncryptCipher::Aead(ref mut cipher) => {
+                for chunk in buf.chunks(aead::MAX_PAYLOAD_SIZE) {
+                    let len_buf = [(chunk.len() >> 8) as u8, chunk.len() as u8];
+                    let mut data = try!(cipher.encrypt(&len_buf).map_err(make_cipher_error));
+                    data.push_all(try!(cipher.encrypt(chunk).map_err(make_cipher_error)).as_slice());
+                    try!(self.writer.write(data.as_slice()));
+                }
+                Ok(())
+            },
+            EncryptCipher::Aead2022(ref mut cipher, ref mut pending_header) => {
+                let mut buf = buf;
+
+                if let Some(header) = pending_header.take() {
+                    let first_len = cmp::min(buf.len(), aead2022::MAX_PAYLOAD_SIZE);
+                    let data = try!(seal_with_header(cipher, header, &buf[..first_len]));
+                    try!(self.writer.write(data.as_slice()));
+
+                    buf = &buf[first_len..];
+                }
+
+                for chunk in buf.chunks(aead2022::MAX_PAYLOAD_SIZE) {
+                    let len_buf = [(chunk.len() >> 8) as u8, chunk.len() as u8];
+                    let mut data = try!(cipher.encrypt(&len_buf).map_err(make_cipher_error));
+                    data.push_all(try!(cipher.encrypt(chunk).map_err(make_cipher_error)).as_slice());
+                    try!(self.writer.write(data.as_slice()));
+                }
+                Ok(())
             }
         }
     }
@@ -207,3 +408,57 @@ impl<W: Writer> Drop for EncryptedWriter<W> {
         self.finalize().unwrap()
     }
 }
+
+#[cfg(all(test, feature = "cipher-aes-gcm"))]
+mod test_stream {
+    use std::io::MemWriter;
+
+    use serialize::hex::FromHex;
+
+    use crypto::cipher::CipherType;
+    use crypto::aead;
+    use relay::tcprelay::stream::{EncryptedWriter, Decryptor};
+
+    #[test]
+    fn test_aead_chunk_stream() {
+        let t = CipherType::Aes128Gcm;
+        let key = t.bytes_to_key(b"password");
+        let salt = range(0, t.salt_size()).map(|x| x as u8).collect::<Vec<u8>>();
+
+        let encrypted = {
+            let mut writer = EncryptedWriter::with_type(MemWriter::new(), t, key.as_slice(), salt.as_slice());
+            writer.write(b"hello world").unwrap();
+            writer.get_ref().get_ref().to_vec()
+        };
+
+        let expected = "5c25f1fce5ac2f6e707995b23c88ed00e9b2947477b58a1be9421248952d7353c54275fb917b18af18e3e4b347";
+        assert_eq!(encrypted, expected.from_hex().unwrap());
+
+        // Feeds the salt and chunks byte by byte, as if they arrive in separate reads
+        let mut decryptor = Decryptor::new(t, key.as_slice());
+        let mut decrypted = Vec::new();
+        for b in salt.iter().chain(encrypted.iter()) {
+            decryptor.feed(&[*b]);
+            decryptor.decrypt(&mut decrypted).unwrap();
+        }
+        assert_eq!(decryptor.iv().unwrap(), salt.as_slice());
+        assert!(!decryptor.has_incomplete());
+        assert_eq!(decrypted.as_slice(), b"hello world");
+    }
+
+    #[test]
+    fn test_oversized_chunk() {
+        let t = CipherType::Aes128Gcm;
+        let key = t.bytes_to_key(b"password");
+        let salt = range(0, t.salt_size()).map(|x| x as u8).collect::<Vec<u8>>();
+
+        // The two high bits of the length are reserved
+        let mut cipher = aead::with_type(t, key.as_slice(), salt.as_slice());
+        let len_block = cipher.encrypt(&[0x40, 0x00]).unwrap();
+
+        let mut decryptor = Decryptor::new(t, key.as_slice());
+        decryptor.feed(salt.as_slice());
+        decryptor.feed(len_block.as_slice());
+        assert!(decryptor.decrypt(&mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,738 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! Relaying data between the client and the remote side of a proxied connection
+//!
+//! Both sides are non-blocking streams driven by an event loop. Data from one side is encrypted
+//! or decrypted into the pending buffer of the other side, and reading from one side pauses
+//! while the other side has too much pending data. EOF is passed on with a half-close after all
+//! pending data has been written.
+//!
+//! A tunnel of `sslocal` could keep what the client has sent until the server responds, so that
+//! the request could be sent again through another server if the server fails before responding.
+//! Connections of `sslocal` which haven't been established are `Routed` by their worker through
+//! other servers if connecting fails.
+//!
+//! Reading is also paused while the rate limits of the tunnel have no tokens, the tunnel should be
+//! made ready again after `throttle_delay`.
+
+use std::io::{IoResult, IoError, OtherIoError, EndOfFile, BrokenPipe, ConnectionReset, ConnectionAborted};
+use std::io::net::ip::{SocketAddr, IpAddr};
+use std::fmt::Display;
+use std::collections::HashSet;
+use std::{cmp, mem, usize};
+
+use config::ServerConfig;
+use crypto::cipher::CipherCategory;
+use crypto::aead2022::make_stream_identity;
+use relay::socks5::Address;
+use relay::eventloop::{EventLoop, Handler, Token, Interest, Ready, TcpStream, now_ms};
+use relay::ratelimit::ConnectionLimiter;
+use relay::tcprelay::aead2022;
+use relay::tcprelay::stream::{EncryptedWriter, Decryptor};
+
+/// Size of buffers for reading from sockets
+pub const RELAY_BUFFER_SIZE: usize = 16384;
+
+/// Stops reading from one side while this many bytes are waiting to be written to the other side
+pub const MAX_PENDING_SIZE: usize = 65536;
+
+/// Data sent by the client is kept for retrying until the server responds, or it exceeds this size
+pub const MAX_RETRY_BUFFER_SIZE: usize = 65536;
+
+/// Token of the timer of a worker, which wakes up tunnels waiting for rate limits
+pub const THROTTLE_TOKEN: Token = Token(usize::MAX);
+
+/// Token of the timer of a worker being shut down, which closes connections not finished in time
+pub const DRAIN_TOKEN: Token = Token(usize::MAX - 1);
+
+/// Token of the client side of connection `id`
+pub fn client_token(id: usize) -> Token {
+    Token(id * 2)
+}
+
+/// Token of the remote side of connection `id`
+pub fn remote_token(id: usize) -> Token {
+    Token(id * 2 + 1)
+}
+
+/// Returns the connection id of `token` and whether it is the client side
+pub fn parse_token(token: Token) -> (usize, bool) {
+    (token.0 / 2, token.0 % 2 == 0)
+}
+
+/// Logs the error which has closed the connection to `addr`
+pub fn log_error<T: Display>(addr: &T, err: &IoError) {
+    match err.kind {
+        EndOfFile | BrokenPipe | ConnectionReset | ConnectionAborted => debug!("{} relay: {}", addr, err),
+        _ => error!("{} relay: {}", addr, err),
+    }
+}
+
+/// Lookup of the domain name of a target which is connected without any server, or of a server.
+///
+/// Lookups would block the worker, so the worker runs them by `CachedDns::resolve_async` and passes
+/// the result back to the connection, which waits in the meantime.
+pub enum Lookup {
+    Idle,
+    /// The worker should resolve the name
+    Requested(String),
+    Waiting,
+    /// Addresses the name has been resolved to, `None` if it has failed
+    Done(Option<Vec<IpAddr>>),
+}
+
+impl Lookup {
+    /// Takes the name which should be resolved, the lookup is waiting for the result after that
+    pub fn take_requested(&mut self) -> Option<String> {
+        match mem::replace(self, Lookup::Waiting) {
+            Lookup::Requested(name) => Some(name),
+            other => {
+                *self = other;
+                None
+            }
+        }
+    }
+
+    /// Takes the result of a finished lookup
+    pub fn take_done(&mut self) -> Option<Option<Vec<IpAddr>>> {
+        match mem::replace(self, Lookup::Idle) {
+            Lookup::Done(addrs) => Some(addrs),
+            other => {
+                *self = other;
+                None
+            }
+        }
+    }
+
+    pub fn is_waiting(&self) -> bool {
+        match *self {
+            Lookup::Requested(..) | Lookup::Waiting => true,
+            Lookup::Idle | Lookup::Done(..) => false,
+        }
+    }
+}
+
+/// Address of a target connected without any server, after its domain name has been resolved to `addrs`
+pub fn resolved_target(addr: &Address, addrs: Option<Vec<IpAddr>>) -> IoResult<SocketAddr> {
+    let port = match *addr {
+        Address::SocketAddress(_, port) => port,
+        Address::DomainNameAddress(_, port) => port,
+    };
+    match addrs.as_ref().and_then(|addrs| addrs.first()) {
+        Some(ip) => Ok(SocketAddr { ip: *ip, port: port }),
+        None => Err(IoError {
+            kind: OtherIoError,
+            desc: "Unable to resolve",
+            detail: Some(addr.to_string()),
+        }),
+    }
+}
+
+/// Address of `server`, after its domain name has been resolved to `addrs`
+pub fn resolved_server(server: &ServerConfig, addrs: Option<Vec<IpAddr>>) -> IoResult<SocketAddr> {
+    resolved_target(&Address::DomainNameAddress(server.addr.clone(), server.port), addrs)
+}
+
+/// One side of a proxied connection
+pub struct Endpoint {
+    pub stream: TcpStream,
+    pub token: Token,
+    /// Data waiting to be written
+    pub out: Vec<u8>,
+    /// EOF has been read
+    pub read_closed: bool,
+    /// EOF has been sent, or the peer couldn't receive anything
+    pub write_closed: bool,
+    /// The stream is connecting and not writable yet
+    pub connecting: bool,
+    registered: Option<Interest>,
+}
+
+impl Endpoint {
+    pub fn new(stream: TcpStream, token: Token) -> Endpoint {
+        Endpoint {
+            stream: stream,
+            token: token,
+            out: Vec::new(),
+            read_closed: false,
+            write_closed: false,
+            connecting: false,
+            registered: None,
+        }
+    }
+
+    /// Endpoint of a stream returned by `TcpStream::connect`
+    pub fn connecting(stream: TcpStream, token: Token) -> Endpoint {
+        let mut ep = Endpoint::new(stream, token);
+        ep.connecting = true;
+        ep
+    }
+
+    /// Checks the result of connecting, should be called when the stream becomes writable
+    pub fn finish_connect(&mut self) -> IoResult<()> {
+        try!(self.stream.take_socket_error());
+        self.connecting = false;
+        Ok(())
+    }
+
+    /// Reads into `buf`, returns `Ok(None)` if there is nothing to read now
+    pub fn read(&mut self, buf: &mut [u8]) -> IoResult<Option<usize>> {
+        let result = try!(self.stream.read(buf));
+        if result == Some(0) {
+            self.read_closed = true;
+        }
+        Ok(result)
+    }
+
+    /// Writes as much pending data as possible
+    pub fn flush(&mut self) -> IoResult<()> {
+        if self.connecting || self.out.is_empty() {
+            return Ok(());
+        }
+
+        let mut pos = 0;
+        while pos < self.out.len() {
+            match try!(self.stream.write(&self.out[pos..])) {
+                Some(n) => pos += n,
+                None => break,
+            }
+        }
+
+        if pos == self.out.len() {
+            self.out.clear();
+        } else if pos > 0 {
+            self.out = self.out[pos..].to_vec();
+        }
+        Ok(())
+    }
+
+    /// Sends EOF if all pending data has been written
+    pub fn shutdown_if_flushed(&mut self) -> IoResult<()> {
+        if !self.write_closed && !self.connecting && self.out.is_empty() {
+            self.write_closed = true;
+            try!(self.stream.shutdown_write());
+        }
+        Ok(())
+    }
+
+    /// The peer has gone, drops pending data which could never be written
+    pub fn hang_up(&mut self) {
+        self.out.clear();
+        self.write_closed = true;
+    }
+
+    /// Both directions are done
+    pub fn is_closed(&self) -> bool {
+        self.read_closed && self.write_closed
+    }
+
+    /// Registers the stream, or updates its interest by its current state.
+    ///
+    /// `readable` tells whether the other side could take more data.
+    pub fn update_interest<H: Handler>(&mut self, event_loop: &mut EventLoop<H>, readable: bool) -> IoResult<()> {
+        let interest = Interest {
+            readable: readable && !self.read_closed && !self.connecting,
+            writable: self.connecting || !self.out.is_empty(),
+        };
+
+        // Hangups are reported until the stream is deregistered, even without any interest
+        if self.is_closed() || (self.write_closed && interest == Interest::none()) {
+            if self.registered.is_some() {
+                try!(event_loop.deregister(&self.stream));
+                self.registered = None;
+            }
+            return Ok(());
+        }
+
+        match self.registered {
+            Some(i) if i == interest => {},
+            Some(..) => try!(event_loop.reregister(&self.stream, self.token, interest)),
+            None => try!(event_loop.register(&self.stream, self.token, interest)),
+        }
+        self.registered = Some(interest);
+        Ok(())
+    }
+}
+
+/// Encrypts data from one side and decrypts data from the other side
+pub struct Codec {
+    // `None` if data is relayed as is, for connections which don't go through a server
+    cipher: Option<CodecCipher>,
+}
+
+struct CodecCipher {
+    encryptor: EncryptedWriter<Vec<u8>>,
+    decryptor: Decryptor,
+    // Data from the client is encrypted in sslocal and decrypted in ssserver
+    encrypt_client: bool,
+    // Salt of the request, if the response header of the 2022 edition hasn't been read
+    response_salt: Option<Vec<u8>>,
+}
+
+impl CodecCipher {
+    fn take_encrypted(&mut self, out: &mut Vec<u8>) {
+        let encrypted = self.encryptor.get_mut();
+        if out.is_empty() {
+            ::std::mem::swap(out, encrypted);
+        } else {
+            out.push_all(encrypted.as_slice());
+            encrypted.clear();
+        }
+    }
+}
+
+impl Codec {
+    /// Codec for sslocal, whose client sends plain data
+    pub fn local(encryptor: EncryptedWriter<Vec<u8>>, decryptor: Decryptor,
+                 response_salt: Option<Vec<u8>>) -> Codec {
+        Codec {
+            cipher: Some(CodecCipher {
+                encryptor: encryptor,
+                decryptor: decryptor,
+                encrypt_client: true,
+                response_salt: response_salt,
+            }),
+        }
+    }
+
+    /// Codec for sslocal relaying to `addr` through `server`.
+    ///
+    /// The IV (or salt) and the encrypted request header are appended to `out`.
+    pub fn for_request(server: &ServerConfig, addr: &Address, out: &mut Vec<u8>) -> IoResult<Codec> {
+        let method = server.method;
+        let key = server.key();
+        let iv = method.gen_init_vec();
+        let mut encryptor = EncryptedWriter::with_type(Vec::new(), method, key.as_slice(), iv.as_slice());
+        if method.category() == CipherCategory::Aead2022 {
+            try!(aead2022::write_request_header(&mut encryptor, addr));
+        } else {
+            try!(addr.write_to(&mut encryptor));
+        }
+
+        out.push_all(iv.as_slice());
+        if let Some(identity_key) = server.identity_key() {
+            // Users of a multi-user server are identified by the header right after the salt
+            let header = try!(make_stream_identity(method, identity_key.as_slice(), iv.as_slice(), key.as_slice())
+                                  .map_err(|err| IoError {
+                                      kind: OtherIoError,
+                                      desc: "Failed to make identity header",
+                                      detail: Some(err.to_string()),
+                                  }));
+            out.push_all(header.as_slice());
+        }
+        let response_salt = if method.category() == CipherCategory::Aead2022 { Some(iv) } else { None };
+        let mut codec = Codec::local(encryptor, Decryptor::new(method, key.as_slice()), response_salt);
+        codec.cipher.as_mut().unwrap().take_encrypted(out);
+        Ok(codec)
+    }
+
+    /// Codec for ssserver, whose client sends encrypted data
+    pub fn server(encryptor: EncryptedWriter<Vec<u8>>, decryptor: Decryptor) -> Codec {
+        Codec {
+            cipher: Some(CodecCipher {
+                encryptor: encryptor,
+                decryptor: decryptor,
+                encrypt_client: false,
+                response_salt: None,
+            }),
+        }
+    }
+
+    /// Codec for sslocal connecting to the target directly, which relays data as is
+    pub fn plain() -> Codec {
+        Codec {
+            cipher: None,
+        }
+    }
+
+    /// Whether data from one side is decrypted
+    pub fn decrypts(&self, from_client: bool) -> bool {
+        match self.cipher {
+            Some(ref cipher) => from_client != cipher.encrypt_client,
+            None => false,
+        }
+    }
+
+    /// Encrypts or decrypts `data` from one side into `out`
+    pub fn transform(&mut self, from_client: bool, data: &[u8], out: &mut Vec<u8>) -> IoResult<()> {
+        let cipher = match self.cipher {
+            Some(ref mut cipher) => cipher,
+            None => {
+                out.push_all(data);
+                return Ok(());
+            }
+        };
+
+        if from_client == cipher.encrypt_client {
+            try!(cipher.encryptor.write(data));
+            cipher.take_encrypted(out);
+            return Ok(());
+        }
+
+        cipher.decryptor.feed(data);
+        if let Some(salt) = cipher.response_salt.take() {
+            if !try!(aead2022::read_response_header(&mut cipher.decryptor, salt.as_slice())) {
+                cipher.response_salt = Some(salt);
+                return Ok(());
+            }
+        }
+        cipher.decryptor.decrypt(out)
+    }
+
+    /// Handles EOF from one side, remaining data is written into `out`
+    pub fn finish(&mut self, from_client: bool, out: &mut Vec<u8>) -> IoResult<()> {
+        let cipher = match self.cipher {
+            Some(ref mut cipher) => cipher,
+            None => return Ok(()),
+        };
+
+        if from_client == cipher.encrypt_client {
+            try!(cipher.encryptor.finalize());
+            cipher.take_encrypted(out);
+            return Ok(());
+        }
+
+        if cipher.decryptor.has_incomplete() {
+            debug!("Stream closed in the middle of a chunk");
+        }
+        cipher.decryptor.finalize(out)
+    }
+}
+
+/// Keeps data from the client for retrying, gives up if there is too much
+pub fn keep_for_replay(replay: &mut Option<Vec<u8>>, data: &[u8]) {
+    let exceeded = match *replay {
+        Some(ref mut kept) => {
+            kept.push_all(data);
+            kept.len() > MAX_RETRY_BUFFER_SIZE
+        },
+        None => false,
+    };
+    if exceeded {
+        *replay = None;
+    }
+}
+
+/// Servers tried by a connection of sslocal, for retrying through another server
+pub struct Attempts {
+    pub tried: Vec<ServerConfig>,
+    pub started: u64,
+}
+
+impl Attempts {
+    pub fn new() -> Attempts {
+        Attempts {
+            tried: Vec::new(),
+            started: now_ms(),
+        }
+    }
+}
+
+/// A connection of sslocal which is relayed through servers picked by its worker, the worker
+/// retries it through other servers while connecting fails
+pub trait Routed {
+    /// Takes the client and the target of the request which is waiting for the worker to pick a server
+    fn take_pending(&mut self) -> Option<(IpAddr, Address)>;
+
+    /// Servers tried for the request
+    fn attempts(&mut self) -> &mut Attempts;
+
+    /// Takes the error of connecting through the last tried server
+    fn take_failed(&mut self) -> Option<IoError>;
+
+    /// Connects through `server` at `server_addr`, which is `Ok(None)` if the domain name of the
+    /// server should be resolved first. Failing to connect is left for `take_failed`.
+    fn connect_through(&mut self, id: usize, server: ServerConfig, server_addr: IoResult<Option<SocketAddr>>)
+                       -> IoResult<()>;
+
+    /// Replies the error of the last attempt to the client, after all attempts have failed
+    fn give_up(&mut self, err: &IoError) -> IoResult<()>;
+}
+
+/// Connections of a worker waiting for rate limits, which share the timer of `THROTTLE_TOKEN`
+pub struct Throttled {
+    ids: HashSet<usize>,
+    timer: bool,
+}
+
+impl Throttled {
+    pub fn new() -> Throttled {
+        Throttled {
+            ids: HashSet::new(),
+            timer: false,
+        }
+    }
+
+    /// Connection `id` should be made ready again after `delay`, or earlier if the timer is running
+    pub fn throttle<H: Handler>(&mut self, event_loop: &mut EventLoop<H>, id: usize, delay: u64) {
+        self.ids.insert(id);
+        if !self.timer {
+            event_loop.timeout_ms(THROTTLE_TOKEN, delay);
+            self.timer = true;
+        }
+    }
+
+    /// Connections to make ready again after the timer has expired, those still waiting should be
+    /// throttled again
+    pub fn resume(&mut self) -> Vec<usize> {
+        self.timer = false;
+        self.ids.drain().collect()
+    }
+}
+
+/// An established proxied connection
+pub struct Tunnel {
+    pub client: Endpoint,
+    pub remote: Endpoint,
+    codec: Codec,
+    /// Data sent by the client, while it could still be sent again through another server
+    replay: Option<Vec<u8>>,
+    /// The remote has sent anything
+    responded: bool,
+    // Bytes read from the client and from the remote, since `take_transferred`
+    client_read: usize,
+    remote_read: usize,
+    limiter: Option<ConnectionLimiter>,
+    // Reading from the side waits for tokens
+    client_throttled: bool,
+    remote_throttled: bool,
+    // Data has failed to be decrypted, which is the error of the tunnel
+    decryption_failed: bool,
+}
+
+impl Tunnel {
+    pub fn new(client: Endpoint, remote: Endpoint, codec: Codec) -> Tunnel {
+        Tunnel {
+            client: client,
+            remote: remote,
+            codec: codec,
+            replay: None,
+            responded: false,
+            client_read: 0,
+            remote_read: 0,
+            limiter: None,
+            client_throttled: false,
+            remote_throttled: false,
+            decryption_failed: false,
+        }
+    }
+
+    /// Limits rates of reading from both sides, reading from the client is uploading
+    pub fn set_limiter(&mut self, limiter: ConnectionLimiter) {
+        self.limiter = Some(limiter);
+    }
+
+    /// Whether the tunnel has failed since data from the other end of the cipher couldn't be decrypted
+    pub fn is_decryption_failed(&self) -> bool {
+        self.decryption_failed
+    }
+
+    /// Milliseconds until a side waiting for tokens could be read again, `None` if no side is waiting
+    pub fn throttle_delay(&self) -> Option<u64> {
+        let limiter = match self.limiter {
+            Some(ref limiter) => limiter,
+            None => return None,
+        };
+
+        let now = now_ms();
+        let client = if self.client_throttled { Some(limiter.delay(true, now)) } else { None };
+        let remote = if self.remote_throttled { Some(limiter.delay(false, now)) } else { None };
+        match (client, remote) {
+            (Some(a), Some(b)) => Some(cmp::min(a, b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Bytes read from the client and from the remote since the last call
+    pub fn take_transferred(&mut self) -> (usize, usize) {
+        let transferred = (self.client_read, self.remote_read);
+        self.client_read = 0;
+        self.remote_read = 0;
+        transferred
+    }
+
+    /// Keeps data from the client for `retry` until the remote responds. EOF from the remote before
+    /// responding is an error instead of being passed on to the client.
+    pub fn keep_for_retry(&mut self) {
+        self.replay = Some(Vec::new());
+    }
+
+    /// Whether the remote has failed before responding, and the request could be sent again
+    pub fn can_retry(&self) -> bool {
+        self.replay.is_some() && !self.responded && !self.client.write_closed
+    }
+
+    /// The remote has sent anything
+    pub fn has_response(&self) -> bool {
+        self.responded
+    }
+
+    /// Stops relaying after the remote has failed before responding, until it is replaced by `retry`.
+    /// The remote is deregistered, and the client is only watched for hangups.
+    pub fn pause<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) -> IoResult<()> {
+        self.remote.hang_up();
+        self.remote.read_closed = true;
+        try!(self.remote.update_interest(event_loop, false));
+        self.client.update_interest(event_loop, false)
+    }
+
+    /// Replaces the remote which has failed before responding, with a new remote connecting
+    /// through another server. Data from the client is sent again.
+    pub fn retry(&mut self, remote: Endpoint, codec: Codec) -> IoResult<()> {
+        self.remote = remote;
+        self.codec = codec;
+        self.remote_throttled = false;
+
+        let data = self.replay.take().unwrap_or(Vec::new());
+        try!(self.codec.transform(true, data.as_slice(), &mut self.remote.out));
+        self.replay = Some(data);
+        if self.client.read_closed {
+            try!(self.codec.finish(true, &mut self.remote.out));
+        }
+        self.flush()
+    }
+
+    /// Handles readiness of one side, `buf` is a buffer for reading.
+    ///
+    /// Sides waiting for tokens are read if there are tokens now, even without any readiness.
+    pub fn ready(&mut self, from_client: bool, ready: Ready, buf: &mut [u8]) -> IoResult<()> {
+        if self.client_throttled {
+            try!(self.pump(true, buf));
+        }
+        if self.remote_throttled {
+            try!(self.pump(false, buf));
+        }
+
+        if ready.error {
+            let ep = if from_client { &self.client } else { &self.remote };
+            try!(ep.stream.take_socket_error());
+        }
+
+        if !from_client && self.remote.connecting {
+            // Connected or failed, the remote has been replaced by `retry`
+            try!(self.remote.finish_connect());
+        }
+
+        if ready.readable || ready.hangup {
+            try!(self.pump(from_client, buf));
+        }
+
+        if ready.hangup {
+            if from_client { self.client.hang_up() } else { self.remote.hang_up() }
+        }
+
+        self.flush()
+    }
+
+    /// Feeds data which has been read from the client before the tunnel is established
+    pub fn feed_client(&mut self, data: &[u8]) -> IoResult<()> {
+        keep_for_replay(&mut self.replay, data);
+        try!(self.codec.transform(true, data, &mut self.remote.out));
+        self.flush()
+    }
+
+    // Reads from one side until it blocks, the other side has too much pending data, or the rate
+    // limits have no tokens
+    fn pump(&mut self, from_client: bool, buf: &mut [u8]) -> IoResult<()> {
+        let (src, dst, throttled) = if from_client {
+            (&mut self.client, &mut self.remote, &mut self.client_throttled)
+        } else {
+            (&mut self.remote, &mut self.client, &mut self.remote_throttled)
+        };
+        *throttled = false;
+
+        while !src.read_closed && dst.out.len() < MAX_PENDING_SIZE {
+            let now = now_ms();
+            let len = match self.limiter.as_ref().and_then(|l| l.available(from_client, now)) {
+                Some(0) => {
+                    *throttled = true;
+                    break;
+                },
+                Some(n) => cmp::min(n, buf.len()),
+                None => buf.len(),
+            };
+
+            match try!(src.read(&mut buf[..len])) {
+                None => break,
+                Some(0) if !from_client && self.replay.is_some() && !self.responded => {
+                    return Err(IoError {
+                        kind: EndOfFile,
+                        desc: "Server closed the connection before responding",
+                        detail: None,
+                    });
+                },
+                Some(0) => try!(self.codec.finish(from_client, &mut dst.out)),
+                Some(n) => {
+                    if let Some(ref limiter) = self.limiter {
+                        limiter.consume(from_client, n, now);
+                    }
+                    if from_client {
+                        self.client_read += n;
+                        keep_for_replay(&mut self.replay, &buf[..n]);
+                    } else {
+                        self.remote_read += n;
+                        if !self.responded {
+                            self.responded = true;
+                            self.replay = None;
+                        }
+                    }
+                    if let Err(err) = self.codec.transform(from_client, &buf[..n], &mut dst.out) {
+                        self.decryption_failed = self.codec.decrypts(from_client);
+                        return Err(err);
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes pending data of both sides
+    pub fn flush(&mut self) -> IoResult<()> {
+        try!(self.client.flush());
+        try!(self.remote.flush());
+
+        // Passes EOF on after all data before it has been written
+        if self.client.read_closed {
+            try!(self.remote.shutdown_if_flushed());
+        }
+        if self.remote.read_closed {
+            try!(self.client.shutdown_if_flushed());
+        }
+        Ok(())
+    }
+
+    /// Both directions are done, the tunnel could be dropped
+    pub fn is_finished(&self) -> bool {
+        (self.client.read_closed || self.remote.write_closed) && self.remote.out.is_empty()
+            && (self.remote.read_closed || self.client.write_closed) && self.client.out.is_empty()
+    }
+
+    pub fn update_interest<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) -> IoResult<()> {
+        let client_readable = self.remote.out.len() < MAX_PENDING_SIZE && !self.remote.write_closed
+            && !self.client_throttled;
+        let remote_readable = self.client.out.len() < MAX_PENDING_SIZE && !self.client.write_closed
+            && !self.remote_throttled;
+        try!(self.client.update_interest(event_loop, client_readable));
+        self.remote.update_interest(event_loop, remote_readable)
+    }
+}
@@ -0,0 +1,350 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! Traffic of servers
+//!
+//! Every port of `ssserver` counts bytes received from clients (upload) and bytes sent back to
+//! them (download), by both TCP and UDP relays, and so does every user of multi-user servers.
+//! Counters are kept until the port is removed.
+//!
+//! Ports and users could have quotas of bytes in both directions, connections of them are closed
+//! and new ones are refused once the quota is exceeded. Counters are saved to a state file in
+//! JSON, so that quotas survive restarts:
+//!
+//! ```ignore
+//! {
+//!     "ports": {"8388": {"upload": 1024, "download": 8192}},
+//!     "users": {"8388": {"alice": {"upload": 1024, "download": 8192}}}
+//! }
+//! ```
+
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::BTreeMap;
+use std::io::{File, IoResult, IoError, InvalidInput, fs};
+use std::io::net::ip::Port;
+use std::usize;
+
+use serialize::json::{self, Json};
+
+/// Bytes transferred by a server or a user
+pub struct Traffic {
+    upload: AtomicUsize,
+    download: AtomicUsize,
+    // `usize::MAX` if it is unlimited, which could never be reached
+    quota: AtomicUsize,
+}
+
+impl Traffic {
+    pub fn new() -> Traffic {
+        Traffic::with_counts(0, 0)
+    }
+
+    fn with_counts(upload: usize, download: usize) -> Traffic {
+        Traffic {
+            upload: AtomicUsize::new(upload),
+            download: AtomicUsize::new(download),
+            quota: AtomicUsize::new(usize::MAX),
+        }
+    }
+
+    /// Counts bytes received from a client
+    pub fn add_upload(&self, n: usize) {
+        if n > 0 {
+            self.upload.fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts bytes sent to a client
+    pub fn add_download(&self, n: usize) {
+        if n > 0 {
+            self.download.fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    pub fn upload(&self) -> usize {
+        self.upload.load(Ordering::Relaxed)
+    }
+
+    pub fn download(&self) -> usize {
+        self.download.load(Ordering::Relaxed)
+    }
+
+    /// Bytes transferred in both directions
+    pub fn total(&self) -> usize {
+        self.upload() + self.download()
+    }
+
+    /// Limits bytes transferred in both directions, or removes the limit with `None`. A quota of 0
+    /// refuses all traffic.
+    pub fn set_quota(&self, quota: Option<usize>) {
+        self.quota.store(quota.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+
+    pub fn quota(&self) -> Option<usize> {
+        match self.quota.load(Ordering::Relaxed) {
+            usize::MAX => None,
+            quota => Some(quota),
+        }
+    }
+
+    /// Whether the quota has been used up
+    pub fn is_exceeded(&self) -> bool {
+        match self.quota() {
+            Some(quota) => self.total() >= quota,
+            None => false,
+        }
+    }
+}
+
+fn get_or_insert<K: Ord + Clone>(map: &RwLock<BTreeMap<K, Arc<Traffic>>>, key: K) -> Arc<Traffic> {
+    if let Some(traffic) = map.read().unwrap().get(&key) {
+        return traffic.clone();
+    }
+
+    let mut map = map.write().unwrap();
+    if let Some(traffic) = map.get(&key) {
+        return traffic.clone();
+    }
+    let traffic = Arc::new(Traffic::new());
+    map.insert(key, traffic.clone());
+    traffic
+}
+
+fn traffic_to_json(traffic: &Traffic) -> Json {
+    let mut o = BTreeMap::new();
+    o.insert("upload".to_string(), Json::U64(traffic.upload() as u64));
+    o.insert("download".to_string(), Json::U64(traffic.download() as u64));
+    Json::Object(o)
+}
+
+fn traffic_from_json(o: &Json) -> Option<Arc<Traffic>> {
+    let upload = o.find("upload").and_then(|u| u.as_u64());
+    let download = o.find("download").and_then(|d| d.as_u64());
+    match (upload, download) {
+        (Some(upload), Some(download)) => Some(Arc::new(Traffic::with_counts(upload as usize, download as usize))),
+        _ => None,
+    }
+}
+
+/// Traffic of all ports and users, shared by the relays and the manager
+pub struct TrafficStats {
+    ports: RwLock<BTreeMap<Port, Arc<Traffic>>>,
+    // Users of multi-user servers, keyed by their ports and names
+    users: RwLock<BTreeMap<(Port, String), Arc<Traffic>>>,
+}
+
+impl TrafficStats {
+    pub fn new() -> TrafficStats {
+        TrafficStats {
+            ports: RwLock::new(BTreeMap::new()),
+            users: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Counters of `port`, which are created for a new port
+    pub fn port(&self, port: Port) -> Arc<Traffic> {
+        get_or_insert(&self.ports, port)
+    }
+
+    /// Counters of the user `name` of the multi-user server on `port`
+    pub fn user(&self, port: Port, name: &str) -> Arc<Traffic> {
+        get_or_insert(&self.users, (port, name.to_string()))
+    }
+
+    /// Forgets the counters of a removed port and its users
+    pub fn remove(&self, port: Port) {
+        self.ports.write().unwrap().remove(&port);
+
+        let mut users = self.users.write().unwrap();
+        let removed = users.keys().filter(|&&(p, _)| p == port).map(|k| k.clone()).collect::<Vec<(Port, String)>>();
+        for key in removed.iter() {
+            users.remove(key);
+        }
+    }
+
+    /// Total bytes transferred by every port, ordered by port
+    pub fn totals(&self) -> Vec<(Port, usize)> {
+        self.ports.read().unwrap().iter().map(|(port, traffic)| (*port, traffic.total())).collect()
+    }
+
+    /// Bytes uploaded and downloaded by every port, ordered by port
+    pub fn ports(&self) -> Vec<(Port, usize, usize)> {
+        self.ports.read().unwrap().iter().map(|(port, traffic)| (*port, traffic.upload(), traffic.download())).collect()
+    }
+
+    /// Counters of all ports and users in the format of the state file
+    pub fn to_json(&self) -> Json {
+        let mut ports = BTreeMap::new();
+        for (port, traffic) in self.ports.read().unwrap().iter() {
+            ports.insert(port.to_string(), traffic_to_json(&**traffic));
+        }
+
+        let mut users = BTreeMap::new();
+        for (&(port, ref name), traffic) in self.users.read().unwrap().iter() {
+            if !users.contains_key(&port) {
+                users.insert(port, BTreeMap::new());
+            }
+            users.get_mut(&port).unwrap().insert(name.clone(), traffic_to_json(&**traffic));
+        }
+        let users = users.into_iter().map(|(port, users)| (port.to_string(), Json::Object(users))).collect();
+
+        let mut o = BTreeMap::new();
+        o.insert("ports".to_string(), Json::Object(ports));
+        o.insert("users".to_string(), Json::Object(users));
+        Json::Object(o)
+    }
+
+    /// Restores counters from the format of the state file, returns `None` if it is malformed
+    pub fn from_json(o: &Json) -> Option<TrafficStats> {
+        let stats = TrafficStats::new();
+
+        if let Some(ports) = o.find("ports").and_then(|p| p.as_object()) {
+            let mut map = stats.ports.write().unwrap();
+            for (port, traffic) in ports.iter() {
+                match (port.parse::<Port>(), traffic_from_json(traffic)) {
+                    (Some(port), Some(traffic)) => { map.insert(port, traffic); },
+                    _ => return None,
+                }
+            }
+        }
+
+        if let Some(ports) = o.find("users").and_then(|u| u.as_object()) {
+            let mut map = stats.users.write().unwrap();
+            for (port, users) in ports.iter() {
+                let (port, users) = match (port.parse::<Port>(), users.as_object()) {
+                    (Some(port), Some(users)) => (port, users),
+                    _ => return None,
+                };
+                for (name, traffic) in users.iter() {
+                    match traffic_from_json(traffic) {
+                        Some(traffic) => { map.insert((port, name.clone()), traffic); },
+                        None => return None,
+                    }
+                }
+            }
+        }
+
+        Some(stats)
+    }
+
+    /// Writes counters to the state file, which is replaced at once so that a crash doesn't break it
+    pub fn save(&self, path: &Path) -> IoResult<()> {
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = try!(File::create(&tmp));
+            try!(file.write_str(self.to_json().to_string().as_slice()));
+            try!(file.fsync());
+        }
+        fs::rename(&tmp, path)
+    }
+
+    /// Reads counters from the state file
+    pub fn load(path: &Path) -> IoResult<TrafficStats> {
+        let content = try!(File::open(path).and_then(|mut f| f.read_to_string()));
+        let stats = json::Json::from_str(content.as_slice()).ok().and_then(|o| TrafficStats::from_json(&o));
+        match stats {
+            Some(stats) => Ok(stats),
+            None => Err(IoError {
+                kind: InvalidInput,
+                desc: "Malformed traffic state file",
+                detail: Some(path.display().to_string()),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_traffic {
+    use serialize::json::Json;
+
+    use relay::traffic::TrafficStats;
+
+    #[test]
+    fn test_traffic_stats() {
+        let stats = TrafficStats::new();
+        stats.port(8389).add_upload(100);
+        stats.port(8388).add_download(20);
+
+        // Connections of the same port share the counters
+        let traffic = stats.port(8389);
+        traffic.add_download(50);
+        traffic.add_upload(0);
+        assert_eq!((traffic.upload(), traffic.download()), (100, 50));
+        assert_eq!(stats.totals(), vec![(8388, 20), (8389, 150)]);
+
+        stats.user(8389, "alice").add_upload(10);
+        stats.remove(8389);
+        assert_eq!(stats.totals(), vec![(8388, 20)]);
+        assert_eq!(stats.port(8389).total(), 0);
+        assert_eq!(stats.user(8389, "alice").total(), 0);
+    }
+
+    #[test]
+    fn test_quota() {
+        let stats = TrafficStats::new();
+        let traffic = stats.port(8388);
+        traffic.add_upload(60);
+        assert!(!traffic.is_exceeded());
+
+        traffic.set_quota(Some(100));
+        assert!(!traffic.is_exceeded());
+        traffic.add_download(40);
+        assert!(traffic.is_exceeded());
+
+        traffic.set_quota(None);
+        assert!(!traffic.is_exceeded());
+    }
+
+    #[test]
+    fn test_zero_quota() {
+        let stats = TrafficStats::new();
+        let traffic = stats.port(8388);
+        assert_eq!(traffic.quota(), None);
+
+        // Nothing could be transferred, and the quota is not taken as unlimited
+        traffic.set_quota(Some(0));
+        assert_eq!(traffic.quota(), Some(0));
+        assert!(traffic.is_exceeded());
+
+        traffic.set_quota(None);
+        assert_eq!(traffic.quota(), None);
+        assert!(!traffic.is_exceeded());
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let stats = TrafficStats::new();
+        stats.port(8388).add_upload(1024);
+        stats.port(8388).add_download(8192);
+        stats.user(8388, "alice").add_upload(24);
+        stats.user(8388, "bob").add_download(192);
+
+        let restored = TrafficStats::from_json(&stats.to_json()).unwrap();
+        assert_eq!(restored.totals(), vec![(8388, 9216)]);
+        assert_eq!(restored.user(8388, "alice").upload(), 24);
+        assert_eq!(restored.user(8388, "bob").download(), 192);
+
+        let malformed = TrafficStats::from_json(&Json::from_str("{\"ports\": {\"http\": {}}}").unwrap());
+        assert!(malformed.is_none());
+    }
+}
@@ -0,0 +1,327 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! UDP sessions of the Shadowsocks 2022 edition (SIP022)
+//!
+//! ```plain
+//! Client body: type 0 | timestamp | padding length | padding | address | payload
+//! Server body: type 1 | timestamp | client session ID | padding length | padding | address | payload
+//! ```
+//!
+//! Every client has its own session ID, and packet IDs in each session are checked with a
+//! sliding window to reject replayed packets.
+
+use std::io::net::ip::SocketAddr;
+use std::io::{BufReader, IoResult};
+use std::rand;
+
+use collect::LruCache;
+use time;
+
+use crypto::aead2022::{self, is_timestamp_valid};
+use crypto::cipher::CipherType;
+
+pub const HEADER_TYPE_CLIENT_PACKET: u8 = 0;
+pub const HEADER_TYPE_SERVER_PACKET: u8 = 1;
+
+const PACKET_ID_WINDOW_SIZE: u64 = 64;
+
+/// Sliding window for detecting replayed packet IDs
+#[derive(Clone, Copy)]
+pub struct PacketIdWindow {
+    max: u64,
+    bitmap: u64,
+    initialized: bool,
+}
+
+impl PacketIdWindow {
+    pub fn new() -> PacketIdWindow {
+        PacketIdWindow {
+            max: 0,
+            bitmap: 0,
+            initialized: false,
+        }
+    }
+
+    /// Returns `false` if the packet ID has been seen or is too old
+    pub fn check_and_update(&mut self, packet_id: u64) -> bool {
+        if !self.initialized {
+            self.initialized = true;
+            self.max = packet_id;
+            self.bitmap = 1;
+            return true;
+        }
+
+        if packet_id > self.max {
+            let shift = packet_id - self.max;
+            self.bitmap = if shift >= PACKET_ID_WINDOW_SIZE { 0 } else { self.bitmap << shift as usize };
+            self.bitmap |= 1;
+            self.max = packet_id;
+            return true;
+        }
+
+        let offset = self.max - packet_id;
+        if offset >= PACKET_ID_WINDOW_SIZE {
+            return false;
+        }
+
+        let mask = 1u64 << offset as usize;
+        if self.bitmap & mask != 0 {
+            return false;
+        }
+        self.bitmap |= mask;
+        true
+    }
+}
+
+fn read_header(reader: &mut BufReader, expected_type: u8) -> IoResult<bool> {
+    let header_type = try!(reader.read_u8());
+    let timestamp = try!(reader.read_be_u64());
+    Ok(header_type == expected_type && is_timestamp_valid(timestamp))
+}
+
+fn skip_padding(reader: &mut BufReader) -> IoResult<()> {
+    let padding_len = try!(reader.read_be_u16()) as usize;
+    try!(reader.read_exact(padding_len));
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+struct ClientSession {
+    session_id: u64,
+    packet_id: u64,
+}
+
+/// Sessions on the local side, one for every SOCKS5 client
+pub struct UdpClientSessions {
+    sessions: LruCache<SocketAddr, ClientSession>,
+    clients: LruCache<u64, SocketAddr>,
+    server_windows: LruCache<u64, PacketIdWindow>,
+}
+
+impl UdpClientSessions {
+    pub fn new(capacity: usize) -> UdpClientSessions {
+        UdpClientSessions {
+            sessions: LruCache::new(capacity),
+            clients: LruCache::new(capacity),
+            server_windows: LruCache::new(capacity),
+        }
+    }
+
+    /// Encrypts `data` (address with payload) from `client_addr`, `identity_key` is the iPSK of
+    /// a multi-user server, and `key` is the uPSK then
+    pub fn encrypt_request(&mut self, t: CipherType, key: &[u8], identity_key: Option<&[u8]>, client_addr: SocketAddr,
+                           data: &[u8]) -> Option<Vec<u8>> {
+        let mut session = match self.sessions.get(&client_addr) {
+            Some(s) => *s,
+            None => ClientSession {
+                session_id: rand::random::<u64>(),
+                packet_id: 0,
+            },
+        };
+        session.packet_id += 1;
+        self.sessions.insert(client_addr, session);
+        self.clients.insert(session.session_id, client_addr);
+
+        let mut body = Vec::with_capacity(1 + 8 + 2 + data.len());
+        body.write_u8(HEADER_TYPE_CLIENT_PACKET).unwrap();
+        body.write_be_u64(time::get_time().sec as u64).unwrap();
+        body.write_be_u16(0).unwrap();
+        body.push_all(data);
+
+        let result = match identity_key {
+            Some(identity_key) => aead2022::encrypt_udp_packet_with_identity(t, identity_key, key, session.session_id,
+                                                                             session.packet_id, body.as_slice()),
+            None => aead2022::encrypt_udp_packet(t, key, session.session_id, session.packet_id, body.as_slice()),
+        };
+        match result {
+            Ok(packet) => Some(packet),
+            Err(err) => {
+                error!("Failed to encrypt UDP packet: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Decrypts a packet from server, returns the client address and address with payload
+    pub fn decrypt_response(&mut self, t: CipherType, key: &[u8], packet: &[u8])
+            -> Option<(SocketAddr, Vec<u8>)> {
+        let (session_id, packet_id, body) = match aead2022::decrypt_udp_packet(t, key, packet) {
+            Ok(r) => r,
+            Err(err) => {
+                error!("Failed to decrypt UDP packet: {}", err);
+                return None;
+            }
+        };
+
+        let mut reader = BufReader::new(body.as_slice());
+        match read_header(&mut reader, HEADER_TYPE_SERVER_PACKET) {
+            Ok(true) => {},
+            _ => {
+                error!("Invalid header in UDP response");
+                return None;
+            }
+        }
+
+        let client_addr = match reader.read_be_u64().ok().and_then(|id| self.clients.get(&id).map(|a| *a)) {
+            Some(addr) => addr,
+            None => {
+                error!("UDP response of an unknown session");
+                return None;
+            }
+        };
+
+        let mut window = self.server_windows.get(&session_id).map(|w| *w).unwrap_or(PacketIdWindow::new());
+        if !window.check_and_update(packet_id) {
+            error!("Replayed UDP response, session {}, packet {}", session_id, packet_id);
+            return None;
+        }
+        self.server_windows.insert(session_id, window);
+
+        if skip_padding(&mut reader).is_err() {
+            error!("Invalid padding in UDP response");
+            return None;
+        }
+
+        reader.read_to_end().ok().map(|data| (client_addr, data))
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ServerSession {
+    session_id: u64,
+    packet_id: u64,
+    client_addr: SocketAddr,
+    window: PacketIdWindow,
+}
+
+/// Sessions on the server side, keyed by client session ID
+pub struct UdpServerSessions {
+    sessions: LruCache<u64, ServerSession>,
+}
+
+impl UdpServerSessions {
+    pub fn new(capacity: usize) -> UdpServerSessions {
+        UdpServerSessions {
+            sessions: LruCache::new(capacity),
+        }
+    }
+
+    /// Decrypts a packet from `src`, returns the client session ID and address with payload.
+    ///
+    /// Packets to a multi-user server are decrypted with the iPSK `key` and the uPSK `user_key`.
+    pub fn decrypt_request(&mut self, t: CipherType, key: &[u8], user_key: Option<&[u8]>, src: SocketAddr,
+                           packet: &[u8]) -> Option<(u64, Vec<u8>)> {
+        let result = match user_key {
+            Some(user_key) => aead2022::decrypt_udp_packet_with_identity(t, key, user_key, packet),
+            None => aead2022::decrypt_udp_packet(t, key, packet),
+        };
+        let (session_id, packet_id, body) = match result {
+            Ok(r) => r,
+            Err(err) => {
+                error!("Failed to decrypt UDP packet: {}", err);
+                return None;
+            }
+        };
+
+        let mut reader = BufReader::new(body.as_slice());
+        match read_header(&mut reader, HEADER_TYPE_CLIENT_PACKET) {
+            Ok(true) => {},
+            _ => {
+                error!("Invalid header in UDP request from {}", src);
+                return None;
+            }
+        }
+
+        let mut session = match self.sessions.get(&session_id) {
+            Some(s) => *s,
+            None => ServerSession {
+                session_id: rand::random::<u64>(),
+                packet_id: 0,
+                client_addr: src,
+                window: PacketIdWindow::new(),
+            },
+        };
+        if !session.window.check_and_update(packet_id) {
+            error!("Replayed UDP request from {}, session {}, packet {}", src, session_id, packet_id);
+            return None;
+        }
+
+        // Clients may change their addresses, always reply to the latest one
+        session.client_addr = src;
+        self.sessions.insert(session_id, session);
+
+        if skip_padding(&mut reader).is_err() {
+            error!("Invalid padding in UDP request from {}", src);
+            return None;
+        }
+
+        reader.read_to_end().ok().map(|data| (session_id, data))
+    }
+
+    /// Encrypts `data` (address with payload) for the client session,
+    /// returns the client address and the packet
+    pub fn encrypt_response(&mut self, t: CipherType, key: &[u8], client_session_id: u64, data: &[u8])
+            -> Option<(SocketAddr, Vec<u8>)> {
+        let mut session = match self.sessions.get(&client_session_id) {
+            Some(s) => *s,
+            None => return None,
+        };
+        session.packet_id += 1;
+        self.sessions.insert(client_session_id, session);
+
+        let mut body = Vec::with_capacity(1 + 8 + 8 + 2 + data.len());
+        body.write_u8(HEADER_TYPE_SERVER_PACKET).unwrap();
+        body.write_be_u64(time::get_time().sec as u64).unwrap();
+        body.write_be_u64(client_session_id).unwrap();
+        body.write_be_u16(0).unwrap();
+        body.push_all(data);
+
+        match aead2022::encrypt_udp_packet(t, key, session.session_id, session.packet_id, body.as_slice()) {
+            Ok(packet) => Some((session.client_addr, packet)),
+            Err(err) => {
+                error!("Failed to encrypt UDP packet: {}", err);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_aead2022 {
+    use relay::udprelay::aead2022::PacketIdWindow;
+
+    #[test]
+    fn test_packet_id_window() {
+        let mut window = PacketIdWindow::new();
+        assert!(window.check_and_update(1));
+        assert!(!window.check_and_update(1));
+        assert!(window.check_and_update(3));
+        assert!(window.check_and_update(2));
+        assert!(!window.check_and_update(2));
+
+        assert!(window.check_and_update(100));
+        // Too old
+        assert!(!window.check_and_update(30));
+        assert!(window.check_and_update(40));
+        assert!(!window.check_and_update(40));
+    }
+}
@@ -54,200 +54,635 @@
 // | Fixed |   Variable   |
 // +-------+--------------+
 
-use std::sync::{Arc, Mutex};
-use std::io::net::udp::UdpSocket;
-use std::io::net::ip::SocketAddr;
-use std::io::net::addrinfo::get_host_addresses;
+use std::io::net::ip::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
 use std::collections::HashMap;
 use std::io::{BufReader, MemWriter, self};
-use std::thread::Thread;
+use std::sync::{Arc, Mutex};
+use std::iter::repeat;
+use std::usize;
 
 use collect::LruCache;
 
-use crypto::{cipher, CryptoMode};
-use crypto::cipher::Cipher;
-use config::{Config, ServerConfig};
+use config::{Config, ServerConfig, LocalMode, LoadBalancing, DEFAULT_DNS_CACHE_CAPACITY};
+use crypto::cipher::CipherCategory;
 use relay::Relay;
 use relay::socks5;
-use relay::loadbalancing::server::{LoadBalancer, RoundRobin};
+use relay::association::Associations;
+use relay::acl::AccessControl;
+use relay::cached_dns::CachedDns;
+use relay::loadbalancing::server::{LoadBalancer, HealthChecker, Failures, new_load_balancer, resolve_servers};
+use relay::eventloop::{EventLoop, Handler, Token, Interest, Ready, UdpSocket, Notifier};
+use relay::ratelimit::RateLimiter;
+use relay::metrics::{Metrics, Cache};
 use relay::udprelay::UDP_RELAY_LOCAL_LRU_CACHE_CAPACITY;
+use relay::udprelay::{encrypt_payload, decrypt_payload, unspecified_addr};
+use relay::udprelay::aead2022::UdpClientSessions;
+use relay::tcprelay::tunnel::resolved_target;
+
+const SOCKET_TOKEN: Token = Token(0);
+// Socket of the i-th tunnel has `Token(TUNNEL_SOCKET_TOKEN + i)`
+const TUNNEL_SOCKET_TOKEN: usize = 1;
+// Sockets sending datagrams directly to targets bypassed by the ACL, for each address family
+const DIRECT_V4_SOCKET_TOKEN: Token = Token(usize::MAX);
+const DIRECT_V6_SOCKET_TOKEN: Token = Token(usize::MAX - 1);
+
+// Number of sockets kept for sending responses to clients of the transparent proxy
+const REDIR_REPLY_SOCKETS_CAPACITY: usize = 256;
+
+/// Servers and the event loop using them, shared by clones of a `UdpRelayLocal`
+struct Servers {
+    list: Vec<ServerConfig>,
+    notifier: Option<Notifier<Message>>,
+    shut_down: bool,
+}
 
 #[derive(Clone)]
 pub struct UdpRelayLocal {
     config: Config,
+    associations: Associations,
+    health_checker: Option<Arc<HealthChecker>>,
+    rate_limiter: Arc<RateLimiter>,
+    metrics: Arc<Metrics>,
+    servers: Arc<Mutex<Servers>>,
 }
 
 impl UdpRelayLocal {
-    pub fn new(config: Config) -> UdpRelayLocal {
+    pub fn new(config: Config, associations: Associations, health_checker: Option<Arc<HealthChecker>>,
+               rate_limiter: Arc<RateLimiter>, metrics: Arc<Metrics>) -> UdpRelayLocal {
+        let servers = Servers {
+            list: config.server.clone(),
+            notifier: None,
+            shut_down: false,
+        };
         UdpRelayLocal {
             config: config,
+            associations: associations,
+            health_checker: health_checker,
+            rate_limiter: rate_limiter,
+            metrics: metrics,
+            servers: Arc::new(Mutex::new(servers)),
         }
     }
+
+    /// Replaces the servers which new requests are relayed through, they are resolved in the calling
+    /// thread.
+    ///
+    /// It could be called before or after `run`.
+    pub fn set_servers(&self, servers: Vec<ServerConfig>) {
+        let (server_set, server_addr) = resolve_servers(servers.as_slice());
+        let mut guard = self.servers.lock().unwrap();
+        if let Some(ref notifier) = guard.notifier {
+            // The event loop has exited if it fails
+            let _ = notifier.notify(Message::Servers(servers.clone(), server_set, server_addr));
+        }
+        guard.list = servers;
+    }
 }
 
-impl Relay for UdpRelayLocal {
-    fn run(&self) {
-        let addr = self.config.local.expect("Local configuration should not be None");
-
-        let mut server_load_balancer = RoundRobin::new(self.config.server.clone());
-
-        let (server_set, server_addr) = {
-            let mut server_set = HashMap::new();
-            let mut server_addr = HashMap::new();
-            for s in self.config.server.iter() {
-                let addrs = match get_host_addresses(s.addr.as_slice()) {
-                    Ok(addr) => addr,
-                    Err(..) => continue,
-                };
-
-                if !addrs.is_empty() {
-                    let addr = SocketAddr {
-                        ip: addrs.first().unwrap().clone(),
-                        port: s.port,
-                    };
+/// Messages to the event loop
+enum Message {
+    /// Replaces the servers of the load balancer, with their resolved addresses
+    Servers(Vec<ServerConfig>, HashMap<SocketAddr, ServerConfig>, HashMap<String, SocketAddr>),
+    /// A target bypassed by the ACL has been resolved, the datagram from the client could be sent to it
+    Resolved(SocketAddr, socks5::Address, Vec<u8>, Option<Vec<IpAddr>>),
+    /// Flushes associations and exits
+    Shutdown,
+}
+
+/// Relays all datagrams in one event loop
+struct UdpLocalHandler {
+    // Socket of the local address, serving SOCKS5 or the transparent proxy
+    socket: Option<UdpSocket>,
+    tunnels: Vec<(UdpSocket, socks5::Address)>,
+    // Responses to clients of tunnels are sent back by the tunnels' sockets without any header
+    tunnel_clients: LruCache<(SocketAddr, socks5::Address), usize>,
+    server_load_balancer: Box<LoadBalancer + Send>,
+    load_balancing: LoadBalancing,
+    health_checker: Option<Arc<HealthChecker>>,
+    failures: Arc<Failures>,
+    server_set: HashMap<SocketAddr, ServerConfig>,
+    server_addr: HashMap<String, SocketAddr>,
+    client_map: LruCache<socks5::Address, SocketAddr>,
+    sessions: UdpClientSessions,
+    // Only clients holding UDP associations are served if authentication is required
+    associations: Option<Associations>,
+    acl: Option<Arc<AccessControl>>,
+    dns: CachedDns,
+    // Sockets for targets bypassed by the ACL by their tokens, and the clients of the targets
+    direct_sockets: HashMap<Token, UdpSocket>,
+    direct_map: LruCache<SocketAddr, (SocketAddr, socks5::Address)>,
+    redir: bool,
+    // Responses are sent to clients of the transparent proxy from their original destinations
+    reply_sockets: LruCache<SocketAddr, UdpSocket>,
+    // Datagrams exceeding the rate limits of the process are dropped
+    rate_limiter: Arc<RateLimiter>,
+    metrics: Arc<Metrics>,
+    buf: Vec<u8>,
+}
 
-                    server_set.insert(addr, s.clone());
-                    server_addr.insert(s.addr.clone(), addr);
+impl UdpLocalHandler {
+    // Receives a datagram with its source, and its original destination for the transparent proxy
+    fn recv(&mut self, tunnel: Option<usize>) -> io::IoResult<Option<(usize, SocketAddr, Option<SocketAddr>)>> {
+        let socket = match tunnel {
+            Some(i) => &self.tunnels[i].0,
+            None if self.redir => return self.socket.as_ref().unwrap().recv_from_to(self.buf.as_mut_slice()),
+            None => self.socket.as_ref().unwrap(),
+        };
+        socket.recv_from(self.buf.as_mut_slice()).map(|r| r.map(|(len, src)| (len, src, None)))
+    }
+
+    // Finds the target address and the payload of a datagram from a client
+    fn parse_request<'a>(&self, data: &'a [u8], src: SocketAddr, dst: Option<SocketAddr>, tunnel: Option<usize>)
+            -> Option<(socks5::Address, &'a [u8])> {
+        if let Some(i) = tunnel {
+            return Some((self.tunnels[i].1.clone(), data));
+        }
+
+        if self.redir {
+            return match dst {
+                Some(dst) => Some((socks5::Address::SocketAddress(dst.ip, dst.port), data)),
+                None => {
+                    error!("Unknown original destination of UDP packet from {}", src);
+                    None
                 }
+            };
+        }
+
+        if data.len() < 4 {
+            error!("UDP request is too short");
+            return None;
+        }
+
+        // According to RFC 1928
+        //
+        // Implementation of fragmentation is optional; an implementation that
+        // does not support fragmentation MUST drop any datagram whose FRAG
+        // field is other than X'00'.
+        if data[2] != 0x00u8 {
+            // Drop it
+            warn!("Does not support fragmentation");
+            return None;
+        }
+
+        let mut bufr = BufReader::new(data);
+        match socks5::UdpAssociateHeader::read_from(&mut bufr) {
+            Ok(header) => {
+                let len = header.len();
+                Some((header.address, &data[len..]))
+            },
+            Err(err) => {
+                error!("Invalid UDP request from {}: {}", src, err);
+                None
+            }
+        }
+    }
+
+    // Sends a response of `addr` back to the client
+    fn reply(&mut self, client_addr: SocketAddr, addr: socks5::Address, payload: &[u8]) -> io::IoResult<()> {
+        if let Some(i) = self.tunnel_clients.get(&(client_addr, addr.clone())).map(|i| *i) {
+            return send_to(&self.tunnels[i].0, payload, client_addr);
+        }
+
+        if !self.redir {
+            let socket = match self.socket {
+                Some(ref socket) => socket,
+                None => return Ok(()),
+            };
+            let mut bufw = MemWriter::new();
+            try!(socks5::UdpAssociateHeader::new(0, addr).write_to(&mut bufw));
+            try!(bufw.write(payload));
+            return send_to(socket, bufw.into_inner().as_slice(), client_addr);
+        }
+
+        let from = match addr {
+            socks5::Address::SocketAddress(ip, port) => SocketAddr { ip: ip, port: port },
+            socks5::Address::DomainNameAddress(..) => {
+                error!("UDP response from a domain name {} could not be redirected", addr);
+                return Ok(());
             }
-            (server_set, server_addr)
         };
 
-        let client_map_arc = Arc::new(Mutex::new(
-                    LruCache::<socks5::Address, SocketAddr>::new(UDP_RELAY_LOCAL_LRU_CACHE_CAPACITY)));
+        if self.reply_sockets.get(&from).is_none() {
+            let socket = try!(UdpSocket::bind_transparent(&from));
+            self.reply_sockets.insert(from, socket);
+        }
+        send_to(self.reply_sockets.get(&from).unwrap(), payload, client_addr)
+    }
+
+    // Sends a datagram to a target bypassed by the ACL, a domain name is resolved in the task pool of
+    // `dns` first
+    fn send_direct(&mut self, event_loop: &mut EventLoop<UdpLocalHandler>, client_addr: SocketAddr,
+                   addr: socks5::Address, payload: &[u8]) -> io::IoResult<()> {
+        let name = match addr {
+            socks5::Address::SocketAddress(ip, port) => {
+                return self.send_direct_to(event_loop, client_addr, addr.clone(), SocketAddr { ip: ip, port: port },
+                                           payload);
+            },
+            socks5::Address::DomainNameAddress(ref name, _) => name.clone(),
+        };
+
+        let notifier = event_loop.notifier();
+        let payload = payload.to_vec();
+        self.dns.resolve_async(name.as_slice(), move |addrs| {
+            // The event loop has exited if it fails
+            let _ = notifier.notify(Message::Resolved(client_addr, addr, payload, addrs));
+        });
+        Ok(())
+    }
+
+    fn send_direct_to(&mut self, event_loop: &mut EventLoop<UdpLocalHandler>, client_addr: SocketAddr,
+                      addr: socks5::Address, target: SocketAddr, payload: &[u8]) -> io::IoResult<()> {
+        let token = match target.ip {
+            Ipv4Addr(..) => DIRECT_V4_SOCKET_TOKEN,
+            Ipv6Addr(..) => DIRECT_V6_SOCKET_TOKEN,
+        };
+        if !self.direct_sockets.contains_key(&token) {
+            let socket = try!(UdpSocket::bind(&unspecified_addr(&target.ip)));
+            try!(event_loop.register(&socket, token, Interest::readable()));
+            self.direct_sockets.insert(token, socket);
+        }
+
+        info!("UDP ASSOCIATE {} directly", addr);
+        debug!("UDP associate {} <-> {}", target, client_addr);
+        if self.metrics.insert_lru(Cache::Udp, &mut self.direct_map, target, (client_addr, addr)) {
+            self.metrics.add_udp_association();
+        }
+        send_to(self.direct_sockets.get(&token).unwrap(), payload, target)
+    }
+
+    // Sends responses of targets bypassed by the ACL back to their clients
+    fn direct_ready(&mut self, token: Token) {
+        loop {
+            let result = match self.direct_sockets.get(&token) {
+                Some(socket) => socket.recv_from(self.buf.as_mut_slice()),
+                None => return,
+            };
+            let (len, source_addr) = match result {
+                Ok(Some(r)) => r,
+                Ok(None) => break,
+                Err(err) => {
+                    error!("Failed in UDP recv_from: {}", err);
+                    break;
+                }
+            };
 
-        let mut socket = UdpSocket::bind(addr).ok().expect("Failed to bind udp socket");
+            let (client_addr, addr) = match self.direct_map.get(&source_addr) {
+                Some(client) => client.clone(),
+                None => {
+                    debug!("Dropped UDP packet from {}, which is not a target", source_addr);
+                    continue;
+                }
+            };
+            if !self.rate_limiter.take_packet(None, false, len) {
+                continue;
+            }
+
+            let payload = self.buf[..len].to_vec();
+            if let Err(err) = self.reply(client_addr, addr, payload.as_slice()) {
+                error!("Failed to relay UDP packet from {}: {}", source_addr, err);
+            }
+        }
+    }
+}
+
+impl Handler for UdpLocalHandler {
+    type Message = Message;
+
+    // Replaced servers are still known, so that responses to earlier requests are received
+    fn notify(&mut self, event_loop: &mut EventLoop<UdpLocalHandler>, msg: Message) {
+        match msg {
+            Message::Servers(servers, server_set, server_addr) => {
+                self.server_load_balancer = new_load_balancer(servers, self.load_balancing,
+                                                              self.health_checker.clone(), self.failures.clone());
+                self.server_set.extend(server_set.into_iter());
+                self.server_addr.extend(server_addr.into_iter());
+            },
+            Message::Resolved(client_addr, addr, payload, addrs) => {
+                let result = resolved_target(&addr, addrs).and_then(|target| {
+                    self.send_direct_to(event_loop, client_addr, addr.clone(), target, payload.as_slice())
+                });
+                if let Err(err) = result {
+                    error!("Failed to relay UDP packet from {} directly: {}", client_addr, err);
+                }
+            },
+            Message::Shutdown => {
+                debug!("Flushed {} UDP associations", self.client_map.len() + self.direct_map.len());
+                event_loop.shutdown();
+            },
+        }
+    }
+
+    fn ready(&mut self, event_loop: &mut EventLoop<UdpLocalHandler>, token: Token, _: Ready) {
+        if self.direct_sockets.contains_key(&token) {
+            self.direct_ready(token);
+            return;
+        }
+
+        let tunnel = if token == SOCKET_TOKEN { None } else { Some(token.0 - TUNNEL_SOCKET_TOKEN) };
 
-        let mut buf = [0u8; 0xffff];
         loop {
-            match socket.recv_from(&mut buf) {
-                Ok((len, source_addr)) => {
-                    if len < 4 {
-                        error!("UDP request is too short");
+            let (len, source_addr, dst_addr) = match self.recv(tunnel) {
+                Ok(Some(r)) => r,
+                Ok(None) => break,
+                Err(err) => {
+                    error!("Failed in UDP recv_from: {}", err);
+                    break;
+                }
+            };
+
+            let message = self.buf[..len].to_vec();
+
+            let result = match self.server_set.get(&source_addr).map(|s| s.clone()) {
+                Some(..) if !self.rate_limiter.take_packet(None, false, len) => continue,
+                Some(s) => {
+                    match handle_response(message.as_slice(), source_addr, &s,
+                                          &mut self.client_map, &mut self.sessions, Some(&*self.metrics)) {
+                        Ok(Some((client_addr, addr, payload))) => self.reply(client_addr, addr, payload.as_slice()),
+                        Ok(None) => Ok(()),
+                        Err(err) => Err(err),
+                    }
+                },
+                None => {
+                    if let (None, Some(associations)) = (tunnel, self.associations.as_ref()) {
+                        if !associations.contains(&source_addr.ip) {
+                            debug!("Dropped UDP packet from {} without UDP ASSOCIATE", source_addr);
+                            continue;
+                        }
+                    }
+                    if !self.rate_limiter.take_packet(None, true, len) {
+                        debug!("Dropped UDP packet from {}, the rate limit has been exceeded", source_addr);
                         continue;
                     }
 
-                    let request_message = buf[..len].to_vec();
-                    let move_socket = socket.clone();
-                    let client_map = client_map_arc.clone();
-
-                    match server_set.get(&source_addr) {
-                        Some(sref) => {
-                            let s = sref.clone();
-                            Thread::spawn(move ||
-                                handle_response(move_socket,
-                                               request_message.as_slice(),
-                                               source_addr,
-                                               &s,
-                                               client_map));
-                        }
-                        None => {
-                            let s = server_load_balancer.pick_server().clone();
-
-                            match server_addr.get(&s.addr) {
-                                Some(saddr) => {
-                                    let saddr = saddr.clone();
-                                    Thread::spawn(move ||
-                                        handle_request(move_socket,
-                                                      request_message.as_slice(),
-                                                      source_addr,
-                                                      saddr,
-                                                      &s,
-                                                      client_map));
-                                },
-                                None => {}
-                            }
+                    let (addr, payload) = match self.parse_request(message.as_slice(), source_addr, dst_addr, tunnel) {
+                        Some(r) => r,
+                        None => continue,
+                    };
+
+                    if let Some(i) = tunnel {
+                        self.tunnel_clients.insert((source_addr, addr.clone()), i);
+                    }
+
+                    if self.acl.as_ref().map_or(false, |acl| acl.check_bypass(&addr)) {
+                        if let Err(err) = self.send_direct(event_loop, source_addr, addr, payload) {
+                            error!("Failed to relay UDP packet from {} directly: {}", source_addr, err);
                         }
+                        continue;
+                    }
+
+                    let s = self.server_load_balancer.pick_server_for(&source_addr.ip, Some(&addr)).clone();
+
+                    match self.server_addr.get(&s.addr).map(|a| *a) {
+                        Some(saddr) => {
+                            // Responses from the server will be received by the same socket
+                            let socket = match tunnel {
+                                Some(i) => &self.tunnels[i].0,
+                                None => self.socket.as_ref().unwrap(),
+                            };
+                            handle_request(socket,
+                                           addr,
+                                           payload,
+                                           source_addr,
+                                           saddr,
+                                           &s,
+                                           &mut self.client_map,
+                                           &mut self.sessions,
+                                           Some(&*self.metrics))
+                        },
+                        None => Ok(()),
                     }
-                },
-                Err(err) => {
-                    error!("Failed in UDP recv_from: {}", err);
-                    break
                 }
+            };
+
+            if let Err(err) = result {
+                error!("Failed to relay UDP packet from {}: {}", source_addr, err);
             }
         }
     }
 }
 
-fn handle_request(mut socket: UdpSocket,
-                  request_message: &[u8],
-                  from_addr: SocketAddr,
-                  server_addr: SocketAddr,
-                  config: &ServerConfig,
-                  client_map: Arc<Mutex<LruCache<socks5::Address, SocketAddr>>>) {
-    // According to RFC 1928
-    //
-    // Implementation of fragmentation is optional; an implementation that
-    // does not support fragmentation MUST drop any datagram whose FRAG
-    // field is other than X'00'.
-    if request_message[2] != 0x00u8 {
-        // Drop it
-        warn!("Does not support fragmentation");
-        return;
+impl Relay for UdpRelayLocal {
+    fn run(&self) {
+        let mut event_loop = EventLoop::new().ok().expect("Failed to create event loop");
+
+        // Servers replaced from now on are sent to the event loop
+        let servers = {
+            let mut servers = self.servers.lock().unwrap();
+            if servers.shut_down {
+                return;
+            }
+            servers.notifier = Some(event_loop.notifier());
+            servers.list.clone()
+        };
+        let (server_set, server_addr) = resolve_servers(servers.as_slice());
+        // Kept when the load balancer is replaced
+        let failures = Arc::new(Failures::new());
+        let server_load_balancer = new_load_balancer(servers, self.config.load_balancing, self.health_checker.clone(),
+                                                     failures.clone());
+
+        let redir = self.config.local_mode == LocalMode::Redir;
+        let socket = self.config.local.map(|addr| {
+            if redir {
+                UdpSocket::bind_transparent(&addr).ok().expect("Failed to bind udp socket for TPROXY")
+            } else {
+                UdpSocket::bind(&addr).ok().expect("Failed to bind udp socket")
+            }
+        });
+        let tunnels: Vec<(UdpSocket, socks5::Address)> = self.config.tunnels.iter().map(|t| {
+            (UdpSocket::bind(&t.local).ok().expect("Failed to bind udp socket of tunnel"), t.forward.clone())
+        }).collect();
+
+        if let Some(ref socket) = socket {
+            event_loop.register(socket, SOCKET_TOKEN, Interest::readable()).ok().expect("Failed to register udp socket");
+        }
+        for (i, &(ref socket, _)) in tunnels.iter().enumerate() {
+            event_loop.register(socket, Token(TUNNEL_SOCKET_TOKEN + i), Interest::readable())
+                      .ok().expect("Failed to register udp socket");
+        }
+
+        let mut handler = UdpLocalHandler {
+            socket: socket,
+            tunnels: tunnels,
+            tunnel_clients: LruCache::new(UDP_RELAY_LOCAL_LRU_CACHE_CAPACITY),
+            server_load_balancer: server_load_balancer,
+            load_balancing: self.config.load_balancing,
+            health_checker: self.health_checker.clone(),
+            failures: failures,
+            server_set: server_set,
+            server_addr: server_addr,
+            client_map: LruCache::new(UDP_RELAY_LOCAL_LRU_CACHE_CAPACITY),
+            sessions: UdpClientSessions::new(UDP_RELAY_LOCAL_LRU_CACHE_CAPACITY),
+            associations: if redir || self.config.local_users.is_empty() {
+                None
+            } else {
+                Some(self.associations.clone())
+            },
+            acl: self.config.acl.clone(),
+            dns: CachedDns::with_capacity(DEFAULT_DNS_CACHE_CAPACITY, self.metrics.clone()),
+            direct_sockets: HashMap::new(),
+            direct_map: LruCache::new(UDP_RELAY_LOCAL_LRU_CACHE_CAPACITY),
+            redir: redir,
+            reply_sockets: LruCache::new(REDIR_REPLY_SOCKETS_CAPACITY),
+            rate_limiter: self.rate_limiter.clone(),
+            metrics: self.metrics.clone(),
+            buf: repeat(0u8).take(0xffff).collect(),
+        };
+
+        if let Err(err) = event_loop.run(&mut handler) {
+            error!("UDP event loop exited: {}", err);
+        }
     }
 
+    // Datagrams have nothing to wait for
+    fn shutdown(&self, _: u64) {
+        let mut servers = self.servers.lock().unwrap();
+        if let Some(ref notifier) = servers.notifier {
+            let _ = notifier.notify(Message::Shutdown);
+        }
+        servers.shut_down = true;
+    }
+}
+
+/// Relays datagrams of a single client through the servers, which is used by the DNS forwarder
+pub struct UdpRelayClient {
+    // Address of the client's socket, which identifies its sessions
+    client_addr: SocketAddr,
+    client_map: LruCache<socks5::Address, SocketAddr>,
+    sessions: UdpClientSessions,
+}
+
+impl UdpRelayClient {
+    pub fn new(client_addr: SocketAddr) -> UdpRelayClient {
+        UdpRelayClient {
+            client_addr: client_addr,
+            client_map: LruCache::new(UDP_RELAY_LOCAL_LRU_CACHE_CAPACITY),
+            sessions: UdpClientSessions::new(UDP_RELAY_LOCAL_LRU_CACHE_CAPACITY),
+        }
+    }
 
-    let mut bufr = BufReader::new(request_message);
-    let request = socks5::UdpAssociateHeader::read_from(&mut bufr).unwrap();
+    /// Sends `payload` to `addr` through the server
+    pub fn send_to(&mut self, socket: &UdpSocket, addr: socks5::Address, payload: &[u8],
+                   server_addr: SocketAddr, config: &ServerConfig) -> io::IoResult<()> {
+        handle_request(socket, addr, payload, self.client_addr, server_addr, config,
+                       &mut self.client_map, &mut self.sessions, None)
+    }
+
+    /// Decrypts a packet from the server, returns the payload
+    pub fn decrypt_response(&mut self, packet: &[u8], from_addr: SocketAddr, config: &ServerConfig)
+            -> io::IoResult<Option<Vec<u8>>> {
+        let response = try!(handle_response(packet, from_addr, config, &mut self.client_map, &mut self.sessions,
+                                            None));
+        Ok(response.map(|(_, _, payload)| payload))
+    }
+}
 
-    let addr = request.address.clone();
+fn send_to(socket: &UdpSocket, data: &[u8], addr: SocketAddr) -> io::IoResult<()> {
+    if try!(socket.send_to(data, &addr)).is_none() {
+        debug!("UDP send buffer is full, dropped packet to {}", addr);
+    }
+    Ok(())
+}
 
+fn handle_request(socket: &UdpSocket,
+                  addr: socks5::Address,
+                  payload: &[u8],
+                  from_addr: SocketAddr,
+                  server_addr: SocketAddr,
+                  config: &ServerConfig,
+                  client_map: &mut LruCache<socks5::Address, SocketAddr>,
+                  sessions: &mut UdpClientSessions,
+                  metrics: Option<&Metrics>) -> io::IoResult<()> {
     info!("UDP ASSOCIATE {}", addr);
     debug!("UDP associate {} <-> {}", addr, from_addr);
 
-    client_map.lock().unwrap().insert(addr, from_addr);
+    match metrics {
+        Some(metrics) => {
+            if metrics.insert_lru(Cache::Udp, client_map, addr.clone(), from_addr) {
+                metrics.add_udp_association();
+            }
+        },
+        None => { client_map.insert(addr.clone(), from_addr); },
+    }
 
-    let key = config.method.bytes_to_key(config.password.as_bytes());
-    let mut iv = config.method.gen_init_vec();
-    let mut encryptor = cipher::with_type(config.method,
-                                          key.as_slice(),
-                                          iv.as_slice(),
-                                          CryptoMode::Encrypt);
+    let key = config.key();
+    let identity_key = config.identity_key();
 
-    let mut wbuf = Vec::new();
-    request.write_to(&mut wbuf).unwrap();
-    io::util::copy(&mut bufr, &mut wbuf).unwrap();
+    let encrypted_data = if config.method.category() == CipherCategory::Aead2022 {
+        // The 2022 edition sends only address and payload in the body
+        let mut wbuf = Vec::new();
+        try!(addr.write_to(&mut wbuf));
+        try!(wbuf.write(payload));
 
-    iv.push_all(encryptor.update(wbuf.as_slice()).unwrap().as_slice());
-    iv.push_all(encryptor.finalize().unwrap().as_slice());
+        let identity_key = identity_key.as_ref().map(|k| k.as_slice());
+        match sessions.encrypt_request(config.method, key.as_slice(), identity_key, from_addr, wbuf.as_slice()) {
+            Some(data) => data,
+            None => return Ok(()),
+        }
+    } else {
+        let mut wbuf = Vec::new();
+        try!(socks5::UdpAssociateHeader::new(0, addr).write_to(&mut wbuf));
+        try!(wbuf.write(payload));
+
+        match encrypt_payload(config.method, key.as_slice(), wbuf.as_slice()) {
+            Ok(data) => data,
+            Err(err) => {
+                error!("Failed to encrypt UDP packet: {}", err);
+                return Ok(());
+            }
+        }
+    };
 
-    socket.send_to(iv.as_slice(), server_addr)
-        .ok().expect("Error occurs while sending to remote");
+    send_to(socket, encrypted_data.as_slice(), server_addr)
 }
 
-fn handle_response(mut socket: UdpSocket,
-                   response_message: &[u8],
+// Returns the client, the address and the payload of a response
+fn handle_response(response_message: &[u8],
                    from_addr: SocketAddr,
                    config: &ServerConfig,
-                   client_map: Arc<Mutex<LruCache<socks5::Address, SocketAddr>>>) {
-    let key = config.method.bytes_to_key(config.password.as_bytes());
-
-    let mut decryptor = cipher::with_type(config.method,
-                                          key.as_slice(),
-                                          &response_message[0..config.method.block_size()],
-                                          CryptoMode::Decrypt);
-    let mut decrypted_data = decryptor.update(&response_message[config.method.block_size()..]).unwrap();
-    decrypted_data.push_all(decryptor.finalize().unwrap().as_slice());
+                   client_map: &mut LruCache<socks5::Address, SocketAddr>,
+                   sessions: &mut UdpClientSessions,
+                   metrics: Option<&Metrics>)
+        -> io::IoResult<Option<(SocketAddr, socks5::Address, Vec<u8>)>> {
+    let key = config.key();
+
+    let decrypted = if config.method.category() == CipherCategory::Aead2022 {
+        sessions.decrypt_response(config.method, key.as_slice(), response_message)
+                .map(|(client_addr, data)| (Some(client_addr), data))
+    } else {
+        decrypt_payload(config.method, key.as_slice(), response_message).map(|data| (None, data))
+    };
+    let (session_client_addr, decrypted_data) = match decrypted {
+        Some(decrypted) => decrypted,
+        None => {
+            if let Some(metrics) = metrics {
+                metrics.add_decryption_failure();
+            }
+            return Ok(None);
+        }
+    };
 
     let mut bufr = BufReader::new(decrypted_data.as_slice());
 
-    let addr = socks5::Address::read_from(&mut bufr).unwrap();
+    let addr = match socks5::Address::read_from(&mut bufr) {
+        Ok(addr) => addr,
+        Err(err) => {
+            error!("Invalid address in UDP response from {}: {}", from_addr, err);
+            return Ok(None);
+        }
+    };
 
-    let client_addr = {
-        let mut cmap = client_map.lock().unwrap();
-        match cmap.get(&addr) {
-            Some(a) => a.clone(),
-            None => return
+    let client_addr = match session_client_addr {
+        Some(a) => a,
+        None => {
+            match client_map.get(&addr) {
+                Some(a) => a.clone(),
+                None => return Ok(None)
+            }
         }
     };
 
     debug!("UDP response {} -> {}", from_addr, client_addr);
 
-    let mut bufw = MemWriter::new();
-    socks5::UdpAssociateHeader::new(0, addr)
-        .write_to(&mut bufw).unwrap();
-    io::util::copy(&mut bufr, &mut bufw).unwrap();
-
-    socket.send_to(bufw.into_inner().as_slice(), client_addr)
-        .ok().expect("Error occurs while sending to local");
+    let payload = try!(bufr.read_to_end());
+    Ok(Some((client_addr, addr, payload)))
 }
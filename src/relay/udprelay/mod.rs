@@ -19,8 +19,93 @@
 // IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
 // CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+use std::iter::repeat;
+use std::io::net::ip::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crypto::cipher::{self, CipherType, CipherCategory, CipherResult};
+use crypto::aead;
+use crypto::CryptoMode;
+
 pub mod local;
 pub mod server;
+mod aead2022;
 
 const UDP_RELAY_LOCAL_LRU_CACHE_CAPACITY: usize = 1024;
 const UDP_RELAY_SERVER_LRU_CACHE_CAPACITY: usize = 10240;
+
+/// Any address and port of the family of `ip`, for binding sockets which send to `ip`
+pub fn unspecified_addr(ip: &IpAddr) -> SocketAddr {
+    let ip = match *ip {
+        Ipv4Addr(..) => Ipv4Addr(0, 0, 0, 0),
+        Ipv6Addr(..) => Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 0),
+    };
+    SocketAddr {
+        ip: ip,
+        port: 0,
+    }
+}
+
+/// Encrypts one UDP packet, returns `[IV or salt][encrypted payload]`.
+///
+/// For AEAD ciphers, every packet is sealed with a fresh subkey and a zero nonce.
+/// Ciphers of the 2022 edition have their own sessions in `aead2022`.
+fn encrypt_payload(t: CipherType, key: &[u8], payload: &[u8]) -> CipherResult<Vec<u8>> {
+    let mut packet = t.gen_init_vec();
+
+    match t.category() {
+        CipherCategory::Stream => {
+            let mut encryptor = cipher::with_type(t, key, packet.as_slice(), CryptoMode::Encrypt);
+
+            // Encrypts into the packet directly, without allocating a temporary buffer
+            let iv_len = packet.len();
+            packet.resize(iv_len + payload.len(), 0u8);
+            let len = try!(encryptor.update_into(payload, &mut packet[iv_len..]));
+            packet.truncate(iv_len + len);
+            packet.push_all(try!(encryptor.finalize()).as_slice());
+        },
+        CipherCategory::Aead => {
+            let mut encryptor = aead::with_type(t, key, packet.as_slice());
+            packet.push_all(try!(encryptor.encrypt(payload)).as_slice());
+        },
+        CipherCategory::Aead2022 => unreachable!(),
+    }
+
+    Ok(packet)
+}
+
+/// Decrypts one UDP packet, returns `None` if the packet is malformed or not authenticated
+fn decrypt_payload(t: CipherType, key: &[u8], packet: &[u8]) -> Option<Vec<u8>> {
+    let iv_size = t.iv_size();
+    if packet.len() < iv_size + t.tag_size() {
+        error!("UDP packet is too short, len: {}", packet.len());
+        return None;
+    }
+
+    let (iv, data) = packet.split_at(iv_size);
+    let result = match t.category() {
+        CipherCategory::Stream => {
+            let mut decryptor = cipher::with_type(t, key, iv, CryptoMode::Decrypt);
+            let mut buf: Vec<u8> = repeat(0u8).take(data.len()).collect();
+            let result = decryptor.update_into(data, buf.as_mut_slice())
+                                  .and_then(|len| decryptor.finalize().map(|fin| (len, fin)));
+            result.map(move |(len, fin)| {
+                buf.truncate(len);
+                buf.push_all(fin.as_slice());
+                buf
+            })
+        },
+        CipherCategory::Aead => {
+            let mut decryptor = aead::with_type(t, key, iv);
+            decryptor.decrypt(data)
+        },
+        CipherCategory::Aead2022 => unreachable!(),
+    };
+
+    match result {
+        Ok(buf) => Some(buf),
+        Err(err) => {
+            error!("Failed to decrypt UDP packet: {}", err);
+            None
+        }
+    }
+}
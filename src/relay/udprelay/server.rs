@@ -19,166 +19,946 @@
 // IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
 // CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+//! UdpRelay server that running on the server side
+//!
+//! Datagrams of all servers are relayed in one event loop. Domain names are resolved in the
+//! task pool of `CachedDns`, and the datagram is sent after the result has come back.
+//!
+//! Servers could be started, stopped and updated while the event loop is running. An updated
+//! server keeps its socket, and relays datagrams received from then on with the new configuration.
+//! Shutting the relay down closes all sockets and flushes associations at once.
+//!
+//! Every client has its own association, which sends datagrams to targets from a socket of its
+//! own, so responses of a target are sent back to the client which has sent requests to it. Clients
+//! of the 2022 edition have an association for each of their sessions. Associations are closed
+//! after they have been idle for a while.
+//!
+//! Responses to clients of a multi-user server are encrypted with the key of the user who has
+//! sent the latest request of the association.
+//!
+//! Datagrams exceeding the rate limits of their ports or of the process are dropped.
+
 use std::sync::{Arc, Mutex};
-use std::io::net::udp::UdpSocket;
-use std::io::net::ip::SocketAddr;
+use std::io::net::ip::{SocketAddr, IpAddr, Port};
 use std::io::net::addrinfo::get_host_addresses;
-use std::io::BufReader;
-use std::thread::Thread;
+use std::io::{IoResult, BufReader};
+use std::iter::repeat;
+use std::collections::HashMap;
 
 use collect::LruCache;
 
 use config::{Config, ServerConfig};
+use crypto::cipher::CipherCategory;
+use crypto::aead2022;
 use relay::Relay;
 use relay::socks5::{Address, self};
+use relay::replay_filter::ReplayFilter;
+use relay::cached_dns::CachedDns;
+use relay::outbound_filter::OutboundFilter;
+use relay::users::{UserTables, UserTable, User};
+use relay::traffic::{TrafficStats, Traffic};
+use relay::ratelimit::{RateLimiter, Buckets};
+use relay::metrics::{Metrics, Cache};
+use relay::eventloop::{EventLoop, Handler, Token, Interest, Ready, UdpSocket, Notifier, now_ms};
 use relay::udprelay::{UDP_RELAY_SERVER_LRU_CACHE_CAPACITY};
-use crypto::{cipher, CryptoMode};
-use crypto::cipher::Cipher;
+use relay::udprelay::{encrypt_payload, decrypt_payload, unspecified_addr};
+use relay::udprelay::aead2022::UdpServerSessions;
+
+/// Milliseconds before an idle association is closed
+const ASSOCIATION_TIMEOUT: u64 = 300000;
+/// Milliseconds between two sweeps of idle associations
+const SWEEP_INTERVAL: u64 = 10000;
+const SWEEP_TOKEN: Token = Token(0);
+/// Associations of each server, the least recently active one is closed to make room for a new one
+const MAX_ASSOCIATIONS: usize = 4096;
+/// Targets remembered by each association
+const ASSOCIATION_TARGETS_CAPACITY: usize = 256;
+
+/// A client address, with the client session ID of the 2022 edition
+type AssociationKey = (SocketAddr, Option<u64>);
+
+/// Servers started before the event loop runs, and the notifier of the loop after it runs.
+/// Shared by clones of a `UdpRelayServer`.
+struct Servers {
+    pending: Vec<ServerState>,
+    ports: Vec<Port>,
+    notifier: Option<Notifier<Message>>,
+    shut_down: bool,
+}
 
 #[derive(Clone)]
 pub struct UdpRelayServer {
-    config: Config
+    config: Config,
+    replay_filter: Arc<ReplayFilter>,
+    outbound_filter: Arc<OutboundFilter>,
+    users: Arc<UserTables>,
+    traffic: Arc<TrafficStats>,
+    rate_limiter: Arc<RateLimiter>,
+    metrics: Arc<Metrics>,
+    servers: Arc<Mutex<Servers>>,
 }
 
 impl UdpRelayServer {
-    pub fn new(config: Config) -> UdpRelayServer {
+    pub fn new(config: Config, replay_filter: Arc<ReplayFilter>, outbound_filter: Arc<OutboundFilter>,
+               users: Arc<UserTables>, traffic: Arc<TrafficStats>, rate_limiter: Arc<RateLimiter>,
+               metrics: Arc<Metrics>) -> UdpRelayServer {
         UdpRelayServer {
-            config: config
+            config: config,
+            replay_filter: replay_filter,
+            outbound_filter: outbound_filter,
+            users: users,
+            traffic: traffic,
+            rate_limiter: rate_limiter,
+            metrics: metrics,
+            servers: Arc::new(Mutex::new(Servers {
+                pending: Vec::new(),
+                ports: Vec::new(),
+                notifier: None,
+                shut_down: false,
+            })),
         }
     }
 
-    fn accept_loop(svr_config: ServerConfig) {
-        let mut socket = UdpSocket::bind((svr_config.addr.as_slice(), svr_config.port))
-            .ok().expect("Unable to bind UDP socket");
-        debug!("UDP server is binding {}", svr_config.addr);
+    /// Starts relaying datagrams of a server, in addition to the running ones.
+    ///
+    /// It could be called before or after `run`.
+    pub fn start_server(&self, config: &ServerConfig) -> IoResult<()> {
+        let state = try!(ServerState::bind(config, &*self.users, &*self.traffic, self.rate_limiter.clone(),
+                                           self.metrics.clone()));
 
-        let client_map_arc = Arc::new(Mutex::new(
-                            LruCache::<Address, SocketAddr>::new(UDP_RELAY_SERVER_LRU_CACHE_CAPACITY)));
-        let remote_map_arc = Arc::new(Mutex::new(
-                            LruCache::<SocketAddr, Address>::new(UDP_RELAY_SERVER_LRU_CACHE_CAPACITY)));
+        let mut guard = self.servers.lock().unwrap();
+        let servers = &mut *guard;
+        servers.ports.push(config.port);
+        match servers.notifier {
+            // The event loop has exited if it fails
+            Some(ref notifier) => { let _ = notifier.notify(Message::Start(state)); },
+            None => servers.pending.push(state),
+        }
+        Ok(())
+    }
 
-        let mut buf = [0u8; 0xffff];
-        loop {
-            match socket.recv_from(&mut buf) {
-                Ok((len, src)) => {
-                    let data = buf[..len].to_vec();
-                    let client_map = client_map_arc.clone();
-                    let remote_map = remote_map_arc.clone();
-                    let mut captured_socket = socket.clone();
-
-                    let method = svr_config.method;
-                    let password = svr_config.password.clone();
-
-                    Thread::spawn(move || {
-                        match remote_map.lock().unwrap().get(&src) {
-                            Some(remote_addr) => {
-                                match client_map.lock().unwrap().get(remote_addr) {
-                                    Some(client_addr) => {
-                                        debug!("UDP response {} -> {}", remote_addr, client_addr);
-
-                                        // Make a header
-                                        let mut response_buf = Vec::new();
-                                        remote_addr.write_to(&mut response_buf).unwrap();
-                                        response_buf.write(data.as_slice()).unwrap();
-
-                                        let key = method.bytes_to_key(password.as_bytes());
-                                        let mut iv = method.gen_init_vec();
-                                        let mut encryptor =
-                                            cipher::with_type(method,
-                                                              key.as_slice(),
-                                                              iv.as_slice(),
-                                                              CryptoMode::Encrypt);
-                                        iv.push_all(encryptor.update(response_buf.as_slice()).unwrap().as_slice());
-                                        iv.push_all(encryptor.finalize().unwrap().as_slice());
-
-                                        captured_socket
-                                            .send_to(iv.as_slice(), client_addr.clone())
-                                            .unwrap();
-                                    },
-                                    None => {
-                                        // Unknown response, drop it.
-                                    }
-                                }
-
-                                return;
-                            },
-                            None => {}
-                        }
-
-                        // Maybe data from a relay client
-                        // Decrypt it and see what's inside
-                        let key = method.bytes_to_key(password.as_bytes());
-                        let mut decryptor =
-                            cipher::with_type(method,
-                                              key.as_slice(),
-                                              &data[0..method.block_size()],
-                                              CryptoMode::Decrypt);
-                        let mut decrypted_data = decryptor.update(&data[method.block_size()..]).unwrap();
-                        decrypted_data.push_all(decryptor.finalize().unwrap().as_slice());
-                        let mut bufr = BufReader::new(decrypted_data.as_slice());
-
-                        let header = socks5::UdpAssociateHeader::read_from(&mut bufr).unwrap();
-
-                        if header.frag != 0 {
-                            // Drop it
-                            return;
-                        }
-
-                        info!("UDP ASSOCIATE {}", header.address);
-                        debug!("UDP request {} -> {}", src, header.address);
-
-                        let sockaddr = match &header.address {
-                            &Address::SocketAddress(ip, port) => {
-                                client_map.lock()
-                                          .unwrap()
-                                          .insert(header.address.clone(), src);
-                                remote_map.lock()
-                                          .unwrap()
-                                          .insert(SocketAddr {ip: ip, port: port}, header.address.clone());
-                                SocketAddr {ip: ip, port: port}
-                            },
-                            &Address::DomainNameAddress(ref dnaddr, port) => {
-                                let ref ipaddrs = get_host_addresses(dnaddr.as_slice())
-                                    .unwrap_or_else(|err| {
-                                        panic!("Unable to resolve {}: {}", dnaddr, err);
-                                    });
-
-                                let remote_addr = SocketAddr {
-                                                      ip: ipaddrs.first().unwrap().clone(),
-                                                      port: port,
-                                                  };
-                                client_map.lock()
-                                          .unwrap()
-                                          .insert(header.address.clone(), src);
-                                remote_map.lock()
-                                          .unwrap()
-                                          .insert(remote_addr, header.address.clone());
-                                remote_addr
-                            }
-                        };
-                        captured_socket.send_to(&decrypted_data.as_slice()[header.len()..], sockaddr).unwrap();
-                    });
+    /// Relays datagrams of the server on the port of `config` with `config` from now on, the socket
+    /// is kept. Returns `false` if there is no such server.
+    pub fn update_server(&self, config: &ServerConfig) -> bool {
+        let update = Update::new(config, &*self.users, &*self.traffic, &*self.rate_limiter);
+
+        let mut guard = self.servers.lock().unwrap();
+        let servers = &mut *guard;
+        if !servers.ports.contains(&config.port) {
+            return false;
+        }
+
+        match servers.notifier {
+            Some(ref notifier) => { let _ = notifier.notify(Message::Update(update)); },
+            None => {
+                for state in servers.pending.iter_mut().filter(|s| s.config.port == config.port) {
+                    state.update(update.clone());
+                }
+            }
+        }
+        true
+    }
+
+    /// Stops the server on `port` and closes its socket, returns `false` if there is no such server
+    pub fn stop_server(&self, port: Port) -> bool {
+        let mut guard = self.servers.lock().unwrap();
+        let servers = &mut *guard;
+        if !servers.ports.contains(&port) {
+            return false;
+        }
+
+        servers.ports.retain(|p| *p != port);
+        match servers.notifier {
+            Some(ref notifier) => { let _ = notifier.notify(Message::Stop(port)); },
+            None => servers.pending.retain(|s| s.config.port != port),
+        }
+        true
+    }
+}
+
+/// Socket and associations of one server
+struct ServerState {
+    config: ServerConfig,
+    key: Vec<u8>,
+    socket: UdpSocket,
+    // Address the socket is bound to, associations bind sockets of the same family
+    ip: IpAddr,
+    dns: CachedDns,
+    // Tokens of associations' sockets
+    associations: HashMap<AssociationKey, usize>,
+    sessions: UdpServerSessions,
+    // Users of a multi-user server
+    users: Option<Arc<UserTable>>,
+    traffic: Arc<Traffic>,
+    rate_limit: Arc<Buckets>,
+    rate_limiter: Arc<RateLimiter>,
+    metrics: Arc<Metrics>,
+}
+
+/// A client of a server, which talks to its targets through its own socket
+struct Association {
+    // Token of the server
+    server: usize,
+    key: AssociationKey,
+    // User of a multi-user server who has sent the latest request
+    user: Option<Arc<User>>,
+    socket: UdpSocket,
+    // Addresses of targets as the client has requested them, by their resolved addresses
+    targets: LruCache<SocketAddr, Address>,
+    last_active: u64,
+}
+
+/// Configuration of a server which could be updated while it is running
+#[derive(Clone)]
+struct Update {
+    config: ServerConfig,
+    users: Option<Arc<UserTable>>,
+    traffic: Arc<Traffic>,
+    rate_limit: Arc<Buckets>,
+}
+
+impl Update {
+    fn new(config: &ServerConfig, users: &UserTables, traffic: &TrafficStats, rate_limiter: &RateLimiter) -> Update {
+        let traffic = traffic.port(config.port);
+        traffic.set_quota(config.quota);
+        let rate_limit = rate_limiter.port(config.port);
+        rate_limit.set_rates(&config.rate_limit);
+
+        Update {
+            config: config.clone(),
+            users: users.get(config.addr.as_slice(), config.port),
+            traffic: traffic,
+            rate_limit: rate_limit,
+        }
+    }
+}
+
+fn send_to(socket: &UdpSocket, data: &[u8], addr: SocketAddr) {
+    match socket.send_to(data, &addr) {
+        Ok(Some(..)) => {},
+        Ok(None) => debug!("UDP send buffer is full, dropped packet to {}", addr),
+        Err(err) => error!("Failed to send UDP packet to {}: {}", addr, err),
+    }
+}
+
+impl ServerState {
+    fn bind(svr_config: &ServerConfig, users: &UserTables, traffic: &TrafficStats, rate_limiter: Arc<RateLimiter>,
+            metrics: Arc<Metrics>) -> IoResult<ServerState> {
+        let update = Update::new(svr_config, users, traffic, &*rate_limiter);
+
+        let ip = match svr_config.addr.parse::<IpAddr>() {
+            Some(ip) => ip,
+            None => {
+                let addrs = try!(get_host_addresses(svr_config.addr.as_slice()));
+                *addrs.first().expect("Unable to resolve UDP address")
+            }
+        };
+        let socket = try!(UdpSocket::bind(&SocketAddr { ip: ip, port: svr_config.port }));
+
+        Ok(ServerState {
+            config: svr_config.clone(),
+            key: svr_config.key(),
+            socket: socket,
+            ip: ip,
+            dns: CachedDns::with_capacity(svr_config.dns_cache_capacity, metrics.clone()),
+            associations: HashMap::new(),
+            sessions: UdpServerSessions::new(UDP_RELAY_SERVER_LRU_CACHE_CAPACITY),
+            users: update.users,
+            traffic: update.traffic,
+            rate_limit: update.rate_limit,
+            rate_limiter: rate_limiter,
+            metrics: metrics,
+        })
+    }
+
+    // Sessions and users are forgotten if keys could have been changed, responses could not be
+    // encrypted with the new keys. Returns `true` if associations should be closed for this reason.
+    fn update(&mut self, update: Update) -> bool {
+        let key = update.config.key();
+        let forget = key != self.key || update.config.method != self.config.method || update.users.is_some();
+        if forget {
+            self.sessions = UdpServerSessions::new(UDP_RELAY_SERVER_LRU_CACHE_CAPACITY);
+        }
+
+        self.key = key;
+        self.config = update.config;
+        self.users = update.users;
+        self.traffic = update.traffic;
+        self.rate_limit = update.rate_limit;
+        debug!("Updated UDP server on port {}", self.config.port);
+        forget
+    }
+
+    // Counts traffic of the server, and of the user of a multi-user server
+    fn count_download(&self, user: &Option<Arc<User>>, n: usize) {
+        self.traffic.add_download(n);
+        if let Some(ref user) = *user {
+            user.traffic().add_download(n);
+        }
+    }
+
+    /// Sends a response of the target at `remote_addr` to the client of an association
+    fn handle_response(&mut self, client: AssociationKey, user: Option<Arc<User>>, remote_addr: Address,
+                       data: &[u8]) {
+        let method = self.config.method;
+        if self.users.is_some() && user.is_none() {
+            return;
+        }
+        let key = match user {
+            Some(ref user) if !user.is_allowed() => return,
+            Some(ref user) => user.key().to_vec(),
+            None => self.key.clone(),
+        };
+        if self.traffic.is_exceeded() || !self.rate_limiter.take_packet(Some(&*self.rate_limit), false, data.len()) {
+            return;
+        }
+
+        // Make a header
+        let mut response_buf = Vec::new();
+        remote_addr.write_to(&mut response_buf).unwrap();
+        response_buf.push_all(data);
+
+        let (client_addr, session_id) = client;
+        if method.category() == CipherCategory::Aead2022 {
+            let session_id = match session_id {
+                Some(id) => id,
+                None => return,
+            };
+
+            let response = self.sessions.encrypt_response(method, key.as_slice(), session_id,
+                                                          response_buf.as_slice());
+            if let Some((client_addr, encrypted_data)) = response {
+                debug!("UDP response {} -> {}", remote_addr, client_addr);
+                self.count_download(&user, encrypted_data.len());
+                send_to(&self.socket, encrypted_data.as_slice(), client_addr);
+            }
+            return;
+        }
+
+        debug!("UDP response {} -> {}", remote_addr, client_addr);
+        match encrypt_payload(method, key.as_slice(), response_buf.as_slice()) {
+            Ok(encrypted_data) => {
+                self.count_download(&user, encrypted_data.len());
+                send_to(&self.socket, encrypted_data.as_slice(), client_addr);
+            },
+            Err(err) => error!("Failed to encrypt UDP packet: {}", err),
+        }
+    }
+
+    /// Identifies the user of a request to a multi-user server, returns `None` if the user is unknown or disabled
+    fn identify(&self, src: SocketAddr, data: &[u8]) -> Option<(Arc<User>, Option<Vec<u8>>)> {
+        let users = self.users.as_ref().unwrap();
+        let method = self.config.method;
+
+        let (user, decrypted_data) = if method.category() == CipherCategory::Aead2022 {
+            let identity = match aead2022::read_udp_identity(method, self.key.as_slice(), data) {
+                Ok(identity) => identity,
+                Err(err) => {
+                    error!("Invalid identity header in UDP request from {}: {}", src, err);
+                    self.metrics.add_decryption_failure();
+                    return None;
+                }
+            };
+            match users.find_identity(identity.as_slice()) {
+                Some(user) => (user, None),
+                None => {
+                    error!("No user matches UDP request from {}", src);
+                    self.metrics.add_decryption_failure();
+                    return None;
+                }
+            }
+        } else {
+            match users.identify_packet(data) {
+                Some((user, decrypted_data)) => (user, Some(decrypted_data)),
+                None => {
+                    error!("No user matches UDP request from {}", src);
+                    self.metrics.add_decryption_failure();
+                    return None;
+                }
+            }
+        };
+
+        if !user.is_enabled() {
+            debug!("Dropped UDP request of disabled user {} from {}", user.name(), src);
+            return None;
+        }
+        if user.traffic().is_exceeded() {
+            debug!("Dropped UDP request of user {} from {}, who has exceeded the quota", user.name(), src);
+            return None;
+        }
+        user.count_packet();
+        Some((user, decrypted_data))
+    }
+
+    /// Decrypts a request, returns the target address, the session ID of the 2022 edition, the user of a
+    /// multi-user server and the payload
+    fn decrypt_request(&mut self, src: SocketAddr, data: &[u8], replay_filter: &ReplayFilter)
+            -> Option<(Address, Option<u64>, Option<Arc<User>>, Vec<u8>)> {
+        let method = self.config.method;
+
+        let (user, identified_data) = if self.users.is_some() {
+            match self.identify(src, data) {
+                Some((user, decrypted_data)) => (Some(user), decrypted_data),
+                None => return None,
+            }
+        } else {
+            (None, None)
+        };
+
+        if method.category() == CipherCategory::Aead2022 {
+            let (session_id, decrypted_data) =
+                match self.sessions.decrypt_request(method, self.key.as_slice(), user.as_ref().map(|u| u.key()),
+                                                    src, data) {
+                    Some(r) => r,
+                    None => {
+                        self.metrics.add_decryption_failure();
+                        return None;
+                    }
+                };
+
+            let address = match Address::read_from(&mut BufReader::new(decrypted_data.as_slice())) {
+                Ok(addr) => addr,
+                Err(err) => {
+                    error!("Invalid address in UDP request from {}: {}", src, err);
+                    return None;
+                }
+            };
+
+            if let Some(ref user) = user {
+                user.traffic().add_upload(data.len());
+            }
+            let payload = decrypted_data[address.len()..].to_vec();
+            return Some((address, Some(session_id), user, payload));
+        }
+
+        let decrypted_data = match identified_data {
+            Some(data) => data,
+            None => match decrypt_payload(method, self.key.as_slice(), data) {
+                Some(data) => data,
+                None => {
+                    self.metrics.add_decryption_failure();
+                    return None;
+                }
+            },
+        };
+
+        // Packets of the 2022 edition are protected by packet IDs in their sessions
+        if !replay_filter.check_and_insert(&data[..method.iv_size()]) {
+            error!("Replayed UDP packet from {}, rejected {} of {} requests",
+                   src, replay_filter.hits(), replay_filter.checked());
+            return None;
+        }
+
+        let header = match socks5::UdpAssociateHeader::read_from(&mut BufReader::new(decrypted_data.as_slice())) {
+            Ok(header) => header,
+            Err(err) => {
+                error!("Invalid UDP request from {}: {}", src, err);
+                return None;
+            }
+        };
+
+        if header.frag != 0 {
+            // Drop it
+            return None;
+        }
+
+        if let Some(ref user) = user {
+            user.traffic().add_upload(data.len());
+        }
+        let payload = decrypted_data[header.len()..].to_vec();
+        Some((header.address, None, user, payload))
+    }
+}
+
+/// A request waiting for its target to be resolved
+struct Resolved {
+    // Token of the server
+    server: usize,
+    src: SocketAddr,
+    address: Address,
+    session_id: Option<u64>,
+    user: Option<Arc<User>>,
+    payload: Vec<u8>,
+    addrs: Option<Vec<IpAddr>>,
+}
+
+/// Messages to the event loop
+enum Message {
+    Resolved(Resolved),
+    /// Starts relaying datagrams of a server
+    Start(ServerState),
+    /// Stops the server on the port
+    Stop(Port),
+    /// Updates the server on the port
+    Update(Update),
+    /// Flushes associations and exits
+    Shutdown,
+}
+
+/// Relays datagrams of all servers in one event loop
+struct UdpServerHandler {
+    // Running servers, keyed by tokens of their sockets
+    servers: HashMap<usize, ServerState>,
+    // Associations of all servers, keyed by tokens of their sockets
+    associations: HashMap<usize, Association>,
+    next_token: usize,
+    replay_filter: Arc<ReplayFilter>,
+    outbound_filter: Arc<OutboundFilter>,
+    metrics: Arc<Metrics>,
+    buf: Vec<u8>,
+}
+
+impl UdpServerHandler {
+    fn handle_packet(&mut self, event_loop: &mut EventLoop<UdpServerHandler>, server: usize, src: SocketAddr,
+                     data: &[u8]) {
+        let (address, session_id, user, payload) = {
+            let state = match self.servers.get_mut(&server) {
+                Some(state) => state,
+                None => return,
+            };
+
+            if state.traffic.is_exceeded() {
+                debug!("Dropped UDP request from {}, port {} has exceeded the quota", src, state.config.port);
+                return;
+            }
+            if !state.rate_limiter.take_packet(Some(&*state.rate_limit), true, data.len()) {
+                debug!("Dropped UDP request from {}, port {} has exceeded the rate limit", src, state.config.port);
+                return;
+            }
+
+            match state.decrypt_request(src, data, &*self.replay_filter) {
+                Some(r) => {
+                    state.traffic.add_upload(data.len());
+                    r
                 },
+                None => return,
+            }
+        };
+
+        if !self.outbound_filter.check(&address) {
+            return;
+        }
+
+        info!("UDP ASSOCIATE {}", address);
+        debug!("UDP request {} -> {}", src, address);
+
+        let name = match address {
+            Address::SocketAddress(ip, port) => {
+                let sockaddr = SocketAddr {ip: ip, port: port};
+                self.forward(event_loop, server, (src, session_id), user, address.clone(), sockaddr,
+                             payload.as_slice());
+                return;
+            },
+            Address::DomainNameAddress(ref name, _) => name.clone(),
+        };
+
+        let notifier = event_loop.notifier();
+        if let Some(state) = self.servers.get(&server) {
+            state.dns.resolve_async(name.as_slice(), move |addrs| {
+                // The event loop has exited if it fails
+                let _ = notifier.notify(Message::Resolved(Resolved {
+                    server: server,
+                    src: src,
+                    address: address,
+                    session_id: session_id,
+                    user: user,
+                    payload: payload,
+                    addrs: addrs,
+                }));
+            });
+        }
+    }
+
+    fn handle_response(&mut self, token: usize, src: SocketAddr, data: &[u8]) {
+        let assoc = match self.associations.get_mut(&token) {
+            Some(assoc) => assoc,
+            None => return,
+        };
+        let remote_addr = match assoc.targets.get(&src) {
+            Some(addr) => addr.clone(),
+            None => {
+                // Unknown response, drop it.
+                debug!("Dropped UDP packet from {}, which is not a target of {}", src, assoc.key.0);
+                return;
+            }
+        };
+        assoc.last_active = now_ms();
+
+        if let Some(state) = self.servers.get_mut(&assoc.server) {
+            state.handle_response(assoc.key, assoc.user.clone(), remote_addr, data);
+        }
+    }
+
+    /// Sends the payload to the target by the association of the client
+    fn forward(&mut self, event_loop: &mut EventLoop<UdpServerHandler>, server: usize, key: AssociationKey,
+               user: Option<Arc<User>>, address: Address, sockaddr: SocketAddr, payload: &[u8]) {
+        let token = match self.associate(event_loop, server, key) {
+            Some(token) => token,
+            None => return,
+        };
+
+        let assoc = self.associations.get_mut(&token).unwrap();
+        assoc.last_active = now_ms();
+        assoc.user = user;
+        if self.metrics.insert_lru(Cache::Udp, &mut assoc.targets, sockaddr, address) {
+            self.metrics.add_udp_association();
+        }
+        send_to(&assoc.socket, payload, sockaddr);
+    }
+
+    // Finds the association of a client, or creates it with a new socket
+    fn associate(&mut self, event_loop: &mut EventLoop<UdpServerHandler>, server: usize, key: AssociationKey)
+            -> Option<usize> {
+        let (ip, full) = match self.servers.get(&server) {
+            Some(state) => match state.associations.get(&key) {
+                Some(token) => return Some(*token),
+                None => (state.ip, state.associations.len() >= MAX_ASSOCIATIONS),
+            },
+            None => return None,
+        };
+
+        if full {
+            let oldest = self.associations.iter().filter(|&(_, assoc)| assoc.server == server)
+                                                 .min_by(|&(_, assoc)| assoc.last_active)
+                                                 .map(|(token, _)| *token);
+            if let Some(token) = oldest {
+                self.close_association(event_loop, token);
+            }
+        }
+
+        let socket = match UdpSocket::bind(&unspecified_addr(&ip)) {
+            Ok(socket) => socket,
+            Err(err) => {
+                error!("Failed to bind UDP socket for {}: {}", key.0, err);
+                return None;
+            }
+        };
+        let token = self.next_token;
+        self.next_token += 1;
+        if let Err(err) = event_loop.register(&socket, Token(token), Interest::readable()) {
+            error!("Failed to register UDP socket: {}", err);
+            return None;
+        }
+
+        self.associations.insert(token, Association {
+            server: server,
+            key: key,
+            user: None,
+            socket: socket,
+            targets: LruCache::new(ASSOCIATION_TARGETS_CAPACITY),
+            last_active: now_ms(),
+        });
+        self.servers.get_mut(&server).unwrap().associations.insert(key, token);
+        Some(token)
+    }
+
+    fn close_association(&mut self, event_loop: &mut EventLoop<UdpServerHandler>, token: usize) {
+        let assoc = match self.associations.remove(&token) {
+            Some(assoc) => assoc,
+            None => return,
+        };
+        if let Err(err) = event_loop.deregister(&assoc.socket) {
+            error!("Failed to deregister UDP socket: {}", err);
+        }
+        if let Some(state) = self.servers.get_mut(&assoc.server) {
+            state.associations.remove(&assoc.key);
+        }
+    }
+
+    fn close_associations_of(&mut self, event_loop: &mut EventLoop<UdpServerHandler>, server: usize) {
+        let closed = self.associations.iter().filter(|&(_, assoc)| assoc.server == server)
+                                             .map(|(token, _)| *token).collect::<Vec<usize>>();
+        for token in closed.iter() {
+            self.close_association(event_loop, *token);
+        }
+    }
+
+    fn start(&mut self, event_loop: &mut EventLoop<UdpServerHandler>, state: ServerState) {
+        let token = self.next_token;
+        self.next_token += 1;
+
+        match event_loop.register(&state.socket, Token(token), Interest::readable()) {
+            Ok(..) => { self.servers.insert(token, state); },
+            Err(err) => error!("Failed to register UDP socket: {}", err),
+        }
+    }
+
+    fn stop(&mut self, event_loop: &mut EventLoop<UdpServerHandler>, port: Port) {
+        let stopped = self.servers.iter().filter(|&(_, state)| state.config.port == port)
+                                         .map(|(token, _)| *token).collect::<Vec<usize>>();
+        for token in stopped.iter() {
+            self.close_associations_of(event_loop, *token);
+            let state = self.servers.remove(token).unwrap();
+            if let Err(err) = event_loop.deregister(&state.socket) {
+                error!("Failed to deregister UDP socket: {}", err);
+            }
+        }
+        debug!("Stopped UDP server on port {}", port);
+    }
+
+    fn update(&mut self, event_loop: &mut EventLoop<UdpServerHandler>, update: Update) {
+        let port = update.config.port;
+        let mut forgotten = Vec::new();
+        for (token, state) in self.servers.iter_mut() {
+            if state.config.port == port && state.update(update.clone()) {
+                forgotten.push(*token);
+            }
+        }
+        for token in forgotten.iter() {
+            self.close_associations_of(event_loop, *token);
+        }
+    }
+
+    fn resolved(&mut self, event_loop: &mut EventLoop<UdpServerHandler>, msg: Resolved) {
+        let port = match msg.address {
+            Address::DomainNameAddress(_, port) => port,
+            Address::SocketAddress(_, port) => port,
+        };
+
+        let addrs = match msg.addrs {
+            Some(addrs) => addrs,
+            None => {
+                error!("Unable to resolve {}", msg.address);
+                return;
+            }
+        };
+
+        // Names may resolve to refused addresses
+        let ip = match self.outbound_filter.filter_resolved(&msg.address, addrs).first() {
+            Some(ip) => *ip,
+            None => return,
+        };
+
+        let sockaddr = SocketAddr {ip: ip, port: port};
+        self.forward(event_loop, msg.server, (msg.src, msg.session_id), msg.user, msg.address, sockaddr,
+                     msg.payload.as_slice());
+    }
+}
+
+impl Handler for UdpServerHandler {
+    type Message = Message;
+
+    fn ready(&mut self, event_loop: &mut EventLoop<UdpServerHandler>, token: Token, _: Ready) {
+        let token = token.0;
+        let is_server = self.servers.contains_key(&token);
+        loop {
+            let result = match (self.servers.get(&token), self.associations.get(&token)) {
+                (Some(state), _) => state.socket.recv_from(self.buf.as_mut_slice()),
+                (None, Some(assoc)) => assoc.socket.recv_from(self.buf.as_mut_slice()),
+                (None, None) => return,
+            };
+            let (len, src) = match result {
+                Ok(Some(r)) => r,
+                Ok(None) => break,
                 Err(err) => {
                     error!("Error occurs while calling recv_from: {}", err);
                     break;
                 }
+            };
+
+            let data = self.buf[..len].to_vec();
+            if is_server {
+                self.handle_packet(event_loop, token, src, data.as_slice());
+            } else {
+                self.handle_response(token, src, data.as_slice());
             }
         }
     }
+
+    fn notify(&mut self, event_loop: &mut EventLoop<UdpServerHandler>, msg: Message) {
+        match msg {
+            Message::Resolved(msg) => self.resolved(event_loop, msg),
+            Message::Start(state) => self.start(event_loop, state),
+            Message::Stop(port) => self.stop(event_loop, port),
+            Message::Update(update) => self.update(event_loop, update),
+            Message::Shutdown => {
+                debug!("Flushed {} UDP associations", self.associations.len());
+                event_loop.shutdown();
+            },
+        }
+    }
+
+    // Closes idle associations
+    fn timeout(&mut self, event_loop: &mut EventLoop<UdpServerHandler>, _: Token) {
+        let now = now_ms();
+        let idle = self.associations.iter().filter(|&(_, assoc)| now - assoc.last_active > ASSOCIATION_TIMEOUT)
+                                           .map(|(token, _)| *token).collect::<Vec<usize>>();
+        for token in idle.iter() {
+            self.close_association(event_loop, *token);
+        }
+        event_loop.timeout_ms(SWEEP_TOKEN, SWEEP_INTERVAL);
+    }
 }
 
 impl Relay for UdpRelayServer {
     fn run(&self) {
-        let mut threads = Vec::new();
         for s in self.config.server.iter() {
-            let s = s.clone();
-            let fut = Thread::scoped(move || UdpRelayServer::accept_loop(s));
-            threads.push(fut);
+            match self.start_server(s) {
+                Ok(..) => debug!("UDP server is binding {}:{}", s.addr, s.port),
+                Err(err) => panic!("Unable to bind UDP socket: {}", err),
+            }
+        }
+
+        let mut event_loop = EventLoop::new().ok().expect("Failed to create event loop");
+        let mut handler = UdpServerHandler {
+            servers: HashMap::new(),
+            associations: HashMap::new(),
+            next_token: 0,
+            replay_filter: self.replay_filter.clone(),
+            outbound_filter: self.outbound_filter.clone(),
+            metrics: self.metrics.clone(),
+            buf: repeat(0u8).take(0xffff).collect(),
+        };
+
+        // Servers started from now on are sent to the event loop
+        {
+            let mut servers = self.servers.lock().unwrap();
+            if servers.shut_down {
+                return;
+            }
+            servers.notifier = Some(event_loop.notifier());
+            for state in servers.pending.drain() {
+                handler.start(&mut event_loop, state);
+            }
+        }
+        event_loop.timeout_ms(SWEEP_TOKEN, SWEEP_INTERVAL);
+
+        if let Err(err) = event_loop.run(&mut handler) {
+            error!("UDP event loop exited: {}", err);
         }
+    }
 
-        for fut in threads.into_iter() {
-            fut.join().ok().expect("A thread failed and exited");
+    // Datagrams have nothing to wait for
+    fn shutdown(&self, _: u64) {
+        let mut servers = self.servers.lock().unwrap();
+        if let Some(ref notifier) = servers.notifier {
+            let _ = notifier.notify(Message::Shutdown);
         }
+        servers.shut_down = true;
+    }
+}
+
+#[cfg(all(test, feature = "cipher-aes-gcm"))]
+mod test_udp_server {
+    use std::sync::Arc;
+    use std::thread::Thread;
+    use std::io::BufReader;
+    use std::io::net::ip::SocketAddr;
+    use std::io::net::udp::UdpSocket;
+
+    use config::{Config, ServerConfig, UserConfig};
+    use crypto::cipher::CipherType;
+    use relay::Relay;
+    use relay::socks5::{Address, UdpAssociateHeader};
+    use relay::replay_filter::ReplayFilter;
+    use relay::outbound_filter::OutboundFilter;
+    use relay::users::UserTables;
+    use relay::traffic::TrafficStats;
+    use relay::ratelimit::RateLimiter;
+    use relay::metrics::Metrics;
+    use relay::udprelay::{encrypt_payload, decrypt_payload};
+    use relay::udprelay::server::UdpRelayServer;
+
+    // The socket is bound before it returns, the server is started by `start_server` instead of `run`
+    fn start(server: &ServerConfig) -> UdpRelayServer {
+        let config = Config::new();
+        let traffic = Arc::new(TrafficStats::new());
+        let relay = UdpRelayServer::new(config.clone(), Arc::new(ReplayFilter::new(1024)),
+                                        Arc::new(OutboundFilter::new(false, None)),
+                                        Arc::new(UserTables::new(&[server.clone()], traffic.clone())),
+                                        traffic, Arc::new(RateLimiter::new(&config)), Arc::new(Metrics::new()));
+        relay.start_server(server).unwrap();
+        let running = relay.clone();
+        Thread::spawn(move || running.run());
+        relay
+    }
+
+    // Receives `n` datagrams, then echoes each of them back to its source
+    fn echo_target(n: usize) -> SocketAddr {
+        let mut socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.socket_name().unwrap();
+        Thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            let received = range(0, n).map(|_| {
+                let (len, src) = socket.recv_from(buf.as_mut_slice()).unwrap();
+                (buf[..len].to_vec(), src)
+            }).collect::<Vec<(Vec<u8>, SocketAddr)>>();
+            for &(ref data, src) in received.iter() {
+                socket.send_to(data.as_slice(), src).unwrap();
+            }
+        });
+        addr
+    }
+
+    fn client() -> UdpSocket {
+        let mut socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(5000));
+        socket
+    }
+
+    fn request(client: &mut UdpSocket, server: &ServerConfig, key: &[u8], target: SocketAddr, data: &[u8]) {
+        let mut buf = Vec::new();
+        UdpAssociateHeader::new(0, Address::SocketAddress(target.ip, target.port)).write_to(&mut buf).unwrap();
+        buf.push_all(data);
+        let packet = encrypt_payload(server.method, key, buf.as_slice()).unwrap();
+        client.send_to(packet.as_slice(), ("127.0.0.1", server.port)).unwrap();
+    }
+
+    // Returns the target address and the payload of a response
+    fn response(client: &mut UdpSocket, server: &ServerConfig, key: &[u8]) -> (Address, Vec<u8>) {
+        let mut buf = [0u8; 1024];
+        let (len, _) = client.recv_from(buf.as_mut_slice()).unwrap();
+        let data = decrypt_payload(server.method, key, &buf[..len]).unwrap();
+        let address = Address::read_from(&mut BufReader::new(data.as_slice())).unwrap();
+        let payload = data[address.len()..].to_vec();
+        (address, payload)
+    }
+
+    #[test]
+    fn test_clients_of_same_target() {
+        let server = ServerConfig::new("127.0.0.1".to_string(), 28388, "server-password".to_string(),
+                                       CipherType::Aes128Gcm);
+        let key = server.key();
+        let relay = start(&server);
+        let target = echo_target(2);
+
+        // Both requests reach the target before it responds to any of them
+        let (mut alice, mut bob) = (client(), client());
+        request(&mut alice, &server, key.as_slice(), target, b"alice");
+        request(&mut bob, &server, key.as_slice(), target, b"bob");
+
+        let (address, payload) = response(&mut alice, &server, key.as_slice());
+        assert_eq!(address, Address::SocketAddress(target.ip, target.port));
+        assert_eq!(payload, b"alice".to_vec());
+        let (_, payload) = response(&mut bob, &server, key.as_slice());
+        assert_eq!(payload, b"bob".to_vec());
+
+        relay.shutdown(0);
+    }
+
+    #[test]
+    fn test_users_of_same_target() {
+        let mut server = ServerConfig::new("127.0.0.1".to_string(), 28389, "server-password".to_string(),
+                                           CipherType::Aes128Gcm);
+        server.users = ["alice", "bob"].iter().map(|name| UserConfig {
+            name: name.to_string(),
+            password: format!("{}-password", name),
+            quota: None,
+        }).collect();
+        let (alice_key, bob_key) = (server.users[0].key(server.method), server.users[1].key(server.method));
+        let relay = start(&server);
+        let target = echo_target(2);
+
+        // Responses are encrypted with the key of the user of each client, not of who has requested last
+        let (mut alice, mut bob) = (client(), client());
+        request(&mut alice, &server, alice_key.as_slice(), target, b"alice");
+        request(&mut bob, &server, bob_key.as_slice(), target, b"bob");
+
+        let (_, payload) = response(&mut alice, &server, alice_key.as_slice());
+        assert_eq!(payload, b"alice".to_vec());
+        let (_, payload) = response(&mut bob, &server, bob_key.as_slice());
+        assert_eq!(payload, b"bob".to_vec());
+
+        relay.shutdown(0);
     }
 }
@@ -0,0 +1,489 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! Users of multi-user servers
+//!
+//! A multi-user server holds a table of users on one port, and every user has its own key. The
+//! user of a request is identified by its identity header in the 2022 edition, or by trying the
+//! keys of all users on the first AEAD chunk of a TCP stream or on a UDP packet.
+//!
+//! Users could be added, removed, enabled and disabled while the server is running. Disabled
+//! users are refused, and their established connections are closed on the next activity, and so
+//! are users who have exceeded their quotas of traffic.
+
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
+use std::io::{IoResult, IoError, OtherIoError};
+use std::io::net::ip::Port;
+
+use config::{ServerConfig, UserConfig};
+use crypto::cipher::{CipherType, CipherCategory};
+use crypto::aead;
+use crypto::aead2022::{self, IDENTITY_HEADER_SIZE};
+use relay::traffic::{Traffic, TrafficStats};
+
+/// A user of a multi-user server
+pub struct User {
+    name: String,
+    key: Vec<u8>,
+    // Hash of the key in identity headers of the 2022 edition
+    identity: Vec<u8>,
+    enabled: AtomicBool,
+    connections: AtomicUsize,
+    active_connections: AtomicUsize,
+    udp_packets: AtomicUsize,
+    traffic: Arc<Traffic>,
+}
+
+impl User {
+    fn new(config: &UserConfig, method: CipherType, traffic: Arc<Traffic>) -> User {
+        let key = config.key(method);
+        let identity = if method.category() == CipherCategory::Aead2022 {
+            aead2022::identity_hash(key.as_slice())
+        } else {
+            Vec::new()
+        };
+        traffic.set_quota(config.quota);
+
+        User {
+            name: config.name.clone(),
+            key: key,
+            identity: identity,
+            enabled: AtomicBool::new(true),
+            connections: AtomicUsize::new(0),
+            active_connections: AtomicUsize::new(0),
+            udp_packets: AtomicUsize::new(0),
+            traffic: traffic,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_slice()
+    }
+
+    /// Key of this user, which is the uPSK in the 2022 edition
+    pub fn key(&self) -> &[u8] {
+        self.key.as_slice()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Counts a UDP packet from this user
+    pub fn count_packet(&self) {
+        self.udp_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Bytes transferred by this user, which are also counted by the server
+    pub fn traffic(&self) -> &Traffic {
+        &*self.traffic
+    }
+
+    /// Whether this user could be served, which is enabled and within the quota
+    pub fn is_allowed(&self) -> bool {
+        self.is_enabled() && !self.traffic.is_exceeded()
+    }
+}
+
+/// Counts a connection of a user as active until it is dropped
+pub struct ActiveUser {
+    user: Arc<User>,
+}
+
+impl ActiveUser {
+    pub fn new(user: Arc<User>) -> ActiveUser {
+        user.connections.fetch_add(1, Ordering::Relaxed);
+        user.active_connections.fetch_add(1, Ordering::Relaxed);
+        ActiveUser {
+            user: user,
+        }
+    }
+
+    pub fn user(&self) -> &User {
+        &*self.user
+    }
+}
+
+impl Drop for ActiveUser {
+    fn drop(&mut self) {
+        self.user.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Statistics of a user
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UserStat {
+    pub name: String,
+    pub enabled: bool,
+    /// TCP connections since the server started
+    pub connections: usize,
+    pub active_connections: usize,
+    pub udp_packets: usize,
+    pub upload: usize,
+    pub download: usize,
+}
+
+#[inline]
+fn make_io_error(desc: &'static str, detail: Option<String>) -> IoError {
+    IoError {
+        kind: OtherIoError,
+        desc: desc,
+        detail: detail,
+    }
+}
+
+/// Users of a multi-user server
+pub struct UserTable {
+    method: CipherType,
+    port: Port,
+    traffic: Arc<TrafficStats>,
+    users: RwLock<Vec<Arc<User>>>,
+}
+
+impl UserTable {
+    /// Creates the table of users of the server on `port`, who have been validated for `method`
+    pub fn new(method: CipherType, port: Port, users: &[UserConfig], traffic: Arc<TrafficStats>) -> UserTable {
+        let users = users.iter().map(|u| {
+            Arc::new(User::new(u, method, traffic.user(port, u.name.as_slice())))
+        }).collect::<Vec<Arc<User>>>();
+        UserTable {
+            method: method,
+            port: port,
+            traffic: traffic,
+            users: RwLock::new(users),
+        }
+    }
+
+    fn make_user(&self, config: &UserConfig) -> User {
+        User::new(config, self.method, self.traffic.user(self.port, config.name.as_slice()))
+    }
+
+    /// Adds a user, returns `false` if there is already a user with the same name
+    pub fn add(&self, config: &UserConfig) -> bool {
+        let mut users = self.users.write().unwrap();
+        if users.iter().any(|u| u.name == config.name) {
+            return false;
+        }
+        users.push(Arc::new(self.make_user(config)));
+        true
+    }
+
+    /// Removes a user, whose established connections are closed like a disabled user
+    pub fn remove(&self, name: &str) -> bool {
+        let mut users = self.users.write().unwrap();
+        match users.iter().position(|u| u.name.as_slice() == name) {
+            Some(pos) => {
+                users.remove(pos).enabled.store(false, Ordering::Relaxed);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Enables or disables a user, returns `false` if the user doesn't exist
+    pub fn set_enabled(&self, name: &str, enabled: bool) -> bool {
+        match self.users.read().unwrap().iter().find(|u| u.name.as_slice() == name) {
+            Some(user) => {
+                user.enabled.store(enabled, Ordering::Relaxed);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn stats(&self) -> Vec<UserStat> {
+        self.users.read().unwrap().iter().map(|u| UserStat {
+            name: u.name.clone(),
+            enabled: u.is_enabled(),
+            connections: u.connections.load(Ordering::Relaxed),
+            active_connections: u.active_connections.load(Ordering::Relaxed),
+            udp_packets: u.udp_packets.load(Ordering::Relaxed),
+            upload: u.traffic.upload(),
+            download: u.traffic.download(),
+        }).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.read().unwrap().len()
+    }
+
+    /// Identifies the user of a TCP stream by its beginning `head`, `key` is the iPSK of the 2022
+    /// edition. Returns `None` if more data is needed.
+    ///
+    /// The identity header of the 2022 edition is not a part of the stream, it should be skipped
+    /// by `identity_header_size` bytes after the salt.
+    pub fn identify_stream(&self, key: &[u8], head: &[u8]) -> IoResult<Option<Arc<User>>> {
+        let salt_size = self.method.iv_size();
+        let users = self.users.read().unwrap();
+
+        let user = if self.method.category() == CipherCategory::Aead2022 {
+            if head.len() < salt_size + IDENTITY_HEADER_SIZE {
+                return Ok(None);
+            }
+            let (salt, header) = (&head[..salt_size], &head[salt_size..salt_size + IDENTITY_HEADER_SIZE]);
+            let identity = try!(aead2022::read_stream_identity(self.method, key, salt, header).map_err(|err| {
+                make_io_error("Invalid identity header", Some(err.to_string()))
+            }));
+            users.iter().find(|u| u.identity == identity)
+        } else {
+            // [salt][encrypted length][tag]
+            let len = salt_size + 2 + self.method.tag_size();
+            if head.len() < len {
+                return Ok(None);
+            }
+            let (salt, chunk) = (&head[..salt_size], &head[salt_size..len]);
+            users.iter().find(|u| aead::with_type(self.method, u.key(), salt).decrypt(chunk).is_ok())
+        };
+
+        match user {
+            Some(user) => Ok(Some(user.clone())),
+            None => Err(make_io_error("No user matches the request", None)),
+        }
+    }
+
+    /// Length of the identity header after the salt of TCP streams
+    pub fn identity_header_size(&self) -> usize {
+        match self.method.category() {
+            CipherCategory::Aead2022 => IDENTITY_HEADER_SIZE,
+            _ => 0,
+        }
+    }
+
+    /// Identifies the user of an AEAD UDP packet, returns the user and the decrypted payload
+    pub fn identify_packet(&self, packet: &[u8]) -> Option<(Arc<User>, Vec<u8>)> {
+        let salt_size = self.method.iv_size();
+        if packet.len() < salt_size + self.method.tag_size() {
+            return None;
+        }
+
+        let (salt, data) = packet.split_at(salt_size);
+        for user in self.users.read().unwrap().iter() {
+            if let Ok(payload) = aead::with_type(self.method, user.key(), salt).decrypt(data) {
+                return Some((user.clone(), payload));
+            }
+        }
+        None
+    }
+
+    /// Finds the user by the hash in an identity header of the 2022 edition
+    pub fn find_identity(&self, identity: &[u8]) -> Option<Arc<User>> {
+        self.users.read().unwrap().iter().find(|u| u.identity.as_slice() == identity).map(|u| u.clone())
+    }
+}
+
+/// Tables of all multi-user servers, shared by the TCP and UDP relays
+pub struct UserTables {
+    traffic: Arc<TrafficStats>,
+    tables: RwLock<Vec<(String, Port, Arc<UserTable>)>>,
+}
+
+impl UserTables {
+    pub fn new(servers: &[ServerConfig], traffic: Arc<TrafficStats>) -> UserTables {
+        let tables = servers.iter().filter(|s| !s.users.is_empty()).map(|s| {
+            let table = UserTable::new(s.method, s.port, s.users.as_slice(), traffic.clone());
+            (s.addr.clone(), s.port, Arc::new(table))
+        }).collect();
+        UserTables {
+            traffic: traffic,
+            tables: RwLock::new(tables),
+        }
+    }
+
+    /// Table of the server, `None` if it is not a multi-user server
+    pub fn get(&self, addr: &str, port: Port) -> Option<Arc<UserTable>> {
+        let tables = self.tables.read().unwrap();
+        tables.iter().find(|&&(ref a, p, _)| a.as_slice() == addr && p == port).map(|&(_, _, ref t)| t.clone())
+    }
+
+    /// Addresses and ports of all multi-user servers
+    pub fn servers(&self) -> Vec<(String, Port)> {
+        self.tables.read().unwrap().iter().map(|&(ref a, p, _)| (a.clone(), p)).collect()
+    }
+
+    /// Replaces the table of the server with the users of `config`, for a reloaded configuration.
+    /// Connections which have been identified keep their users.
+    pub fn set(&self, config: &ServerConfig) {
+        let mut tables = self.tables.write().unwrap();
+        tables.retain(|&(ref a, p, _)| !(*a == config.addr && p == config.port));
+        if !config.users.is_empty() {
+            let table = UserTable::new(config.method, config.port, config.users.as_slice(), self.traffic.clone());
+            tables.push((config.addr.clone(), config.port, Arc::new(table)));
+        }
+    }
+
+    /// Removes the table of the server on `port`
+    pub fn remove(&self, port: Port) {
+        self.tables.write().unwrap().retain(|&(_, p, _)| p != port);
+    }
+}
+
+#[cfg(all(test, feature = "cipher-aes-gcm", feature = "cipher-2022-blake3-aes-256-gcm"))]
+mod test_users {
+    use std::sync::Arc;
+
+    use config::{ServerConfig, UserConfig};
+    use crypto::cipher::CipherType;
+    use crypto::aead;
+    use crypto::aead2022;
+    use relay::users::{UserTables, UserTable, ActiveUser};
+    use relay::traffic::TrafficStats;
+
+    fn user(name: &str, password: &str) -> UserConfig {
+        UserConfig {
+            name: name.to_string(),
+            password: password.to_string(),
+            quota: None,
+        }
+    }
+
+    fn aead_table() -> UserTable {
+        let users = [user("alice", "alice-password"), user("bob", "bob-password")];
+        UserTable::new(CipherType::Aes128Gcm, 8388, &users, Arc::new(TrafficStats::new()))
+    }
+
+    // Salt and the first chunk of a stream, which is what identifies the user
+    fn aead_head(password: &str) -> Vec<u8> {
+        let t = CipherType::Aes128Gcm;
+        let key = t.bytes_to_key(password.as_bytes());
+        let mut head = t.gen_init_vec();
+        let chunk = aead::with_type(t, key.as_slice(), head.as_slice()).encrypt(&[0, 5]).unwrap();
+        head.push_all(chunk.as_slice());
+        head
+    }
+
+    #[test]
+    fn test_identify_aead_stream() {
+        let table = aead_table();
+        let head = aead_head("bob-password");
+
+        assert!(table.identify_stream(&[], &head[..head.len() - 1]).unwrap().is_none());
+        let user = table.identify_stream(&[], head.as_slice()).unwrap().unwrap();
+        assert_eq!(user.name(), "bob");
+
+        assert!(table.identify_stream(&[], aead_head("eve-password").as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_identify_aead_packet() {
+        let t = CipherType::Aes128Gcm;
+        let table = aead_table();
+        let key = t.bytes_to_key(b"alice-password");
+        let mut packet = t.gen_init_vec();
+        let sealed = aead::with_type(t, key.as_slice(), packet.as_slice()).encrypt(b"payload").unwrap();
+        packet.push_all(sealed.as_slice());
+
+        let (user, payload) = table.identify_packet(packet.as_slice()).unwrap();
+        assert_eq!(user.name(), "alice");
+        assert_eq!(payload.as_slice(), b"payload");
+    }
+
+    #[test]
+    fn test_identify_2022_stream() {
+        let t = CipherType::Blake3Aes256Gcm;
+        let ipsk = aead2022::decode_psk(t, "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=").unwrap();
+        let upsk = "ICEiIyQlJicoKSorLC0uLzAxMjM0NTY3ODk6Ozw9Pj8=";
+        let table = UserTable::new(t, 8388, &[user("carol", upsk)], Arc::new(TrafficStats::new()));
+
+        let mut head = t.gen_init_vec();
+        let identity = aead2022::make_stream_identity(t, ipsk.as_slice(), head.as_slice(),
+                                                      aead2022::decode_psk(t, upsk).unwrap().as_slice()).unwrap();
+        head.push_all(identity.as_slice());
+
+        let user = table.identify_stream(ipsk.as_slice(), head.as_slice()).unwrap().unwrap();
+        assert_eq!(user.name(), "carol");
+        assert_eq!(table.identity_header_size(), aead2022::IDENTITY_HEADER_SIZE);
+    }
+
+    #[test]
+    fn test_manage_users() {
+        let table = aead_table();
+        assert!(!table.add(&user("alice", "another-password")));
+        assert!(table.add(&user("carol", "carol-password")));
+        assert_eq!(table.len(), 3);
+
+        let carol = table.identify_stream(&[], aead_head("carol-password").as_slice()).unwrap().unwrap();
+        {
+            let _active = ActiveUser::new(carol.clone());
+            let stat = table.stats().into_iter().find(|s| s.name.as_slice() == "carol").unwrap();
+            assert_eq!((stat.connections, stat.active_connections), (1, 1));
+        }
+        let stat = table.stats().into_iter().find(|s| s.name.as_slice() == "carol").unwrap();
+        assert_eq!((stat.connections, stat.active_connections), (1, 0));
+
+        assert!(table.set_enabled("carol", false));
+        assert!(!carol.is_enabled());
+        assert!(table.set_enabled("carol", true));
+        assert!(!table.set_enabled("dave", false));
+
+        assert!(table.remove("carol"));
+        assert!(!carol.is_enabled());
+        assert!(table.identify_stream(&[], aead_head("carol-password").as_slice()).is_err());
+        assert_eq!(Arc::strong_count(&carol), 1);
+    }
+
+    #[test]
+    fn test_user_quota() {
+        let traffic = Arc::new(TrafficStats::new());
+        let mut limited = user("alice", "alice-password");
+        limited.quota = Some(100);
+        let table = UserTable::new(CipherType::Aes128Gcm, 8388, &[limited], traffic.clone());
+
+        let alice = table.identify_stream(&[], aead_head("alice-password").as_slice()).unwrap().unwrap();
+        alice.traffic().add_upload(60);
+        assert!(alice.is_allowed());
+        alice.traffic().add_download(40);
+        assert!(!alice.is_allowed());
+
+        // Counters are kept in the shared statistics, also for users added later
+        assert_eq!(traffic.user(8388, "alice").total(), 100);
+        assert!(table.add(&user("bob", "bob-password")));
+        traffic.user(8388, "bob").add_upload(7);
+        let stat = table.stats().into_iter().find(|s| s.name.as_slice() == "bob").unwrap();
+        assert_eq!((stat.upload, stat.download), (7, 0));
+    }
+
+    #[test]
+    fn test_replace_tables() {
+        let mut server = ServerConfig::new("127.0.0.1".to_string(), 8388, "server-password".to_string(),
+                                           CipherType::Aes128Gcm);
+        server.users = vec![user("alice", "alice-password"), user("bob", "bob-password")];
+        let tables = UserTables::new(&[server.clone()], Arc::new(TrafficStats::new()));
+        let old = tables.get("127.0.0.1", 8388).unwrap();
+
+        // Connections holding the old table are not affected
+        server.users.pop();
+        tables.set(&server);
+        assert_eq!(tables.get("127.0.0.1", 8388).unwrap().len(), 1);
+        assert_eq!(old.len(), 2);
+
+        server.users.clear();
+        tables.set(&server);
+        assert!(tables.get("127.0.0.1", 8388).is_none());
+
+        server.users.push(user("carol", "carol-password"));
+        tables.set(&server);
+        assert_eq!(tables.servers(), vec![("127.0.0.1".to_string(), 8388)]);
+        tables.remove(8388);
+        assert!(tables.servers().is_empty());
+    }
+}